@@ -0,0 +1,284 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+
+/// Longest address accepted: a 256-octet path minus the angle brackets
+/// (RFC 5321 §4.5.3.1.3).
+pub const MAX_ADDRESS_LEN: usize = 254;
+/// RFC 5321 §4.5.3.1.1.
+pub const MAX_LOCAL_PART_LEN: usize = 64;
+/// RFC 5321 §4.5.3.1.2.
+pub const MAX_DOMAIN_LEN: usize = 255;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum AddressError {
+    #[error("invalid domain {0:?}")]
+    InvalidDomain(String),
+    #[error("invalid local part {0:?}")]
+    InvalidLocalPart(String),
+    #[error("missing domain")]
+    MissingDomain,
+    #[error("address too long")]
+    TooLong,
+}
+
+/// Check an address (without angle brackets) against the RFC 5321 mailbox
+/// syntax: a dot-atom or quoted-string local part, and a domain of
+/// letter/digit/hyphen labels or an address literal. UTF-8 is allowed in
+/// atoms and labels (RFC 6531). A bare `postmaster` is valid without a
+/// domain.
+pub fn validate(address: &str) -> Result<(), AddressError> {
+    if address.len() > MAX_ADDRESS_LEN {
+        return Err(AddressError::TooLong);
+    }
+    let Some((local, domain)) = address.rsplit_once('@') else {
+        if address.eq_ignore_ascii_case("postmaster") {
+            return Ok(());
+        }
+        return Err(AddressError::MissingDomain);
+    };
+    if local.len() > MAX_LOCAL_PART_LEN {
+        return Err(AddressError::TooLong);
+    }
+    if !is_dot_atom(local) && !is_quoted_string(local) {
+        return Err(AddressError::InvalidLocalPart(local.to_string()));
+    }
+    validate_domain(domain)
+}
+
+/// Check a domain name or address literal. A single trailing dot is tolerated.
+pub fn validate_domain(domain: &str) -> Result<(), AddressError> {
+    if domain.len() > MAX_DOMAIN_LEN {
+        return Err(AddressError::TooLong);
+    }
+    let invalid = || AddressError::InvalidDomain(domain.to_string());
+    if let Some(literal) = domain.strip_prefix('[') {
+        let literal = literal.strip_suffix(']').ok_or_else(invalid)?;
+        let valid = match literal.get(..5) {
+            Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => {
+                literal[5..].parse::<Ipv6Addr>().is_ok()
+            }
+            _ => literal.parse::<Ipv4Addr>().is_ok(),
+        };
+        return if valid { Ok(()) } else { Err(invalid()) };
+    }
+    let name = domain.strip_suffix('.').unwrap_or(domain);
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || !c.is_ascii())
+    };
+    if name.split('.').all(valid_label) {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
+
+fn is_dot_atom(s: &str) -> bool {
+    !s.is_empty()
+        && s.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || is_atext_special(c) || !c.is_ascii())
+        })
+}
+
+/// A quoted string of printable characters and spaces, where `\` escapes
+/// any printable character (RFC 5321 `Quoted-string`).
+fn is_quoted_string(s: &str) -> bool {
+    let Some(inner) = s.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) else {
+        return false;
+    };
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // The guard consumes the escaped character
+            '\\' if !chars.next().is_some_and(|e| (' '..='~').contains(&e)) => return false,
+            '"' => return false,
+            c if c.is_control() => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Byte range of the `<...>` path in MAIL/RCPT arguments, brackets
+/// included. A `>` inside a quoted local part (`<"a>b"@x>`) does not close
+/// the path. `None` when there is no `<` or it is never closed.
+pub fn find_path(args: &str) -> Option<Range<usize>> {
+    let start = args.find('<')?;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in args[start + 1..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '>' if !quoted => return Some(start..start + 1 + i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Drop an obsolete source route (`@relay.example,@other.example:`) from
+/// the front of a path's content, leaving the final mailbox (RFC 5321
+/// §4.1.2, §C). A route with a malformed hop is left in place so the
+/// address fails validation.
+pub fn strip_source_route(path: &str) -> &str {
+    if !path.starts_with('@') {
+        return path;
+    }
+    let Some((route, mailbox)) = path.split_once(':') else {
+        return path;
+    };
+    let well_formed = route.split(',').all(|hop| {
+        hop.strip_prefix('@')
+            .is_some_and(|domain| validate_domain(domain).is_ok())
+    });
+    if well_formed {
+        mailbox
+    } else {
+        path
+    }
+}
+
+/// Split an address at its last `@` into local part and domain.
+///
+/// An address without `@` (e.g. `postmaster`) has an empty domain.
+pub fn split(address: &str) -> (&str, &str) {
+    address.rsplit_once('@').unwrap_or((address, ""))
+}
+
+/// The domain of an address, or `""` if it has none.
+pub fn domain(address: &str) -> &str {
+    split(address).1
+}
+
+/// Canonical form of a domain: lowercase A-labels (`xn--...`) without a
+/// trailing dot. Unicode and punycode spellings of the same domain normalize
+/// to the same string. Address literals (`[192.0.2.1]`) are only lowercased.
+pub fn normalize_domain(domain: &str) -> Result<String, AddressError> {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    if domain.starts_with('[') || domain.is_ascii() && !domain.contains("xn--") {
+        return Ok(domain.to_ascii_lowercase());
+    }
+    idna::domain_to_ascii(domain).map_err(|_| AddressError::InvalidDomain(domain.to_string()))
+}
+
+/// Unicode spelling of a normalized domain (`xn--bcher-kva.example` is
+/// `bücher.example`), for matching entries that other systems store in
+/// Unicode. Labels that fail to decode are kept as A-labels. Domains without
+/// A-labels are borrowed.
+pub fn domain_to_unicode(domain: &str) -> Cow<'_, str> {
+    if !domain.contains("xn--") {
+        return Cow::Borrowed(domain);
+    }
+    Cow::Owned(idna::domain_to_unicode(domain).0)
+}
+
+/// `address` with its domain spelled in Unicode (see [`domain_to_unicode`]).
+/// Borrowed when the domain has no A-labels.
+pub fn with_unicode_domain(address: &str) -> Cow<'_, str> {
+    let (local, domain) = split(address);
+    match domain_to_unicode(domain) {
+        Cow::Borrowed(_) => Cow::Borrowed(address),
+        Cow::Owned(domain) => Cow::Owned(format!("{}@{}", local, domain)),
+    }
+}
+
+/// Canonical form of an address, used for matching, lookups and logging.
+///
+/// The domain goes through [`normalize_domain`]. A quoted local part whose
+/// content needs no quoting is unquoted (`"john"@x` is `john@x`). The local
+/// part is lowercased unless `preserve_local_case` is set.
+pub fn normalize(address: &str, preserve_local_case: bool) -> Result<String, AddressError> {
+    let (local, domain) = split(address);
+    let local = unquote(local);
+    let local = if preserve_local_case {
+        local.to_string()
+    } else {
+        local.to_lowercase()
+    };
+    if !address.contains('@') {
+        return Ok(local);
+    }
+    Ok(format!("{}@{}", local, normalize_domain(domain)?))
+}
+
+/// Case-folded canonical form, the shape of every Redis key and set member.
+///
+/// Falls back to plain lowercasing when the domain is not a valid IDN, so
+/// lookups never panic on input the session did not validate. An address
+/// that is already in this form (anything the session normalized without
+/// `preserve_local_case`) is borrowed rather than rebuilt.
+pub fn lookup_form(address: &str) -> Cow<'_, str> {
+    if is_lookup_form(address) {
+        return Cow::Borrowed(address);
+    }
+    Cow::Owned(normalize(address, false).unwrap_or_else(|_| address.to_lowercase()))
+}
+
+/// Lowercase ASCII with an unquoted local part and no trailing dot: every
+/// step of [`normalize`] leaves such an address unchanged.
+fn is_lookup_form(address: &str) -> bool {
+    address.is_ascii()
+        && !address.bytes().any(|b| b.is_ascii_uppercase())
+        && !address.starts_with('"')
+        && !address.ends_with('.')
+}
+
+/// Log field that prints addresses as `[a, b]`, formatted only when the
+/// line is emitted instead of collected into a `Vec` up front.
+pub struct AddressList<I>(pub I);
+
+impl<I> fmt::Display for AddressList<I>
+where
+    I: Iterator + Clone,
+    I::Item: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, address) in self.0.clone().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", address)?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Drop the quotes from a quoted local part when its content is a plain
+/// dot-atom. Anything that genuinely needs quoting is returned unchanged.
+fn unquote(local: &str) -> &str {
+    let Some(inner) = local
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    else {
+        return local;
+    };
+    let dot_atom = !inner.is_empty()
+        && inner.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || is_atext_special(c))
+        });
+    if dot_atom {
+        inner
+    } else {
+        local
+    }
+}
+
+/// RFC 5322 `atext` punctuation.
+fn is_atext_special(c: char) -> bool {
+    "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
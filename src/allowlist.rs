@@ -0,0 +1,185 @@
+//! Static allowlist of always-accepted recipients from `ALLOWLIST_FILE`,
+//! reloaded when the file changes.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+use crate::address;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AllowlistError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid entry {entry:?} on line {line}")]
+    Invalid { line: usize, entry: String },
+}
+
+/// Addresses and domains accepted whatever the lookup backend says.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Entries {
+    /// In lookup form.
+    pub addresses: HashSet<String>,
+    /// Normalized, in punycode.
+    pub domains: HashSet<String>,
+}
+
+impl Entries {
+    /// Parse a JSON object with `addresses` and `domains` arrays, or else a
+    /// plaintext list: one entry per line, with `#` comments. Entries with
+    /// an `@` are addresses, others domains. For JSON, `line` in errors is
+    /// the position in its array, and 0 for a field that is not an array.
+    pub fn parse(text: &str) -> Result<Self, AllowlistError> {
+        let mut entries = Self::default();
+        if text.trim_start().starts_with('{') {
+            let file: serde_json::Value = serde_json::from_str(text)?;
+            for field in ["addresses", "domains"] {
+                let Some(list) = file.get(field) else {
+                    continue;
+                };
+                let list = list.as_array().ok_or_else(|| AllowlistError::Invalid {
+                    line: 0,
+                    entry: field.to_string(),
+                })?;
+                for (i, entry) in list.iter().enumerate() {
+                    let entry = match entry {
+                        serde_json::Value::String(entry) => entry.as_str(),
+                        _ => "",
+                    };
+                    if field == "addresses" {
+                        entries.add_address(i + 1, entry)?;
+                    } else {
+                        entries.add_domain(i + 1, entry)?;
+                    }
+                }
+            }
+            return Ok(entries);
+        }
+        for (i, line) in text.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if entry.contains('@') {
+                entries.add_address(i + 1, entry)?;
+            } else {
+                entries.add_domain(i + 1, entry)?;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn add_address(&mut self, line: usize, entry: &str) -> Result<(), AllowlistError> {
+        let entry = entry.trim();
+        let normalized = address::validate(entry)
+            .and_then(|_| address::normalize(entry, false))
+            .map_err(|_| AllowlistError::Invalid {
+                line,
+                entry: entry.to_string(),
+            })?;
+        self.addresses.insert(normalized);
+        Ok(())
+    }
+
+    fn add_domain(&mut self, line: usize, entry: &str) -> Result<(), AllowlistError> {
+        let entry = entry.trim();
+        let normalized = address::validate_domain(entry)
+            .and_then(|_| address::normalize_domain(entry))
+            .map_err(|_| AllowlistError::Invalid {
+                line,
+                entry: entry.to_string(),
+            })?;
+        self.domains.insert(normalized);
+        Ok(())
+    }
+
+    /// Whether `address`, in lookup form, or its domain is listed.
+    pub fn contains(&self, address: &str) -> bool {
+        self.addresses.contains(address) || self.domains.contains(address::domain(address))
+    }
+}
+
+/// What tells a changed file apart: modification time and size.
+type Stamp = Option<(SystemTime, u64)>;
+
+/// The entries of an allowlist file, swapped whole on reload.
+pub struct Allowlist {
+    path: PathBuf,
+    entries: RwLock<Arc<Entries>>,
+    /// The file as of the last read, successful or not.
+    stamp: Mutex<Stamp>,
+}
+
+impl Allowlist {
+    /// Read and parse the file at `path`.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, AllowlistError> {
+        let path = path.into();
+        let stamp = std::fs::metadata(&path).ok().and_then(|m| stamp(&m));
+        let entries = Entries::parse(&std::fs::read_to_string(&path)?)?;
+        Ok(Self {
+            path,
+            entries: RwLock::new(Arc::new(entries)),
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    /// Whether `address` is allowlisted.
+    pub fn contains(&self, address: &str) -> bool {
+        self.entries().contains(&address::lookup_form(address))
+    }
+
+    pub fn entries(&self) -> Arc<Entries> {
+        self.entries.read().unwrap().clone()
+    }
+
+    /// Read the file again. On error the current entries are kept.
+    pub async fn reload(&self) -> Result<(), AllowlistError> {
+        *self.stamp.lock().unwrap() = self.current_stamp().await;
+        let entries = Entries::parse(&tokio::fs::read_to_string(&self.path).await?)?;
+        info!(
+            path = %self.path.display(),
+            addresses = entries.addresses.len(),
+            domains = entries.domains.len(),
+            "allowlist reloaded"
+        );
+        *self.entries.write().unwrap() = Arc::new(entries);
+        Ok(())
+    }
+
+    /// Every `interval`, reload the file if its modification time or size
+    /// changed since it was last read. Runs until the runtime shuts down.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let current = self.current_stamp().await;
+            if current.is_none() || current == *self.stamp.lock().unwrap() {
+                continue;
+            }
+            if let Err(e) = self.reload().await {
+                warn!(
+                    path = %self.path.display(),
+                    error = %e,
+                    "[ALLOWLIST-ERROR] allowlist not reloaded, keeping previous entries"
+                );
+            }
+        }
+    }
+
+    async fn current_stamp(&self) -> Stamp {
+        tokio::fs::metadata(&self.path)
+            .await
+            .ok()
+            .and_then(|m| stamp(&m))
+    }
+}
+
+fn stamp(metadata: &std::fs::Metadata) -> Stamp {
+    Some((metadata.modified().ok()?, metadata.len()))
+}
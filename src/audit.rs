@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::store::{SharedStore, StoreError};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Most transactions kept in one audit entry; later ones are only counted
+/// in `messages_relayed` and the command totals.
+pub const MAX_TRANSACTIONS: usize = 50;
+
+/// Bytes read from and written to a client connection.
+#[derive(Default)]
+pub struct ByteCounters {
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+/// Stream wrapper that counts bytes in both directions.
+///
+/// Sits below TLS, so the counts are what actually crossed the wire.
+pub struct CountingStream<S> {
+    inner: S,
+    counters: Arc<ByteCounters>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S, counters: Arc<ByteCounters>) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.counters
+            .bytes_in
+            .fetch_add(read as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.counters
+                .bytes_out
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// One mail transaction, from MAIL FROM to its end.
+pub struct AuditTransaction {
+    pub sender: String,
+    /// Accepted recipients, sorted.
+    pub recipients: Vec<String>,
+    pub rcpt_rejected: u32,
+    /// Message size in bytes, once DATA was received.
+    pub size: Option<usize>,
+    /// How it ended: `relayed`, `relay_failed`, `discarded`, `too_large`,
+    /// `bare_line_ending`, `timeout`, or `aborted` (RSET, new EHLO, or the
+    /// client leaving before the message was complete).
+    pub outcome: &'static str,
+}
+
+impl AuditTransaction {
+    fn to_json(&self, anonymize: bool) -> serde_json::Value {
+        let address = |a: &String| {
+            if anonymize {
+                anonymize_address(a)
+            } else {
+                a.clone()
+            }
+        };
+        serde_json::json!({
+            "sender": address(&self.sender),
+            "recipients": self.recipients.iter().map(address).collect::<Vec<_>>(),
+            "rcpt_rejected": self.rcpt_rejected,
+            "size": self.size,
+            "outcome": self.outcome,
+        })
+    }
+}
+
+/// One connection-level audit record, written when the session closes.
+pub struct AuditEntry {
+    pub peer: SocketAddr,
+    /// Session start, seconds since the Unix epoch.
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub tls: bool,
+    pub rcpt_accepted: u32,
+    pub rcpt_rejected: u32,
+    pub messages_relayed: u32,
+    /// How the session ended: `completed`, `error`, or `timeout`.
+    pub outcome: &'static str,
+    /// Commands received, by verb. Unrecognized verbs count as `UNKNOWN`.
+    pub commands: BTreeMap<&'static str, u32>,
+    /// Mail transactions in order, at most [`MAX_TRANSACTIONS`].
+    pub transactions: Vec<AuditTransaction>,
+}
+
+impl AuditEntry {
+    /// Serialize to JSON, truncating the client IP when `anonymize` is set.
+    pub fn to_json(&self, anonymize: bool) -> String {
+        let ip = if anonymize {
+            anonymize_ip(self.peer.ip())
+        } else {
+            self.peer.ip()
+        };
+        serde_json::json!({
+            "ip": ip.to_string(),
+            "started_at": self.started_at,
+            "duration_ms": self.duration_ms,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "tls": self.tls,
+            "rcpt_accepted": self.rcpt_accepted,
+            "rcpt_rejected": self.rcpt_rejected,
+            "messages_relayed": self.messages_relayed,
+            "outcome": self.outcome,
+            "commands": self.commands,
+            "transactions": self
+                .transactions
+                .iter()
+                .map(|t| t.to_json(anonymize))
+                .collect::<Vec<_>>(),
+        })
+        .to_string()
+    }
+}
+
+/// Truncate an IP for anonymized storage: IPv4 to /24, IPv6 to /48.
+pub fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+/// Drop the local part of an address for anonymized storage, keeping the
+/// domain (`*@example.com`). The null sender stays empty.
+pub fn anonymize_address(address: &str) -> String {
+    match address.rsplit_once('@') {
+        Some((_, domain)) => format!("*@{}", domain),
+        None if address.is_empty() => String::new(),
+        None => "*".to_string(),
+    }
+}
+
+/// Rewrite the `ip` field of a stored JSON entry with its truncated form,
+/// and the envelope addresses of its transactions with their domains only.
+///
+/// Entries that don't parse are returned unchanged.
+pub fn anonymize_entry(json: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<serde_json::Value>(json) else {
+        return json.to_string();
+    };
+    let truncated = value
+        .get("ip")
+        .and_then(|ip| ip.as_str())
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+        .map(anonymize_ip);
+    if let Some(ip) = truncated {
+        value["ip"] = serde_json::Value::String(ip.to_string());
+    }
+    let anonymize = |v: &mut serde_json::Value| {
+        if let Some(address) = v.as_str() {
+            *v = serde_json::Value::String(anonymize_address(address));
+        }
+    };
+    if let Some(transactions) = value.get_mut("transactions").and_then(|t| t.as_array_mut()) {
+        for txn in transactions {
+            if let Some(sender) = txn.get_mut("sender") {
+                anonymize(sender);
+            }
+            if let Some(recipients) = txn.get_mut("recipients").and_then(|r| r.as_array_mut()) {
+                recipients.iter_mut().for_each(anonymize);
+            }
+        }
+    }
+    value.to_string()
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Connection audit trail stored in Redis.
+///
+/// Entries are appended to one list per UTC day (`{prefix}:{days since epoch}`)
+/// that expires after the retention period. Once a day is older than
+/// `anonymize_after_days`, its entries are rewritten with truncated IPs.
+#[derive(Clone)]
+pub struct AuditLog {
+    store: SharedStore,
+    key_prefix: String,
+    retention_days: u64,
+    anonymize_after_days: u64,
+}
+
+impl AuditLog {
+    pub fn new(store: SharedStore, config: &Config) -> Self {
+        Self {
+            store,
+            key_prefix: config.audit_key_prefix.clone(),
+            retention_days: config.audit_retention_days,
+            anonymize_after_days: config.audit_anonymize_after_days,
+        }
+    }
+
+    fn day_key(&self, day: u64) -> String {
+        format!("{}:{}", self.key_prefix, day)
+    }
+
+    fn retention_secs(&self) -> u64 {
+        self.retention_days.max(1) * SECS_PER_DAY
+    }
+
+    /// Append an entry to today's list. Errors are logged, never propagated.
+    pub async fn record(&self, entry: &AuditEntry) {
+        let key = self.day_key(entry.started_at / SECS_PER_DAY);
+        let json = entry.to_json(self.anonymize_after_days == 0);
+        let result = self
+            .store
+            .list_push(&key, &json, self.retention_secs())
+            .await;
+        if let Err(e) = result {
+            warn!(error = %e, key = %key, "failed to write audit entry");
+        }
+    }
+
+    /// Anonymize every day list that has aged past `anonymize_after_days`.
+    ///
+    /// Each day is rewritten once; a `{key}:anon` marker with the same TTL
+    /// records that it has been done.
+    pub async fn anonymize_expired(&self) -> Result<(), StoreError> {
+        if self.anonymize_after_days == 0 || self.anonymize_after_days >= self.retention_days {
+            return Ok(());
+        }
+        let today = unix_now() / SECS_PER_DAY;
+        let newest = today.saturating_sub(self.anonymize_after_days);
+        let oldest = today.saturating_sub(self.retention_days);
+
+        for day in oldest..newest {
+            let key = self.day_key(day);
+            let marker = format!("{}:anon", key);
+            if self.store.exists(&marker).await? {
+                continue;
+            }
+            let entries = self.store.list_all(&key).await?;
+            let Some(ttl) = self.store.ttl(&key).await? else {
+                continue;
+            };
+            if entries.is_empty() {
+                continue;
+            }
+            let anonymized: Vec<String> = entries.iter().map(|e| anonymize_entry(e)).collect();
+            self.store.list_replace(&key, &anonymized, ttl).await?;
+            self.store.set_ex(&marker, "1", ttl).await?;
+            info!(key = %key, entries = entries.len(), "audit entries anonymized");
+        }
+        debug!("audit anonymization pass complete");
+        Ok(())
+    }
+}
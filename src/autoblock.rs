@@ -0,0 +1,147 @@
+//! Temporary blocklist of client IPs that earned too many user-unknown
+//! rejections (`AUTO_BLOCK_THRESHOLD`) or wrote to a spamtrap, kept in the
+//! store with a TTL so every instance refuses them at connect.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::banhook::BanHook;
+use crate::config::Config;
+use crate::ratelimit::StoreRateLimiter;
+use crate::rejectlog;
+use crate::session::Metrics;
+use crate::store::SharedStore;
+
+#[derive(Clone)]
+pub struct AutoBlocklist {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: SharedStore,
+    /// Rejections per IP; a denied one is the rejection that crosses the
+    /// threshold.
+    strikes: StoreRateLimiter,
+    /// Prefix of the block keys, `{prefix}ip:{ip}`.
+    key_prefix: String,
+    ttl_secs: u64,
+    metrics: Arc<Metrics>,
+    /// IPs this instance blocked, until `sweep` finds their block gone.
+    blocked: Mutex<HashSet<IpAddr>>,
+    /// Run for each IP this instance blocks.
+    hook: Option<BanHook>,
+}
+
+impl AutoBlocklist {
+    /// `None` when `AUTO_BLOCK_THRESHOLD` is 0.
+    pub fn from_config(store: SharedStore, config: &Config, metrics: Arc<Metrics>) -> Option<Self> {
+        let threshold = config.auto_block_threshold;
+        if threshold == 0 {
+            return None;
+        }
+        let prefix = &config.auto_block_key_prefix;
+        Some(Self {
+            inner: Arc::new(Inner {
+                strikes: StoreRateLimiter::new(
+                    store.clone(),
+                    &format!("{}strikes:", prefix),
+                    threshold - 1,
+                    Duration::from_secs(config.auto_block_window_secs),
+                ),
+                store,
+                key_prefix: format!("{}ip:", prefix),
+                ttl_secs: config.auto_block_ttl_secs,
+                hook: BanHook::from_config(config, metrics.clone()),
+                metrics,
+                blocked: Mutex::new(HashSet::new()),
+            }),
+        })
+    }
+
+    fn key(&self, ip: IpAddr) -> String {
+        format!("{}{}", self.inner.key_prefix, ip)
+    }
+
+    /// Whether `ip` is blocked. A store error lets the IP in.
+    pub async fn is_blocked(&self, ip: IpAddr) -> bool {
+        match self.inner.store.exists(&self.key(ip)).await {
+            Ok(blocked) => blocked,
+            Err(e) => {
+                warn!(ip = %ip, error = %e, "auto-block check failed, allowing");
+                false
+            }
+        }
+    }
+
+    /// Count a user-unknown rejection against `ip`, blocking it once it
+    /// reaches the threshold. Returns true if this blocked the IP.
+    pub async fn strike(&self, ip: IpAddr) -> bool {
+        let inner = &self.inner;
+        match inner.strikes.check_and_increment(&ip.to_string()).await {
+            Ok(true) => return false,
+            Ok(false) => {}
+            Err(e) => {
+                warn!(ip = %ip, error = %e, "auto-block strike not counted");
+                return false;
+            }
+        }
+        self.block(ip, "unknown_recipients").await
+    }
+
+    /// Block `ip` for `AUTO_BLOCK_TTL_SECS` right away, e.g. for writing to
+    /// a spamtrap. Returns true if this blocked the IP.
+    pub async fn block(&self, ip: IpAddr, reason: &'static str) -> bool {
+        let inner = &self.inner;
+        if inner.blocked.lock().unwrap().contains(&ip) {
+            return false;
+        }
+        if let Err(e) = inner.store.set_ex(&self.key(ip), "1", inner.ttl_secs).await {
+            warn!(ip = %ip, error = %e, "auto-block not stored");
+            return false;
+        }
+        inner.blocked.lock().unwrap().insert(ip);
+        inner.metrics.auto_blocked.fetch_add(1, Ordering::Relaxed);
+        info!(
+            ip = %ip,
+            reason = reason,
+            ttl_secs = inner.ttl_secs,
+            "[IP-AUTO-BLOCKED] blocking client IP"
+        );
+        rejectlog::blocked(ip, reason, inner.ttl_secs);
+        if let Some(hook) = &inner.hook {
+            hook.fire(ip, reason, inner.ttl_secs);
+        }
+        true
+    }
+
+    /// Forget IPs whose block expired or was deleted, counting them as
+    /// unblocked. Returns how many there were.
+    pub async fn sweep(&self) -> usize {
+        let inner = &self.inner;
+        let blocked: Vec<IpAddr> = inner.blocked.lock().unwrap().iter().copied().collect();
+        let mut lifted = 0;
+        for ip in blocked {
+            if let Ok(false) = inner.store.exists(&self.key(ip)).await {
+                inner.blocked.lock().unwrap().remove(&ip);
+                inner.metrics.auto_unblocked.fetch_add(1, Ordering::Relaxed);
+                info!(ip = %ip, "[IP-AUTO-UNBLOCKED] temporary block lifted");
+                lifted += 1;
+            }
+        }
+        lifted
+    }
+
+    /// Sweep every `interval`. Runs until the runtime shuts down.
+    pub async fn watch(self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            self.sweep().await;
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Tells the network layer about automatically blocked IPs: runs
+//! `BAN_HOOK_COMMAND` and/or POSTs to `BAN_HOOK_URL`, so a firewall can
+//! drop the client before it reaches the gateway.
+
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::process::Command;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, warn};
+
+use crate::audit::unix_now;
+use crate::config::Config;
+use crate::http::{self, HttpUrl, Request};
+use crate::session::Metrics;
+use crate::tls;
+
+#[derive(Clone)]
+pub struct BanHook {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Program and leading arguments; the IP is appended.
+    command: Vec<String>,
+    url: Option<HttpUrl>,
+    /// Only for `https` URLs.
+    tls: Option<TlsConnector>,
+    timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl BanHook {
+    /// `None` when neither `BAN_HOOK_COMMAND` nor `BAN_HOOK_URL` is set.
+    /// Panics on an invalid URL, like other startup configuration errors.
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Option<Self> {
+        let command: Vec<String> = config
+            .ban_hook_command
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        let url = config.ban_hook_url.as_deref().map(|url| {
+            url.parse::<HttpUrl>()
+                .unwrap_or_else(|e| panic!("BAN_HOOK_URL: {}", e))
+        });
+        if command.is_empty() && url.is_none() {
+            return None;
+        }
+        let tls = url.as_ref().filter(|url| url.https).map(|_| {
+            tls::verifying_connector(&config.webhook_ca_path)
+                .unwrap_or_else(|e| panic!("WEBHOOK_CA_PATH: {}", e))
+        });
+        Some(Self {
+            inner: Arc::new(Inner {
+                command,
+                url,
+                tls,
+                timeout: Duration::from_millis(config.ban_hook_timeout_ms),
+                metrics,
+            }),
+        })
+    }
+
+    /// Run the hook in the background.
+    pub fn fire(&self, ip: IpAddr, reason: &'static str, ttl_secs: u64) {
+        let hook = self.clone();
+        tokio::spawn(async move { hook.run(ip, reason, ttl_secs).await });
+    }
+
+    /// Run the command and POST the event, each within
+    /// `BAN_HOOK_TIMEOUT_MS`. Returns false if either failed.
+    pub async fn run(&self, ip: IpAddr, reason: &str, ttl_secs: u64) -> bool {
+        let inner = &self.inner;
+        let mut ok = true;
+        if !inner.command.is_empty() {
+            let exec = tokio::time::timeout(inner.timeout, self.exec(ip, reason, ttl_secs));
+            let result = exec.await.unwrap_or_else(|_| Err("timed out".to_string()));
+            ok &= self.outcome("command", ip, result);
+        }
+        if let Some(url) = &inner.url {
+            let post = tokio::time::timeout(inner.timeout, self.post(url, ip, reason, ttl_secs));
+            let result = post.await.unwrap_or_else(|_| Err("timed out".to_string()));
+            ok &= self.outcome("url", ip, result);
+        }
+        ok
+    }
+
+    async fn exec(&self, ip: IpAddr, reason: &str, ttl_secs: u64) -> Result<(), String> {
+        let (program, args) = self.inner.command.split_first().unwrap();
+        let status = Command::new(program)
+            .args(args)
+            .arg(ip.to_string())
+            .env("BURNGATE_IP", ip.to_string())
+            .env("BURNGATE_REASON", reason)
+            .env("BURNGATE_TTL_SECS", ttl_secs.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await
+            .map_err(|e| e.to_string())?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(status.to_string())
+        }
+    }
+
+    async fn post(
+        &self,
+        url: &HttpUrl,
+        ip: IpAddr,
+        reason: &str,
+        ttl_secs: u64,
+    ) -> Result<(), String> {
+        let body = json!({
+            "event": "ip_blocked",
+            "ip": ip.to_string(),
+            "reason": reason,
+            "ttl_secs": ttl_secs,
+            "timestamp": unix_now(),
+        })
+        .to_string();
+        let request = Request {
+            method: "POST",
+            path: &url.path,
+            token: None,
+            headers: &[],
+            body: Some(("application/json", body.as_bytes())),
+        };
+        match http::send(url, self.inner.tls.as_ref(), &request).await {
+            Ok(status) if (200..300).contains(&status) => Ok(()),
+            Ok(status) => Err(format!("HTTP {status}")),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn outcome(&self, hook: &str, ip: IpAddr, result: Result<(), String>) -> bool {
+        match result {
+            Ok(()) => {
+                debug!(ip = %ip, hook = hook, "ban hook ran");
+                true
+            }
+            Err(e) => {
+                self.inner
+                    .metrics
+                    .ban_hook_errors
+                    .fetch_add(1, Ordering::Relaxed);
+                warn!(ip = %ip, hook = hook, error = %e, "[BAN-HOOK-ERROR] ban hook failed");
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Bloom filter of known addresses, so unknown recipients can be rejected
+//! without a lookup.
+
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts::LN_2;
+use std::hash::{Hash, Hasher};
+
+/// A set that can answer "definitely not present" or "maybe present".
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sized for `items` entries with a false positive rate of `fp_rate`.
+    pub fn new(items: usize, fp_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let fp_rate = fp_rate.clamp(1e-9, 0.5);
+        let bits = (-items * fp_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0);
+        let hashes = (bits / items * LN_2).round().clamp(1.0, 30.0) as u32;
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes,
+        }
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        for bit in self.positions(item) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False only if `item` was never inserted.
+    pub fn contains(&self, item: &str) -> bool {
+        self.positions(item)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Size of the filter in bytes.
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Bit indexes of `item`, by double hashing.
+    fn positions(&self, item: &str) -> impl Iterator<Item = usize> {
+        let h1 = hash(0, item);
+        let h2 = hash(1, item) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+fn hash(seed: u8, item: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    item.hash(&mut hasher);
+    hasher.finish()
+}
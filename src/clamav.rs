@@ -0,0 +1,95 @@
+//! Virus scanning with ClamAV: each message is streamed to clamd at
+//! `CLAMAV_ADDR` with `INSTREAM` after DATA.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+/// Bytes per `INSTREAM` chunk.
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClamAvError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("timed out")]
+    Timeout,
+    #[error("clamd: {0}")]
+    Failed(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Scan {
+    Clean,
+    /// The signature that matched, e.g. `Eicar-Test-Signature`.
+    Infected(String),
+}
+
+impl Scan {
+    /// Parse clamd's reply to `INSTREAM`: `stream: OK`,
+    /// `stream: <signature> FOUND` or `<message> ERROR`.
+    pub fn parse(reply: &[u8]) -> Result<Self, ClamAvError> {
+        let reply = String::from_utf8_lossy(reply);
+        let reply = reply.trim_end_matches(['\0', '\n']);
+        let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+        if result == "OK" {
+            Ok(Self::Clean)
+        } else if let Some(signature) = result.strip_suffix(" FOUND") {
+            Ok(Self::Infected(signature.to_string()))
+        } else {
+            Err(ClamAvError::Failed(result.to_string()))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClamAv {
+    addr: String,
+    timeout: Duration,
+    /// Larger messages are not scanned.
+    max_size: usize,
+}
+
+impl ClamAv {
+    pub fn new(addr: &str, config: &Config) -> Self {
+        Self {
+            addr: addr.to_string(),
+            timeout: Duration::from_millis(config.clamav_timeout_ms),
+            max_size: config.clamav_max_size,
+        }
+    }
+
+    /// Scan `message`, giving up after `CLAMAV_TIMEOUT_MS`. `None` when the
+    /// message is larger than `CLAMAV_MAX_SIZE` and was not scanned.
+    pub async fn scan(&self, message: &[u8]) -> Result<Option<Scan>, ClamAvError> {
+        if self.max_size > 0 && message.len() > self.max_size {
+            return Ok(None);
+        }
+        let reply = tokio::time::timeout(self.timeout, self.instream(message))
+            .await
+            .map_err(|_| ClamAvError::Timeout)??;
+        Scan::parse(&reply).map(Some)
+    }
+
+    /// `zINSTREAM`, then the message in length-prefixed chunks and a
+    /// zero-length chunk to end it.
+    async fn instream(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in message.chunks(CHUNK) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        stream.flush().await?;
+        let mut reply = Vec::new();
+        stream.take(4096).read_to_end(&mut reply).await?;
+        Ok(reply)
+    }
+}
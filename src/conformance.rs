@@ -0,0 +1,461 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::tls;
+
+/// Conformance scenarios with the section each one checks, in run order.
+pub const SCENARIOS: &[(&str, &str)] = &[
+    ("greeting", "RFC 5321 §4.3.1"),
+    ("helo", "RFC 5321 §4.1.1.1"),
+    ("ehlo_multiline", "RFC 5321 §4.2.1"),
+    ("noop", "RFC 5321 §4.1.1.9"),
+    ("unknown_command", "RFC 5321 §4.2.4"),
+    ("mail_requires_ehlo", "RFC 5321 §4.1.4"),
+    ("rcpt_requires_mail", "RFC 5321 §4.1.4"),
+    ("data_requires_rcpt", "RFC 5321 §4.1.4"),
+    ("nested_mail", "RFC 5321 §4.1.4"),
+    ("rset_clears_transaction", "RFC 5321 §4.1.1.5"),
+    ("ehlo_resets_transaction", "RFC 5321 §4.1.4"),
+    ("data_without_valid_recipients", "RFC 5321 §3.3"),
+    ("pipelined_replies_in_order", "RFC 2920 §3.1"),
+    ("quit_closes", "RFC 5321 §4.1.1.10"),
+    ("starttls_resets_session", "RFC 3207 §4.2"),
+    ("dot_stuffed_lines", "RFC 5321 §4.5.2"),
+    ("split_terminator", "RFC 5321 §4.1.1.4"),
+];
+
+/// Time allowed for one scenario, including rejection delays on the target.
+const SCENARIO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sender used in every transaction.
+const SENDER: &str = "conformance@example.org";
+
+/// Recipient in a domain no gateway accepts.
+const UNKNOWN_RECIPIENT: &str = "nobody@invalid.invalid";
+
+/// The instance under test.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub addr: SocketAddr,
+    /// A recipient the target accepts. Scenarios that need a message body
+    /// are skipped without one.
+    pub recipient: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ScenarioResult {
+    pub name: &'static str,
+    pub reference: &'static str,
+    pub verdict: Verdict,
+}
+
+/// Results of a full run, printable as a plain-text report.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub results: Vec<ScenarioResult>,
+}
+
+impl Report {
+    /// Whether no scenario failed. Skipped scenarios do not count.
+    pub fn passed(&self) -> bool {
+        !self
+            .results
+            .iter()
+            .any(|r| matches!(r.verdict, Verdict::Fail(_)))
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let results: Vec<_> = self
+            .results
+            .iter()
+            .map(|r| {
+                let (verdict, detail) = match &r.verdict {
+                    Verdict::Pass => ("pass", None),
+                    Verdict::Fail(d) => ("fail", Some(d)),
+                    Verdict::Skip(d) => ("skip", Some(d)),
+                };
+                serde_json::json!({
+                    "scenario": r.name,
+                    "reference": r.reference,
+                    "verdict": verdict,
+                    "detail": detail,
+                })
+            })
+            .collect();
+        serde_json::json!({ "passed": self.passed(), "results": results })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+        for r in &self.results {
+            let (tag, detail) = match &r.verdict {
+                Verdict::Pass => {
+                    passed += 1;
+                    ("PASS", None)
+                }
+                Verdict::Fail(d) => {
+                    failed += 1;
+                    ("FAIL", Some(d))
+                }
+                Verdict::Skip(d) => {
+                    skipped += 1;
+                    ("SKIP", Some(d))
+                }
+            };
+            write!(f, "{tag}  {:<32} {}", r.name, r.reference)?;
+            match detail {
+                Some(d) => writeln!(f, " -- {d}")?,
+                None => writeln!(f)?,
+            }
+        }
+        write!(f, "{passed} passed, {failed} failed, {skipped} skipped")
+    }
+}
+
+/// Run every scenario against `target`, each on a fresh connection.
+pub async fn run_all(target: &Target) -> Report {
+    let mut report = Report::default();
+    for &(name, reference) in SCENARIOS {
+        let verdict = match tokio::time::timeout(SCENARIO_TIMEOUT, run(name, target)).await {
+            Ok(Ok(())) => Verdict::Pass,
+            Ok(Err(Check::Fail(reason))) => Verdict::Fail(reason),
+            Ok(Err(Check::Skip(reason))) => Verdict::Skip(reason),
+            Err(_) => Verdict::Fail("timed out".to_string()),
+        };
+        report.results.push(ScenarioResult {
+            name,
+            reference,
+            verdict,
+        });
+    }
+    report
+}
+
+/// Why a scenario stopped early.
+enum Check {
+    Fail(String),
+    Skip(String),
+}
+
+impl From<std::io::Error> for Check {
+    fn from(e: std::io::Error) -> Self {
+        Check::Fail(format!("I/O error: {e}"))
+    }
+}
+
+type Result<T = ()> = std::result::Result<T, Check>;
+
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(Check::Fail(format!($($arg)+)));
+        }
+    };
+}
+
+async fn run(name: &str, target: &Target) -> Result {
+    match name {
+        "greeting" => Client::connect(target).await.map(drop),
+        "helo" => {
+            let mut c = Client::connect(target).await?;
+            let reply = c.command("HELO conformance.example.org").await?;
+            ensure!(reply.code == 250, "HELO: {reply}");
+            ensure!(reply.lines.len() == 1, "HELO reply is multiline: {reply}");
+            Ok(())
+        }
+        "ehlo_multiline" => {
+            let mut c = Client::connect(target).await?;
+            let reply = c.ehlo().await?;
+            for (i, line) in reply.lines.iter().enumerate() {
+                let last = i + 1 == reply.lines.len();
+                let sep = if last { " " } else { "-" };
+                ensure!(
+                    line.starts_with("250") && line.get(3..4).is_some_and(|s| s == sep),
+                    "line {} of EHLO reply: {line:?}",
+                    i + 1
+                );
+                if i > 0 {
+                    let keyword = line[4..].split(' ').next().unwrap_or("");
+                    ensure!(
+                        !keyword.is_empty()
+                            && keyword
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '-'),
+                        "malformed extension keyword in {line:?}"
+                    );
+                }
+            }
+            Ok(())
+        }
+        "noop" => {
+            let mut c = Client::connect(target).await?;
+            c.expect("NOOP", 250).await?;
+            c.ehlo().await?;
+            c.expect("NOOP", 250).await
+        }
+        "unknown_command" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            let reply = c.command("FROBNICATE").await?;
+            ensure!(
+                reply.code == 500 || reply.code == 502,
+                "unknown command: {reply}"
+            );
+            c.expect("NOOP", 250).await
+        }
+        "mail_requires_ehlo" => {
+            let mut c = Client::connect(target).await?;
+            c.expect(&mail_from(), 503).await
+        }
+        "rcpt_requires_mail" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            c.expect(&rcpt_to(UNKNOWN_RECIPIENT), 503).await
+        }
+        "data_requires_rcpt" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            c.expect("DATA", 503).await?;
+            c.expect(&mail_from(), 250).await?;
+            c.expect("DATA", 503).await
+        }
+        "nested_mail" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            c.expect(&mail_from(), 250).await?;
+            c.expect(&mail_from(), 503).await
+        }
+        "rset_clears_transaction" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            c.expect(&mail_from(), 250).await?;
+            c.expect("RSET", 250).await?;
+            c.expect(&rcpt_to(UNKNOWN_RECIPIENT), 503).await?;
+            c.expect(&mail_from(), 250).await
+        }
+        "ehlo_resets_transaction" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            c.expect(&mail_from(), 250).await?;
+            c.ehlo().await?;
+            c.expect(&rcpt_to(UNKNOWN_RECIPIENT), 503).await
+        }
+        "data_without_valid_recipients" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            c.expect(&mail_from(), 250).await?;
+            let reply = c.command(&rcpt_to(UNKNOWN_RECIPIENT)).await?;
+            ensure!(reply.code >= 500, "RCPT to unknown domain: {reply}");
+            let reply = c.command("DATA").await?;
+            ensure!(
+                reply.code == 503 || reply.code == 554,
+                "DATA without recipients: {reply}"
+            );
+            Ok(())
+        }
+        "pipelined_replies_in_order" => {
+            let mut c = Client::connect(target).await?;
+            c.ehlo().await?;
+            c.write(&format!(
+                "{}\r\n{}\r\nNOOP\r\n",
+                mail_from(),
+                rcpt_to(UNKNOWN_RECIPIENT)
+            ))
+            .await?;
+            let codes = [c.reply().await?, c.reply().await?, c.reply().await?]
+                .map(|reply| reply.code / 100);
+            ensure!(
+                codes == [2, 5, 2],
+                "reply classes {codes:?}, expected [2, 5, 2]"
+            );
+            Ok(())
+        }
+        "quit_closes" => {
+            let mut c = Client::connect(target).await?;
+            c.expect("QUIT", 221).await?;
+            ensure!(c.is_closed().await?, "connection still open after QUIT");
+            Ok(())
+        }
+        "starttls_resets_session" => {
+            let mut c = Client::connect(target).await?;
+            if !c.ehlo().await?.advertises("STARTTLS") {
+                return Err(Check::Skip("STARTTLS not advertised".to_string()));
+            }
+            c.expect("STARTTLS", 220).await?;
+            let mut c = c.starttls(target.addr).await?;
+            c.expect(&mail_from(), 503).await?;
+            let reply = c.ehlo().await?;
+            ensure!(
+                !reply.advertises("STARTTLS"),
+                "STARTTLS advertised after TLS is active"
+            );
+            c.expect(&mail_from(), 250).await
+        }
+        "dot_stuffed_lines" => {
+            let mut c = open_data(target).await?;
+            c.write("Subject: conformance\r\n\r\n..\r\n.x\r\n. \r\n..\r\n.\r\nHELP\r\n")
+                .await?;
+            finish_data(&mut c).await
+        }
+        "split_terminator" => {
+            let mut c = open_data(target).await?;
+            c.write("Subject: conformance\r\n\r\nbody\r\n").await?;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            c.write(".").await?;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            c.write("\r\nHELP\r\n").await?;
+            finish_data(&mut c).await
+        }
+        _ => Err(Check::Fail(format!("unknown scenario {name}"))),
+    }
+}
+
+fn mail_from() -> String {
+    format!("MAIL FROM:<{SENDER}>")
+}
+
+fn rcpt_to(recipient: &str) -> String {
+    format!("RCPT TO:<{recipient}>")
+}
+
+/// Open a transaction to the target's recipient and send DATA.
+async fn open_data(target: &Target) -> Result<Client> {
+    let Some(recipient) = &target.recipient else {
+        return Err(Check::Skip("no accepted recipient configured".to_string()));
+    };
+    let mut c = Client::connect(target).await?;
+    c.ehlo().await?;
+    c.expect(&mail_from(), 250).await?;
+    c.expect(&rcpt_to(recipient), 250).await?;
+    c.expect("DATA", 354).await?;
+    Ok(c)
+}
+
+/// After a body followed by HELP: exactly one final reply to the body (its
+/// code depends on the backend), then the 214 for HELP. An early terminator
+/// shows up as command replies in between.
+async fn finish_data(c: &mut Client) -> Result {
+    let reply = c.reply().await?;
+    ensure!(reply.code != 354, "second 354 after body: {reply}");
+    let reply = c.reply().await?;
+    ensure!(
+        reply.code == 214,
+        "expected HELP reply after message, got {reply}"
+    );
+    Ok(())
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    fn advertises(&self, keyword: &str) -> bool {
+        self.lines
+            .iter()
+            .skip(1)
+            .any(|line| crate::relay::ehlo_keyword(line).as_deref() == Some(keyword))
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.lines.join(" | "))
+    }
+}
+
+struct Client {
+    reader: BufReader<Box<dyn Io>>,
+}
+
+impl Client {
+    /// Connect and read the banner, which must be a 220.
+    async fn connect(target: &Target) -> Result<Self> {
+        let stream = TcpStream::connect(target.addr).await?;
+        let mut client = Self {
+            reader: BufReader::new(Box::new(stream)),
+        };
+        let banner = client.reply().await?;
+        ensure!(banner.code == 220, "banner: {banner}");
+        Ok(client)
+    }
+
+    /// Complete a STARTTLS upgrade after the 220 reply.
+    async fn starttls(self, addr: SocketAddr) -> Result<Self> {
+        let server_name = ServerName::IpAddress(addr.ip().into());
+        let stream = tls::loopback_connector()
+            .connect(server_name, self.reader.into_inner())
+            .await
+            .map_err(|e| Check::Fail(format!("TLS handshake: {e}")))?;
+        Ok(Self {
+            reader: BufReader::new(Box::new(stream)),
+        })
+    }
+
+    async fn ehlo(&mut self) -> Result<Reply> {
+        let reply = self.command("EHLO conformance.example.org").await?;
+        ensure!(reply.code == 250, "EHLO: {reply}");
+        Ok(reply)
+    }
+
+    async fn write(&mut self, data: &str) -> Result {
+        let writer = self.reader.get_mut();
+        writer.write_all(data.as_bytes()).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn command(&mut self, line: &str) -> Result<Reply> {
+        self.write(&format!("{line}\r\n")).await?;
+        self.reply().await
+    }
+
+    async fn expect(&mut self, line: &str, code: u16) -> Result {
+        let reply = self.command(line).await?;
+        ensure!(reply.code == code, "{line}: expected {code}, got {reply}");
+        Ok(())
+    }
+
+    /// Read a (possibly multiline) reply.
+    async fn reply(&mut self) -> Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(Check::Fail("connection closed".to_string()));
+            }
+            let line = line.trim_end().to_string();
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line);
+            if last {
+                break;
+            }
+        }
+        let code = lines[0].get(..3).and_then(|c| c.parse().ok()).unwrap_or(0);
+        Ok(Reply { code, lines })
+    }
+
+    async fn is_closed(&mut self) -> Result<bool> {
+        let mut line = String::new();
+        Ok(self.reader.read_line(&mut line).await? == 0)
+    }
+}
@@ -0,0 +1,191 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::audit::unix_now;
+use crate::config::Config;
+use crate::esmtp::EsmtpParams;
+use crate::relay::{ClientInfo, Recipient, RelayError};
+use crate::session::Metrics;
+use crate::spool;
+use crate::store::SharedStore;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Keeps messages the backend refused for good, so operators can inspect
+/// and replay them instead of losing them to a log line.
+///
+/// The sink is either a directory, with each message as a raw `.eml` file
+/// next to a `.json` file holding its envelope and the refusal, or a Redis
+/// list of JSON entries with the message inline.
+#[derive(Clone)]
+pub struct DeadLetter {
+    inner: Arc<DeadLetterInner>,
+}
+
+struct DeadLetterInner {
+    sink: Sink,
+    /// Suffix that keeps file names unique within the same nanosecond.
+    seq: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+enum Sink {
+    Dir(PathBuf),
+    Redis {
+        store: SharedStore,
+        key: String,
+        ttl_secs: u64,
+    },
+}
+
+/// A message the backend permanently refused.
+pub struct DeadMessage<'a> {
+    /// `None` for messages from the spool.
+    pub session_id: Option<&'a str>,
+    pub client: Option<&'a ClientInfo>,
+    pub sender: &'a str,
+    pub mail_params: &'a EsmtpParams,
+    pub recipients: &'a [Recipient<'a>],
+    pub error: &'a RelayError,
+    pub data: &'a [u8],
+}
+
+impl DeadMessage<'_> {
+    /// The spool's envelope fields, plus the refusal.
+    fn envelope(&self) -> serde_json::Value {
+        let mut envelope =
+            spool::envelope_json(self.client, self.sender, self.mail_params, self.recipients);
+        envelope["session_id"] = self.session_id.into();
+        envelope["error"] = self.error.to_string().into();
+        envelope["failed_at"] = unix_now().into();
+        envelope
+    }
+}
+
+impl DeadLetter {
+    /// A directory sink, created if needed.
+    pub fn dir(dir: &str, metrics: Arc<Metrics>) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self::with_sink(Sink::Dir(PathBuf::from(dir)), metrics))
+    }
+
+    /// A Redis list sink. The list expires `retention_days` after the last
+    /// message was added.
+    pub fn redis(
+        store: SharedStore,
+        key: &str,
+        retention_days: u64,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let sink = Sink::Redis {
+            store,
+            key: key.to_string(),
+            ttl_secs: retention_days.max(1) * SECS_PER_DAY,
+        };
+        Self::with_sink(sink, metrics)
+    }
+
+    /// The sink from `DEAD_LETTER_DIR` or `DEAD_LETTER_REDIS_KEY`, if either
+    /// is set.
+    pub fn from_config(
+        config: &Config,
+        store: SharedStore,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<Option<Self>> {
+        if let Some(dir) = &config.dead_letter_dir {
+            return Self::dir(dir, metrics).map(Some);
+        }
+        Ok(config
+            .dead_letter_redis_key
+            .as_deref()
+            .map(|key| Self::redis(store, key, config.dead_letter_retention_days, metrics)))
+    }
+
+    fn with_sink(sink: Sink, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner: Arc::new(DeadLetterInner {
+                sink,
+                seq: AtomicU64::new(0),
+                metrics,
+            }),
+        }
+    }
+
+    /// Keep `message`. Errors are logged; returns whether it was kept.
+    pub async fn store(&self, message: &DeadMessage<'_>) -> bool {
+        let stored = match &self.inner.sink {
+            Sink::Dir(dir) => self.store_file(dir, message).await,
+            Sink::Redis {
+                store,
+                key,
+                ttl_secs,
+            } => {
+                let mut entry = message.envelope();
+                // Lossy for 8-bit bodies; the directory sink keeps exact bytes
+                entry["message"] = String::from_utf8_lossy(message.data).into();
+                store
+                    .list_push(key, &entry.to_string(), *ttl_secs)
+                    .await
+                    .map(|_| key.clone())
+                    .map_err(|e| e.to_string())
+            }
+        };
+        match stored {
+            Ok(location) => {
+                self.inner
+                    .metrics
+                    .dead_lettered
+                    .fetch_add(1, Ordering::Relaxed);
+                info!(
+                    sender = message.sender,
+                    error = %message.error,
+                    location = %location,
+                    "[DEAD-LETTER] refused message kept for inspection"
+                );
+                true
+            }
+            Err(e) => {
+                warn!(
+                    sender = message.sender,
+                    error = %e,
+                    "[DEAD-LETTER-ERROR] refused message could not be kept"
+                );
+                false
+            }
+        }
+    }
+
+    /// Write `<id>.json`, then `<id>.eml`, so every `.eml` has its envelope.
+    async fn store_file(&self, dir: &Path, message: &DeadMessage<'_>) -> Result<String, String> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let seq = self.inner.seq.fetch_add(1, Ordering::Relaxed);
+        let base = dir.join(format!("{:x}-{:x}", nanos, seq));
+        let eml = base.with_extension("eml");
+        let envelope = message.envelope().to_string();
+        async {
+            write_file(&base.with_extension("json"), envelope.as_bytes()).await?;
+            write_file(&eml, message.data).await
+        }
+        .await
+        .map(|_| eml.display().to_string())
+        .map_err(|e: io::Error| e.to_string())
+    }
+}
+
+/// Write to a temporary file, sync it, then rename it into place.
+async fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
@@ -0,0 +1,496 @@
+//! Minimal stub resolver: A, AAAA, MX, PTR and SRV queries over UDP
+//! (RFC 1035), sent to the nameservers from `/etc/resolv.conf`. Used for
+//! rDNS, sender domain checks and to find and resolve backend servers.
+
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::config::Config;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Largest response read; answers are accepted even if truncated.
+const MAX_RESPONSE: usize = 1232;
+/// PTR names checked against forward records.
+const MAX_PTR_NAMES: usize = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DnsError {
+    #[error("no nameservers configured")]
+    NoServers,
+    #[error("DNS query timed out")]
+    Timeout,
+    #[error("DNS I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed DNS response")]
+    Malformed,
+    #[error("DNS server failure (rcode {0})")]
+    ServerFailure(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Mx,
+    Ptr,
+    Srv,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Ptr => 12,
+            RecordType::Mx => 15,
+            RecordType::Aaaa => 28,
+            RecordType::Srv => 33,
+        }
+    }
+}
+
+/// An answer record of a queried type. Names are lowercase without the
+/// trailing dot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Mx(Mx),
+    Ptr(String),
+    Srv(Srv),
+}
+
+/// A mail exchanger (RFC 1035 §3.3.9).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mx {
+    /// Lower is preferred.
+    pub preference: u16,
+    /// Empty for the root, as in a null MX (RFC 7505).
+    pub exchange: String,
+}
+
+/// A service location (RFC 2782).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Srv {
+    /// Lower is preferred.
+    pub priority: u16,
+    /// Relative share among targets of the same priority.
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Cheap to clone; clones share the nameserver list.
+#[derive(Clone)]
+pub struct Resolver {
+    inner: Arc<ResolverInner>,
+}
+
+struct ResolverInner {
+    servers: Vec<SocketAddr>,
+    /// Per nameserver tried.
+    timeout: Duration,
+}
+
+impl Resolver {
+    pub fn new(servers: Vec<SocketAddr>, timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(ResolverInner { servers, timeout }),
+        }
+    }
+
+    /// Nameservers from `/etc/resolv.conf`, or the local one if it lists none.
+    pub fn from_config(config: &Config) -> Self {
+        let conf = std::fs::read_to_string(RESOLV_CONF).unwrap_or_default();
+        let mut servers = parse_resolv_conf(&conf);
+        if servers.is_empty() {
+            servers.push(SocketAddr::from(([127, 0, 0, 1], 53)));
+        }
+        Self::new(servers, Duration::from_millis(config.dns_timeout_ms))
+    }
+
+    /// Records of `rtype` for `name`, trying each nameserver in turn.
+    /// A name that does not exist has no records.
+    pub async fn query(&self, name: &str, rtype: RecordType) -> Result<Vec<Record>, DnsError> {
+        let answers = self.query_with_ttl(name, rtype).await?;
+        Ok(answers.into_iter().map(|(record, _)| record).collect())
+    }
+
+    /// [`Resolver::query`], with the TTL of each record in seconds.
+    pub async fn query_with_ttl(
+        &self,
+        name: &str,
+        rtype: RecordType,
+    ) -> Result<Vec<(Record, u32)>, DnsError> {
+        let mut last = DnsError::NoServers;
+        for &server in &self.inner.servers {
+            let attempt = query_server(server, name, rtype);
+            match tokio::time::timeout(self.inner.timeout, attempt).await {
+                Ok(Ok(records)) => return Ok(records),
+                Ok(Err(e)) => last = e,
+                Err(_) => last = DnsError::Timeout,
+            }
+        }
+        Err(last)
+    }
+
+    /// IPv4 then IPv6 addresses of `name`, and how long they may be
+    /// cached: the smallest TTL among them.
+    pub async fn lookup_ip(&self, name: &str) -> Result<(Vec<IpAddr>, Duration), DnsError> {
+        let (v4, v6) = tokio::join!(
+            self.query_with_ttl(name, RecordType::A),
+            self.query_with_ttl(name, RecordType::Aaaa)
+        );
+        // One family failing is fine if the other answered
+        let answers = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => v4
+                .unwrap_or_default()
+                .into_iter()
+                .chain(v6.unwrap_or_default()),
+        };
+        let mut ttl = u32::MAX;
+        let mut addrs = Vec::new();
+        for (record, record_ttl) in answers {
+            let ip = match record {
+                Record::A(a) => IpAddr::V4(a),
+                Record::Aaaa(a) => IpAddr::V6(a),
+                _ => continue,
+            };
+            ttl = ttl.min(record_ttl);
+            addrs.push(ip);
+        }
+        let ttl = if addrs.is_empty() { 0 } else { ttl };
+        Ok((addrs, Duration::from_secs(ttl.into())))
+    }
+
+    /// Whether mail can be sent back to `domain`: its MX records, or
+    /// failing those its address records (RFC 5321 §5.1).
+    pub async fn mail_domain(&self, domain: &str) -> Result<MailDomain, DnsError> {
+        let exchanges: Vec<Mx> = self
+            .query(domain, RecordType::Mx)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Mx(mx) => Some(mx),
+                _ => None,
+            })
+            .collect();
+        if !exchanges.is_empty() {
+            return Ok(if exchanges.iter().all(|mx| mx.exchange.is_empty()) {
+                MailDomain::NullMx
+            } else {
+                MailDomain::Mx
+            });
+        }
+        let (addrs, _) = self.lookup_ip(domain).await?;
+        Ok(if addrs.is_empty() {
+            MailDomain::Missing
+        } else {
+            MailDomain::Implicit
+        })
+    }
+
+    /// Forward-confirmed reverse DNS: the first PTR name of `ip` that
+    /// resolves back to `ip`.
+    pub async fn reverse(&self, ip: IpAddr) -> Result<Option<String>, DnsError> {
+        Ok(match self.reverse_check(ip, true).await? {
+            Rdns::Confirmed(name) => Some(name),
+            _ => None,
+        })
+    }
+
+    /// The PTR name of `ip`. With `verify`, the first name that resolves
+    /// back to `ip`, or the first name marked as unconfirmed if none does.
+    pub async fn reverse_check(&self, ip: IpAddr, verify: bool) -> Result<Rdns, DnsError> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        let forward = match ip {
+            IpAddr::V4(_) => RecordType::A,
+            IpAddr::V6(_) => RecordType::Aaaa,
+        };
+        let names: Vec<String> = self
+            .query(&reverse_name(ip), RecordType::Ptr)
+            .await?
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Ptr(name) => Some(name),
+                _ => None,
+            })
+            .take(MAX_PTR_NAMES)
+            .collect();
+        let Some(first) = names.first() else {
+            return Ok(Rdns::Missing);
+        };
+        if !verify {
+            return Ok(Rdns::Unconfirmed(first.clone()));
+        }
+        for name in &names {
+            let confirmed = self.query(name, forward).await?.iter().any(|r| match r {
+                Record::A(a) => IpAddr::V4(*a) == ip,
+                Record::Aaaa(a) => IpAddr::V6(*a) == ip,
+                _ => false,
+            });
+            if confirmed {
+                return Ok(Rdns::Confirmed(name.clone()));
+            }
+        }
+        Ok(Rdns::Forged(first.clone()))
+    }
+}
+
+/// What DNS says about where mail for a domain goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MailDomain {
+    /// The domain has MX records.
+    Mx,
+    /// No MX records, but address records to deliver to.
+    Implicit,
+    /// The domain declares that it accepts no mail (RFC 7505).
+    NullMx,
+    /// Neither MX nor address records: the name does not exist or has no
+    /// mail route.
+    Missing,
+}
+
+impl MailDomain {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mx => "mx",
+            Self::Implicit => "implicit",
+            Self::NullMx => "null_mx",
+            Self::Missing => "missing",
+        }
+    }
+
+    /// Whether bounces to the domain can be delivered.
+    pub fn accepts_mail(&self) -> bool {
+        matches!(self, Self::Mx | Self::Implicit)
+    }
+}
+
+/// What reverse DNS says about a client address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rdns {
+    /// No PTR record.
+    Missing,
+    /// A PTR name that was not checked against forward DNS.
+    Unconfirmed(String),
+    /// A PTR name, none of which resolve back to the address.
+    Forged(String),
+    /// A PTR name that resolves back to the address.
+    Confirmed(String),
+}
+
+impl Rdns {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Rdns::Missing => None,
+            Rdns::Unconfirmed(name) | Rdns::Forged(name) | Rdns::Confirmed(name) => Some(name),
+        }
+    }
+
+    /// `none`, `unconfirmed`, `forged` or `confirmed`, for logs.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rdns::Missing => "none",
+            Rdns::Unconfirmed(_) => "unconfirmed",
+            Rdns::Forged(_) => "forged",
+            Rdns::Confirmed(_) => "confirmed",
+        }
+    }
+}
+
+/// `nameserver` entries of a resolv.conf.
+pub fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            (words.next()? == "nameserver").then_some(())?;
+            // Drop an IPv6 zone index; the socket picks the interface
+            let addr = words.next()?.split('%').next()?;
+            Some(SocketAddr::new(addr.parse().ok()?, 53))
+        })
+        .collect()
+}
+
+/// The `in-addr.arpa` or `ip6.arpa` name queried for PTR records of `ip`.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(v6) => {
+            let mut name = String::with_capacity(72);
+            for byte in v6.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+async fn query_server(
+    server: SocketAddr,
+    name: &str,
+    rtype: RecordType,
+) -> Result<Vec<(Record, u32)>, DnsError> {
+    let local: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    let id = RandomState::new().build_hasher().finish() as u16;
+    socket.send(&encode_query(id, name, rtype)?).await?;
+
+    let mut buf = vec![0u8; MAX_RESPONSE];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Anything but the answer to this query is spoofed or stale
+        if len < 2 || u16::from_be_bytes([buf[0], buf[1]]) != id {
+            continue;
+        }
+        return parse_answers(&buf[..len], rtype);
+    }
+}
+
+/// A recursive query for `name`.
+pub fn encode_query(id: u16, name: &str, rtype: RecordType) -> Result<Vec<u8>, DnsError> {
+    let mut packet = Vec::with_capacity(name.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // RD set; one question
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::Malformed);
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&rtype.code().to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes()); // IN
+    Ok(packet)
+}
+
+/// Answer records of `rtype` in a response. NXDOMAIN is an empty answer.
+pub fn parse_response(packet: &[u8], rtype: RecordType) -> Result<Vec<Record>, DnsError> {
+    let answers = parse_answers(packet, rtype)?;
+    Ok(answers.into_iter().map(|(record, _)| record).collect())
+}
+
+/// [`parse_response`], with the TTL of each record in seconds.
+pub fn parse_answers(packet: &[u8], rtype: RecordType) -> Result<Vec<(Record, u32)>, DnsError> {
+    if packet.len() < 12 || packet[2] & 0x80 == 0 {
+        return Err(DnsError::Malformed);
+    }
+    match packet[3] & 0x0f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => return Err(DnsError::ServerFailure(rcode)),
+    }
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let answers = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(packet, pos)?.1;
+        let header = packet.get(pos..pos + 10).ok_or(DnsError::Malformed)?;
+        let code = u16::from_be_bytes([header[0], header[1]]);
+        let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let start = pos + 10;
+        let data = packet.get(start..start + len).ok_or(DnsError::Malformed)?;
+        pos = start + len;
+        // CNAMEs and other types in the chain are skipped
+        if code != rtype.code() {
+            continue;
+        }
+        let record = match rtype {
+            RecordType::A => {
+                let octets: [u8; 4] = data.try_into().map_err(|_| DnsError::Malformed)?;
+                Record::A(octets.into())
+            }
+            RecordType::Aaaa => {
+                let octets: [u8; 16] = data.try_into().map_err(|_| DnsError::Malformed)?;
+                Record::Aaaa(octets.into())
+            }
+            RecordType::Ptr => Record::Ptr(read_name(packet, start)?.0),
+            RecordType::Mx => Record::Mx(Mx {
+                preference: data
+                    .get(0..2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    .ok_or(DnsError::Malformed)?,
+                exchange: read_name(packet, start + 2)?.0,
+            }),
+            RecordType::Srv => {
+                let field = |i: usize| {
+                    data.get(i..i + 2)
+                        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                        .ok_or(DnsError::Malformed)
+                };
+                Record::Srv(Srv {
+                    priority: field(0)?,
+                    weight: field(2)?,
+                    port: field(4)?,
+                    target: read_name(packet, start + 6)?.0,
+                })
+            }
+        };
+        // The top bit is reserved; such TTLs count as 0 (RFC 2181 §8)
+        records.push((record, if ttl > i32::MAX as u32 { 0 } else { ttl }));
+    }
+    Ok(records)
+}
+
+/// The name at `pos`, following compression pointers, and the offset just
+/// past it.
+fn read_name(packet: &[u8], mut pos: usize) -> Result<(String, usize), DnsError> {
+    let mut name = String::new();
+    let mut end = None;
+    // Each pointer must go backwards, so this terminates
+    let mut limit = pos;
+    loop {
+        let len = *packet.get(pos).ok_or(DnsError::Malformed)? as usize;
+        match len {
+            0 => {
+                return Ok((name, end.unwrap_or(pos + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let low = *packet.get(pos + 1).ok_or(DnsError::Malformed)? as usize;
+                let target = (l & 0x3f) << 8 | low;
+                if target >= limit {
+                    return Err(DnsError::Malformed);
+                }
+                end.get_or_insert(pos + 2);
+                limit = target;
+                pos = target;
+            }
+            l if l <= 63 => {
+                let label = packet
+                    .get(pos + 1..pos + 1 + l)
+                    .ok_or(DnsError::Malformed)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + l;
+            }
+            _ => return Err(DnsError::Malformed),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! DNS blocklist checks of connecting clients (`DNSBL_LISTS`), with a
+//! per-list policy: reject, tag the message, or add to a score.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::config::{Config, DnsblAction, DnsblList};
+use crate::dns::{self, Record, RecordType, Resolver};
+
+/// Cached verdicts kept before expired ones are swept.
+const CACHE_CLEANUP_THRESHOLD: usize = 10_000;
+
+/// A list that has the client, and the address it answered with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listing {
+    pub zone: String,
+    pub code: Ipv4Addr,
+    pub action: DnsblAction,
+}
+
+/// What the lists say about one client.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DnsblVerdict {
+    pub listings: Vec<Listing>,
+    /// Sum of the scores of `score` lists that have the client.
+    pub score: u32,
+    /// The list that rejects the client: the first `reject` list that has
+    /// it, or the last listing once the score reaches the threshold.
+    pub rejected_by: Option<String>,
+}
+
+impl DnsblVerdict {
+    /// `X-DNSBL` field value naming the `tag` and `score` listings, e.g.
+    /// `bl.spamcop.net=127.0.0.2, b.barracudacentral.org=127.0.0.2; score=2`.
+    pub fn tag(&self) -> Option<String> {
+        let tagged: Vec<String> = self
+            .listings
+            .iter()
+            .filter(|listing| listing.action != DnsblAction::Reject)
+            .map(|listing| format!("{}={}", listing.zone, listing.code))
+            .collect();
+        if tagged.is_empty() {
+            return None;
+        }
+        let mut tag = tagged.join(", ");
+        if self.score > 0 {
+            tag.push_str(&format!("; score={}", self.score));
+        }
+        Some(tag)
+    }
+}
+
+/// Checks client addresses against the configured lists. Cheap to clone;
+/// clones share the verdict cache and hit counters.
+#[derive(Clone)]
+pub struct Dnsbl {
+    inner: Arc<DnsblInner>,
+}
+
+struct DnsblInner {
+    resolver: Resolver,
+    lists: Vec<DnsblList>,
+    /// Clients found on each list, in `lists` order.
+    hits: Vec<AtomicU64>,
+    threshold: u32,
+    ttl: Duration,
+    cache: Mutex<HashMap<IpAddr, (Arc<DnsblVerdict>, Instant)>>,
+}
+
+impl Dnsbl {
+    /// `None` when `DNSBL_LISTS` is empty.
+    pub fn from_config(config: &Config, resolver: Resolver) -> Option<Self> {
+        if config.dnsbl_lists.is_empty() {
+            return None;
+        }
+        Some(Self::new(
+            config.dnsbl_lists.clone(),
+            config.dnsbl_score_threshold,
+            Duration::from_secs(config.dnsbl_cache_ttl_secs),
+            resolver,
+        ))
+    }
+
+    pub fn new(lists: Vec<DnsblList>, threshold: u32, ttl: Duration, resolver: Resolver) -> Self {
+        Self {
+            inner: Arc::new(DnsblInner {
+                resolver,
+                hits: lists.iter().map(|_| AtomicU64::new(0)).collect(),
+                lists,
+                threshold,
+                ttl,
+                cache: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Ask every list about `ip` at once. A list that fails to answer is
+    /// taken as not listing the client, and the verdict is then not cached.
+    pub async fn check(&self, ip: IpAddr) -> Arc<DnsblVerdict> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        if let Some(verdict) = self.cached(ip) {
+            return verdict;
+        }
+        let inner = &self.inner;
+        let answers = join_all(inner.lists.iter().map(|list| {
+            let name = query_name(ip, &list.zone);
+            async move { inner.resolver.query(&name, RecordType::A).await }
+        }))
+        .await;
+
+        let mut verdict = DnsblVerdict::default();
+        let mut complete = true;
+        for (i, (list, answer)) in inner.lists.iter().zip(answers).enumerate() {
+            let records = match answer {
+                Ok(records) => records,
+                Err(e) => {
+                    debug!(zone = %list.zone, ip = %ip, error = %e, "DNSBL query failed");
+                    complete = false;
+                    continue;
+                }
+            };
+            let Some(code) = records.into_iter().find_map(|record| match record {
+                Record::A(a) if is_listing(a) => Some(a),
+                _ => None,
+            }) else {
+                continue;
+            };
+            inner.hits[i].fetch_add(1, Ordering::Relaxed);
+            match list.action {
+                DnsblAction::Reject if verdict.rejected_by.is_none() => {
+                    verdict.rejected_by = Some(list.zone.clone());
+                }
+                DnsblAction::Score(score) => {
+                    verdict.score += score;
+                    if inner.threshold > 0
+                        && verdict.score >= inner.threshold
+                        && verdict.rejected_by.is_none()
+                    {
+                        verdict.rejected_by = Some(list.zone.clone());
+                    }
+                }
+                _ => {}
+            }
+            verdict.listings.push(Listing {
+                zone: list.zone.clone(),
+                code,
+                action: list.action,
+            });
+        }
+        let verdict = Arc::new(verdict);
+        if complete && !inner.ttl.is_zero() {
+            let now = Instant::now();
+            let mut cache = inner.cache.lock().unwrap();
+            if cache.len() > CACHE_CLEANUP_THRESHOLD {
+                cache.retain(|_, (_, expires)| *expires > now);
+            }
+            cache.insert(ip, (verdict.clone(), now + inner.ttl));
+        }
+        verdict
+    }
+
+    /// `zone=count` for each list, for the metrics log.
+    pub fn hits(&self) -> String {
+        self.inner
+            .lists
+            .iter()
+            .zip(&self.inner.hits)
+            .map(|(list, hits)| format!("{}={}", list.zone, hits.load(Ordering::Relaxed)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn cached(&self, ip: IpAddr) -> Option<Arc<DnsblVerdict>> {
+        let cache = self.inner.cache.lock().unwrap();
+        cache
+            .get(&ip)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(verdict, _)| verdict.clone())
+    }
+}
+
+/// The name queried for `ip` on `zone`: the reversed octets (or IPv6
+/// nibbles) under the zone, e.g. `2.0.0.127.zen.spamhaus.org`.
+pub fn query_name(ip: IpAddr, zone: &str) -> String {
+    let reversed = dns::reverse_name(ip);
+    let reversed = reversed
+        .strip_suffix(".in-addr.arpa")
+        .or_else(|| reversed.strip_suffix(".ip6.arpa"))
+        .unwrap_or(&reversed);
+    format!("{}.{}", reversed, zone)
+}
+
+/// Lists answer 127.0.0.0/8 for a listed client. 127.255.255.0/24 reports
+/// a refused query (e.g. Spamhaus through a public resolver), not a listing.
+fn is_listing(code: Ipv4Addr) -> bool {
+    let [a, b, c, _] = code.octets();
+    a == 127 && (b, c) != (255, 255)
+}
@@ -0,0 +1,332 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::address;
+
+/// Parameters burngate understands on MAIL FROM.
+pub const MAIL_PARAMS: &[&str] = &["SIZE", "BODY", "RET", "ENVID", "SMTPUTF8", "AUTH"];
+
+/// Parameters burngate understands on RCPT TO.
+pub const RCPT_PARAMS: &[&str] = &["NOTIFY", "ORCPT"];
+
+/// Parameters consumed by burngate itself and never forwarded.
+const CONSUMED_PARAMS: &[&str] = &["SIZE"];
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParamError {
+    #[error("malformed parameter: {0}")]
+    Malformed(String),
+    #[error("unrecognized parameter: {0}")]
+    Unrecognized(String),
+    #[error("invalid {0} parameter")]
+    Invalid(&'static str),
+    #[error("unsupported {0} value")]
+    Unsupported(&'static str),
+}
+
+/// Message body type from `BODY=` (RFC 6152).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Body {
+    SevenBit,
+    EightBitMime,
+}
+
+/// How much of the message a DSN should return, from `RET=` (RFC 3461).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ret {
+    Full,
+    Hdrs,
+}
+
+/// DSN conditions from `NOTIFY=` (RFC 3461). All false means `NEVER`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Notify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+/// Validated MAIL FROM parameters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MailParams {
+    pub size: Option<usize>,
+    pub body: Option<Body>,
+    pub smtputf8: bool,
+    pub ret: Option<Ret>,
+    /// `ENVID=` as received (xtext-encoded).
+    pub envid: Option<String>,
+    /// `AUTH=` as received (xtext-encoded, `<>` for unknown).
+    pub auth: Option<String>,
+    /// The raw list, for forwarding to the backend.
+    pub params: EsmtpParams,
+}
+
+impl MailParams {
+    /// Parse and validate the parameters following `FROM:<path>`.
+    pub fn parse(params: &str) -> Result<Self, ParamError> {
+        let params = EsmtpParams::parse_known(params, MAIL_PARAMS)?;
+        let size = match params.get("SIZE") {
+            Some(v) if v.bytes().all(|b| b.is_ascii_digit()) => {
+                Some(v.parse().map_err(|_| ParamError::Invalid("SIZE"))?)
+            }
+            Some(_) => return Err(ParamError::Invalid("SIZE")),
+            None if params.contains("SIZE") => return Err(ParamError::Invalid("SIZE")),
+            None => None,
+        };
+        let body = match params.get("BODY").map(str::to_ascii_uppercase).as_deref() {
+            Some("7BIT") => Some(Body::SevenBit),
+            Some("8BITMIME") => Some(Body::EightBitMime),
+            Some("BINARYMIME") => return Err(ParamError::Unsupported("BODY")),
+            Some(_) => return Err(ParamError::Invalid("BODY")),
+            None if params.contains("BODY") => return Err(ParamError::Invalid("BODY")),
+            None => None,
+        };
+        if params.get("SMTPUTF8").is_some() {
+            return Err(ParamError::Invalid("SMTPUTF8"));
+        }
+        let ret = match params.get("RET").map(str::to_ascii_uppercase).as_deref() {
+            Some("FULL") => Some(Ret::Full),
+            Some("HDRS") => Some(Ret::Hdrs),
+            Some(_) => return Err(ParamError::Invalid("RET")),
+            None if params.contains("RET") => return Err(ParamError::Invalid("RET")),
+            None => None,
+        };
+        let envid = xtext_value(&params, "ENVID", |v| v.len() <= 100)?;
+        let auth = xtext_value(&params, "AUTH", |v| v == "<>" || is_xtext(v))?;
+        Ok(Self {
+            size,
+            body,
+            smtputf8: params.contains("SMTPUTF8"),
+            ret,
+            envid,
+            auth,
+            params,
+        })
+    }
+}
+
+/// Validated RCPT TO parameters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RcptParams {
+    pub notify: Option<Notify>,
+    /// `ORCPT=` as received (`addr-type;xtext`).
+    pub orcpt: Option<String>,
+    /// The raw list, for forwarding to the backend.
+    pub params: EsmtpParams,
+}
+
+impl RcptParams {
+    /// Parse and validate the parameters following `TO:<path>`.
+    pub fn parse(params: &str) -> Result<Self, ParamError> {
+        let params = EsmtpParams::parse_known(params, RCPT_PARAMS)?;
+        let notify = match params.get("NOTIFY") {
+            Some(v) => Some(parse_notify(v).ok_or(ParamError::Invalid("NOTIFY"))?),
+            None if params.contains("NOTIFY") => return Err(ParamError::Invalid("NOTIFY")),
+            None => None,
+        };
+        let orcpt = xtext_value(&params, "ORCPT", |v| {
+            v.split_once(';').is_some_and(|(addr_type, addr)| {
+                !addr_type.is_empty()
+                    && addr_type
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                    && !addr.is_empty()
+            })
+        })?;
+        Ok(Self {
+            notify,
+            orcpt,
+            params,
+        })
+    }
+}
+
+/// `NEVER`, or a comma list of `SUCCESS`, `FAILURE` and `DELAY`.
+fn parse_notify(value: &str) -> Option<Notify> {
+    if value.eq_ignore_ascii_case("NEVER") {
+        return Some(Notify::default());
+    }
+    let mut notify = Notify::default();
+    for condition in value.split(',') {
+        let flag = match condition.to_ascii_uppercase().as_str() {
+            "SUCCESS" => &mut notify.success,
+            "FAILURE" => &mut notify.failure,
+            "DELAY" => &mut notify.delay,
+            _ => return None,
+        };
+        if *flag {
+            return None;
+        }
+        *flag = true;
+    }
+    Some(notify)
+}
+
+/// A required xtext value that also passes `check`.
+fn xtext_value(
+    params: &EsmtpParams,
+    key: &'static str,
+    check: impl Fn(&str) -> bool,
+) -> Result<Option<String>, ParamError> {
+    match params.get(key) {
+        Some(v) if is_xtext(v) && check(v) => Ok(Some(v.to_string())),
+        Some(_) => Err(ParamError::Invalid(key)),
+        None if params.contains(key) => Err(ParamError::Invalid(key)),
+        None => Ok(None),
+    }
+}
+
+/// RFC 3461 xtext: printable ASCII except `+` and `=`, with `+XX` hex escapes.
+fn is_xtext(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                let hex = bytes.get(i + 1..i + 3);
+                if !hex.is_some_and(|h| h.iter().all(|b| matches!(b, b'0'..=b'9' | b'A'..=b'F'))) {
+                    return false;
+                }
+                i += 3;
+            }
+            b'=' => return false,
+            b if (33..=126).contains(&b) => i += 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Encode `value` as RFC 3461 xtext.
+pub fn encode_xtext(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for &b in value.as_bytes() {
+        if (33..=126).contains(&b) && b != b'+' && b != b'=' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("+{:02X}", b));
+        }
+    }
+    out
+}
+
+/// ESMTP parameters from a MAIL FROM or RCPT TO command, in client order.
+///
+/// Keywords are uppercased; values are kept verbatim (xtext is not decoded).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EsmtpParams(Vec<(String, Option<String>)>);
+
+impl EsmtpParams {
+    /// Parse the `keyword[=value]` list that follows the address.
+    pub fn parse(params: &str) -> Result<Self, ParamError> {
+        let mut parsed = Vec::new();
+        for param in params.split_ascii_whitespace() {
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (param, None),
+            };
+            let valid_key = key
+                .bytes()
+                .next()
+                .is_some_and(|b| b.is_ascii_alphanumeric())
+                && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+            let valid_value = value.is_none_or(|v| {
+                !v.is_empty() && v.bytes().all(|b| (33..=126).contains(&b) && b != b'=')
+            });
+            let duplicate = parsed
+                .iter()
+                .any(|(k, _): &(String, _)| k.eq_ignore_ascii_case(key));
+            if !valid_key || !valid_value || duplicate {
+                return Err(ParamError::Malformed(param.to_string()));
+            }
+            parsed.push((key.to_ascii_uppercase(), value.map(str::to_string)));
+        }
+        Ok(Self(parsed))
+    }
+
+    /// Parse and reject any keyword not in `known`.
+    pub fn parse_known(params: &str, known: &[&str]) -> Result<Self, ParamError> {
+        let parsed = Self::parse(params)?;
+        if let Some((key, _)) = parsed.0.iter().find(|(k, _)| !known.contains(&k.as_str())) {
+            return Err(ParamError::Unrecognized(key.clone()));
+        }
+        Ok(parsed)
+    }
+
+    /// Whether the keyword is present (with or without a value).
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.iter().any(|(k, _)| k.eq_ignore_ascii_case(key))
+    }
+
+    /// Value of a `KEY=value` parameter.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_deref()))
+    }
+
+    /// Render the parameters to forward to a backend, each prefixed with a
+    /// space, keeping only those whose extension the backend advertised.
+    pub fn to_wire(&self, backend_caps: &HashSet<String>) -> String {
+        let mut out = String::new();
+        for (key, value) in self.iter() {
+            if CONSUMED_PARAMS.contains(&key) {
+                continue;
+            }
+            let supported = required_extension(key).is_some_and(|ext| backend_caps.contains(ext));
+            if !supported {
+                continue;
+            }
+            out.push(' ');
+            out.push_str(key);
+            if let Some(value) = value {
+                out.push('=');
+                out.push_str(value);
+            }
+        }
+        out
+    }
+}
+
+/// Space-separated, as received (`SIZE=10 BODY=8BITMIME`). Parses back with
+/// [`EsmtpParams::parse`].
+impl fmt::Display for EsmtpParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(key)?;
+            if let Some(value) = value {
+                write!(f, "={}", value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// EHLO keyword a backend must advertise before a parameter is forwarded.
+pub fn required_extension(key: &str) -> Option<&'static str> {
+    match key {
+        "SIZE" => Some("SIZE"),
+        "BODY" => Some("8BITMIME"),
+        "RET" | "ENVID" | "NOTIFY" | "ORCPT" => Some("DSN"),
+        "SMTPUTF8" => Some("SMTPUTF8"),
+        "AUTH" => Some("AUTH"),
+        _ => None,
+    }
+}
+
+/// The parameter list following the `<path>` in MAIL/RCPT arguments.
+pub fn params_part(args: &str) -> &str {
+    address::find_path(args).map_or("", |path| &args[path.end..])
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use tracing::debug;
+
+use crate::address;
+use crate::store::{Store, StoreError};
+
+/// How a single feature flag is rolled out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlagRule {
+    /// Enabled for all traffic.
+    On,
+    /// Disabled for all traffic.
+    Off,
+    /// Enabled for a stable percentage (0-100) of bucket keys.
+    Percent(u8),
+    /// Enabled only for the listed domains (and their subdomains).
+    Domains(Vec<String>),
+}
+
+impl FlagRule {
+    /// Parse a rule: `on`, `off`, `25%`, or `example.com|example.org`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().to_lowercase();
+        match s.as_str() {
+            "" => None,
+            "on" | "true" | "1" => Some(FlagRule::On),
+            "off" | "false" | "0" => Some(FlagRule::Off),
+            _ => {
+                if let Some(pct) = s.strip_suffix('%') {
+                    return pct
+                        .trim()
+                        .parse::<u8>()
+                        .ok()
+                        .map(|p| FlagRule::Percent(p.min(100)));
+                }
+                let domains: Vec<String> = s
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|d| !d.is_empty())
+                    .map(|d| address::normalize_domain(d).unwrap_or_else(|_| d.to_string()))
+                    .collect();
+                if domains.is_empty() {
+                    None
+                } else {
+                    Some(FlagRule::Domains(domains))
+                }
+            }
+        }
+    }
+
+    fn matches(&self, flag: &str, bucket_key: &str, domain: Option<&str>) -> bool {
+        match self {
+            FlagRule::On => true,
+            FlagRule::Off => false,
+            FlagRule::Percent(pct) => bucket(flag, bucket_key) < u32::from(*pct),
+            FlagRule::Domains(domains) => domain
+                .map(|d| {
+                    domains.iter().any(|allowed| {
+                        d == allowed
+                            || d.strip_suffix(allowed.as_str())
+                                .is_some_and(|prefix| prefix.ends_with('.'))
+                    })
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Parse a flag list like `strict_crlf=25%,new_filter=example.com|example.org`.
+///
+/// Entries with an unparseable rule are skipped.
+pub fn parse_flags(spec: &str) -> HashMap<String, FlagRule> {
+    spec.split(',')
+        .filter_map(|entry| {
+            let (name, rule) = entry.split_once('=')?;
+            let name = name.trim().to_lowercase();
+            if name.is_empty() {
+                return None;
+            }
+            Some((name, FlagRule::parse(rule)?))
+        })
+        .collect()
+}
+
+/// Stable 0-99 bucket for a flag and key (FNV-1a), identical across
+/// processes so a client sees the same behavior on every instance.
+fn bucket(flag: &str, key: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for b in flag.bytes().chain(std::iter::once(b':')).chain(key.bytes()) {
+        hash ^= u32::from(b);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash % 100
+}
+
+/// Feature flags from configuration, with optional Redis overrides.
+///
+/// Overrides replace the configured rule for the same flag name. Unknown
+/// flags are disabled.
+#[derive(Clone, Default)]
+pub struct FeatureFlags {
+    defaults: Arc<HashMap<String, FlagRule>>,
+    overrides: Arc<RwLock<HashMap<String, FlagRule>>>,
+}
+
+impl FeatureFlags {
+    pub fn new(defaults: HashMap<String, FlagRule>) -> Self {
+        Self {
+            defaults: Arc::new(defaults),
+            overrides: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Check whether `flag` is enabled for this piece of traffic.
+    ///
+    /// `bucket_key` selects the percentage bucket (e.g. the client IP) and
+    /// `domain` is matched against domain-scoped rules.
+    pub fn is_enabled(&self, flag: &str, bucket_key: &str, domain: Option<&str>) -> bool {
+        if let Ok(overrides) = self.overrides.read() {
+            if let Some(rule) = overrides.get(flag) {
+                return rule.matches(flag, bucket_key, domain);
+            }
+        }
+        self.defaults
+            .get(flag)
+            .map(|rule| rule.matches(flag, bucket_key, domain))
+            .unwrap_or(false)
+    }
+
+    /// Replace all Redis overrides.
+    pub fn set_overrides(&self, overrides: HashMap<String, FlagRule>) {
+        if let Ok(mut current) = self.overrides.write() {
+            *current = overrides;
+        }
+    }
+
+    /// Reload overrides from a hash of `flag -> rule`.
+    pub async fn refresh_from_store(&self, store: &dyn Store, key: &str) -> Result<(), StoreError> {
+        let raw = store.hash_get_all(key).await?;
+        let overrides: HashMap<String, FlagRule> = raw
+            .into_iter()
+            .filter_map(|(name, rule)| Some((name.trim().to_lowercase(), FlagRule::parse(&rule)?)))
+            .collect();
+        debug!(
+            key = key,
+            count = overrides.len(),
+            "feature flag overrides refreshed"
+        );
+        self.set_overrides(overrides);
+        Ok(())
+    }
+}
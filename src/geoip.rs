@@ -0,0 +1,394 @@
+//! Country lookups in a MaxMind DB file (`GEOIP_DATABASE`, e.g.
+//! GeoLite2-Country.mmdb) and the per-country policies of `GEOIP_POLICY`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tracing::debug;
+
+use crate::config::{Config, GeoAction};
+use crate::ratelimit::IpRateLimiter;
+
+/// Start of the metadata section, the last occurrence in the file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Deepest nesting of maps, arrays and pointers decoded.
+const MAX_DEPTH: u8 = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum GeoIpError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid database: {0}")]
+    Invalid(&'static str),
+}
+
+/// A value from the data section.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    /// Any of the unsigned types, up to 128 bits.
+    Uint(u128),
+    Int(i32),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    Bool(bool),
+    Float(f32),
+}
+
+impl Value {
+    /// The entry `key` of a map.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Uint(n) => u64::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// A MaxMind DB file, held in memory.
+pub struct Database {
+    data: Vec<u8>,
+    node_count: u32,
+    /// Bits per record: 24, 28 or 32.
+    record_size: u32,
+    ip_version: u16,
+    /// Start of the data section, after the search tree and 16 zero bytes.
+    data_start: usize,
+    /// Node reached by the 96 zero bits before an IPv4 address in an IPv6
+    /// tree.
+    ipv4_start: u32,
+}
+
+impl Database {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GeoIpError> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, GeoIpError> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or(GeoIpError::Invalid("no metadata"))?;
+        let (metadata, _) = decode(&data[marker + METADATA_MARKER.len()..], 0, 0)?;
+        let field = |name| {
+            metadata
+                .get(name)
+                .and_then(Value::as_u64)
+                .ok_or(GeoIpError::Invalid("incomplete metadata"))
+        };
+        let node_count =
+            u32::try_from(field("node_count")?).map_err(|_| GeoIpError::Invalid("node_count"))?;
+        let record_size = match field("record_size")? {
+            size @ (24 | 28 | 32) => size as u32,
+            _ => return Err(GeoIpError::Invalid("unsupported record_size")),
+        };
+        let ip_version = match field("ip_version")? {
+            4 => 4,
+            6 => 6,
+            _ => return Err(GeoIpError::Invalid("unsupported ip_version")),
+        };
+        let tree_size = node_count as usize * record_size as usize / 4;
+        if tree_size + 16 > marker {
+            return Err(GeoIpError::Invalid("search tree larger than file"));
+        }
+
+        let mut db = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start: tree_size + 16,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, false)?;
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    pub fn node_count(&self) -> u32 {
+        self.node_count
+    }
+
+    /// The record for the network holding `ip`, if any.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, GeoIpError> {
+        let (bytes, bits, mut node) = match ip {
+            IpAddr::V4(v4) => {
+                let mut bytes = [0; 16];
+                bytes[..4].copy_from_slice(&v4.octets());
+                (bytes, 32, self.ipv4_start)
+            }
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => return self.lookup(IpAddr::V4(v4)),
+                None if self.ip_version == 4 => return Ok(None),
+                None => (v6.octets(), 128, 0),
+            },
+        };
+        for i in 0..bits {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bytes[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit == 1)?;
+        }
+        if node <= self.node_count {
+            return Ok(None);
+        }
+        let offset = (node - self.node_count) as usize - 16;
+        let section = &self.data[self.data_start..];
+        Ok(Some(decode(section, offset, 0)?.0))
+    }
+
+    /// ISO code of the country of `ip`, or else of the country its network
+    /// is registered in.
+    pub fn country(&self, ip: IpAddr) -> Result<Option<String>, GeoIpError> {
+        let Some(record) = self.lookup(ip)? else {
+            return Ok(None);
+        };
+        Ok(["country", "registered_country"]
+            .iter()
+            .find_map(|key| record.get(key)?.get("iso_code")?.as_str())
+            .map(str::to_string))
+    }
+
+    /// The left or right record of `node`.
+    fn record(&self, node: u32, right: bool) -> Result<u32, GeoIpError> {
+        let node_bytes = self.record_size as usize / 4;
+        let start = node as usize * node_bytes;
+        let b = self
+            .data
+            .get(start..start + node_bytes)
+            .ok_or(GeoIpError::Invalid("truncated search tree"))?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| n << 8 | u32::from(b));
+        Ok(match (self.record_size, right) {
+            (24, false) => be(&b[0..3]),
+            (24, true) => be(&b[3..6]),
+            (28, false) => u32::from(b[3] & 0xf0) << 20 | be(&b[0..3]),
+            (28, true) => u32::from(b[3] & 0x0f) << 24 | be(&b[4..7]),
+            (_, false) => be(&b[0..4]),
+            (_, true) => be(&b[4..8]),
+        })
+    }
+}
+
+/// Decode the value at `pos` of `section`, returning it and the position
+/// after it. Pointers are relative to the start of `section`.
+fn decode(section: &[u8], mut pos: usize, depth: u8) -> Result<(Value, usize), GeoIpError> {
+    if depth > MAX_DEPTH {
+        return Err(GeoIpError::Invalid("data nested too deep"));
+    }
+    let take = |pos: usize, len: usize| {
+        section
+            .get(pos..pos + len)
+            .ok_or(GeoIpError::Invalid("truncated data"))
+    };
+    let be = |bytes: &[u8]| bytes.iter().fold(0u128, |n, &b| n << 8 | u128::from(b));
+
+    let ctrl = take(pos, 1)?[0];
+    pos += 1;
+    let mut kind = ctrl >> 5;
+
+    if kind == 1 {
+        let len = usize::from((ctrl >> 3) & 3) + 1;
+        let vvv = u128::from(ctrl & 7);
+        let bytes = take(pos, len)?;
+        pos += len;
+        let target = match len {
+            1 => vvv << 8 | be(bytes),
+            2 => (vvv << 16 | be(bytes)) + 2048,
+            3 => (vvv << 24 | be(bytes)) + 526_336,
+            _ => be(bytes),
+        };
+        let (value, _) = decode(section, target as usize, depth + 1)?;
+        return Ok((value, pos));
+    }
+    if kind == 0 {
+        kind = take(pos, 1)?[0].saturating_add(7);
+        pos += 1;
+    }
+
+    let mut size = usize::from(ctrl & 0x1f);
+    if size >= 29 {
+        let len = size - 28;
+        let extra = be(take(pos, len)?) as usize;
+        pos += len;
+        size = extra
+            + match len {
+                1 => 29,
+                2 => 285,
+                _ => 65_821,
+            };
+    }
+
+    match kind {
+        7 => {
+            let mut entries = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (key, next) = decode(section, pos, depth + 1)?;
+                let Value::String(key) = key else {
+                    return Err(GeoIpError::Invalid("map key is not a string"));
+                };
+                let (value, next) = decode(section, next, depth + 1)?;
+                entries.push((key, value));
+                pos = next;
+            }
+            Ok((Value::Map(entries), pos))
+        }
+        11 => {
+            let mut items = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (value, next) = decode(section, pos, depth + 1)?;
+                items.push(value);
+                pos = next;
+            }
+            Ok((Value::Array(items), pos))
+        }
+        14 => Ok((Value::Bool(size != 0), pos)),
+        _ => {
+            let bytes = take(pos, size)?;
+            pos += size;
+            let value = match (kind, size) {
+                (2, _) => Value::String(String::from_utf8_lossy(bytes).into_owned()),
+                (3, 8) => Value::Double(f64::from_be_bytes(bytes.try_into().unwrap())),
+                (4, _) => Value::Bytes(bytes.to_vec()),
+                (5, ..=2) | (6, ..=4) | (9, ..=8) | (10, ..=16) => Value::Uint(be(bytes)),
+                (8, ..=4) => Value::Int(be(bytes) as u32 as i32),
+                (15, 4) => Value::Float(f32::from_be_bytes(bytes.try_into().unwrap())),
+                _ => return Err(GeoIpError::Invalid("bad data field")),
+            };
+            Ok((value, pos))
+        }
+    }
+}
+
+/// Where a client connects from, and what its country's policy adds to
+/// its score.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub country: String,
+    pub score: u32,
+}
+
+/// The database with `GEOIP_POLICY`, and connections counted by country.
+#[derive(Clone)]
+pub struct GeoIp {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    db: Database,
+    policy: HashMap<String, GeoAction>,
+    /// Connection limits of the `rate=N` countries, per IP.
+    limiters: HashMap<String, IpRateLimiter>,
+    connections: Mutex<BTreeMap<String, u64>>,
+}
+
+impl GeoIp {
+    /// `None` when `GEOIP_DATABASE` is unset.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, GeoIpError> {
+        let Some(path) = &config.geoip_database else {
+            return Ok(None);
+        };
+        Ok(Some(Self::new(Database::open(path)?, &config.geoip_policy)))
+    }
+
+    pub fn new(db: Database, policy: &[(String, GeoAction)]) -> Self {
+        let limiters = policy
+            .iter()
+            .filter_map(|(country, action)| match action {
+                GeoAction::RateLimit(per_minute) => {
+                    Some((country.clone(), IpRateLimiter::new(*per_minute)))
+                }
+                _ => None,
+            })
+            .collect();
+        Self {
+            inner: Arc::new(Inner {
+                db,
+                policy: policy.iter().cloned().collect(),
+                limiters,
+                connections: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.inner.db
+    }
+
+    /// Country of a connecting client, counted for the metrics log. Lookup
+    /// errors count as no country.
+    pub fn locate(&self, ip: IpAddr) -> Option<Location> {
+        let country = match self.inner.db.country(ip) {
+            Ok(country) => country?,
+            Err(e) => {
+                debug!(ip = %ip, error = %e, "GeoIP lookup failed");
+                return None;
+            }
+        };
+        *self
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .entry(country.clone())
+            .or_insert(0) += 1;
+        let score = match self.action(&country) {
+            Some(GeoAction::Score(score)) => score,
+            _ => 0,
+        };
+        Some(Location { country, score })
+    }
+
+    pub fn action(&self, country: &str) -> Option<GeoAction> {
+        self.inner.policy.get(country).copied()
+    }
+
+    /// Whether `ip` is within the connection rate of its country; true for
+    /// countries without `rate=N`.
+    pub async fn check_rate(&self, country: &str, ip: IpAddr) -> bool {
+        match self.inner.limiters.get(country) {
+            Some(limiter) => limiter.check_and_increment(ip).await,
+            None => true,
+        }
+    }
+
+    /// `country=count` of connections so far, for the metrics log.
+    pub fn connections(&self) -> String {
+        self.inner
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(country, count)| format!("{}={}", country, count))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
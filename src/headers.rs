@@ -0,0 +1,301 @@
+//! Header fields added to relayed messages: `Received:`, `X-Original-To:`
+//! and the W3C trace context.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header fields to prepend to a message, in order.
+///
+/// Fields are only ever added on top of an existing header section, see
+/// [`is_header_block`]; a message that starts with anything else is left
+/// untouched rather than having its first line turned into a header.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderInjection {
+    block: Vec<u8>,
+}
+
+impl HeaderInjection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `name: value`. `value` may be folded with CRLF followed by
+    /// whitespace. A field with an invalid name or a line break that is not
+    /// folding is dropped, and `false` returned.
+    pub fn push(&mut self, name: &str, value: &str) -> bool {
+        let folded = value
+            .split("\r\n")
+            .skip(1)
+            .all(|line| line.starts_with([' ', '\t']));
+        if !is_field_name(name.as_bytes())
+            || !folded
+            || value.replace("\r\n", "").contains(['\r', '\n'])
+        {
+            return false;
+        }
+        self.block.extend_from_slice(name.as_bytes());
+        self.block.extend_from_slice(b": ");
+        self.block.extend_from_slice(value.as_bytes());
+        self.block.extend_from_slice(b"\r\n");
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.block.is_empty()
+    }
+
+    /// The fields as written to the wire.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.block
+    }
+
+    /// Prepend the fields to `message`. Returns `false`, leaving the
+    /// message as it was, if it does not start with a header section.
+    pub fn prepend_to(&self, message: &mut Vec<u8>) -> bool {
+        if !is_header_block(message) {
+            return false;
+        }
+        message.splice(0..0, self.block.iter().copied());
+        true
+    }
+}
+
+/// Whether `message` starts with an RFC 5322 header section: a header
+/// field, or the empty line of an empty header section.
+pub fn is_header_block(message: &[u8]) -> bool {
+    if message.starts_with(b"\r\n") {
+        return true;
+    }
+    match message.iter().position(|&b| b == b':') {
+        Some(colon) => is_field_name(&message[..colon]),
+        None => false,
+    }
+}
+
+/// The unfolded, trimmed value of the first `name` field (matched without
+/// regard to case) in the header section of `message`.
+pub fn field(message: &[u8], name: &str) -> Option<String> {
+    let mut value: Option<String> = None;
+    for line in message.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if let Some(value) = value.as_mut() {
+                value.push(' ');
+                value.push_str(String::from_utf8_lossy(line).trim());
+            }
+            continue;
+        }
+        if value.is_some() || line.is_empty() {
+            break;
+        }
+        let colon = line.iter().position(|&b| b == b':')?;
+        if line[..colon].eq_ignore_ascii_case(name.as_bytes()) {
+            value = Some(
+                String::from_utf8_lossy(&line[colon + 1..])
+                    .trim()
+                    .to_string(),
+            );
+        }
+    }
+    value.map(|value| value.trim().to_string())
+}
+
+/// Every field in the header section of `message`, in order, as its name
+/// and unfolded, trimmed value.
+pub fn fields(message: &[u8]) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in message.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(String::from_utf8_lossy(line).trim());
+            }
+            continue;
+        }
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            break;
+        };
+        fields.push((
+            String::from_utf8_lossy(&line[..colon]).trim().to_string(),
+            String::from_utf8_lossy(&line[colon + 1..])
+                .trim()
+                .to_string(),
+        ));
+    }
+    fields
+}
+
+/// The body of `message`: everything after the empty line that ends the
+/// header section. Empty when there is none.
+pub fn body(message: &[u8]) -> &[u8] {
+    if message.starts_with(b"\r\n") {
+        return &message[2..];
+    }
+    match message.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => &message[end + 4..],
+        None => match message.windows(2).position(|w| w == b"\n\n") {
+            Some(end) => &message[end + 2..],
+            None => &[],
+        },
+    }
+}
+
+/// `traceparent` and `tracestate` fields (W3C Trace Context) for the
+/// current span. Empty when OpenTelemetry is not configured.
+pub fn trace_context() -> HeaderInjection {
+    use opentelemetry::propagation::TextMapPropagator;
+    let propagator = opentelemetry_sdk::propagation::TraceContextPropagator::new();
+    let mut carrier = HashMap::<String, String>::new();
+    propagator.inject_context(
+        &tracing_opentelemetry::OpenTelemetrySpanExt::context(&tracing::Span::current()),
+        &mut carrier,
+    );
+    let mut fields = HeaderInjection::new();
+    if let Some(parent) = carrier.get("traceparent") {
+        fields.push("traceparent", parent);
+        if let Some(state) = carrier.get("tracestate").filter(|s| !s.is_empty()) {
+            fields.push("tracestate", state);
+        }
+    }
+    fields
+}
+
+/// RFC 5322 §3.6.8 field name: printable US-ASCII except colon.
+fn is_field_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.iter().all(|&b| (33..=126).contains(&b) && b != b':')
+}
+
+/// The `Received:` field this gateway prepends (RFC 5321 §4.4). Displays
+/// as the field value, e.g.
+///
+/// ```text
+/// from mail.example.org (mail.example.org [192.0.2.1])
+///     by mx.tempy.email (burngate) with ESMTPS id 671E2C4000001
+///     (using TLSv1.3 with cipher TLS13_AES_256_GCM_SHA384)
+///     for <alice@tempy.email>; Thu, 15 Oct 2026 10:00:00 +0000
+/// ```
+pub struct Received<'a> {
+    /// Name from the client's EHLO/HELO/LHLO.
+    pub helo: Option<&'a str>,
+    /// Reverse DNS name of the client.
+    pub rdns: Option<&'a str>,
+    /// `rdns` does not resolve back to `ip`; it is marked `(may be forged)`.
+    pub rdns_forged: bool,
+    pub ip: IpAddr,
+    /// Our own name (`SERVER_NAME`).
+    pub by: &'a str,
+    /// RFC 3848 protocol, e.g. `ESMTPS`.
+    pub protocol: &'a str,
+    /// Negotiated TLS version and cipher suite.
+    pub tls: Option<(&'a str, &'a str)>,
+    /// Session ID, also recorded on the `smtp.session` span.
+    pub id: &'a str,
+    /// Only named when the message has a single recipient, so one copy
+    /// does not disclose the others.
+    pub recipient: Option<&'a str>,
+    pub date: SystemTime,
+}
+
+impl fmt::Display for Received<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let helo = self.helo.map(sanitize).filter(|h| !h.is_empty());
+        let ip = match self.ip {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => format!("[{v4}]"),
+                None => format!("[IPv6:{v6}]"),
+            },
+            IpAddr::V4(v4) => format!("[{v4}]"),
+        };
+        write!(f, "from {} (", helo.as_deref().unwrap_or("unknown"))?;
+        match self.rdns {
+            Some(rdns) if self.rdns_forged => {
+                write!(f, "{} {ip} (may be forged))", sanitize(rdns))?
+            }
+            Some(rdns) => write!(f, "{} {ip})", sanitize(rdns))?,
+            None => write!(f, "{ip})")?,
+        }
+        write!(
+            f,
+            "\r\n\tby {} (burngate) with {} id {}",
+            self.by, self.protocol, self.id
+        )?;
+        if let Some((version, cipher)) = self.tls {
+            write!(f, "\r\n\t(using {version} with cipher {cipher})")?;
+        }
+        match self.recipient {
+            Some(rcpt) => write!(f, "\r\n\tfor <{}>;", sanitize(rcpt))?,
+            None => f.write_str(";")?,
+        }
+        write!(f, " {}", rfc5322_date(self.date))
+    }
+}
+
+/// Client-supplied text reduced to characters that cannot break out of
+/// the header or its comments.
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_ascii_graphic() && !matches!(c, '(' | ')' | '\\' | ';' | '<' | '>'))
+        .collect()
+}
+
+/// `Thu, 15 Oct 2026 10:00:00 +0000` (RFC 5322 §3.3), in UTC.
+pub fn rfc5322_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    let (year, month, day) = civil_from_days(days);
+    let rem = secs % 86_400;
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// `2026-10-15T10:00:00Z` (RFC 3339), in UTC.
+pub fn rfc3339_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let rem = secs % 86_400;
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Year, month and day of the `days`th day after 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's algorithm, shifted so years start on March 1st
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,50 @@
+//! Checks of the EHLO/HELO/LHLO argument against names bots typically
+//! send (`HELO_CHECKS`).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::address;
+use crate::config::HeloCheck;
+
+/// Whether `helo`, from the client at `client`, fails `check`. `server_name`
+/// is our own name (`SERVER_NAME`).
+pub fn fails(check: HeloCheck, helo: &str, client: IpAddr, server_name: &str) -> bool {
+    match check {
+        HeloCheck::BareIp => helo.parse::<IpAddr>().is_ok(),
+        HeloCheck::LiteralMismatch => match literal(helo) {
+            Some(ip) => ip != canonical(client),
+            None => helo.starts_with('['),
+        },
+        HeloCheck::OwnName => {
+            let name = helo.strip_suffix('.').unwrap_or(helo);
+            name.eq_ignore_ascii_case(server_name.trim_end_matches('.'))
+        }
+        // Address literals and bare IPs are left to the checks above
+        HeloCheck::NonFqdn => {
+            !helo.starts_with('[')
+                && helo.parse::<IpAddr>().is_err()
+                && (!helo.trim_end_matches('.').contains('.')
+                    || address::validate_domain(helo).is_err())
+        }
+    }
+}
+
+/// The address in a `[192.0.2.1]` or `[IPv6:2001:db8::1]` literal.
+fn literal(helo: &str) -> Option<IpAddr> {
+    let literal = helo.strip_prefix('[')?.strip_suffix(']')?;
+    let ip = match literal.get(..5) {
+        Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => {
+            IpAddr::V6(literal[5..].parse::<Ipv6Addr>().ok()?)
+        }
+        _ => IpAddr::V4(literal.parse::<Ipv4Addr>().ok()?),
+    };
+    Some(canonical(ip))
+}
+
+/// IPv4-mapped IPv6 addresses as IPv4, as a dual-stack listener reports them.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
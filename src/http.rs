@@ -0,0 +1,236 @@
+//! Minimal HTTP/1.1 client for the webhook, the HTTP mailbox lookup and
+//! Rspamd: one request per connection, and the body of the reply is only
+//! read when asked for.
+
+use std::io;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// An `http://` or `https://` URL split for the request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    /// Path and query, at least `/`.
+    pub path: String,
+}
+
+impl std::str::FromStr for HttpUrl {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, String> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("{url:?} is not an http(s) URL"));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let default_port = if https { 443 } else { 80 };
+        // Bracketed IPv6 literals keep their colons
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("invalid port in {url:?}"))?,
+            ),
+            _ => (authority, default_port),
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err(format!("invalid host in {url:?}"));
+        }
+        Ok(Self {
+            https,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// A request to send to an [`HttpUrl`].
+pub struct Request<'a> {
+    pub method: &'a str,
+    /// Path and query, replacing the URL's own.
+    pub path: &'a str,
+    /// Sent as `Authorization: Bearer <token>`.
+    pub token: Option<&'a str>,
+    /// Further header fields; a name may repeat.
+    pub headers: &'a [(&'a str, &'a str)],
+    /// The body and its content type.
+    pub body: Option<(&'a str, &'a [u8])>,
+}
+
+/// The largest response body [`fetch`] reads.
+pub const MAX_RESPONSE_BODY: usize = 1024 * 1024;
+
+/// Send `request` to `url`'s host, over TLS for `https` URLs, and return
+/// the response status. `tls` must be set for `https` URLs.
+pub async fn send(
+    url: &HttpUrl,
+    tls: Option<&TlsConnector>,
+    request: &Request<'_>,
+) -> io::Result<u16> {
+    Ok(connect(url, tls, request, false).await?.0)
+}
+
+/// Like [`send`], but also read the response body, up to
+/// [`MAX_RESPONSE_BODY`].
+pub async fn fetch(
+    url: &HttpUrl,
+    tls: Option<&TlsConnector>,
+    request: &Request<'_>,
+) -> io::Result<(u16, Vec<u8>)> {
+    connect(url, tls, request, true).await
+}
+
+async fn connect(
+    url: &HttpUrl,
+    tls: Option<&TlsConnector>,
+    request: &Request<'_>,
+    read_body: bool,
+) -> io::Result<(u16, Vec<u8>)> {
+    let host = url.host.trim_matches(['[', ']']);
+    let tcp = TcpStream::connect((host, url.port)).await?;
+    match tls.filter(|_| url.https) {
+        Some(connector) => {
+            let name = ServerName::try_from(host.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            exchange(connector.connect(name, tcp).await?, url, request, read_body).await
+        }
+        None => exchange(tcp, url, request, read_body).await,
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    url: &HttpUrl,
+    request: &Request<'_>,
+    read_body: bool,
+) -> io::Result<(u16, Vec<u8>)> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: burngate\r\nConnection: close\r\n",
+        request.method, request.path, url.host
+    );
+    if let Some((content_type, body)) = request.body {
+        head.push_str(&format!(
+            "Content-Type: {content_type}\r\nContent-Length: {}\r\n",
+            body.len()
+        ));
+    }
+    if let Some(token) = request.token {
+        head.push_str(&format!("Authorization: Bearer {token}\r\n"));
+    }
+    for (name, value) in request.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    let mut stream = BufReader::new(stream);
+    stream.get_mut().write_all(head.as_bytes()).await?;
+    if let Some((_, body)) = request.body {
+        stream.get_mut().write_all(body).await?;
+    }
+    stream.get_mut().flush().await?;
+
+    let mut status_line = String::new();
+    stream.read_line(&mut status_line).await?;
+    // HTTP/1.1 204 No Content
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("malformed HTTP status line"))?;
+    if !read_body {
+        return Ok((status, Vec::new()));
+    }
+
+    let mut length = None;
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(invalid("HTTP response ended in the header section"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(
+                value
+                    .parse()
+                    .map_err(|_| invalid("invalid Content-Length"))?,
+            );
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let mut size = String::new();
+            stream.read_line(&mut size).await?;
+            let size = size.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk"))?;
+            if size == 0 {
+                break;
+            }
+            if body.len() + size > MAX_RESPONSE_BODY {
+                return Err(invalid("HTTP response body too large"));
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            stream.read_exact(&mut body[start..]).await?;
+            let mut crlf = String::new();
+            stream.read_line(&mut crlf).await?;
+        }
+    } else if let Some(length) = length {
+        if length > MAX_RESPONSE_BODY {
+            return Err(invalid("HTTP response body too large"));
+        }
+        body.resize(length, 0);
+        stream.read_exact(&mut body).await?;
+    } else {
+        // Connection: close, so the body runs to the end of the stream
+        stream
+            .take(MAX_RESPONSE_BODY as u64 + 1)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() > MAX_RESPONSE_BODY {
+            return Err(invalid("HTTP response body too large"));
+        }
+    }
+    Ok((status, body))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// `value` percent-encoded for a query string: everything but RFC 3986
+/// unreserved characters is escaped.
+pub fn query_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &b in value.as_bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{b:02X}"));
+        }
+    }
+    escaped
+}
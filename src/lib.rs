@@ -1,6 +1,43 @@
+pub mod address;
+pub mod allowlist;
+pub mod audit;
+pub mod autoblock;
+pub mod banhook;
+pub mod bloom;
+pub mod clamav;
 pub mod config;
+pub mod conformance;
+pub mod deadletter;
+pub mod dns;
+pub mod dnsbl;
+pub mod esmtp;
+pub mod flags;
+pub mod geoip;
+pub mod headers;
+pub mod helo;
+pub mod http;
 pub mod lookup;
+pub mod memcached;
+pub mod mirror;
+pub mod netlist;
+pub mod network;
+pub mod pools;
+pub mod postgres;
+pub mod proxy;
 pub mod ratelimit;
+pub mod rejectlog;
 pub mod relay;
+pub mod reply;
+pub mod reputation;
+pub mod rspamd;
+pub mod rules;
+pub mod sampling;
+pub mod selftest;
 pub mod session;
+pub mod spamd;
+pub mod spamtrap;
+pub mod spool;
+pub mod stats;
+pub mod store;
 pub mod tls;
+pub mod webhook;
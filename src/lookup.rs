@@ -1,89 +1,1332 @@
-use redis::aio::ConnectionManager;
-use redis::AsyncCommands;
-use tracing::{debug, error};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use crate::config::{CheckMode, Config};
+use async_trait::async_trait;
+use redis::IntoConnectionInfo;
+use tokio::time::Instant;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, info, warn};
+
+use crate::address;
+use crate::allowlist::Allowlist;
+use crate::bloom::BloomFilter;
+use crate::config::{
+    ChainAction, ChainStage, CheckMode, Config, DomainForm, LookupKind, StageKind,
+};
+use crate::http::{self, HttpUrl, Request};
+use crate::memcached::McPool;
+use crate::postgres::{PgPool, PgUrl};
+use crate::session::Metrics;
+use crate::store::{self, SharedStore, Store, StoreError};
+use crate::tls;
+
+/// Cached answers before expired ones are evicted.
+const CACHE_CLEANUP_THRESHOLD: usize = 10_000;
+
+/// Keyspace events that create or remove a mailbox key.
+const KEYSPACE_EVENTS: [&str; 4] = ["set", "del", "expired", "evicted"];
+
+/// Outcome of a mailbox existence check, recorded on the session span.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupOutcome {
+    /// The active mailbox key exists.
+    KeyHit,
+    /// The address is a member of the known-addresses set.
+    SetHit,
+    /// A backend without tiers found the address.
+    Found,
+    /// The address was not found, but its domain has a catch-all.
+    CatchAll,
+    /// The address was not found, and its mailbox key was created.
+    Created,
+    /// The address or its domain is in `ALLOWLIST_FILE`.
+    Allowlisted,
+    /// Neither check found the address.
+    Miss,
+    /// The backend returned an error and no check found the address (fail
+    /// closed).
+    Error,
+}
+
+impl LookupOutcome {
+    /// Whether the mailbox should accept mail.
+    pub fn is_hit(self) -> bool {
+        matches!(
+            self,
+            LookupOutcome::KeyHit
+                | LookupOutcome::SetHit
+                | LookupOutcome::Found
+                | LookupOutcome::CatchAll
+                | LookupOutcome::Created
+                | LookupOutcome::Allowlisted
+        )
+    }
+
+    /// Attribute value used in spans and logs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LookupOutcome::KeyHit => "key_hit",
+            LookupOutcome::SetHit => "set_hit",
+            LookupOutcome::Found => "found",
+            LookupOutcome::CatchAll => "catch_all",
+            LookupOutcome::Created => "created",
+            LookupOutcome::Allowlisted => "allowlisted",
+            LookupOutcome::Miss => "miss",
+            LookupOutcome::Error => "error",
+        }
+    }
+}
+
+/// Answers whether a mailbox exists, and whether its owner blocked a sender.
+#[async_trait]
+pub trait LookupBackend: Send + Sync {
+    /// Run the backend's checks and report which (if any) matched.
+    async fn check(&self, address: &str) -> LookupOutcome;
+
+    /// Check if the recipient's owner has blocked this sender. Backends
+    /// without blocklists never block.
+    async fn is_sender_blocked(&self, _recipient: &str, _sender: &str) -> bool {
+        false
+    }
+
+    /// Check if the mailbox should accept mail.
+    async fn should_accept(&self, address: &str) -> bool {
+        self.check(address).await.is_hit()
+    }
+
+    /// Note that mail was delivered to `address`. Backends without expiring
+    /// mailboxes ignore it.
+    async fn delivered(&self, _address: &str) {}
+
+    /// The mailbox an alias address stands for, or `None` if `address` is
+    /// not an alias. Backends without aliases never rewrite.
+    async fn resolve_alias(&self, _address: &str) -> Result<Option<String>, AliasError> {
+        Ok(None)
+    }
+}
+
+/// Why an alias could not be resolved.
+#[derive(Debug, thiserror::Error)]
+pub enum AliasError {
+    /// The chain came back to an address, or exceeded `ALIAS_MAX_HOPS`.
+    #[error("alias loop")]
+    Loop,
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+pub type SharedLookup = Arc<dyn LookupBackend>;
+
+/// The lookup backend selected by `LOOKUP_BACKEND`, or the stages of
+/// `LOOKUP_CHAIN`, behind a [`CachedLookup`] unless both cache TTLs are 0.
+/// Without a chain, `ALLOWLIST_FILE` puts an [`AllowlistLookup`] in front.
+/// Must be called within a Tokio runtime when `LOOKUP_INVALIDATE_CHANNEL`,
+/// `LOOKUP_KEYSPACE_NOTIFICATIONS` or `ALLOWLIST_FILE` is set.
+pub fn from_config(store: SharedStore, config: &Config, metrics: Arc<Metrics>) -> SharedLookup {
+    let allowlist = config
+        .allowlist_file
+        .as_deref()
+        .map(|path| load_allowlist(path, config));
+    let backend: SharedLookup = if !config.lookup_chain.is_empty() {
+        Arc::new(LookupChain::from_config(
+            store.clone(),
+            config,
+            allowlist.clone(),
+        ))
+    } else {
+        match config.lookup_backend {
+            LookupKind::Redis => Arc::new(MailboxLookup::new(store.clone(), config)),
+            LookupKind::Http => Arc::new(HttpLookup::new(
+                config.lookup_http_url.as_deref().unwrap_or_default(),
+                config,
+            )),
+            LookupKind::Postgres => Arc::new(PostgresLookup::new(
+                config.lookup_postgres_url.as_deref().unwrap_or_default(),
+                config,
+            )),
+            LookupKind::Memcached => Arc::new(MemcachedLookup::new(
+                config.lookup_memcached_addr.as_deref().unwrap_or_default(),
+                config,
+            )),
+        }
+    };
+    let lookup: SharedLookup =
+        if config.lookup_cache_ttl_secs == 0 && config.lookup_negative_cache_ttl_secs == 0 {
+            backend
+        } else {
+            let cached = Arc::new(CachedLookup::new(backend, config, metrics.clone()));
+            if let Some(channel) = &config.lookup_invalidate_channel {
+                tokio::spawn(
+                    cached
+                        .clone()
+                        .follow_invalidations(store.clone(), channel.clone()),
+                );
+            }
+            if config.lookup_keyspace_notifications {
+                tokio::spawn(cached.clone().follow_keyspace(
+                    store.clone(),
+                    redis_db(config),
+                    key_patterns(config),
+                ));
+            }
+            cached
+        };
+    let lookup: SharedLookup = if config.bloom_filter {
+        let bloom = Arc::new(BloomLookup::new(lookup, store.clone(), config, metrics));
+        let refresh = Duration::from_secs(config.bloom_filter_refresh_secs.max(1));
+        tokio::spawn(bloom.clone().maintain(refresh));
+        if let Some(channel) = &config.lookup_invalidate_channel {
+            tokio::spawn(bloom.clone().follow_additions(store, channel.clone()));
+        }
+        bloom
+    } else {
+        lookup
+    };
+    match allowlist {
+        Some(allowlist) if config.lookup_chain.is_empty() => {
+            Arc::new(AllowlistLookup::new(allowlist, lookup))
+        }
+        _ => lookup,
+    }
+}
+
+/// Subscribe to `channel`, retrying until it works. `what` names the
+/// messages in logs.
+async fn subscribe(
+    store: &SharedStore,
+    channel: &str,
+    what: &str,
+) -> tokio::sync::mpsc::UnboundedReceiver<String> {
+    loop {
+        match store.subscribe(channel).await {
+            Ok(messages) => return messages,
+            Err(e) => {
+                warn!(error = %e, channel = %channel, "cannot subscribe to {}, retrying", what);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Prefix and suffix around `{address}` of each mailbox key pattern in
+/// use, without duplicates. Empty when keys are not checked.
+fn key_patterns(config: &Config) -> Vec<(String, String)> {
+    let mut key_patterns = Vec::new();
+    if config.redis_check_mode != CheckMode::SetOnly {
+        for pattern in std::iter::once(&config.redis_key_pattern)
+            .chain(config.redis_domain_key_patterns.values())
+        {
+            let (prefix, suffix) = pattern.split_once("{address}").unwrap_or((pattern, ""));
+            let pattern = (prefix.to_string(), suffix.to_string());
+            if !key_patterns.contains(&pattern) {
+                key_patterns.push(pattern);
+            }
+        }
+    }
+    key_patterns
+}
+
+/// The address in `key` if it matches one of `key_patterns`.
+fn address_in_key<'a>(key: &'a str, key_patterns: &[(String, String)]) -> Option<&'a str> {
+    key_patterns.iter().find_map(|(prefix, suffix)| {
+        key.strip_prefix(prefix.as_str())?
+            .strip_suffix(suffix.as_str())
+            .filter(|address| address.contains('@'))
+    })
+}
+
+/// Redis database of the first `REDIS_URL`, whose keyspace is watched.
+fn redis_db(config: &Config) -> i64 {
+    config
+        .redis_urls
+        .first()
+        .and_then(|url| url.as_str().into_connection_info().ok())
+        .map_or(0, |info| info.redis.db)
+}
+
+/// Load `ALLOWLIST_FILE` and start watching it. Panics on an unreadable
+/// file, like other startup configuration errors.
+fn load_allowlist(path: &str, config: &Config) -> Arc<Allowlist> {
+    let allowlist = Arc::new(
+        Allowlist::load(path).unwrap_or_else(|e| panic!("ALLOWLIST_FILE: {}: {}", path, e)),
+    );
+    let entries = allowlist.entries();
+    info!(
+        path = %path,
+        addresses = entries.addresses.len(),
+        domains = entries.domains.len(),
+        "static allowlist loaded"
+    );
+    if config.allowlist_reload_secs > 0 {
+        let interval = Duration::from_secs(config.allowlist_reload_secs);
+        tokio::spawn(allowlist.clone().watch(interval));
+    }
+    allowlist
+}
 
 /// Handles Redis-based mailbox existence checks.
 #[derive(Clone)]
 pub struct MailboxLookup {
-    conn: ConnectionManager,
+    store: SharedStore,
     key_pattern: String,
     set_name: String,
+    /// Per-domain overrides of `key_pattern` and `set_name`.
+    domain_key_patterns: HashMap<String, String>,
+    domain_set_names: HashMap<String, String>,
     check_mode: CheckMode,
+    domain_form: DomainForm,
+    blocklist_pattern: String,
+    catch_all_pattern: String,
+    /// TTL of mailbox keys created on a miss; `None` if they are not.
+    auto_create_ttl: Option<u64>,
+    /// `MAILBOX_EXTEND_SECS` and its cap; `None` if delivery does not
+    /// extend mailboxes.
+    extend: Option<(u64, u64)>,
+    alias_hash: String,
+    alias_max_hops: usize,
+    /// Set in script mode.
+    script: Option<CheckScript>,
+    /// `LOOKUP_REDIS_TIMEOUT_MS`, for each check or alias resolution as a
+    /// whole.
+    timeout: Duration,
+}
+
+/// The `REDIS_CHECK_SCRIPT` source and the SHA1 it runs under.
+#[derive(Clone)]
+struct CheckScript {
+    source: String,
+    sha: String,
+}
+
+impl CheckScript {
+    /// Panics if the script cannot be read, like other startup
+    /// configuration errors.
+    fn from_config(config: &Config) -> Option<Self> {
+        if !config.uses_lookup(StageKind::Redis) || config.redis_check_mode != CheckMode::Script {
+            return None;
+        }
+        let path = config.redis_check_script.as_deref().unwrap_or_default();
+        let source = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("REDIS_CHECK_SCRIPT: {}: {}", path, e));
+        let sha = store::script_sha(&source);
+        Some(Self { source, sha })
+    }
+}
+
+/// Load the script of `REDIS_CHECK_MODE=script` into Redis at startup, so
+/// a broken script is found before the first lookup. Returns its SHA1, or
+/// `None` in other modes.
+pub async fn load_check_script(
+    store: &dyn Store,
+    config: &Config,
+) -> Result<Option<String>, StoreError> {
+    match CheckScript::from_config(config) {
+        Some(script) => store.script_load(&script.source).await.map(Some),
+        None => Ok(None),
+    }
 }
 
 impl MailboxLookup {
-    pub fn new(conn: ConnectionManager, config: &Config) -> Self {
+    pub fn new(store: SharedStore, config: &Config) -> Self {
         Self {
-            conn,
+            store,
             key_pattern: config.redis_key_pattern.clone(),
             set_name: config.redis_set_name.clone(),
+            domain_key_patterns: config.redis_domain_key_patterns.clone(),
+            domain_set_names: config.redis_domain_set_names.clone(),
             check_mode: config.redis_check_mode.clone(),
+            domain_form: config.redis_domain_form,
+            blocklist_pattern: config.blocklist_key_pattern.clone(),
+            catch_all_pattern: config.catch_all_key_pattern.clone(),
+            auto_create_ttl: config
+                .auto_create_mailboxes
+                .then_some(config.auto_create_ttl_secs),
+            extend: (config.mailbox_extend_secs > 0)
+                .then_some((config.mailbox_extend_secs, config.mailbox_extend_max_secs)),
+            alias_hash: config.alias_hash.clone(),
+            alias_max_hops: config.alias_max_hops,
+            script: CheckScript::from_config(config),
+            timeout: Duration::from_millis(config.lookup_redis_timeout_ms),
+        }
+    }
+
+    /// One Redis check alone, for a `LOOKUP_CHAIN` stage: without the
+    /// catch-all or auto-created mailboxes.
+    pub fn tier(store: SharedStore, config: &Config, check_mode: CheckMode) -> Self {
+        Self {
+            check_mode,
+            catch_all_pattern: String::new(),
+            auto_create_ttl: None,
+            script: None,
+            ..Self::new(store, config)
+        }
+    }
+
+    /// An address as it is stored: the lookup form, with the domain in
+    /// Unicode when `REDIS_DOMAIN_FORM=unicode`.
+    fn stored_form<'a>(&self, address: &'a str) -> Cow<'a, str> {
+        let address = address::lookup_form(address);
+        match self.domain_form {
+            DomainForm::Unicode if address.contains("xn--") => {
+                Cow::Owned(address::with_unicode_domain(&address).into_owned())
+            }
+            _ => address,
         }
     }
 
-    /// Build the Redis key for a given address using the configured pattern.
+    /// Build the Redis key for a given address using the configured pattern
+    /// for its domain.
     fn key_for(&self, address: &str) -> String {
-        self.key_pattern
-            .replace("{address}", &address.to_lowercase())
+        let pattern = self
+            .domain_key_patterns
+            .get(address::domain(&address::lookup_form(address)))
+            .unwrap_or(&self.key_pattern);
+        pattern.replace("{address}", &self.stored_form(address))
+    }
+
+    /// The known-addresses set for a given address's domain; empty if it
+    /// has none.
+    fn set_name_for(&self, address: &str) -> &str {
+        self.domain_set_names
+            .get(address::domain(&address::lookup_form(address)))
+            .unwrap_or(&self.set_name)
     }
 
     /// Check if a mailbox is currently active (EXISTS on the key).
-    pub async fn is_active(&self, address: &str) -> Result<bool, redis::RedisError> {
+    pub async fn is_active(&self, address: &str) -> Result<bool, StoreError> {
         let key = self.key_for(address);
-        let mut conn = self.conn.clone();
-        let exists: bool = conn.exists(&key).await?;
+        let exists = self.store.exists(&key).await?;
         debug!(address = address, key = %key, exists = exists, "mailbox active check");
         Ok(exists)
     }
 
     /// Check if an address exists in the configured Redis SET.
-    pub async fn is_known(&self, address: &str) -> Result<bool, redis::RedisError> {
-        let mut conn = self.conn.clone();
-        let exists: bool = conn
-            .sismember(&self.set_name, address.to_lowercase())
-            .await?;
-        debug!(address = address, set = %self.set_name, exists = exists, "mailbox known check");
+    pub async fn is_known(&self, address: &str) -> Result<bool, StoreError> {
+        let set = self.set_name_for(address);
+        let address = self.stored_form(address);
+        let found = self.store.set_contains(set, &[&address]).await?;
+        let exists = found.first().copied().unwrap_or(false);
+        debug!(address = %address, set = %set, exists = exists, "mailbox known check");
         Ok(exists)
     }
 
-    /// Check if the mailbox should accept mail, respecting the configured check mode.
-    pub async fn should_accept(&self, address: &str) -> bool {
-        match self.check_mode {
-            CheckMode::KeyOnly => self.check_key(address).await,
-            CheckMode::SetOnly => self.check_set(address).await,
-            CheckMode::Both => {
-                if self.check_key(address).await {
-                    return true;
+    async fn check_key(&self, address: &str) -> LookupOutcome {
+        match self.is_active(address).await {
+            Ok(true) => LookupOutcome::KeyHit,
+            Ok(false) => LookupOutcome::Miss,
+            Err(e) => {
+                error!(error = %e, address = address, "redis error on key check");
+                LookupOutcome::Error // fail closed
+            }
+        }
+    }
+
+    async fn check_set(&self, address: &str) -> LookupOutcome {
+        if self.set_name_for(address).is_empty() {
+            return LookupOutcome::Miss;
+        }
+        match self.is_known(address).await {
+            Ok(true) => LookupOutcome::SetHit,
+            Ok(false) => LookupOutcome::Miss,
+            Err(e) => {
+                error!(error = %e, address = address, "redis error on set check, rejecting");
+                LookupOutcome::Error // fail closed
+            }
+        }
+    }
+
+    /// Key and set in one round trip. The set catches mail arriving in the
+    /// brief window between mailbox expiry and the sender's retry.
+    async fn check_both(&self, address: &str) -> LookupOutcome {
+        let key = self.key_for(address);
+        let member = self.stored_form(address);
+        match self
+            .store
+            .exists_and_contains(&key, self.set_name_for(address), &member)
+            .await
+        {
+            Ok((true, _)) => LookupOutcome::KeyHit,
+            Ok((false, true)) => LookupOutcome::SetHit,
+            Ok((false, false)) => LookupOutcome::Miss,
+            Err(e) => {
+                // One failing tier fails the whole pipeline; ask each on its
+                // own so the other can still answer
+                debug!(error = %e, address = address, "combined check failed, checking tiers separately");
+                self.check_tiers(address).await
+            }
+        }
+    }
+
+    /// Run the check script with the mailbox key (and the set, if any) as
+    /// KEYS and the address as ARGV[1]. A positive reply accepts.
+    async fn check_script(&self, script: &CheckScript, address: &str) -> LookupOutcome {
+        let key = self.key_for(address);
+        let set = self.set_name_for(address);
+        let keys = [key.as_str(), set];
+        let keys = if set.is_empty() {
+            &keys[..1]
+        } else {
+            &keys[..]
+        };
+        let args = [&*self.stored_form(address)];
+        let mut reply = self.store.eval_sha(&script.sha, keys, &args).await;
+        if let Err(StoreError::NoScript(_)) = reply {
+            // Redis restarted or flushed its scripts since startup
+            warn!(sha = %script.sha, "check script not loaded in Redis, loading it again");
+            reply = match self.store.script_load(&script.source).await {
+                Ok(_) => self.store.eval_sha(&script.sha, keys, &args).await,
+                Err(e) => Err(e),
+            };
+        }
+        match reply {
+            Ok(verdict) => {
+                debug!(address = address, key = %key, verdict, "check script");
+                if verdict > 0 {
+                    LookupOutcome::Found
+                } else {
+                    LookupOutcome::Miss
                 }
-                // Fallback: check the set. Catches mail arriving in the brief
-                // window between mailbox expiry and the sender's retry.
-                self.check_set(address).await
+            }
+            Err(e) => {
+                error!(error = %e, address = address, "redis error on check script, rejecting");
+                LookupOutcome::Error // fail closed
             }
         }
     }
 
-    async fn check_key(&self, address: &str) -> bool {
-        match self.is_active(address).await {
-            Ok(exists) => exists,
+    /// Whether the recipient's domain has a catch-all key, for domains that
+    /// create mailboxes when mail arrives.
+    async fn check_catch_all(&self, address: &str) -> LookupOutcome {
+        let address = self.stored_form(address);
+        let key = self
+            .catch_all_pattern
+            .replace("{domain}", address::domain(&address));
+        match self.store.exists(&key).await {
+            Ok(exists) => {
+                debug!(key = %key, exists = exists, "catch-all check");
+                if exists {
+                    LookupOutcome::CatchAll
+                } else {
+                    LookupOutcome::Miss
+                }
+            }
             Err(e) => {
-                error!(error = %e, address = address, "redis error on key check");
-                false // fail closed
+                error!(error = %e, key = %key, "redis error on catch-all check, rejecting");
+                LookupOutcome::Error // fail closed
+            }
+        }
+    }
+
+    /// Create the mailbox key for `address`, expiring after `ttl` seconds.
+    async fn create(&self, address: &str, ttl: u64) -> LookupOutcome {
+        let key = self.key_for(address);
+        match self.store.set_ex(&key, "1", ttl).await {
+            Ok(()) => {
+                info!(address = address, key = %key, ttl, "mailbox created on first delivery");
+                LookupOutcome::Created
+            }
+            Err(e) => {
+                error!(error = %e, key = %key, "redis error creating mailbox, rejecting");
+                LookupOutcome::Error // fail closed
+            }
+        }
+    }
+
+    async fn check_mailbox(&self, address: &str) -> LookupOutcome {
+        let outcome = match self.check_mode {
+            CheckMode::KeyOnly => self.check_key(address).await,
+            CheckMode::SetOnly => self.check_set(address).await,
+            CheckMode::Both if self.set_name_for(address).is_empty() => {
+                self.check_key(address).await
+            }
+            CheckMode::Both => self.check_both(address).await,
+            CheckMode::Script => match &self.script {
+                Some(script) => self.check_script(script, address).await,
+                None => LookupOutcome::Error,
+            },
+        };
+        let outcome = if outcome == LookupOutcome::Miss && !self.catch_all_pattern.is_empty() {
+            self.check_catch_all(address).await
+        } else {
+            outcome
+        };
+        match self.auto_create_ttl {
+            Some(ttl) if outcome == LookupOutcome::Miss => self.create(address, ttl).await,
+            _ => outcome,
+        }
+    }
+
+    async fn follow_aliases(&self, address: &str) -> Result<Option<String>, AliasError> {
+        let mut chain = vec![address::lookup_form(address).into_owned()];
+        loop {
+            let current = self.stored_form(&chain[chain.len() - 1]).into_owned();
+            let Some(target) = self.store.hash_get(&self.alias_hash, &current).await? else {
+                break;
+            };
+            let target = address::lookup_form(target.trim()).into_owned();
+            if chain.contains(&target) || chain.len() > self.alias_max_hops {
+                warn!(address = address, chain = ?chain, "alias loop");
+                return Err(AliasError::Loop);
             }
+            chain.push(target);
         }
+        debug!(address = address, chain = ?chain, "alias resolution");
+        Ok(chain.pop().filter(|_| !chain.is_empty()))
     }
 
-    async fn check_set(&self, address: &str) -> bool {
-        if self.set_name.is_empty() {
+    async fn check_tiers(&self, address: &str) -> LookupOutcome {
+        let key = self.check_key(address).await;
+        if key.is_hit() {
+            return key;
+        }
+        match self.check_set(address).await {
+            LookupOutcome::Miss if key == LookupOutcome::Error => LookupOutcome::Error,
+            set => set,
+        }
+    }
+}
+
+#[async_trait]
+impl LookupBackend for MailboxLookup {
+    /// Check if the recipient's owner has blocked this sender.
+    ///
+    /// The per-mailbox set may hold full sender addresses (`spam@evil.com`)
+    /// or whole domains (`evil.com`), with IDN domains in either punycode or
+    /// Unicode. Redis errors fail open: the blocklist is a user convenience,
+    /// not the primary filter.
+    async fn is_sender_blocked(&self, recipient: &str, sender: &str) -> bool {
+        if self.blocklist_pattern.is_empty() || sender.is_empty() {
             return false;
         }
-        match self.is_known(address).await {
-            Ok(known) => known,
+        let key = self
+            .blocklist_pattern
+            .replace("{address}", &self.stored_form(recipient));
+        let sender = address::lookup_form(sender);
+        let domain = address::domain(&sender);
+        let unicode_domain = address::domain_to_unicode(domain);
+        let unicode_sender;
+        let members: &[&str] = if unicode_domain != domain {
+            let (local, _) = address::split(&sender);
+            unicode_sender = format!("{}@{}", local, unicode_domain);
+            &[&sender, domain, &unicode_sender, &unicode_domain]
+        } else {
+            &[&sender, domain]
+        };
+        let found = tokio::time::timeout(self.timeout, self.store.set_contains(&key, members));
+        match found.await.unwrap_or(Err(StoreError::Timeout)) {
+            Ok(found) => {
+                let by_address = found.iter().step_by(2).any(|&hit| hit);
+                let by_domain = found.iter().skip(1).step_by(2).any(|&hit| hit);
+                debug!(key = %key, sender = %sender, by_address, by_domain, "sender blocklist check");
+                by_address || by_domain
+            }
             Err(e) => {
-                error!(error = %e, address = address, "redis error on set check, rejecting");
-                false // fail closed
+                error!(error = %e, key = %key, "redis error on blocklist check, allowing");
+                false
+            }
+        }
+    }
+
+    /// Run the checks of `REDIS_CHECK_MODE`, then the catch-all if none
+    /// found the address, and report which tier (if any) matched. A miss
+    /// creates the mailbox when `AUTO_CREATE_MAILBOXES` is on. Taking longer
+    /// than `LOOKUP_REDIS_TIMEOUT_MS` is an error.
+    async fn check(&self, address: &str) -> LookupOutcome {
+        match tokio::time::timeout(self.timeout, self.check_mailbox(address)).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                error!(
+                    address = address,
+                    timeout_ms = self.timeout.as_millis() as u64,
+                    "redis lookup timed out"
+                );
+                LookupOutcome::Error
+            }
+        }
+    }
+
+    /// Push back the expiry of the mailbox key by `MAILBOX_EXTEND_SECS`, to
+    /// at most `MAILBOX_EXTEND_MAX_SECS` from now. Keys that never expire
+    /// are left alone; errors are logged and ignored.
+    async fn delivered(&self, address: &str) {
+        let Some((by, max)) = self.extend else {
+            return;
+        };
+        let key = self.key_for(address);
+        let extended = async {
+            let Some(ttl) = self.store.ttl(&key).await? else {
+                return Ok(None);
+            };
+            let extended = ttl.saturating_add(by).min(max);
+            if extended <= ttl {
+                return Ok(None);
+            }
+            self.store
+                .expire(&key, extended)
+                .await
+                .map(|exists| exists.then_some(extended))
+        }
+        .await;
+        match extended {
+            Ok(Some(ttl)) => debug!(key = %key, ttl, "mailbox extended"),
+            Ok(None) => {}
+            Err::<_, StoreError>(e) => {
+                warn!(error = %e, key = %key, "redis error extending mailbox")
+            }
+        }
+    }
+
+    /// Follow `ALIAS_HASH` from `address` until an address that is not an
+    /// alias. Fields and values are addresses as they are stored.
+    async fn resolve_alias(&self, address: &str) -> Result<Option<String>, AliasError> {
+        if self.alias_hash.is_empty() {
+            return Ok(None);
+        }
+        tokio::time::timeout(self.timeout, self.follow_aliases(address))
+            .await
+            .unwrap_or(Err(AliasError::Store(StoreError::Timeout)))
+    }
+}
+
+/// Asks an HTTP service whether mailboxes exist, for deployments that keep
+/// them outside Redis.
+///
+/// Each lookup is `GET <LOOKUP_HTTP_URL>?address=<recipient>`, with the
+/// domain in punycode. A 2xx status means the mailbox exists and 404 that it
+/// does not; anything else, a timeout or a connection failure is an error
+/// and fails closed.
+pub struct HttpLookup {
+    url: HttpUrl,
+    /// Sent as `Authorization: Bearer <token>`.
+    token: Option<String>,
+    /// Only for `https` URLs.
+    tls: Option<TlsConnector>,
+    timeout: Duration,
+}
+
+impl HttpLookup {
+    /// Panics on an invalid `LOOKUP_HTTP_URL` or `LOOKUP_HTTP_CA_PATH`, like
+    /// other startup configuration errors.
+    pub fn new(url: &str, config: &Config) -> Self {
+        let url: HttpUrl = url
+            .parse()
+            .unwrap_or_else(|e| panic!("LOOKUP_HTTP_URL: {}", e));
+        let tls = url.https.then(|| {
+            tls::verifying_connector(&config.lookup_http_ca_path)
+                .unwrap_or_else(|e| panic!("LOOKUP_HTTP_CA_PATH: {}", e))
+        });
+        Self {
+            url,
+            token: config.lookup_http_token.clone(),
+            tls,
+            timeout: Duration::from_millis(config.lookup_http_timeout_ms),
+        }
+    }
+
+    /// Ask the service; `None` on an error, which has been logged.
+    async fn ask(&self, address: &str) -> Option<bool> {
+        let separator = if self.url.path.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let path = format!(
+            "{}{}address={}",
+            self.url.path,
+            separator,
+            http::query_escape(address)
+        );
+        let request = Request {
+            method: "GET",
+            path: &path,
+            token: self.token.as_deref(),
+            headers: &[],
+            body: None,
+        };
+        let sent = tokio::time::timeout(
+            self.timeout,
+            http::send(&self.url, self.tls.as_ref(), &request),
+        );
+        let error = match sent.await {
+            Ok(Ok(status)) if (200..300).contains(&status) => return Some(true),
+            Ok(Ok(404)) => return Some(false),
+            Ok(Ok(status)) => format!("HTTP {status}"),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+        error!(
+            error = %error,
+            host = %self.url.host,
+            address = address,
+            "lookup service error, rejecting"
+        );
+        None
+    }
+}
+
+#[async_trait]
+impl LookupBackend for HttpLookup {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        let address = address::lookup_form(address);
+        match self.ask(&address).await {
+            Some(exists) => {
+                debug!(address = %address, exists, "mailbox http check");
+                found_or_miss(exists)
+            }
+            None => LookupOutcome::Error, // fail closed
+        }
+    }
+}
+
+/// Looks mailboxes up in PostgreSQL with `LOOKUP_POSTGRES_QUERY`, e.g.
+/// `SELECT 1 FROM mailboxes WHERE address = $1 AND expires_at > now()`.
+///
+/// `$1` is the address in its lookup form, with the domain in punycode;
+/// any returned row means the mailbox exists. The query is prepared once
+/// per pooled connection. Errors and timeouts fail closed.
+pub struct PostgresLookup {
+    pool: PgPool,
+}
+
+impl PostgresLookup {
+    /// Panics on an invalid `LOOKUP_POSTGRES_URL` or CA bundle, like other
+    /// startup configuration errors. Connects lazily.
+    pub fn new(url: &str, config: &Config) -> Self {
+        let url: PgUrl = url
+            .parse()
+            .unwrap_or_else(|e| panic!("LOOKUP_POSTGRES_URL: {}", e));
+        let pool = PgPool::new(
+            url,
+            &config.lookup_postgres_query,
+            config.lookup_postgres_pool_size,
+            Duration::from_millis(config.lookup_postgres_timeout_ms),
+            &config.lookup_postgres_ca_path,
+        )
+        .unwrap_or_else(|e| panic!("LOOKUP_POSTGRES_CA_PATH: {}", e));
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl LookupBackend for PostgresLookup {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        let address = address::lookup_form(address);
+        match self.pool.exists(&address).await {
+            Ok(exists) => {
+                debug!(address = %address, exists, "mailbox postgres check");
+                found_or_miss(exists)
+            }
+            Err(e) => {
+                error!(error = %e, address = %address, "postgres error on lookup, rejecting");
+                LookupOutcome::Error // fail closed
+            }
+        }
+    }
+}
+
+/// Checks for the recipient's key in memcached, for deployments that keep
+/// their index of active mailboxes there. Errors fail closed.
+pub struct MemcachedLookup {
+    pool: McPool,
+    key_pattern: String,
+}
+
+impl MemcachedLookup {
+    /// Connects lazily.
+    pub fn new(addr: &str, config: &Config) -> Self {
+        Self {
+            pool: McPool::new(
+                addr,
+                config.lookup_memcached_pool_size,
+                Duration::from_millis(config.lookup_memcached_timeout_ms),
+            ),
+            key_pattern: config.lookup_memcached_key_pattern.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl LookupBackend for MemcachedLookup {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        let address = address::lookup_form(address);
+        let key = self.key_pattern.replace("{address}", &address);
+        match self.pool.exists(&key).await {
+            Ok(exists) => {
+                debug!(address = %address, exists, "mailbox memcached check");
+                found_or_miss(exists)
+            }
+            Err(e) => {
+                error!(error = %e, address = %address, "memcached error on lookup, rejecting");
+                LookupOutcome::Error // fail closed
+            }
+        }
+    }
+}
+
+/// Remembers another backend's answers in-process, so a burst of RCPTs to
+/// the same mailbox costs one lookup: hits for `LOOKUP_CACHE_TTL` and
+/// misses, which absorb dictionary attacks, for the shorter
+/// `LOOKUP_NEGATIVE_CACHE_TTL`. Errors are never cached, and blocklist
+/// checks always go to the backend.
+///
+/// An address published to `LOOKUP_INVALIDATE_CHANNEL` is forgotten at
+/// once, so a mailbox created right after a miss does not wait out the
+/// negative TTL. With `LOOKUP_KEYSPACE_NOTIFICATIONS`, so
+/// is one whose mailbox key Redis reports created, deleted or expired.
+pub struct CachedLookup {
+    backend: SharedLookup,
+    ttl: Duration,
+    negative_ttl: Duration,
+    /// Address -> answer, and when it expires.
+    map: Mutex<HashMap<String, (LookupOutcome, Instant)>>,
+    metrics: Arc<Metrics>,
+}
+
+impl CachedLookup {
+    pub fn new(backend: SharedLookup, config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            backend,
+            ttl: Duration::from_secs(config.lookup_cache_ttl_secs),
+            negative_ttl: Duration::from_secs(config.lookup_negative_cache_ttl_secs),
+            map: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Forget the cached answer for `address`, found or not.
+    pub fn invalidate(&self, address: &str) {
+        let address = address::lookup_form(address);
+        let removed = self.map.lock().unwrap().remove(address.as_ref()).is_some();
+        debug!(address = %address, removed, "mailbox lookup cache invalidated");
+    }
+
+    /// Invalidate each address published to `channel`, e.g. by the
+    /// application right after it creates the mailbox.
+    pub async fn follow_invalidations(self: Arc<Self>, store: SharedStore, channel: String) {
+        let mut addresses = subscribe(&store, &channel, "lookup invalidations").await;
+        while let Some(address) = addresses.recv().await {
+            self.invalidate(address.trim());
+        }
+    }
+
+    /// Invalidate the address of each mailbox key created, deleted,
+    /// expired or evicted in Redis database `db`, as told by keyspace
+    /// notifications (`notify-keyspace-events` must include `Eg$xe`).
+    /// `key_patterns` are the prefix and suffix around `{address}`.
+    pub async fn follow_keyspace(
+        self: Arc<Self>,
+        store: SharedStore,
+        db: i64,
+        key_patterns: Vec<(String, String)>,
+    ) {
+        let mut tasks = tokio::task::JoinSet::new();
+        for event in KEYSPACE_EVENTS {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            let (cache, store, key_patterns) = (self.clone(), store.clone(), key_patterns.clone());
+            tasks.spawn(async move {
+                let mut keys = subscribe(&store, &channel, "keyspace notifications").await;
+                while let Some(key) = keys.recv().await {
+                    if let Some(address) = address_in_key(&key, &key_patterns) {
+                        cache.invalidate(address);
+                    }
+                }
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
+    fn get(&self, address: &str) -> Option<LookupOutcome> {
+        let map = self.map.lock().unwrap();
+        map.get(address)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|&(outcome, _)| outcome)
+    }
+
+    fn put(&self, address: String, outcome: LookupOutcome) {
+        let ttl = match outcome {
+            LookupOutcome::Miss => self.negative_ttl,
+            LookupOutcome::Error => return,
+            _ => self.ttl,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut map = self.map.lock().unwrap();
+        if map.len() > CACHE_CLEANUP_THRESHOLD {
+            map.retain(|_, (_, expires)| *expires > now);
+        }
+        map.insert(address, (outcome, now + ttl));
+    }
+}
+
+#[async_trait]
+impl LookupBackend for CachedLookup {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        let key = address::lookup_form(address);
+        if let Some(outcome) = self.get(&key) {
+            tracing::Span::current().record("lookup.cache", "hit");
+            self.metrics
+                .lookup_cache_hits
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(address = %key, outcome = outcome.as_str(), "mailbox lookup cache hit");
+            return outcome;
+        }
+        tracing::Span::current().record("lookup.cache", "miss");
+        self.metrics
+            .lookup_cache_misses
+            .fetch_add(1, Ordering::Relaxed);
+        let outcome = self.backend.check(address).await;
+        self.put(key.into_owned(), outcome);
+        outcome
+    }
+
+    async fn is_sender_blocked(&self, recipient: &str, sender: &str) -> bool {
+        self.backend.is_sender_blocked(recipient, sender).await
+    }
+
+    async fn delivered(&self, address: &str) {
+        self.backend.delivered(address).await
+    }
+
+    async fn resolve_alias(&self, address: &str) -> Result<Option<String>, AliasError> {
+        self.backend.resolve_alias(address).await
+    }
+}
+
+/// Rejects recipients that a Bloom filter of the mailbox keys and sets has
+/// never seen, so random local parts cost no Redis lookup. Anything the
+/// filter may know goes on to the backend.
+///
+/// The filter is rebuilt from Redis every `BLOOM_FILTER_REFRESH_SECS`, and
+/// addresses published to `LOOKUP_INVALIDATE_CHANNEL` are added at once.
+/// Until the first build succeeds, every recipient is looked up.
+pub struct BloomLookup {
+    backend: SharedLookup,
+    store: SharedStore,
+    filter: RwLock<Option<BloomFilter>>,
+    /// Addresses added while a rebuild is scanning, so the new filter
+    /// gets them too. `None` outside a rebuild.
+    added: Mutex<Option<Vec<String>>>,
+    /// Prefix and suffix of each key pattern, around `{address}`.
+    key_patterns: Vec<(String, String)>,
+    set_names: Vec<String>,
+    fp_rate: f64,
+    metrics: Arc<Metrics>,
+}
+
+impl BloomLookup {
+    pub fn new(
+        backend: SharedLookup,
+        store: SharedStore,
+        config: &Config,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let mut set_names = Vec::new();
+        if config.redis_check_mode != CheckMode::KeyOnly {
+            for name in std::iter::once(&config.redis_set_name)
+                .chain(config.redis_domain_set_names.values())
+            {
+                if !name.is_empty() && !set_names.contains(name) {
+                    set_names.push(name.clone());
+                }
+            }
+        }
+        Self {
+            backend,
+            store,
+            filter: RwLock::new(None),
+            added: Mutex::new(None),
+            key_patterns: key_patterns(config),
+            set_names,
+            fp_rate: config.bloom_filter_fp_rate,
+            metrics,
+        }
+    }
+
+    /// Replace the filter with one built from every mailbox key and set
+    /// member. Returns how many addresses it holds; on error the current
+    /// filter is kept.
+    pub async fn rebuild(&self) -> Result<usize, StoreError> {
+        *self.added.lock().unwrap() = Some(Vec::new());
+        let scanned = self.scan().await;
+        let mut added = self.added.lock().unwrap();
+        let recent = added.take().unwrap_or_default();
+        let addresses = scanned?;
+        let mut filter = BloomFilter::new(addresses.len() + recent.len(), self.fp_rate);
+        for address in addresses.iter().chain(&recent) {
+            filter.insert(&address::lookup_form(address));
+        }
+        *self.filter.write().unwrap() = Some(filter);
+        Ok(addresses.len() + recent.len())
+    }
+
+    async fn scan(&self) -> Result<Vec<String>, StoreError> {
+        let mut addresses = Vec::new();
+        for (prefix, suffix) in &self.key_patterns {
+            let keys = self.store.keys_between(prefix, suffix).await?;
+            addresses.extend(
+                keys.into_iter()
+                    .map(|key| key[prefix.len()..key.len() - suffix.len()].to_string()),
+            );
+        }
+        for name in &self.set_names {
+            addresses.extend(self.store.set_members(name).await?);
+        }
+        Ok(addresses)
+    }
+
+    /// Add `address`, e.g. a mailbox created since the last rebuild.
+    pub fn add(&self, address: &str) {
+        let address = address::lookup_form(address).into_owned();
+        let mut added = self.added.lock().unwrap();
+        if let Some(filter) = self.filter.write().unwrap().as_mut() {
+            filter.insert(&address);
+        }
+        if let Some(added) = added.as_mut() {
+            added.push(address);
+        }
+    }
+
+    /// Rebuild the filter now and every `refresh`.
+    pub async fn maintain(self: Arc<Self>, refresh: Duration) {
+        let mut interval = tokio::time::interval(refresh);
+        loop {
+            interval.tick().await;
+            let started = Instant::now();
+            match self.rebuild().await {
+                Ok(addresses) => info!(
+                    addresses,
+                    bytes = self
+                        .filter
+                        .read()
+                        .unwrap()
+                        .as_ref()
+                        .map_or(0, BloomFilter::size),
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "bloom filter rebuilt"
+                ),
+                Err(e) => {
+                    warn!(error = %e, "failed to rebuild bloom filter, keeping the previous one")
+                }
+            }
+        }
+    }
+
+    /// Add each address published to `channel`, like
+    /// [`CachedLookup::follow_invalidations`].
+    pub async fn follow_additions(self: Arc<Self>, store: SharedStore, channel: String) {
+        let mut addresses = subscribe(&store, &channel, "bloom filter additions").await;
+        while let Some(address) = addresses.recv().await {
+            self.add(address.trim());
+        }
+    }
+}
+
+#[async_trait]
+impl LookupBackend for BloomLookup {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        let unknown = self
+            .filter
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|filter| !filter.contains(&address::lookup_form(address)));
+        if unknown {
+            self.metrics.bloom_rejected.fetch_add(1, Ordering::Relaxed);
+            debug!(address = address, "not in bloom filter");
+            return LookupOutcome::Miss;
+        }
+        self.backend.check(address).await
+    }
+
+    async fn is_sender_blocked(&self, recipient: &str, sender: &str) -> bool {
+        self.backend.is_sender_blocked(recipient, sender).await
+    }
+
+    async fn delivered(&self, address: &str) {
+        self.backend.delivered(address).await
+    }
+
+    async fn resolve_alias(&self, address: &str) -> Result<Option<String>, AliasError> {
+        self.backend.resolve_alias(address).await
+    }
+}
+
+/// Accepts the recipients of an [`Allowlist`] without asking the backend,
+/// for system addresses and for mailboxes not yet migrated to it.
+pub struct AllowlistLookup {
+    allowlist: Arc<Allowlist>,
+    backend: SharedLookup,
+}
+
+impl AllowlistLookup {
+    pub fn new(allowlist: Arc<Allowlist>, backend: SharedLookup) -> Self {
+        Self { allowlist, backend }
+    }
+}
+
+#[async_trait]
+impl LookupBackend for AllowlistLookup {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        if self.allowlist.contains(address) {
+            debug!(address = address, "recipient allowlisted");
+            return LookupOutcome::Allowlisted;
+        }
+        self.backend.check(address).await
+    }
+
+    async fn is_sender_blocked(&self, recipient: &str, sender: &str) -> bool {
+        self.backend.is_sender_blocked(recipient, sender).await
+    }
+
+    async fn delivered(&self, address: &str) {
+        self.backend.delivered(address).await
+    }
+
+    async fn resolve_alias(&self, address: &str) -> Result<Option<String>, AliasError> {
+        self.backend.resolve_alias(address).await
+    }
+}
+
+/// The allowlist alone, for a `LOOKUP_CHAIN` stage: a miss for anything
+/// not listed.
+#[async_trait]
+impl LookupBackend for Allowlist {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        if self.contains(address) {
+            LookupOutcome::Allowlisted
+        } else {
+            LookupOutcome::Miss
+        }
+    }
+}
+
+/// Asks the `LOOKUP_CHAIN` stages in order. Each answer (hit, miss or
+/// error) accepts, rejects, or goes on to the next stage, as the stage is
+/// configured; past the last stage the recipient is rejected, or the lookup
+/// fails if a stage had an error.
+pub struct LookupChain {
+    stages: Vec<(ChainStage, SharedLookup)>,
+    /// Blocklists, aliases and mailbox extension, when a stage uses Redis.
+    redis: Option<SharedLookup>,
+}
+
+impl LookupChain {
+    pub fn new(stages: Vec<(ChainStage, SharedLookup)>, redis: Option<SharedLookup>) -> Self {
+        Self { stages, redis }
+    }
+
+    /// The stages of `LOOKUP_CHAIN`. `allowlist` must be given if a stage
+    /// uses it.
+    pub fn from_config(
+        store: SharedStore,
+        config: &Config,
+        allowlist: Option<Arc<Allowlist>>,
+    ) -> Self {
+        let redis: SharedLookup = Arc::new(MailboxLookup::new(store.clone(), config));
+        let tier =
+            |mode| -> SharedLookup { Arc::new(MailboxLookup::tier(store.clone(), config, mode)) };
+        let stages = config
+            .lookup_chain
+            .iter()
+            .map(|stage| {
+                let backend: SharedLookup = match stage.kind {
+                    StageKind::Allowlist => allowlist
+                        .clone()
+                        .expect("ALLOWLIST_FILE is required for an allowlist stage"),
+                    StageKind::Key => tier(CheckMode::KeyOnly),
+                    StageKind::Set => tier(CheckMode::SetOnly),
+                    StageKind::Redis => redis.clone(),
+                    StageKind::Http => Arc::new(HttpLookup::new(
+                        config.lookup_http_url.as_deref().unwrap_or_default(),
+                        config,
+                    )),
+                    StageKind::Postgres => Arc::new(PostgresLookup::new(
+                        config.lookup_postgres_url.as_deref().unwrap_or_default(),
+                        config,
+                    )),
+                    StageKind::Memcached => Arc::new(MemcachedLookup::new(
+                        config.lookup_memcached_addr.as_deref().unwrap_or_default(),
+                        config,
+                    )),
+                };
+                (stage.clone(), backend)
+            })
+            .collect();
+        let uses_redis = config.lookup_chain.iter().any(|stage| {
+            matches!(
+                stage.kind,
+                StageKind::Key | StageKind::Set | StageKind::Redis
+            )
+        });
+        Self::new(stages, uses_redis.then_some(redis))
+    }
+}
+
+#[async_trait]
+impl LookupBackend for LookupChain {
+    async fn check(&self, address: &str) -> LookupOutcome {
+        let mut failed = false;
+        for (stage, backend) in &self.stages {
+            let outcome = backend.check(address).await;
+            let action = match outcome {
+                LookupOutcome::Error => {
+                    failed = true;
+                    stage.on_error
+                }
+                outcome if outcome.is_hit() => stage.on_hit,
+                _ => stage.on_miss,
+            };
+            debug!(
+                address = address,
+                stage = stage.kind.as_str(),
+                outcome = outcome.as_str(),
+                action = ?action,
+                "lookup chain stage"
+            );
+            match action {
+                ChainAction::Accept => return outcome,
+                ChainAction::Reject if outcome == LookupOutcome::Error => return outcome,
+                ChainAction::Reject => return LookupOutcome::Miss,
+                ChainAction::Continue => {}
             }
         }
+        if failed {
+            LookupOutcome::Error
+        } else {
+            LookupOutcome::Miss
+        }
+    }
+
+    async fn is_sender_blocked(&self, recipient: &str, sender: &str) -> bool {
+        match &self.redis {
+            Some(redis) => redis.is_sender_blocked(recipient, sender).await,
+            None => false,
+        }
+    }
+
+    async fn delivered(&self, address: &str) {
+        if let Some(redis) = &self.redis {
+            redis.delivered(address).await
+        }
+    }
+
+    async fn resolve_alias(&self, address: &str) -> Result<Option<String>, AliasError> {
+        match &self.redis {
+            Some(redis) => redis.resolve_alias(address).await,
+            None => Ok(None),
+        }
+    }
+}
+
+fn found_or_miss(exists: bool) -> LookupOutcome {
+    if exists {
+        LookupOutcome::Found
+    } else {
+        LookupOutcome::Miss
     }
 }
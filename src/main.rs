@@ -1,19 +1,45 @@
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use redis::Client;
-use tokio::net::TcpListener;
-use tokio::sync::Semaphore;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::task::JoinSet;
 use tracing::{error, info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
-use burngate::config::Config;
-use burngate::lookup::MailboxLookup;
-use burngate::ratelimit::IpRateLimiter;
-use burngate::session::Metrics;
+use burngate::audit::AuditLog;
+use burngate::autoblock::AutoBlocklist;
+use burngate::clamav::ClamAv;
+use burngate::config::{Config, GeoAction, LookupKind, RdnsCheck};
+use burngate::deadletter::DeadLetter;
+use burngate::dns::Resolver;
+use burngate::dnsbl::Dnsbl;
+use burngate::flags::FeatureFlags;
+use burngate::geoip::GeoIp;
+use burngate::lookup;
+use burngate::mirror::Mirror;
+use burngate::netlist::{Listed, NetworkList};
+use burngate::network;
+use burngate::pools::ConnectionPools;
+use burngate::proxy::{self, ProxyMode};
+use burngate::ratelimit::{ConnectionRateLimiter, StoreRateLimiter};
+use burngate::rejectlog::{self, RejectLog};
+use burngate::relay::Backend;
+use burngate::reply::{self, SmtpReply};
+use burngate::reputation::Reputation;
+use burngate::rspamd::Rspamd;
+use burngate::rules::RuleSet;
+use burngate::sampling::{self, LogSampler};
+use burngate::selftest;
+use burngate::session::{self, Metrics};
+use burngate::spamd::Spamd;
+use burngate::spamtrap::Spamtraps;
+use burngate::spool::Spool;
+use burngate::stats::DeliveryStats;
+use burngate::store::{FailoverStore, SharedStore};
 use burngate::tls::TlsConfig;
+use burngate::webhook::Webhook;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -52,22 +78,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!(
         listen = %config.listen_addr,
-        backend = %config.backend_addr,
+        backends = ?config.backend_addrs,
         domains = ?config.accepted_domains,
         tls = config.tls_available(),
         "starting burngate"
     );
 
+    let metrics = Arc::new(Metrics::new());
+
     // Connect to Redis
-    let redis_client = Client::open(config.redis_url.as_str())?;
-    let conn_manager = redis::aio::ConnectionManager::new(redis_client).await?;
-    let lookup = MailboxLookup::new(conn_manager, &config);
-    info!(
-        key_pattern = %config.redis_key_pattern,
-        set_name = %config.redis_set_name,
-        check_mode = ?config.redis_check_mode,
-        "connected to Redis"
-    );
+    let redis = FailoverStore::new(&config.redis_urls, &config, metrics.clone())?;
+    let node = redis.connect().await?;
+    info!(node = %node, nodes = config.redis_urls.len(), "connected to Redis");
+    if config.redis_urls.len() > 1 && config.redis_failback_secs > 0 {
+        tokio::spawn(
+            redis
+                .clone()
+                .fail_back(tokio::time::Duration::from_secs(config.redis_failback_secs)),
+        );
+    }
+    let store: SharedStore = Arc::new(redis);
+    if let Some(sha) = lookup::load_check_script(store.as_ref(), &config).await? {
+        info!(sha = %sha, "loaded check script");
+    }
+    let lookup = lookup::from_config(store.clone(), &config, metrics.clone());
+    match config.lookup_backend {
+        _ if !config.lookup_chain.is_empty() => info!(
+            stages = ?config
+                .lookup_chain
+                .iter()
+                .map(|stage| stage.kind.as_str())
+                .collect::<Vec<_>>(),
+            "mailbox lookups through a chain"
+        ),
+        LookupKind::Redis => info!(
+            key_pattern = %config.redis_key_pattern,
+            set_name = %config.redis_set_name,
+            check_mode = ?config.redis_check_mode,
+            "mailbox lookups in Redis"
+        ),
+        LookupKind::Http => info!(
+            url = config.lookup_http_url.as_deref().unwrap_or_default(),
+            "mailbox lookups over HTTP"
+        ),
+        LookupKind::Postgres => info!(
+            query = %config.lookup_postgres_query,
+            pool_size = config.lookup_postgres_pool_size,
+            "mailbox lookups in Postgres"
+        ),
+        LookupKind::Memcached => info!(
+            addr = config.lookup_memcached_addr.as_deref().unwrap_or_default(),
+            key_pattern = %config.lookup_memcached_key_pattern,
+            "mailbox lookups in memcached"
+        ),
+    }
 
     // Load TLS config if available
     let tls_config = if config.tls_available() {
@@ -88,26 +152,305 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         None
     };
 
-    let config = Arc::new(config);
-    let metrics = Arc::new(Metrics::new());
-
-    // Connection semaphore (0 = unlimited, use a very large value)
-    let semaphore = Arc::new(Semaphore::new(if config.max_connections > 0 {
-        config.max_connections
-    } else {
-        Semaphore::MAX_PERMITS
-    }));
+    // Feature flags from config, with periodic Redis overrides
+    let flags = FeatureFlags::new(config.feature_flags.clone());
+    if !config.feature_flags_redis_key.is_empty() && config.feature_flags_refresh_secs > 0 {
+        let flags = flags.clone();
+        let store = store.clone();
+        let key = config.feature_flags_redis_key.clone();
+        let interval_secs = config.feature_flags_refresh_secs;
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(tokio::time::Duration::from_secs(interval_secs));
+            loop {
+                interval.tick().await;
+                if let Err(e) = flags.refresh_from_store(store.as_ref(), &key).await {
+                    warn!(error = %e, key = %key, "failed to refresh feature flags");
+                }
+            }
+        });
+    }
+    info!(flags = ?config.feature_flags, "feature flags loaded");
+    if config.shadow_mode {
+        warn!("shadow mode: policy rejections are logged but not enforced");
+    }
 
-    // Per-IP rate limiter (None if disabled)
-    let rate_limiter = if config.max_connections_per_ip > 0 {
-        Some(Arc::new(IpRateLimiter::new(config.max_connections_per_ip)))
+    // Connection audit trail with periodic IP anonymization
+    let audit = if config.audit_enabled {
+        let audit = AuditLog::new(store.clone(), &config);
+        let anonymizer = audit.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                if let Err(e) = anonymizer.anonymize_expired().await {
+                    warn!(error = %e, "audit anonymization failed");
+                }
+            }
+        });
+        info!(
+            prefix = %config.audit_key_prefix,
+            retention_days = config.audit_retention_days,
+            anonymize_after_days = config.audit_anonymize_after_days,
+            "connection audit trail enabled"
+        );
+        Some(audit)
     } else {
         None
     };
 
+    let config = Arc::new(config);
+
+    // Stub resolver for the client's rDNS name in Received headers and
+    // backend SRV discovery
+    let resolver = Resolver::from_config(&config);
+
+    // DNS blocklists for client IPs
+    let dnsbl = Dnsbl::from_config(&config, resolver.clone());
+    if !config.dnsbl_lists.is_empty() {
+        info!(
+            lists = ?config
+                .dnsbl_lists
+                .iter()
+                .map(|list| list.zone.as_str())
+                .collect::<Vec<_>>(),
+            check_at = ?config.dnsbl_check_at,
+            "DNSBL checks enabled"
+        );
+    }
+
+    if config.rdns_check != RdnsCheck::Off {
+        info!(
+            check = ?config.rdns_check,
+            action = ?config.rdns_action,
+            "Reverse DNS checks enabled"
+        );
+    }
+
+    if !config.helo_checks.is_empty() {
+        info!(
+            checks = ?config
+                .helo_checks
+                .iter()
+                .map(|(check, action)| format!("{}:{:?}", check.as_str(), action))
+                .collect::<Vec<_>>(),
+            "HELO checks enabled"
+        );
+    }
+
+    // Relay target; idle backend sessions are kept open for reuse
+    let backend = Backend::from_config(&config);
+    if let Some(name) = config.backend_srv.clone() {
+        info!(
+            name = %name,
+            refresh_secs = config.backend_srv_refresh_secs,
+            "backend discovery via SRV enabled"
+        );
+        let interval = tokio::time::Duration::from_secs(config.backend_srv_refresh_secs.max(1));
+        tokio::spawn(backend.clone().discover(resolver.clone(), name, interval));
+    }
+
+    // Background copies of relayed mail to a standby backend
+    let mirror = config.mirror_backend_addr.as_deref().map(|addr| {
+        info!(
+            backend = addr,
+            max_pending = config.mirror_max_pending,
+            "mirror delivery enabled"
+        );
+        Mirror::new(addr, &config, metrics.clone())
+    });
+
+    // Delivery events for the frontend
+    let webhook = config.webhook_url.as_deref().map(|url| {
+        info!(
+            max_pending = config.webhook_max_pending,
+            "delivery webhook enabled"
+        );
+        Webhook::new(url, &config, metrics.clone())
+    });
+
+    // Content scanning after DATA
+    let rspamd = config.rspamd_url.as_deref().map(|url| {
+        info!(
+            url = url,
+            timeout_ms = config.rspamd_timeout_ms,
+            "Rspamd scanning enabled"
+        );
+        Rspamd::new(url, &config)
+    });
+    let spamd = config.spamd_addr.as_deref().map(|addr| {
+        info!(
+            addr = addr,
+            reject_score = ?config.spamd_reject_score,
+            "SpamAssassin scanning enabled"
+        );
+        Spamd::new(addr, &config)
+    });
+    let clamav = config.clamav_addr.as_deref().map(|addr| {
+        info!(
+            addr = addr,
+            max_size = config.clamav_max_size,
+            fail_open = config.clamav_fail_open,
+            "ClamAV scanning enabled"
+        );
+        ClamAv::new(addr, &config)
+    });
+    // Content rules, reloaded when the file changes
+    let rules = config.rules_file.as_deref().map(|path| {
+        let rules =
+            Arc::new(RuleSet::load(path).unwrap_or_else(|e| panic!("RULES_FILE: {}: {}", path, e)));
+        info!(
+            path = path,
+            rules = rules.rules().rules.len(),
+            "content rules loaded"
+        );
+        if config.rules_reload_secs > 0 {
+            let interval = tokio::time::Duration::from_secs(config.rules_reload_secs);
+            tokio::spawn(rules.clone().watch(interval));
+        }
+        rules
+    });
+
+    // Messages the backend refused for good, kept for inspection and replay
+    let dead_letter = DeadLetter::from_config(&config, store.clone(), metrics.clone())?;
+    if dead_letter.is_some() {
+        info!(
+            dir = ?config.dead_letter_dir,
+            redis_key = ?config.dead_letter_redis_key,
+            "dead-letter sink enabled"
+        );
+    }
+
+    // Per-domain and per-mailbox delivery counters for the dashboard
+    let stats = config.stats_enabled.then(|| {
+        info!(
+            prefix = %config.stats_key_prefix,
+            retention_days = config.stats_retention_days,
+            "delivery counters enabled"
+        );
+        DeliveryStats::new(store.clone(), &config)
+    });
+
+    // On-disk queue for mail accepted while the backend is unreachable
+    let spool = match config.spool_dir.as_deref() {
+        Some(dir) => {
+            let spool = Spool::open(dir, &config, backend.clone(), metrics.clone())?
+                .with_webhook(webhook.clone())
+                .with_dead_letter(dead_letter.clone())
+                .with_stats(stats.clone());
+            info!(
+                dir = dir,
+                queued = spool.len(),
+                max_messages = config.spool_max_messages,
+                "relay spool enabled"
+            );
+            tokio::spawn(spool.clone().run());
+            Some(spool)
+        }
+        None => None,
+    };
+
+    // Connection slots (0 = unlimited), part of them reserved for trusted/TLS clients
+    let pools = ConnectionPools::new(config.max_connections, config.reserved_connections);
+
+    // Client networks always allowed or refused, reloaded when the file changes
+    let network_list = config.network_list_file.as_deref().map(|path| {
+        let list = Arc::new(
+            NetworkList::load(path)
+                .unwrap_or_else(|e| panic!("NETWORK_LIST_FILE: {}: {}", path, e)),
+        );
+        let (allow, block) = list.counts();
+        info!(path = path, allow, block, "network list loaded");
+        if config.network_list_reload_secs > 0 {
+            let interval = tokio::time::Duration::from_secs(config.network_list_reload_secs);
+            tokio::spawn(list.clone().watch(interval));
+        }
+        list
+    });
+
+    // Rejections and auto-blocks in a fixed format, for fail2ban
+    if let Some(path) = config.reject_log_file.as_deref() {
+        let log =
+            RejectLog::open(path).unwrap_or_else(|e| panic!("REJECT_LOG_FILE: {}: {}", path, e));
+        rejectlog::install(log);
+        info!(path = path, "rejection log enabled");
+    }
+
+    // Country of each client, for GEOIP_POLICY and the logs
+    let geoip = GeoIp::from_config(&config).unwrap_or_else(|e| panic!("GEOIP_DATABASE: {}", e));
+    if let Some(geoip) = &geoip {
+        info!(
+            path = config.geoip_database.as_deref().unwrap_or_default(),
+            nodes = geoip.database().node_count(),
+            policies = config.geoip_policy.len(),
+            "GeoIP database loaded"
+        );
+    }
+
+    // Per-IP rate limiter (None if disabled), per instance or shared in Redis
+    let rate_limiter = ConnectionRateLimiter::from_config(store.clone(), &config).map(Arc::new);
+
+    // Per-sender and per-mailbox message rates, shared with other
+    // instances through Redis
+    let sender_limiter = StoreRateLimiter::senders(store.clone(), &config);
+    if sender_limiter.is_some() {
+        info!(
+            limit = config.sender_rate_limit,
+            window_secs = config.sender_rate_window_secs,
+            by = ?config.sender_rate_by,
+            "Sender rate limiting enabled"
+        );
+    }
+    let recipient_limiters = StoreRateLimiter::recipients(store.clone(), &config);
+    if !recipient_limiters.is_empty() {
+        info!(
+            per_minute = config.recipient_rate_limit_per_minute,
+            per_hour = config.recipient_rate_limit_per_hour,
+            "Mailbox rate limiting enabled"
+        );
+    }
+
+    // IPs with too many unknown recipients, refused at connect for a while
+    let auto_block = AutoBlocklist::from_config(store.clone(), &config, metrics.clone());
+    // Spamtrap addresses, whose senders are auto-blocked
+    let spamtraps = Spamtraps::from_config(store.clone(), &config);
+    if spamtraps.is_some() {
+        info!(
+            addresses = config.spamtrap_addresses.len(),
+            redis_set = config.spamtrap_redis_set.as_deref().unwrap_or_default(),
+            "spamtraps enabled"
+        );
+    }
+    // Decaying per-IP scores behind tarpitting, greylisting and refusals
+    let reputation = Reputation::from_config(store.clone(), &config);
+    if reputation.is_some() {
+        info!(
+            half_life_secs = config.reputation_half_life_secs,
+            tarpit_score = config.reputation_tarpit_score,
+            greylist_score = config.reputation_greylist_score,
+            reject_score = config.reputation_reject_score,
+            "client reputation enabled"
+        );
+    }
+    if let Some(auto_block) = &auto_block {
+        info!(
+            threshold = config.auto_block_threshold,
+            window_secs = config.auto_block_window_secs,
+            ttl_secs = config.auto_block_ttl_secs,
+            "Automatic IP blocking enabled"
+        );
+        tokio::spawn(
+            auto_block
+                .clone()
+                .watch(tokio::time::Duration::from_secs(60)),
+        );
+    }
+
     // Spawn metrics reporter (disabled when METRICS_INTERVAL=0)
     if config.metrics_interval_secs > 0 {
         let metrics_clone = metrics.clone();
+        let dnsbl = dnsbl.clone();
+        let geoip = geoip.clone();
         let interval_secs = config.metrics_interval_secs;
         tokio::spawn(async move {
             let mut interval =
@@ -119,69 +462,448 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     rejected = metrics_clone.rejected.load(Ordering::Relaxed),
                     connections = metrics_clone.connections.load(Ordering::Relaxed),
                     relay_errors = metrics_clone.relay_errors.load(Ordering::Relaxed),
+                    early_talker_rejected =
+                        metrics_clone.early_talker_rejected.load(Ordering::Relaxed),
+                    pool_exhausted = metrics_clone.pool_exhausted.load(Ordering::Relaxed),
+                    mirror_errors = metrics_clone.mirror_errors.load(Ordering::Relaxed),
+                    shadow_rejected = metrics_clone.shadow_rejected.load(Ordering::Relaxed),
+                    spooled = metrics_clone.spooled.load(Ordering::Relaxed),
+                    webhook_errors = metrics_clone.webhook_errors.load(Ordering::Relaxed),
+                    dead_lettered = metrics_clone.dead_lettered.load(Ordering::Relaxed),
+                    lookup_cache_hits = metrics_clone.lookup_cache_hits.load(Ordering::Relaxed),
+                    lookup_cache_misses = metrics_clone.lookup_cache_misses.load(Ordering::Relaxed),
+                    lookup_errors = metrics_clone.lookup_errors.load(Ordering::Relaxed),
+                    lookup_fail_open = metrics_clone.lookup_fail_open.load(Ordering::Relaxed),
+                    bloom_rejected = metrics_clone.bloom_rejected.load(Ordering::Relaxed),
+                    redis_consecutive_errors = metrics_clone
+                        .redis_consecutive_errors
+                        .load(Ordering::Relaxed),
+                    redis_reconnects = metrics_clone.redis_reconnects.load(Ordering::Relaxed),
+                    redis_last_success = metrics_clone.redis_last_success.load(Ordering::Relaxed),
+                    dnsbl_rejected = metrics_clone.dnsbl_rejected.load(Ordering::Relaxed),
+                    dnsbl_hits = dnsbl.as_ref().map(Dnsbl::hits).unwrap_or_default(),
+                    geoip_countries = geoip.as_ref().map(GeoIp::connections).unwrap_or_default(),
+                    rdns_rejected = metrics_clone.rdns_rejected.load(Ordering::Relaxed),
+                    helo_rejected = metrics_clone.helo_rejected.load(Ordering::Relaxed),
+                    sender_domain_rejected =
+                        metrics_clone.sender_domain_rejected.load(Ordering::Relaxed),
+                    sender_rate_limited = metrics_clone.sender_rate_limited.load(Ordering::Relaxed),
+                    recipient_rate_limited =
+                        metrics_clone.recipient_rate_limited.load(Ordering::Relaxed),
+                    network_blocked = metrics_clone.network_blocked.load(Ordering::Relaxed),
+                    auto_blocked = metrics_clone.auto_blocked.load(Ordering::Relaxed),
+                    auto_unblocked = metrics_clone.auto_unblocked.load(Ordering::Relaxed),
+                    auto_block_refused = metrics_clone.auto_block_refused.load(Ordering::Relaxed),
+                    rspamd_rejected = metrics_clone.rspamd_rejected.load(Ordering::Relaxed),
+                    rspamd_deferred = metrics_clone.rspamd_deferred.load(Ordering::Relaxed),
+                    rspamd_tagged = metrics_clone.rspamd_tagged.load(Ordering::Relaxed),
+                    rspamd_errors = metrics_clone.rspamd_errors.load(Ordering::Relaxed),
+                    spamd_rejected = metrics_clone.spamd_rejected.load(Ordering::Relaxed),
+                    spamd_errors = metrics_clone.spamd_errors.load(Ordering::Relaxed),
+                    viruses_detected = metrics_clone.viruses_detected.load(Ordering::Relaxed),
+                    clamav_errors = metrics_clone.clamav_errors.load(Ordering::Relaxed),
+                    rules_rejected = metrics_clone.rules_rejected.load(Ordering::Relaxed),
+                    rules_discarded = metrics_clone.rules_discarded.load(Ordering::Relaxed),
+                    spamtrap_hits = metrics_clone.spamtrap_hits.load(Ordering::Relaxed),
+                    geoip_blocked = metrics_clone.geoip_blocked.load(Ordering::Relaxed),
+                    ban_hook_errors = metrics_clone.ban_hook_errors.load(Ordering::Relaxed),
+                    reputation_rejected = metrics_clone.reputation_rejected.load(Ordering::Relaxed),
+                    reputation_greylisted =
+                        metrics_clone.reputation_greylisted.load(Ordering::Relaxed),
+                    reputation_tarpitted =
+                        metrics_clone.reputation_tarpitted.load(Ordering::Relaxed),
                     "[METRICS]"
                 );
             }
         });
     }
 
+    // Sample repetitive hot-path log lines, with one aggregate per window
+    if config.log_sample_burst > 0 && config.log_sample_window_secs > 0 {
+        if let Some(sampler) = sampling::install(LogSampler::new(config.log_sample_burst)) {
+            let window_secs = config.log_sample_window_secs;
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(tokio::time::Duration::from_secs(window_secs));
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let (suppressed, overflow) = sampler.drain();
+                    for s in suppressed {
+                        info!(
+                            event = s.event,
+                            ip = %s.ip,
+                            suppressed = s.count,
+                            window_secs = window_secs,
+                            "[LOG-SAMPLED] repeated events not logged"
+                        );
+                    }
+                    if overflow > 0 {
+                        info!(
+                            suppressed = overflow,
+                            window_secs = window_secs,
+                            "[LOG-SAMPLED] events from too many clients not logged"
+                        );
+                    }
+                }
+            });
+        }
+    }
+
     // Bind and accept connections
-    let listener = TcpListener::bind(config.listen_addr).await?;
+    let listener = bind_listener(config.listen_addr, config.listen_reuse_port)?;
     info!(
         addr = %config.listen_addr,
         max_connections = config.max_connections,
         max_connections_per_ip = config.max_connections_per_ip,
+        reuse_port = config.listen_reuse_port,
         "listening for SMTP connections"
     );
 
+    // Optional loopback transaction through the real accept path; the
+    // instance only reports ready once it passes.
+    let mut self_test = if config.self_test {
+        let target = selftest::loopback_target(listener.local_addr()?);
+        let recipient = config.self_test_address.clone();
+        let use_tls = tls_config.is_some();
+        let send_proxy = config.proxy_protocol != ProxyMode::Deny;
+        let lmtp = config.lmtp;
+        Some(tokio::spawn(async move {
+            let limit = tokio::time::Duration::from_secs(30);
+            tokio::time::timeout(
+                limit,
+                selftest::run(target, &recipient, use_tls, send_proxy, lmtp),
+            )
+            .await
+        }))
+    } else {
+        info!("[READY] accepting mail");
+        None
+    };
+
+    let mut sessions = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
     loop {
-        let (stream, peer_addr) = match listener.accept().await {
-            Ok(conn) => conn,
-            Err(e) => {
-                error!(error = %e, "accept error");
+        let (mut stream, socket_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!(error = %e, "accept error");
+                    continue;
+                }
+            },
+            // Reap finished sessions so the set doesn't grow unbounded
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => continue,
+            result = async { self_test.as_mut().unwrap().await }, if self_test.is_some() => {
+                self_test = None;
+                let failure = match result {
+                    Ok(Ok(Ok(()))) => None,
+                    Ok(Ok(Err(e))) => Some(e.to_string()),
+                    Ok(Err(_)) => Some("timed out".to_string()),
+                    Err(e) => Some(e.to_string()),
+                };
+                if let Some(reason) = failure {
+                    error!(error = %reason, "startup self-test failed, exiting");
+                    return Err(format!("startup self-test failed: {reason}").into());
+                }
+                info!("[READY] startup self-test passed, accepting mail");
                 continue;
             }
+            _ = &mut shutdown => break,
         };
 
-        // Per-IP rate limiting
-        if let Some(ref limiter) = rate_limiter {
-            if !limiter.check_and_increment(peer_addr.ip()).await {
-                warn!(peer = %peer_addr, "per-IP rate limit exceeded, rejecting");
-                // Send 421 and close — best-effort, ignore errors
-                use tokio::io::AsyncWriteExt;
-                let mut stream = stream;
-                let _ = stream
-                    .write_all(b"421 4.7.0 Too many connections from your IP\r\n")
-                    .await;
-                let _ = stream.shutdown().await;
-                continue;
-            }
-        }
-
-        // Acquire connection semaphore permit
-        let permit = match semaphore.clone().acquire_owned().await {
-            Ok(permit) => permit,
-            Err(_) => {
-                error!("connection semaphore closed");
-                break;
+        // Take a slot before spawning, so a flood is refused without a task
+        // or any store lookup per connection. Whether the client may keep a
+        // reserved slot is only known once its real address is.
+        let Some(permit) = pools.try_acquire(true) else {
+            metrics.pool_exhausted.fetch_add(1, Ordering::Relaxed);
+            if sampling::sampled("pool_exhausted", socket_addr.ip()) {
+                warn!(peer = %socket_addr, "no connection slot free, rejecting");
             }
+            refuse_now(&stream, &reply::TOO_MANY_CONNECTIONS);
+            continue;
         };
 
         let config = config.clone();
         let lookup = lookup.clone();
         let tls_config = tls_config.clone();
         let metrics = metrics.clone();
+        let audit = audit.clone();
+        let backend = backend.clone();
+        let mirror = mirror.clone();
+        let spool = spool.clone();
+        let webhook = webhook.clone();
+        let dead_letter = dead_letter.clone();
+        let stats = stats.clone();
+        let resolver = resolver.clone();
+        let dnsbl = dnsbl.clone();
+        let flags = flags.clone();
+        let rate_limiter = rate_limiter.clone();
+        let network_list = network_list.clone();
+        let sender_limiter = sender_limiter.clone();
+        let recipient_limiters = recipient_limiters.clone();
+        let auto_block = auto_block.clone();
+        let rspamd = rspamd.clone();
+        let spamd = spamd.clone();
+        let clamav = clamav.clone();
+        let rules = rules.clone();
+        let spamtraps = spamtraps.clone();
+        let geoip = geoip.clone();
+        let reputation = reputation.clone();
+        sessions.spawn(async move {
+            // Resolve the real client address from the PROXY header, if any
+            let peer_addr = if config.proxy_protocol == ProxyMode::Deny {
+                socket_addr
+            } else {
+                let timeout = tokio::time::Duration::from_millis(config.proxy_protocol_timeout_ms);
+                match proxy::read_header(&mut stream, config.proxy_protocol, timeout).await {
+                    Ok(Some(addr)) => addr,
+                    Ok(None) => socket_addr,
+                    Err(e) => {
+                        warn!(peer = %socket_addr, error = %e, "PROXY header rejected, closing");
+                        return;
+                    }
+                }
+            };
 
-        tokio::spawn(async move {
-            burngate::session::handle_session(
-                stream, peer_addr, config, lookup, tls_config, metrics,
+            // Exempt from the per-IP, country and DNSBL checks, and allowed
+            // to use the reserved slots
+            let trusted = network::contains(&config.trusted_networks, peer_addr.ip());
+
+            // Untrusted clients may only use the reserve if they can upgrade
+            // to TLS, and must do so before MAIL
+            if permit.is_reserved() && !trusted && tls_config.is_none() {
+                metrics.pool_exhausted.fetch_add(1, Ordering::Relaxed);
+                if sampling::sampled("pool_exhausted", peer_addr.ip()) {
+                    warn!(peer = %peer_addr, "no connection slot free, rejecting");
+                }
+                refuse(stream, &reply::TOO_MANY_CONNECTIONS).await;
+                return;
+            }
+            let require_tls = permit.is_reserved() && !trusted;
+
+            // Listed networks: refused outright, or exempt from rate limits
+            let listed = network_list
+                .as_ref()
+                .and_then(|list| list.check(peer_addr.ip()));
+            if listed == Some(Listed::Block)
+                && !session::shadow_pass(&config, &metrics, peer_addr, "network_blocked")
+            {
+                metrics.network_blocked.fetch_add(1, Ordering::Relaxed);
+                if sampling::sampled("network_blocked", peer_addr.ip()) {
+                    info!(peer = %peer_addr, "[CONN-BLOCKED] client network is blocked");
+                }
+                rejectlog::rejected(peer_addr.ip(), "network_blocked");
+                refuse(stream, &reply::NETWORK_BLOCKED).await;
+                return;
+            }
+            let allowed_network = listed == Some(Listed::Allow);
+
+            // Country policies, except for allowed and trusted networks
+            let location = geoip.as_ref().and_then(|geoip| geoip.locate(peer_addr.ip()));
+            let exempt = allowed_network || trusted;
+            if let (Some(geoip), Some(location)) = (geoip.as_ref(), location.as_ref()) {
+                let country = location.country.as_str();
+                match geoip.action(country).filter(|_| !exempt) {
+                    Some(GeoAction::Block)
+                        if !session::shadow_pass(&config, &metrics, peer_addr, "geoip_blocked") =>
+                    {
+                        metrics.geoip_blocked.fetch_add(1, Ordering::Relaxed);
+                        if sampling::sampled("geoip_blocked", peer_addr.ip()) {
+                            info!(peer = %peer_addr, country, "[CONN-GEO-BLOCKED] client country is blocked");
+                        }
+                        rejectlog::rejected(peer_addr.ip(), "geoip_blocked");
+                        refuse(stream, &reply::GEOIP_BLOCKED).await;
+                        return;
+                    }
+                    Some(GeoAction::RateLimit(_))
+                        if !geoip.check_rate(country, peer_addr.ip()).await
+                            && !session::shadow_pass(&config, &metrics, peer_addr, "rate_limited") =>
+                    {
+                        if sampling::sampled("rate_limited", peer_addr.ip()) {
+                            warn!(peer = %peer_addr, country, "per-country rate limit exceeded, rejecting");
+                        }
+                        rejectlog::rejected(peer_addr.ip(), "rate_limited");
+                        refuse(stream, &reply::TOO_MANY_CONNECTIONS_FROM_IP).await;
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+
+            // Temporarily blocked for too many unknown recipients
+            if let Some(auto_block) = auto_block.as_ref().filter(|_| !allowed_network) {
+                if auto_block.is_blocked(peer_addr.ip()).await
+                    && !session::shadow_pass(&config, &metrics, peer_addr, "auto_blocked")
+                {
+                    metrics.auto_block_refused.fetch_add(1, Ordering::Relaxed);
+                    if sampling::sampled("auto_blocked", peer_addr.ip()) {
+                        info!(peer = %peer_addr, "[CONN-AUTO-BLOCKED] client IP is temporarily blocked");
+                    }
+                    rejectlog::rejected(peer_addr.ip(), "auto_blocked");
+                    refuse(stream, &reply::AUTO_BLOCKED).await;
+                    return;
+                }
+            }
+
+            // Poor reputation, looked up once for the whole session
+            let reputation_score = match reputation.as_ref().filter(|_| !exempt) {
+                Some(reputation) => reputation.score(peer_addr.ip()).await,
+                None => 0.0,
+            };
+            if reputation
+                .as_ref()
+                .is_some_and(|reputation| reputation.refuses(reputation_score))
+                && !session::shadow_pass(&config, &metrics, peer_addr, "reputation")
+            {
+                metrics.reputation_rejected.fetch_add(1, Ordering::Relaxed);
+                if sampling::sampled("reputation", peer_addr.ip()) {
+                    info!(
+                        peer = %peer_addr,
+                        score = reputation_score,
+                        "[CONN-REPUTATION-REJECTED] client IP has a poor reputation"
+                    );
+                }
+                rejectlog::rejected(peer_addr.ip(), "reputation");
+                refuse(stream, &reply::REPUTATION_REJECTED).await;
+                return;
+            }
+
+            // Per-IP rate limiting
+            if let Some(limiter) = rate_limiter
+                .as_ref()
+                .filter(|_| !allowed_network && !trusted)
+            {
+                if !limiter.check_and_increment(peer_addr.ip()).await
+                    && !session::shadow_pass(&config, &metrics, peer_addr, "rate_limited")
+                {
+                    if sampling::sampled("rate_limited", peer_addr.ip()) {
+                        warn!(peer = %peer_addr, "per-IP rate limit exceeded, rejecting");
+                    }
+                    rejectlog::rejected(peer_addr.ip(), "rate_limited");
+                    refuse(stream, &reply::TOO_MANY_CONNECTIONS_FROM_IP).await;
+                    return;
+                }
+            }
+
+            session::handle_session(
+                stream,
+                peer_addr,
+                config,
+                lookup,
+                tls_config,
+                metrics,
+                audit,
+                backend,
+                mirror,
+                spool,
+                webhook,
+                dead_letter,
+                stats,
+                resolver,
+                dnsbl,
+                sender_limiter,
+                recipient_limiters,
+                auto_block,
+                rspamd,
+                spamd,
+                clamav,
+                rules,
+                spamtraps,
+                reputation,
+                flags,
+                require_tls,
+                allowed_network,
+                location,
+                reputation_score,
             )
             .await;
-            // Permit is dropped here, releasing the semaphore slot
+            // Permit is dropped here, releasing the connection slot
             drop(permit);
         });
     }
 
+    // Stop accepting so a replacement process (bound with SO_REUSEPORT) takes
+    // all new connections, then give in-flight sessions time to finish.
+    drop(listener);
+    info!(
+        in_flight = sessions.len(),
+        timeout_secs = config.shutdown_timeout_secs,
+        "shutting down, draining sessions"
+    );
+    let drain = async { while sessions.join_next().await.is_some() {} };
+    let grace = tokio::time::Duration::from_secs(config.shutdown_timeout_secs);
+    if tokio::time::timeout(grace, drain).await.is_err() {
+        warn!(
+            remaining = sessions.len(),
+            "shutdown timeout reached, aborting sessions"
+        );
+        sessions.shutdown().await;
+    }
+
     opentelemetry::global::shutdown_tracer_provider();
     Ok(())
 }
+
+/// Bind the SMTP listener, optionally with SO_REUSEPORT.
+///
+/// With `reuse_port` set, a freshly started burngate can bind the same address
+/// while the old process is still running; the kernel spreads new connections
+/// across both until the old one stops accepting on SIGTERM.
+fn bind_listener(addr: std::net::SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuseport(true)?;
+    }
+    #[cfg(not(unix))]
+    if reuse_port {
+        warn!("LISTEN_REUSEPORT is only supported on Unix, ignoring");
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Send `reply` without waiting, for refusals in the accept loop, which must
+/// not stall on a slow client. The stream is closed when the caller drops it.
+fn refuse_now(stream: &tokio::net::TcpStream, reply: &SmtpReply) {
+    let _ = stream.try_write(format!("{reply}\r\n").as_bytes());
+}
+
+/// Send a final reply and close — best-effort, errors are ignored.
+async fn refuse(mut stream: tokio::net::TcpStream, reply: &SmtpReply) {
+    use tokio::io::AsyncWriteExt;
+    let _ = reply.write_to(&mut stream).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Resolve when the process receives SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to install SIGTERM handler");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
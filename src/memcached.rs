@@ -0,0 +1,159 @@
+//! Minimal memcached client for the memcached mailbox lookup: the text
+//! protocol's `get` over a small pool of plain TCP connections.
+
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+/// Longest key memcached accepts.
+const MAX_KEY: usize = 250;
+/// Largest value read back; mailbox entries are markers, not data.
+const MAX_VALUE: usize = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum McError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// `ERROR`, `CLIENT_ERROR` or `SERVER_ERROR` from the server.
+    #[error("server error: {0}")]
+    Server(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("timed out")]
+    Timeout,
+}
+
+/// Looks keys up over pooled connections to one memcached server.
+pub struct McPool {
+    addr: String,
+    idle: Mutex<Vec<McConnection>>,
+    /// One permit per connection allowed at once.
+    slots: Semaphore,
+    timeout: Duration,
+}
+
+impl McPool {
+    /// `addr` is `host:port`. No connection is made until the first lookup.
+    pub fn new(addr: &str, size: usize, timeout: Duration) -> Self {
+        Self {
+            addr: addr.to_string(),
+            idle: Mutex::new(Vec::new()),
+            slots: Semaphore::new(size.max(1)),
+            timeout,
+        }
+    }
+
+    /// Whether `key` is set. Keys memcached cannot store (too long, or with
+    /// spaces or control characters) are never set. Waits for a free
+    /// connection, but gives up after the timeout.
+    pub async fn exists(&self, key: &str) -> Result<bool, McError> {
+        if !valid_key(key) {
+            return Ok(false);
+        }
+        tokio::time::timeout(self.timeout, self.run(key))
+            .await
+            .unwrap_or(Err(McError::Timeout))
+    }
+
+    async fn run(&self, key: &str) -> Result<bool, McError> {
+        let _slot = self.slots.acquire().await.expect("pool semaphore closed");
+        loop {
+            let pooled = self.idle.lock().unwrap().pop();
+            let fresh = pooled.is_none();
+            let mut conn = match pooled {
+                Some(conn) => conn,
+                None => McConnection::connect(&self.addr).await?,
+            };
+            match conn.exists(key).await {
+                Ok(found) => {
+                    self.idle.lock().unwrap().push(conn);
+                    return Ok(found);
+                }
+                // The error line was the whole reply
+                Err(e @ McError::Server(_)) => {
+                    self.idle.lock().unwrap().push(conn);
+                    return Err(e);
+                }
+                // The server may have closed the connection while it sat idle
+                Err(McError::Io(e)) if !fresh => {
+                    debug!(error = %e, "discarding stale pooled memcached connection");
+                }
+                // Anything else may leave unread replies behind
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY
+        && !key.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+struct McConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl McConnection {
+    async fn connect(addr: &str) -> Result<Self, McError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// `get <key>`: a `VALUE` block then `END` if set, `END` alone if not.
+    async fn exists(&mut self, key: &str) -> Result<bool, McError> {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("get {}\r\n", key).as_bytes())
+            .await?;
+        let line = self.read_line().await?;
+        if line == "END" {
+            return Ok(false);
+        }
+        let mut fields = line.split(' ');
+        if fields.next() != Some("VALUE") || fields.next() != Some(key) {
+            return Err(reply_error(line));
+        }
+        let len: usize = fields
+            .nth(1)
+            .and_then(|len| len.parse().ok())
+            .filter(|&len| len <= MAX_VALUE)
+            .ok_or_else(|| McError::Protocol(format!("bad VALUE line {:?}", line)))?;
+        let mut value = vec![0; len + 2];
+        self.stream.read_exact(&mut value).await?;
+        if !value.ends_with(b"\r\n") {
+            return Err(McError::Protocol("value not followed by CRLF".to_string()));
+        }
+        match self.read_line().await? {
+            end if end == "END" => Ok(true),
+            other => Err(reply_error(other)),
+        }
+    }
+
+    /// One reply line without its CRLF.
+    async fn read_line(&mut self) -> Result<String, McError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(McError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        line.truncate(line.trim_end_matches(['\r', '\n']).len());
+        Ok(line)
+    }
+}
+
+fn reply_error(line: String) -> McError {
+    if line == "ERROR" || line.starts_with("CLIENT_ERROR") || line.starts_with("SERVER_ERROR") {
+        McError::Server(line)
+    } else {
+        McError::Protocol(format!("unexpected reply {:?}", line))
+    }
+}
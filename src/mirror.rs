@@ -0,0 +1,100 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::esmtp::EsmtpParams;
+use crate::relay::{self, Backend, ClientInfo, Recipient};
+use crate::session::Metrics;
+
+/// Background copy of every relayed message to a standby backend.
+///
+/// The copy is sent after the client already has its 250, so a slow or
+/// unreachable mirror never delays or fails primary delivery. Copies beyond
+/// `MIRROR_MAX_PENDING` in flight are dropped and counted as mirror errors.
+#[derive(Clone)]
+pub struct Mirror {
+    backend_addr: Arc<str>,
+    backend: Backend,
+    pending: Arc<Semaphore>,
+    timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+/// A relayed message to copy to the mirror.
+pub struct MirrorMessage {
+    pub client: Option<ClientInfo>,
+    pub sender: String,
+    pub mail_params: EsmtpParams,
+    pub recipients: Vec<(String, EsmtpParams)>,
+    pub data: Vec<u8>,
+}
+
+impl Mirror {
+    pub fn new(backend_addr: &str, config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            backend_addr: Arc::from(backend_addr),
+            backend: Backend::new(
+                backend_addr,
+                config.backend_lmtp,
+                config.backend_pool_size,
+                Duration::from_secs(config.backend_pool_idle_secs),
+            )
+            .with_config(config),
+            pending: Arc::new(Semaphore::new(config.mirror_max_pending)),
+            timeout: Duration::from_secs(config.data_timeout_secs),
+            metrics,
+        }
+    }
+
+    /// Queue a copy without waiting for it. Returns false when too many
+    /// copies are already in flight and this one was dropped.
+    pub fn submit(&self, message: MirrorMessage) -> bool {
+        let Ok(permit) = self.pending.clone().try_acquire_owned() else {
+            self.metrics.mirror_errors.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                backend = %self.backend_addr,
+                "[MIRROR-ERROR] too many copies in flight, message not mirrored"
+            );
+            return false;
+        };
+        let mirror = self.clone();
+        tokio::spawn(async move {
+            mirror.deliver(&message).await;
+            drop(permit);
+        });
+        true
+    }
+
+    async fn deliver(&self, message: &MirrorMessage) {
+        let recipients: Vec<Recipient> = message
+            .recipients
+            .iter()
+            .map(|(address, params)| Recipient { address, params })
+            .collect();
+        let relay = self.backend.relay(
+            message.client.as_ref(),
+            &message.sender,
+            &message.mail_params,
+            &recipients,
+            &message.data,
+        );
+        match tokio::time::timeout(self.timeout, relay)
+            .await
+            .unwrap_or(Err(relay::RelayError::Timeout))
+        {
+            Ok(_) => debug!(backend = %self.backend_addr, "message mirrored"),
+            Err(e) => {
+                self.metrics.mirror_errors.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    backend = %self.backend_addr,
+                    error = %e,
+                    "[MIRROR-ERROR] failed to copy message to mirror backend"
+                );
+            }
+        }
+    }
+}
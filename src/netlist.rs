@@ -0,0 +1,160 @@
+//! CIDR allow and block lists of client networks from `NETWORK_LIST_FILE`,
+//! reloaded when the file changes.
+
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+use crate::network::IpNetwork;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkListError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid entry {entry:?} on line {line}")]
+    Invalid { line: usize, entry: String },
+}
+
+/// What a list says about a client address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Listed {
+    /// Skips rate limits and DNSBL checks.
+    Allow,
+    /// Refused at accept time.
+    Block,
+}
+
+/// Networks with what listing them does.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Entries {
+    pub networks: Vec<(IpNetwork, Listed)>,
+}
+
+impl Entries {
+    /// Parse one `allow <network>` or `block <network>` per line, with `#`
+    /// comments, e.g. `block 203.0.113.0/24`.
+    pub fn parse(text: &str) -> Result<Self, NetworkListError> {
+        let mut entries = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let invalid = || NetworkListError::Invalid {
+                line: i + 1,
+                entry: entry.to_string(),
+            };
+            let (action, network) = entry.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let listed = match action.to_lowercase().as_str() {
+                "allow" => Listed::Allow,
+                "block" => Listed::Block,
+                _ => return Err(invalid()),
+            };
+            let network = network.trim().parse().map_err(|_| invalid())?;
+            entries.networks.push((network, listed));
+        }
+        Ok(entries)
+    }
+
+    /// The entry for `ip`: the most specific network containing it, and
+    /// `Block` over `Allow` for the same network size.
+    pub fn check(&self, ip: IpAddr) -> Option<Listed> {
+        self.networks
+            .iter()
+            .filter(|(network, _)| network.contains(ip))
+            .max_by_key(|(network, listed)| (network.prefix(), *listed == Listed::Block))
+            .map(|(_, listed)| *listed)
+    }
+
+    fn count(&self, listed: Listed) -> usize {
+        self.networks.iter().filter(|(_, l)| *l == listed).count()
+    }
+}
+
+/// What tells a changed file apart: modification time and size.
+type Stamp = Option<(SystemTime, u64)>;
+
+/// The entries of a network list file, swapped whole on reload.
+pub struct NetworkList {
+    path: PathBuf,
+    entries: RwLock<Arc<Entries>>,
+    /// The file as of the last read, successful or not.
+    stamp: Mutex<Stamp>,
+}
+
+impl NetworkList {
+    /// Read and parse the file at `path`.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, NetworkListError> {
+        let path = path.into();
+        let stamp = std::fs::metadata(&path).ok().and_then(|m| stamp(&m));
+        let entries = Entries::parse(&std::fs::read_to_string(&path)?)?;
+        Ok(Self {
+            path,
+            entries: RwLock::new(Arc::new(entries)),
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Option<Listed> {
+        self.entries().check(ip)
+    }
+
+    pub fn entries(&self) -> Arc<Entries> {
+        self.entries.read().unwrap().clone()
+    }
+
+    /// Number of `allow` and `block` networks, for logs.
+    pub fn counts(&self) -> (usize, usize) {
+        let entries = self.entries();
+        (entries.count(Listed::Allow), entries.count(Listed::Block))
+    }
+
+    /// Read the file again. On error the current entries are kept.
+    pub async fn reload(&self) -> Result<(), NetworkListError> {
+        *self.stamp.lock().unwrap() = self.current_stamp().await;
+        let entries = Entries::parse(&tokio::fs::read_to_string(&self.path).await?)?;
+        info!(
+            path = %self.path.display(),
+            allow = entries.count(Listed::Allow),
+            block = entries.count(Listed::Block),
+            "network list reloaded"
+        );
+        *self.entries.write().unwrap() = Arc::new(entries);
+        Ok(())
+    }
+
+    /// Every `interval`, reload the file if its modification time or size
+    /// changed since it was last read. Runs until the runtime shuts down.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let current = self.current_stamp().await;
+            if current.is_none() || current == *self.stamp.lock().unwrap() {
+                continue;
+            }
+            if let Err(e) = self.reload().await {
+                warn!(
+                    path = %self.path.display(),
+                    error = %e,
+                    "[NETWORK-LIST-ERROR] network list not reloaded, keeping previous entries"
+                );
+            }
+        }
+    }
+
+    async fn current_stamp(&self) -> Stamp {
+        tokio::fs::metadata(&self.path)
+            .await
+            .ok()
+            .and_then(|m| stamp(&m))
+    }
+}
+
+fn stamp(metadata: &std::fs::Metadata) -> Stamp {
+    Some((metadata.modified().ok()?, metadata.len()))
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Connection slots split into a general pool and a reserve.
+///
+/// Every client draws from the general pool first. When it is full, only
+/// trusted clients and clients that will upgrade to TLS may take a reserved
+/// slot, so a plaintext flood can fill the general pool without locking
+/// legitimate senders out.
+#[derive(Clone)]
+pub struct ConnectionPools {
+    general: Arc<Semaphore>,
+    reserved: Arc<Semaphore>,
+}
+
+/// A held connection slot, released on drop.
+pub struct ConnectionPermit {
+    _permit: OwnedSemaphorePermit,
+    reserved: bool,
+}
+
+impl ConnectionPermit {
+    /// Whether the slot came from the reserve.
+    pub fn is_reserved(&self) -> bool {
+        self.reserved
+    }
+}
+
+impl ConnectionPools {
+    /// `max` total slots (0 = unlimited), `reserved` of which are held back.
+    /// The reserve is capped at `max`.
+    pub fn new(max: usize, reserved: usize) -> Self {
+        let (general, reserved) = if max == 0 {
+            (Semaphore::MAX_PERMITS, 0)
+        } else {
+            let reserved = reserved.min(max);
+            (max - reserved, reserved)
+        };
+        Self {
+            general: Arc::new(Semaphore::new(general)),
+            reserved: Arc::new(Semaphore::new(reserved)),
+        }
+    }
+
+    /// Take a slot without waiting. `may_reserve` lets the client fall back
+    /// to the reserve when the general pool is full.
+    pub fn try_acquire(&self, may_reserve: bool) -> Option<ConnectionPermit> {
+        if let Ok(permit) = self.general.clone().try_acquire_owned() {
+            return Some(ConnectionPermit {
+                _permit: permit,
+                reserved: false,
+            });
+        }
+        if !may_reserve {
+            return None;
+        }
+        let permit = self.reserved.clone().try_acquire_owned().ok()?;
+        Some(ConnectionPermit {
+            _permit: permit,
+            reserved: true,
+        })
+    }
+
+    /// Free slots in the general pool and the reserve.
+    pub fn available(&self) -> (usize, usize) {
+        (
+            self.general.available_permits(),
+            self.reserved.available_permits(),
+        )
+    }
+}
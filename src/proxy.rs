@@ -0,0 +1,175 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+/// PROXY protocol v2 signature (12 bytes).
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximum length of a v1 header line including CRLF (per the spec).
+const V1_MAX_LEN: usize = 107;
+
+/// Whether the listener expects a HAProxy PROXY protocol header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyMode {
+    /// Never parse a PROXY header (default). A header sent anyway is treated
+    /// as an unknown SMTP command.
+    Deny,
+    /// Use the header if the load balancer sends one, otherwise the socket peer.
+    Allow,
+    /// Drop connections that don't start with a valid PROXY header.
+    Require,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("missing PROXY header")]
+    Missing,
+    #[error("invalid PROXY header: {0}")]
+    Invalid(&'static str),
+}
+
+/// Parse a v1 header line such as `PROXY TCP4 1.2.3.4 5.6.7.8 1234 25\r\n`.
+///
+/// Returns `None` for `PROXY UNKNOWN`, meaning the socket peer should be used.
+pub fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, ProxyError> {
+    let line = std::str::from_utf8(line).map_err(|_| ProxyError::Invalid("v1 not ASCII"))?;
+    let line = line
+        .strip_suffix("\r\n")
+        .ok_or(ProxyError::Invalid("v1 missing CRLF"))?;
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(ProxyError::Invalid("v1 missing PROXY prefix"));
+    }
+    match parts.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4") | Some("TCP6") => {
+            let src: IpAddr = parts
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or(ProxyError::Invalid("v1 bad source address"))?;
+            let _dst = parts
+                .next()
+                .ok_or(ProxyError::Invalid("v1 missing fields"))?;
+            let port: u16 = parts
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or(ProxyError::Invalid("v1 bad source port"))?;
+            Ok(Some(SocketAddr::new(src, port)))
+        }
+        _ => Err(ProxyError::Invalid("v1 unknown protocol")),
+    }
+}
+
+/// Parse a v2 header: the 16-byte fixed part and the address block that follows.
+///
+/// Returns `None` for LOCAL connections (health checks) and unsupported
+/// address families, meaning the socket peer should be used.
+pub fn parse_v2(header: &[u8; 16], body: &[u8]) -> Result<Option<SocketAddr>, ProxyError> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(ProxyError::Invalid("v2 bad signature"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(ProxyError::Invalid("v2 bad version"));
+    }
+    match header[12] & 0x0f {
+        0x0 => return Ok(None), // LOCAL
+        0x1 => {}               // PROXY
+        _ => return Err(ProxyError::Invalid("v2 bad command")),
+    }
+    match header[13] >> 4 {
+        // AF_INET: src(4) dst(4) src_port(2) dst_port(2)
+        0x1 => {
+            if body.len() < 12 {
+                return Err(ProxyError::Invalid("v2 short IPv4 block"));
+            }
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6: src(16) dst(16) src_port(2) dst_port(2)
+        0x2 => {
+            if body.len() < 36 {
+                return Err(ProxyError::Invalid("v2 short IPv6 block"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Read a v1 header line byte-by-byte so nothing past the CRLF is consumed.
+pub async fn read_v1<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, ProxyError> {
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    loop {
+        let byte = reader.read_u8().await?;
+        line.push(byte);
+        if line.ends_with(b"\r\n") {
+            return Ok(line);
+        }
+        if line.len() >= V1_MAX_LEN {
+            return Err(ProxyError::Invalid("v1 header too long"));
+        }
+    }
+}
+
+/// Read a complete v2 header (fixed part plus address block).
+pub async fn read_v2<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<([u8; 16], Vec<u8>), ProxyError> {
+    let mut header = [0u8; 16];
+    reader.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok((header, body))
+}
+
+/// Consume a PROXY header from a freshly accepted connection.
+///
+/// Peeks first so that a client without a header loses no bytes. In `Allow`
+/// mode a client that sends nothing within `timeout` is assumed to be a
+/// direct (non-proxied) SMTP client waiting for the banner.
+///
+/// Returns the original client address, or `None` to use the socket peer.
+pub async fn read_header(
+    stream: &mut TcpStream,
+    mode: ProxyMode,
+    timeout: std::time::Duration,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    if mode == ProxyMode::Deny {
+        return Ok(None);
+    }
+
+    let mut peek = [0u8; 12];
+    let peeked = match tokio::time::timeout(timeout, stream.peek(&mut peek)).await {
+        Ok(result) => result?,
+        Err(_) if mode == ProxyMode::Allow => return Ok(None),
+        Err(_) => return Err(ProxyError::Missing),
+    };
+    let prefix = &peek[..peeked];
+
+    if !prefix.is_empty() && V2_SIGNATURE.starts_with(prefix) {
+        let (header, body) = tokio::time::timeout(timeout, read_v2(stream))
+            .await
+            .map_err(|_| ProxyError::Invalid("v2 header timed out"))??;
+        parse_v2(&header, &body)
+    } else if !prefix.is_empty() && b"PROXY ".starts_with(&prefix[..prefix.len().min(6)]) {
+        let line = tokio::time::timeout(timeout, read_v1(stream))
+            .await
+            .map_err(|_| ProxyError::Invalid("v1 header timed out"))??;
+        parse_v1(&line)
+    } else if mode == ProxyMode::Allow {
+        Ok(None)
+    } else {
+        Err(ProxyError::Missing)
+    }
+}
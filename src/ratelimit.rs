@@ -1,48 +1,200 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::time::Duration;
 
 use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::audit::unix_now;
+use crate::config::{Config, RateLimitBackend};
+use crate::store::{SharedStore, StoreError};
 
 /// Number of entries before triggering stale-entry eviction.
 const CLEANUP_THRESHOLD: usize = 10_000;
 
-/// Per-IP connection tracking with a sliding window.
+/// Per-IP connection tracking with a token bucket: each IP may connect
+/// `burst` times at once, and earns `per_minute` connections back a minute.
 pub struct IpRateLimiter {
-    map: Mutex<HashMap<IpAddr, (u32, tokio::time::Instant)>>,
-    max_per_ip: u32,
-    window: std::time::Duration,
+    /// Tokens left and when they were counted, per IP.
+    map: Mutex<HashMap<IpAddr, (f64, tokio::time::Instant)>>,
+    per_minute: u32,
+    burst: u32,
 }
 
 impl IpRateLimiter {
+    /// `max_per_ip` connections a minute, all of which may come at once.
     pub fn new(max_per_ip: u32) -> Self {
+        Self::with_burst(max_per_ip, max_per_ip)
+    }
+
+    pub fn with_burst(per_minute: u32, burst: u32) -> Self {
         Self {
             map: Mutex::new(HashMap::new()),
-            max_per_ip,
-            window: std::time::Duration::from_secs(60),
+            per_minute,
+            burst: burst.max(1),
         }
     }
 
     /// Returns true if the IP is allowed, false if rate-limited.
     pub async fn check_and_increment(&self, ip: IpAddr) -> bool {
         let now = tokio::time::Instant::now();
+        let rate = f64::from(self.per_minute) / 60.0;
+        let burst = f64::from(self.burst);
         let mut map = self.map.lock().await;
 
-        // Evict stale entries when the map grows too large
+        // Evict buckets that have filled up again when the map grows too large
         if map.len() > CLEANUP_THRESHOLD {
-            let window = self.window;
-            map.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < window);
+            map.retain(|_, (tokens, counted)| {
+                *tokens + now.duration_since(*counted).as_secs_f64() * rate < burst
+            });
         }
 
-        let entry = map.entry(ip).or_insert((0, now));
-        // Reset window if expired
-        if now.duration_since(entry.1) >= self.window {
-            entry.0 = 0;
-            entry.1 = now;
-        }
-        if entry.0 >= self.max_per_ip {
+        let (tokens, counted) = map.entry(ip).or_insert((burst, now));
+        *tokens = (*tokens + now.duration_since(*counted).as_secs_f64() * rate).min(burst);
+        *counted = now;
+        if *tokens < 1.0 {
             return false;
         }
-        entry.0 += 1;
+        *tokens -= 1.0;
         true
     }
 }
+
+/// Per-IP connection rate limit (`MAX_CONNECTIONS_PER_IP`), kept in this
+/// process or shared through the store (`RATE_LIMIT_BACKEND`).
+pub enum ConnectionRateLimiter {
+    Local(IpRateLimiter),
+    Shared(StoreRateLimiter),
+}
+
+impl ConnectionRateLimiter {
+    /// `None` when `MAX_CONNECTIONS_PER_IP` is 0.
+    pub fn from_config(store: SharedStore, config: &Config) -> Option<Self> {
+        let limit = config.max_connections_per_ip;
+        if limit == 0 {
+            return None;
+        }
+        Some(match config.rate_limit_backend {
+            RateLimitBackend::Memory => Self::Local(IpRateLimiter::with_burst(
+                limit,
+                config.rate_limit_burst.unwrap_or(limit),
+            )),
+            RateLimitBackend::Redis => Self::Shared(StoreRateLimiter::new(
+                store,
+                &config.rate_limit_key_prefix,
+                limit,
+                Duration::from_secs(60),
+            )),
+        })
+    }
+
+    /// Returns true if the IP is allowed, false if rate-limited. The shared
+    /// limiter lets the IP in when the store cannot be reached.
+    pub async fn check_and_increment(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::Local(limiter) => limiter.check_and_increment(ip).await,
+            Self::Shared(limiter) => match limiter.check_and_increment(&ip.to_string()).await {
+                Ok(allowed) => allowed,
+                Err(e) => {
+                    warn!(ip = %ip, error = %e, "shared rate limit check failed, allowing");
+                    true
+                }
+            },
+        }
+    }
+}
+
+/// Per-key event counts over a sliding window, kept in the store so every
+/// instance shares them.
+///
+/// Each key has a counter per fixed window, `{prefix}{key}:{window}`; the
+/// sliding window's count is the current counter plus the previous one
+/// weighted by how much of the previous window it still covers.
+#[derive(Clone)]
+pub struct StoreRateLimiter {
+    store: SharedStore,
+    key_prefix: String,
+    limit: u32,
+    window_secs: u64,
+}
+
+impl StoreRateLimiter {
+    pub fn new(store: SharedStore, key_prefix: &str, limit: u32, window: Duration) -> Self {
+        Self {
+            store,
+            key_prefix: key_prefix.to_string(),
+            limit,
+            window_secs: window.as_secs().max(1),
+        }
+    }
+
+    /// The `SENDER_RATE_LIMIT` limiter, `None` when disabled.
+    pub fn senders(store: SharedStore, config: &Config) -> Option<Self> {
+        (config.sender_rate_limit > 0).then(|| {
+            Self::new(
+                store,
+                &config.sender_rate_key_prefix,
+                config.sender_rate_limit,
+                Duration::from_secs(config.sender_rate_window_secs),
+            )
+        })
+    }
+
+    /// The per-minute and per-hour mailbox limiters that are enabled.
+    pub fn recipients(store: SharedStore, config: &Config) -> Vec<Self> {
+        let prefix = &config.recipient_rate_key_prefix;
+        [
+            ("m:", config.recipient_rate_limit_per_minute, 60),
+            ("h:", config.recipient_rate_limit_per_hour, 3600),
+        ]
+        .into_iter()
+        .filter(|&(_, limit, _)| limit > 0)
+        .map(|(window, limit, secs)| {
+            Self::new(
+                store.clone(),
+                &format!("{}{}", prefix, window),
+                limit,
+                Duration::from_secs(secs),
+            )
+        })
+        .collect()
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+
+    /// Count an event for `key` unless it already had `limit` in the last
+    /// window. Returns true if the event is allowed.
+    ///
+    /// The event is counted first and taken back if it is over the limit,
+    /// so concurrent callers, on this instance or another, each see a
+    /// distinct count and no more than `limit` get through.
+    pub async fn check_and_increment(&self, key: &str) -> Result<bool, StoreError> {
+        let now = unix_now();
+        let window = now / self.window_secs;
+        let counter = |window: u64| format!("{}{}:{}", self.key_prefix, key, window);
+
+        let previous = self
+            .store
+            .get(&counter(window.saturating_sub(1)))
+            .await?
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        // Kept through the next window, which still weighs it
+        let ttl_secs = 2 * self.window_secs;
+        let current = self.store.incr(&counter(window), ttl_secs).await?;
+        let remaining = self.window_secs - now % self.window_secs;
+        let estimate = current.max(0) as u64 + previous * remaining / self.window_secs;
+        if estimate <= u64::from(self.limit) {
+            return Ok(true);
+        }
+        // Refused events are not counted
+        self.store.decr(&counter(window), ttl_secs).await?;
+        Ok(false)
+    }
+}
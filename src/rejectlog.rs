@@ -0,0 +1,76 @@
+//! One line per refused client in `REJECT_LOG_FILE`, in a fixed format
+//! for fail2ban and similar tools:
+//!
+//! ```text
+//! 2026-10-15T10:00:00Z burngate rejected ip=192.0.2.1 reason=mailbox_not_found
+//! 2026-10-15T10:00:05Z burngate blocked ip=192.0.2.1 reason=unknown_recipients ttl=3600
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use tracing::warn;
+
+use crate::headers::rfc3339_date;
+
+static REJECT_LOG: OnceLock<RejectLog> = OnceLock::new();
+
+/// An append-only log file. Opened in append mode, so it can be rotated
+/// with `copytruncate`.
+pub struct RejectLog {
+    file: Mutex<File>,
+}
+
+impl RejectLog {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// A rejection of `ip` for `reason`, e.g. `mailbox_not_found`.
+    pub fn rejected(&self, ip: IpAddr, reason: &str) {
+        self.write(&format!("rejected ip={} reason={}", ip, reason));
+    }
+
+    /// An automatic block of `ip` for `ttl_secs`.
+    pub fn blocked(&self, ip: IpAddr, reason: &str, ttl_secs: u64) {
+        self.write(&format!(
+            "blocked ip={} reason={} ttl={}",
+            ip, reason, ttl_secs
+        ));
+    }
+
+    fn write(&self, event: &str) {
+        let line = format!("{} burngate {}\n", rfc3339_date(SystemTime::now()), event);
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!(error = %e, "[REJECT-LOG-ERROR] rejection not logged");
+        }
+    }
+}
+
+/// Install the process-wide log. Returns `None` if one is already set.
+pub fn install(log: RejectLog) -> Option<&'static RejectLog> {
+    REJECT_LOG.set(log).ok()?;
+    REJECT_LOG.get()
+}
+
+/// Log a rejection, if a log is installed.
+pub fn rejected(ip: IpAddr, reason: &str) {
+    if let Some(log) = REJECT_LOG.get() {
+        log.rejected(ip, reason);
+    }
+}
+
+/// Log an automatic block, if a log is installed.
+pub fn blocked(ip: IpAddr, reason: &str, ttl_secs: u64) {
+    if let Some(log) = REJECT_LOG.get() {
+        log.blocked(ip, reason, ttl_secs);
+    }
+}
@@ -1,145 +1,1229 @@
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
-use tracing::{debug, error, info};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, info, warn};
+
+use crate::address::AddressList;
+use crate::config::{BackendTls, Config};
+use crate::dns::{Record, RecordType, Resolver, Srv};
+use crate::esmtp::{self, EsmtpParams};
+use crate::headers;
+use crate::reply::SmtpReply;
+use crate::tls;
+
+/// Cache limit for backend addresses unless configured.
+const DEFAULT_DNS_MAX_TTL: Duration = Duration::from_secs(300);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An accepted recipient with the ESMTP parameters from its RCPT TO,
+/// borrowed from the session's transaction state.
+#[derive(Clone, Copy, Debug)]
+pub struct Recipient<'a> {
+    pub address: &'a str,
+    pub params: &'a EsmtpParams,
+}
+
+/// A backend session kept by one client session between its messages, so
+/// consecutive transactions skip the connect and greeting. Hand it back
+/// with [`Backend::release`] when the client leaves.
+#[derive(Default)]
+pub struct BackendSession {
+    /// The upstream and the open session.
+    held: Option<(Arc<Upstream>, Connection)>,
+    /// Client the session was last used for.
+    client: Option<ClientInfo>,
+}
+
+/// The SMTP client a relayed message came from, passed to backends that
+/// offer XCLIENT or XFORWARD.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    /// Name from the client's EHLO/HELO/LHLO.
+    pub helo: Option<String>,
+    /// RFC 3848 protocol name, e.g. `ESMTPS` for ESMTP over TLS.
+    pub proto: String,
+}
+
+impl ClientInfo {
+    /// ` NAME=value` attributes for XCLIENT/XFORWARD, limited to those the
+    /// backend listed in `supported`.
+    fn attributes(&self, supported: &HashSet<String>) -> String {
+        let addr = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("IPV6:{}", ip),
+        };
+        let port = self.addr.port().to_string();
+        [
+            ("ADDR", Some(addr.as_str())),
+            ("PORT", Some(port.as_str())),
+            ("PROTO", Some(self.proto.as_str())),
+            ("HELO", self.helo.as_deref()),
+        ]
+        .into_iter()
+        .filter(|(name, _)| supported.contains(*name))
+        .filter_map(|(name, value)| Some(format!(" {}={}", name, esmtp::encode_xtext(value?))))
+        .collect()
+    }
+}
 
-/// Read a single SMTP response line and extract the status code.
-async fn read_response(
-    reader: &mut BufReader<tokio::io::ReadHalf<TcpStream>>,
-    buf: &mut String,
-) -> Result<(u16, String), RelayError> {
-    buf.clear();
-    reader.read_line(buf).await?;
-    let code: u16 = buf.get(..3).and_then(|s| s.parse().ok()).unwrap_or(0);
-    Ok((code, buf.clone()))
+/// What the backend did with each recipient of a relayed message.
+#[derive(Debug, Default)]
+pub struct RelayOutcome<'a> {
+    /// Recipients the backend took responsibility for.
+    pub delivered: Vec<&'a str>,
+    /// Recipients refused at RCPT TO or, over LMTP, after DATA.
+    pub rejected: Vec<(&'a str, SmtpReply)>,
 }
 
-/// Relay a complete SMTP message to the backend server.
+impl<'a> RelayOutcome<'a> {
+    /// File a recipient under its RCPT TO reply.
+    fn record(&mut self, address: &'a str, reply: SmtpReply) {
+        if reply.code == 250 {
+            self.delivered.push(address);
+        } else {
+            error!(recipient = %address, response = %reply, "backend rejected recipient");
+            self.rejected.push((address, reply));
+        }
+    }
+}
+
+/// The backend servers to relay to, with a pool of idle sessions per
+/// server kept open between messages. Clones share the pools.
 ///
-/// Performs a full SMTP transaction: connect, EHLO, MAIL FROM, RCPT TO (for each
-/// recipient), DATA, message body, QUIT.
-#[tracing::instrument(skip(message_data), fields(size = message_data.len()))]
-pub async fn relay_message(
-    backend_addr: &str,
-    sender: &str,
-    recipients: &[String],
-    message_data: &[u8],
-) -> Result<(), RelayError> {
-    let stream = TcpStream::connect(backend_addr)
-        .await
-        .map_err(|e| RelayError::Connect(e.to_string()))?;
-
-    let (reader, mut writer) = tokio::io::split(stream);
-    let mut reader = BufReader::new(reader);
-    let mut line_buf = String::new();
-
-    // Read banner
-    let (code, resp) = read_response(&mut reader, &mut line_buf).await?;
-    if code != 220 {
-        return Err(RelayError::Protocol(format!(
-            "unexpected banner: {}",
-            resp.trim()
-        )));
-    }
-    debug!(response = %resp.trim(), "backend banner");
-
-    // EHLO
-    writer.write_all(b"EHLO burngate\r\n").await?;
-    // Read all EHLO response lines (multi-line: "250-..." continues, "250 ..." ends)
-    loop {
-        line_buf.clear();
-        reader.read_line(&mut line_buf).await?;
-        if line_buf.len() < 4 {
+/// With several servers, each message goes to the next one in smooth
+/// weighted round-robin order: a server of weight 3 gets three messages
+/// for every one sent to a server of weight 1, interleaved rather than in
+/// bursts.
+#[derive(Clone)]
+pub struct Backend {
+    inner: Arc<BackendInner>,
+}
+
+struct BackendInner {
+    /// Replaced as a whole when SRV discovery finds a different set.
+    upstreams: Mutex<Vec<Arc<Upstream>>>,
+    lmtp: bool,
+    starttls: Option<StartTls>,
+    /// Further attempts after a transient failure, each on the next upstream.
+    retries: u32,
+    /// Delay before the first retry; doubles with each further one.
+    retry_backoff: Duration,
+    /// Prepend the W3C trace context of the relay span to each message.
+    trace_context: bool,
+    /// Bounds simultaneous relays across all clones; `None` = unlimited.
+    limit: Option<Semaphore>,
+    max_idle: usize,
+    idle_timeout: Duration,
+    /// Looks up backend hostnames; `None` = the system resolver only.
+    resolver: Option<Resolver>,
+    /// Longest a hostname's addresses are cached, whatever their TTL.
+    dns_max_ttl: Duration,
+    /// Per address tried.
+    connect_timeout: Duration,
+}
+
+struct Upstream {
+    addr: String,
+    weight: u32,
+    /// SRV priority; lower ones are used while any of them is reachable.
+    priority: u16,
+    /// Running round-robin score, only changed under the upstream list lock.
+    current: AtomicI64,
+    /// Dropped by SRV discovery; its sessions are closed, not pooled.
+    retired: AtomicBool,
+    /// Addresses `addr` resolved to, and when they go stale.
+    resolved: Mutex<Option<(Vec<SocketAddr>, Instant)>>,
+    /// Idle sessions, most recently used last.
+    idle: Mutex<Vec<Connection>>,
+}
+
+/// STARTTLS settings for backend connections.
+struct StartTls {
+    /// Fail instead of relaying in plaintext when the upgrade is not offered
+    /// or refused.
+    required: bool,
+    connector: TlsConnector,
+    /// Name to send as SNI and verify the certificate against, instead of
+    /// the host part of the backend address.
+    server_name: Option<String>,
+}
+
+impl Upstream {
+    fn new(addr: String, weight: u32, priority: u16) -> Self {
+        Self {
+            addr,
+            weight,
+            priority,
+            current: AtomicI64::new(0),
+            retired: AtomicBool::new(false),
+            resolved: Mutex::new(None),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// A backend session past the banner and EHLO/LHLO (and STARTTLS, if used).
+struct Connection {
+    stream: BufReader<Box<dyn Io>>,
+    caps: HashSet<String>,
+    /// Attributes listed after the XCLIENT and XFORWARD keywords.
+    xclient: HashSet<String>,
+    xforward: HashSet<String>,
+    /// XCLIENT was accepted; the session now speaks for another client and
+    /// is not reused.
+    impersonating: bool,
+    /// The end-of-data dot of the current transaction was written, so the
+    /// backend may have taken the message even if no reply arrives.
+    body_sent: bool,
+    line_buf: String,
+    idle_since: Instant,
+}
+
+impl Connection {
+    async fn open(
+        stream: TcpStream,
+        addr: &str,
+        lmtp: bool,
+        starttls: Option<&StartTls>,
+    ) -> Result<Self, RelayError> {
+        let mut conn = Connection {
+            stream: BufReader::new(Box::new(stream)),
+            caps: HashSet::new(),
+            xclient: HashSet::new(),
+            xforward: HashSet::new(),
+            impersonating: false,
+            body_sent: false,
+            line_buf: String::new(),
+            idle_since: Instant::now(),
+        };
+
+        let reply = conn.read_reply().await?;
+        if reply.code != 220 {
             return Err(RelayError::Protocol(format!(
-                "short EHLO response: {}",
-                line_buf.trim()
+                "unexpected banner: {}",
+                reply
             )));
         }
-        if &line_buf[3..4] == " " {
-            break;
+        debug!(response = %reply, "backend banner");
+
+        conn.greet(lmtp).await?;
+        let Some(starttls) = starttls else {
+            return Ok(conn);
+        };
+
+        let reply = if conn.caps.contains("STARTTLS") {
+            Some(conn.command("STARTTLS").await?)
+        } else {
+            None
+        };
+        match reply {
+            Some(reply) if reply.code == 220 => {}
+            reply if starttls.required => {
+                let why = match reply {
+                    Some(reply) => format!("STARTTLS refused: {}", reply),
+                    None => "STARTTLS not offered".to_string(),
+                };
+                conn.quit().await;
+                return Err(RelayError::Tls(why));
+            }
+            _ => {
+                debug!(
+                    backend = addr,
+                    "STARTTLS unavailable, relaying in plaintext"
+                );
+                return Ok(conn);
+            }
         }
+
+        let host = starttls
+            .server_name
+            .as_deref()
+            .unwrap_or_else(|| host_part(addr));
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| RelayError::Tls(format!("invalid server name {:?}", host)))?;
+        let tls = starttls
+            .connector
+            .connect(server_name, conn.stream.into_inner())
+            .await
+            .map_err(|e| RelayError::Tls(e.to_string()))?;
+        debug!(backend = addr, "backend session upgraded to TLS");
+
+        // Capabilities may differ after the upgrade (RFC 3207 §4.2)
+        conn.stream = BufReader::new(Box::new(tls));
+        conn.greet(lmtp).await?;
+        Ok(conn)
     }
 
-    // MAIL FROM
-    let mail_from = format!("MAIL FROM:<{}>\r\n", sender);
-    writer.write_all(mail_from.as_bytes()).await?;
-    let (code, resp) = read_response(&mut reader, &mut line_buf).await?;
-    if code != 250 {
-        return Err(RelayError::Protocol(format!(
-            "MAIL FROM rejected: {}",
-            resp.trim()
-        )));
+    /// Send EHLO, or LHLO for an LMTP backend, and record the extensions.
+    async fn greet(&mut self, lmtp: bool) -> Result<(), RelayError> {
+        let greeting = if lmtp {
+            "LHLO burngate"
+        } else {
+            "EHLO burngate"
+        };
+        let reply = self.command(greeting).await?;
+        self.caps.clear();
+        self.xclient.clear();
+        self.xforward.clear();
+        // The first line is the greeting; the rest start with an extension keyword
+        for line in reply.lines().skip(1) {
+            let mut words = line.split_ascii_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            let keyword = keyword.to_ascii_uppercase();
+            let attributes = match keyword.as_str() {
+                "XCLIENT" => &mut self.xclient,
+                "XFORWARD" => &mut self.xforward,
+                _ => {
+                    self.caps.insert(keyword);
+                    continue;
+                }
+            };
+            attributes.extend(words.map(str::to_ascii_uppercase));
+            self.caps.insert(keyword);
+        }
+        debug!(caps = ?self.caps, "backend capabilities");
+        Ok(())
     }
 
-    // RCPT TO for each recipient
-    for rcpt in recipients {
-        let rcpt_to = format!("RCPT TO:<{}>\r\n", rcpt);
-        writer.write_all(rcpt_to.as_bytes()).await?;
-        let (code, resp) = read_response(&mut reader, &mut line_buf).await?;
-        if code != 250 {
-            error!(recipient = %rcpt, response = %resp.trim(), "backend rejected recipient");
+    /// Tell the backend who the real client is, preferring XCLIENT (which
+    /// the backend's policy also applies to) over XFORWARD (logging only).
+    /// A refusal is logged and the message is relayed without it.
+    async fn forward_client(&mut self, client: &ClientInfo, lmtp: bool) -> Result<(), RelayError> {
+        if self.caps.contains("XCLIENT") {
+            let attributes = client.attributes(&self.xclient);
+            if attributes.is_empty() {
+                return Ok(());
+            }
+            let reply = self.command(&format!("XCLIENT{}", attributes)).await?;
+            if reply.code != 220 {
+                warn!(response = %reply, "backend refused XCLIENT");
+                return Ok(());
+            }
+            // XCLIENT restarts the session, so greet again (as the client)
+            self.impersonating = true;
+            self.greet(lmtp).await
+        } else if self.caps.contains("XFORWARD") {
+            let attributes = client.attributes(&self.xforward);
+            if attributes.is_empty() {
+                return Ok(());
+            }
+            let reply = self.command(&format!("XFORWARD{}", attributes)).await?;
+            if reply.code != 250 {
+                warn!(response = %reply, "backend refused XFORWARD");
+            }
+            Ok(())
+        } else {
+            Ok(())
         }
     }
 
-    // DATA
-    writer.write_all(b"DATA\r\n").await?;
-    let (code, resp) = read_response(&mut reader, &mut line_buf).await?;
-    if code != 354 {
-        return Err(RelayError::Protocol(format!(
-            "DATA not accepted: {}",
-            resp.trim()
-        )));
+    /// Read a complete, possibly multiline, reply.
+    async fn read_reply(&mut self) -> Result<SmtpReply, RelayError> {
+        let mut lines = Vec::new();
+        loop {
+            self.line_buf.clear();
+            if self.stream.read_line(&mut self.line_buf).await? == 0 {
+                return Err(RelayError::Protocol("connection closed".to_string()));
+            }
+            let line = self.line_buf.trim_end().to_string();
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line);
+            if last {
+                break;
+            }
+        }
+        SmtpReply::parse(&lines)
+            .ok_or_else(|| RelayError::Protocol(format!("malformed reply: {}", lines.join(" | "))))
     }
 
-    // Inject W3C traceparent header so Ratatoskr can continue this trace.
-    // No-op when OTel is not configured (carrier stays empty, nothing is written).
-    {
-        use opentelemetry::propagation::TextMapPropagator;
-        let propagator = opentelemetry_sdk::propagation::TraceContextPropagator::new();
-        let mut carrier = std::collections::HashMap::<String, String>::new();
-        propagator.inject_context(
-            &tracing_opentelemetry::OpenTelemetrySpanExt::context(&tracing::Span::current()),
-            &mut carrier,
-        );
-        if let Some(tp) = carrier.get("traceparent") {
-            writer
-                .write_all(format!("traceparent: {}\r\n", tp).as_bytes())
-                .await?;
-            if let Some(ts) = carrier.get("tracestate").filter(|s| !s.is_empty()) {
-                writer
-                    .write_all(format!("tracestate: {}\r\n", ts).as_bytes())
-                    .await?;
+    /// Send one command line and read its reply.
+    async fn command(&mut self, line: &str) -> Result<SmtpReply, RelayError> {
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.read_reply().await
+    }
+
+    /// MAIL FROM, RCPT TO for each recipient and DATA, one at a time.
+    /// Returns once the backend is ready for the message body.
+    async fn envelope<'a>(
+        &mut self,
+        mail_from: &str,
+        rcpt_to: &[String],
+        recipients: &[Recipient<'a>],
+    ) -> Result<RelayOutcome<'a>, RelayError> {
+        let reply = self.command(mail_from).await?;
+        if reply.code != 250 {
+            return Err(RelayError::Rejected {
+                stage: "MAIL FROM rejected",
+                reply,
+            });
+        }
+
+        let mut outcome = RelayOutcome::default();
+        for (rcpt, line) in recipients.iter().zip(rcpt_to) {
+            let reply = self.command(line).await?;
+            outcome.record(rcpt.address, reply);
+        }
+        if outcome.delivered.is_empty() {
+            return Err(RelayError::all_rejected(
+                "all recipients rejected",
+                outcome.rejected,
+            ));
+        }
+
+        let reply = self.command("DATA").await?;
+        if reply.code != 354 {
+            return Err(RelayError::Rejected {
+                stage: "DATA not accepted",
+                reply,
+            });
+        }
+        Ok(outcome)
+    }
+
+    /// The same envelope as [`Connection::envelope`], sent in a single
+    /// write with the replies read afterwards (RFC 2920), so a message
+    /// costs one round trip before the body whatever its recipient count.
+    async fn pipelined_envelope<'a>(
+        &mut self,
+        mail_from: &str,
+        rcpt_to: &[String],
+        recipients: &[Recipient<'a>],
+    ) -> Result<RelayOutcome<'a>, RelayError> {
+        let mut batch = String::with_capacity(mail_from.len() + rcpt_to.len() * 64 + 8);
+        for line in std::iter::once(mail_from).chain(rcpt_to.iter().map(String::as_str)) {
+            batch.push_str(line);
+            batch.push_str("\r\n");
+        }
+        batch.push_str("DATA\r\n");
+        self.stream.write_all(batch.as_bytes()).await?;
+
+        // Every reply is read before judging any, to stay in step
+        let mail_reply = self.read_reply().await?;
+        let mut outcome = RelayOutcome::default();
+        for rcpt in recipients {
+            let reply = self.read_reply().await?;
+            outcome.record(rcpt.address, reply);
+        }
+        let data_reply = self.read_reply().await?;
+
+        // A backend that said 354 regardless now expects a body. None is
+        // sent: the session is dropped on error, which aborts the
+        // transaction without delivering anything.
+        if mail_reply.code != 250 {
+            return Err(RelayError::Rejected {
+                stage: "MAIL FROM rejected",
+                reply: mail_reply,
+            });
+        }
+        if outcome.delivered.is_empty() {
+            return Err(RelayError::all_rejected(
+                "all recipients rejected",
+                outcome.rejected,
+            ));
+        }
+        if data_reply.code != 354 {
+            return Err(RelayError::Rejected {
+                stage: "DATA not accepted",
+                reply: data_reply,
+            });
+        }
+        Ok(outcome)
+    }
+
+    /// End the session without waiting for the 221.
+    async fn quit(mut self) {
+        let _ = self.stream.write_all(b"QUIT\r\n").await;
+    }
+}
+
+impl Backend {
+    /// A single backend server. `max_idle` sessions are kept open for reuse
+    /// (0 = QUIT after every message); sessions idle longer than
+    /// `idle_timeout` are closed.
+    pub fn new(addr: &str, lmtp: bool, max_idle: usize, idle_timeout: Duration) -> Self {
+        Self::weighted(&[(addr.to_string(), 1)], lmtp, max_idle, idle_timeout)
+    }
+
+    /// Several backend servers with their weights, balanced per message.
+    /// The pool settings apply to each server.
+    pub fn weighted(
+        targets: &[(String, u32)],
+        lmtp: bool,
+        max_idle: usize,
+        idle_timeout: Duration,
+    ) -> Self {
+        assert!(!targets.is_empty(), "at least one backend is required");
+        let upstreams = targets
+            .iter()
+            .map(|(addr, weight)| Arc::new(Upstream::new(addr.clone(), *weight, 0)))
+            .collect();
+        Self::with_upstreams(upstreams, lmtp, max_idle, idle_timeout)
+    }
+
+    /// A backend whose servers are set later, by [`Backend::discover`] or
+    /// [`Backend::set_servers`]. Until then relaying fails as if the
+    /// backend were unreachable.
+    pub fn discovered(lmtp: bool, max_idle: usize, idle_timeout: Duration) -> Self {
+        Self::with_upstreams(Vec::new(), lmtp, max_idle, idle_timeout)
+    }
+
+    fn with_upstreams(
+        upstreams: Vec<Arc<Upstream>>,
+        lmtp: bool,
+        max_idle: usize,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            inner: Arc::new(BackendInner {
+                upstreams: Mutex::new(upstreams),
+                lmtp,
+                starttls: None,
+                retries: 0,
+                retry_backoff: Duration::ZERO,
+                trace_context: true,
+                limit: None,
+                max_idle,
+                idle_timeout,
+                resolver: None,
+                dns_max_ttl: DEFAULT_DNS_MAX_TTL,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            }),
+        }
+    }
+
+    /// The primary backends from `BACKEND_SMTP`, or to be discovered via
+    /// `BACKEND_SRV` if that is set.
+    pub fn from_config(config: &Config) -> Self {
+        let idle_timeout = Duration::from_secs(config.backend_pool_idle_secs);
+        let backend = match &config.backend_srv {
+            Some(_) => {
+                Self::discovered(config.backend_lmtp, config.backend_pool_size, idle_timeout)
+            }
+            None => Self::weighted(
+                &config.backend_addrs,
+                config.backend_lmtp,
+                config.backend_pool_size,
+                idle_timeout,
+            ),
+        };
+        backend.with_config(config)
+    }
+
+    /// Look up the SRV records of `name` now and every `interval`, and
+    /// relay to the servers they name from then on. Sessions to servers no
+    /// longer listed are closed. A failed or empty lookup keeps the current
+    /// servers.
+    pub async fn discover(self, resolver: Resolver, name: String, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match resolver.query(&name, RecordType::Srv).await {
+                Ok(records) => {
+                    let targets: Vec<Srv> = records
+                        .into_iter()
+                        .filter_map(|record| match record {
+                            Record::Srv(srv) => Some(srv),
+                            _ => None,
+                        })
+                        .collect();
+                    if targets.is_empty() {
+                        warn!(name = %name, "no SRV records for backend, keeping current servers");
+                    } else {
+                        self.set_servers(&targets).await;
+                    }
+                }
+                Err(e) => {
+                    warn!(name = %name, error = %e, "SRV lookup for backend failed, keeping current servers");
+                }
             }
         }
     }
 
-    // Send message body
-    writer.write_all(message_data).await?;
+    /// Relay to the servers named by `targets` (SRV records). Servers
+    /// listed before with the same priority and weight keep their pools.
+    /// A target of `.` (service not offered) is skipped; weight 0 counts
+    /// as 1.
+    pub async fn set_servers(&self, targets: &[Srv]) {
+        let retired = {
+            let mut upstreams = self.inner.upstreams.lock().unwrap();
+            let mut kept = Vec::with_capacity(targets.len());
+            for srv in targets.iter().filter(|srv| !srv.target.is_empty()) {
+                let addr = if srv.target.contains(':') {
+                    format!("[{}]:{}", srv.target, srv.port)
+                } else {
+                    format!("{}:{}", srv.target, srv.port)
+                };
+                let weight = u32::from(srv.weight.max(1));
+                let existing = upstreams
+                    .iter()
+                    .find(|u| u.addr == addr && u.weight == weight && u.priority == srv.priority);
+                kept.push(match existing {
+                    Some(upstream) => upstream.clone(),
+                    None => Arc::new(Upstream::new(addr, weight, srv.priority)),
+                });
+            }
+            let changed = kept.len() != upstreams.len()
+                || kept
+                    .iter()
+                    .zip(upstreams.iter())
+                    .any(|(a, b)| !Arc::ptr_eq(a, b));
+            if !changed {
+                return;
+            }
+            let old = std::mem::replace(&mut *upstreams, kept);
+            info!(
+                backends = ?upstreams.iter().map(|u| (u.addr.as_str(), u.priority, u.weight)).collect::<Vec<_>>(),
+                "backend servers updated from SRV"
+            );
+            old.into_iter()
+                .filter(|old| !upstreams.iter().any(|u| Arc::ptr_eq(u, old)))
+                .collect::<Vec<_>>()
+        };
+        for upstream in retired {
+            upstream.retired.store(true, Ordering::Relaxed);
+            let idle = std::mem::take(&mut *upstream.idle.lock().unwrap());
+            for conn in idle {
+                conn.quit().await;
+            }
+        }
+    }
 
-    // Ensure message ends with \r\n.\r\n
-    if !message_data.ends_with(b"\r\n") {
-        writer.write_all(b"\r\n").await?;
+    /// Upgrade new sessions with STARTTLS when the backend offers it. With
+    /// `required`, relaying fails rather than falling back to plaintext.
+    /// `server_name` overrides the host part of the address for SNI and
+    /// certificate verification.
+    ///
+    /// Must be called before the backend is cloned.
+    pub fn with_starttls(
+        mut self,
+        required: bool,
+        connector: TlsConnector,
+        server_name: Option<String>,
+    ) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("STARTTLS is configured before the backend is shared")
+            .starttls = Some(StartTls {
+            required,
+            connector,
+            server_name,
+        });
+        self
     }
-    writer.write_all(b".\r\n").await?;
 
-    let (code, resp) = read_response(&mut reader, &mut line_buf).await?;
-    if code != 250 {
-        return Err(RelayError::Protocol(format!(
-            "message not accepted: {}",
-            resp.trim()
-        )));
+    /// Retry a failed relay up to `retries` times, waiting `backoff` before
+    /// the first retry and twice as long before each further one. Each retry
+    /// goes to the next backend in rotation. Only failures that leave the
+    /// message undelivered are retried: not 5xx refusals, and not errors
+    /// after the body was sent but before the backend answered.
+    ///
+    /// Must be called before the backend is cloned.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        let inner = Arc::get_mut(&mut self.inner)
+            .expect("retries are configured before the backend is shared");
+        inner.retries = retries;
+        inner.retry_backoff = backoff;
+        self
     }
 
-    // QUIT
-    writer.write_all(b"QUIT\r\n").await?;
+    /// Let at most `max` relays run at once across all clones of this
+    /// backend; further ones wait for a slot. `0` = unlimited.
+    ///
+    /// Must be called before the backend is cloned.
+    pub fn with_concurrency_limit(mut self, max: usize) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("the relay limit is configured before the backend is shared")
+            .limit = (max > 0).then(|| Semaphore::new(max));
+        self
+    }
 
-    info!(
-        sender = sender,
-        recipients = ?recipients,
-        size = message_data.len(),
-        "message relayed to backend"
-    );
+    /// Whether to prepend `traceparent`/`tracestate` header fields to
+    /// relayed messages (on by default; a no-op without OpenTelemetry).
+    ///
+    /// Must be called before the backend is cloned.
+    pub fn with_trace_context(mut self, enabled: bool) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("trace context is configured before the backend is shared")
+            .trace_context = enabled;
+        self
+    }
 
-    Ok(())
+    /// Resolve backend hostnames through `resolver`, caching the addresses
+    /// for their TTL but no longer than `max_ttl`. Names it cannot find
+    /// (e.g. from `/etc/hosts`) still go to the system resolver. Without
+    /// this, only the system resolver is used and its answers are kept for
+    /// `max_ttl`.
+    ///
+    /// Must be called before the backend is cloned.
+    pub fn with_resolver(mut self, resolver: Option<Resolver>, max_ttl: Duration) -> Self {
+        let inner = Arc::get_mut(&mut self.inner)
+            .expect("the resolver is configured before the backend is shared");
+        inner.resolver = resolver;
+        inner.dns_max_ttl = max_ttl;
+        self
+    }
+
+    /// Give up on a backend address after `timeout` and try the next one.
+    ///
+    /// Must be called before the backend is cloned.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("the connect timeout is configured before the backend is shared")
+            .connect_timeout = timeout;
+        self
+    }
+
+    /// Apply the `BACKEND_TLS*`, `BACKEND_DNS_MAX_TTL`,
+    /// `BACKEND_CONNECT_TIMEOUT`, `RELAY_RETRY*`, `TRACE_CONTEXT_HEADER` and
+    /// `MAX_CONCURRENT_RELAYS` settings.
+    pub(crate) fn with_config(self, config: &Config) -> Self {
+        let backend = self
+            .with_resolver(
+                Some(Resolver::from_config(config)),
+                Duration::from_secs(config.backend_dns_max_ttl_secs),
+            )
+            .with_connect_timeout(Duration::from_secs(config.backend_connect_timeout_secs))
+            .with_retries(
+                config.relay_retries,
+                Duration::from_millis(config.relay_retry_backoff_ms),
+            )
+            .with_trace_context(config.trace_context_header)
+            .with_concurrency_limit(config.max_concurrent_relays);
+        let required = match config.backend_tls {
+            BackendTls::Disabled => return backend,
+            BackendTls::Opportunistic => false,
+            BackendTls::Required => true,
+        };
+        let connector = tls::backend_connector(config.backend_tls_ca_path.as_deref())
+            .unwrap_or_else(|e| panic!("BACKEND_TLS_CA_PATH: {}", e));
+        backend.with_starttls(required, connector, config.backend_tls_server_name.clone())
+    }
+
+    /// Sessions currently idle across all pools.
+    pub fn idle_connections(&self) -> usize {
+        self.inner
+            .upstreams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|upstream| upstream.idle.lock().unwrap().len())
+            .sum()
+    }
+
+    /// The upstream for the next attempt (smooth weighted round-robin, as
+    /// in nginx), from the lowest priority that has servers not yet in
+    /// `tried`. Once every server was tried, the lowest priority again.
+    fn next(&self, tried: &[Arc<Upstream>]) -> Option<Arc<Upstream>> {
+        let upstreams = self.inner.upstreams.lock().unwrap();
+        let untried = |u: &Arc<Upstream>| !tried.iter().any(|t| Arc::ptr_eq(t, u));
+        let (priority, fresh) = match upstreams
+            .iter()
+            .filter(|u| untried(u))
+            .map(|u| u.priority)
+            .min()
+        {
+            Some(priority) => (priority, true),
+            None => (upstreams.iter().map(|u| u.priority).min()?, false),
+        };
+        let candidates: Vec<&Arc<Upstream>> = upstreams
+            .iter()
+            .filter(|u| u.priority == priority && (!fresh || untried(u)))
+            .collect();
+        if let [only] = candidates[..] {
+            return Some(only.clone());
+        }
+        let mut best = candidates[0];
+        let mut total = 0;
+        for upstream in &candidates {
+            let weight = i64::from(upstream.weight);
+            total += weight;
+            let current = upstream.current.fetch_add(weight, Ordering::Relaxed) + weight;
+            if current > best.current.load(Ordering::Relaxed) {
+                best = upstream;
+            }
+        }
+        best.current.fetch_sub(total, Ordering::Relaxed);
+        Some(best.clone())
+    }
+
+    /// Take a pooled session that still answers RSET, or open a new one.
+    async fn checkout(&self, upstream: &Upstream) -> Result<Connection, RelayError> {
+        loop {
+            let pooled = upstream.idle.lock().unwrap().pop();
+            let Some(mut conn) = pooled else {
+                let stream = self.connect(upstream).await?;
+                return Connection::open(
+                    stream,
+                    &upstream.addr,
+                    self.inner.lmtp,
+                    self.inner.starttls.as_ref(),
+                )
+                .await;
+            };
+            if conn.idle_since.elapsed() >= self.inner.idle_timeout {
+                conn.quit().await;
+                continue;
+            }
+            // The backend may have closed the session while it sat idle
+            match conn.command("RSET").await {
+                Ok(reply) if reply.code == 250 => return Ok(conn),
+                _ => debug!(backend = %upstream.addr, "discarding stale pooled connection"),
+            }
+        }
+    }
+
+    /// Connect to the first reachable address of `upstream`, in the order
+    /// they resolved.
+    async fn connect(&self, upstream: &Upstream) -> Result<TcpStream, RelayError> {
+        let addrs = self.addresses(upstream).await?;
+        let mut error = format!("{} resolved to no addresses", upstream.addr);
+        for addr in addrs {
+            let connect = TcpStream::connect(addr);
+            match tokio::time::timeout(self.inner.connect_timeout, connect).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => error = e.to_string(),
+                Err(_) => error = "connect timed out".to_string(),
+            }
+            debug!(backend = %upstream.addr, address = %addr, error = %error, "backend address unreachable");
+        }
+        // The backend may have moved; look it up afresh next time
+        upstream.resolved.lock().unwrap().take();
+        Err(RelayError::Connect(error))
+    }
+
+    /// The addresses of `upstream`, cached until their TTL runs out. If a
+    /// fresh lookup fails, the last known addresses are used.
+    async fn addresses(&self, upstream: &Upstream) -> Result<Vec<SocketAddr>, RelayError> {
+        if let Ok(addr) = upstream.addr.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        if let Some((addrs, expires)) = &*upstream.resolved.lock().unwrap() {
+            if Instant::now() < *expires {
+                return Ok(addrs.clone());
+            }
+        }
+        match self.resolve(&upstream.addr).await {
+            Ok((addrs, ttl)) => {
+                debug!(backend = %upstream.addr, addresses = ?addrs, ttl_secs = ttl.as_secs(), "backend resolved");
+                *upstream.resolved.lock().unwrap() = Some((addrs.clone(), Instant::now() + ttl));
+                Ok(addrs)
+            }
+            Err(e) => match &*upstream.resolved.lock().unwrap() {
+                Some((addrs, _)) => {
+                    warn!(backend = %upstream.addr, error = %e, "backend lookup failed, using last known addresses");
+                    Ok(addrs.clone())
+                }
+                None => Err(RelayError::Connect(format!(
+                    "cannot resolve {}: {}",
+                    upstream.addr, e
+                ))),
+            },
+        }
+    }
+
+    /// Look up a `host:port` address and how long the answer may be kept.
+    async fn resolve(&self, addr: &str) -> std::io::Result<(Vec<SocketAddr>, Duration)> {
+        let port = addr
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "missing port"))?;
+        if let Some(resolver) = &self.inner.resolver {
+            match resolver.lookup_ip(host_part(addr)).await {
+                Ok((ips, ttl)) if !ips.is_empty() => {
+                    let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port));
+                    return Ok((addrs.collect(), ttl.min(self.inner.dns_max_ttl)));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    debug!(backend = addr, error = %e, "DNS lookup failed, trying the system resolver")
+                }
+            }
+        }
+        let addrs = tokio::net::lookup_host(addr).await?.collect();
+        Ok((addrs, self.inner.dns_max_ttl))
+    }
+
+    /// Return a session to its pool, or close it when the pool is full.
+    async fn checkin(&self, upstream: &Upstream, mut conn: Connection) {
+        conn.idle_since = Instant::now();
+        let overflow = {
+            let mut idle = upstream.idle.lock().unwrap();
+            let retired = upstream.retired.load(Ordering::Relaxed);
+            if idle.len() < self.inner.max_idle && !conn.impersonating && !retired {
+                idle.push(conn);
+                None
+            } else {
+                Some(conn)
+            }
+        };
+        if let Some(conn) = overflow {
+            conn.quit().await;
+        }
+    }
+
+    /// Relay a complete message to the next backend, on a session of its
+    /// own that goes back to the pool afterwards. See [`Backend::relay_in`].
+    pub async fn relay<'a>(
+        &self,
+        client: Option<&ClientInfo>,
+        sender: &str,
+        mail_params: &EsmtpParams,
+        recipients: &[Recipient<'a>],
+        message_data: &[u8],
+    ) -> Result<RelayOutcome<'a>, RelayError> {
+        let mut session = BackendSession::default();
+        let result = self
+            .relay_in(
+                &mut session,
+                client,
+                sender,
+                mail_params,
+                recipients,
+                message_data,
+            )
+            .await;
+        self.release(session).await;
+        result
+    }
+
+    /// Relay a complete message, reusing the backend session `session`
+    /// holds from an earlier message if it still answers RSET, and keeping
+    /// the session there afterwards. Otherwise the next backend is used.
+    ///
+    /// Runs MAIL FROM, RCPT TO (for each recipient), DATA and the body on a
+    /// pooled or new session. Client ESMTP parameters (e.g. DSN's
+    /// RET/ENVID/NOTIFY/ORCPT) are forwarded when the backend advertises the
+    /// matching extension and dropped otherwise. The outcome is recorded on
+    /// the `smtp.relay` span as `relay.outcome` (and `relay.error` on failure).
+    ///
+    /// Over LMTP (RFC 2033) the backend sends one reply per accepted
+    /// recipient after the body. The message fails only when no recipient
+    /// was delivered. A session that saw any error is closed, not pooled.
+    ///
+    /// With `client`, the original client's address, HELO name and protocol
+    /// are sent first via XCLIENT or XFORWARD when the backend offers either.
+    #[tracing::instrument(
+        name = "smtp.relay",
+        skip(self, session, message_data),
+        fields(
+            size = message_data.len(),
+            relay.backend = tracing::field::Empty,
+            relay.outcome = tracing::field::Empty,
+            relay.error = tracing::field::Empty,
+        )
+    )]
+    pub async fn relay_in<'a>(
+        &self,
+        session: &mut BackendSession,
+        client: Option<&ClientInfo>,
+        sender: &str,
+        mail_params: &EsmtpParams,
+        recipients: &[Recipient<'a>],
+        message_data: &[u8],
+    ) -> Result<RelayOutcome<'a>, RelayError> {
+        let _slot = match &self.inner.limit {
+            Some(limit) => Some(limit.acquire().await.expect("relay limit is never closed")),
+            None => None,
+        };
+        let mut retry = 0;
+        let mut tried = Vec::new();
+        let result = loop {
+            let (upstream, held) = match self.resume(session, client).await {
+                Some((upstream, conn)) => (upstream, Some(conn)),
+                None => match self.next(&tried) {
+                    Some(upstream) => (upstream, None),
+                    None => break Err(RelayError::Connect("no backend servers known".to_string())),
+                },
+            };
+            tracing::Span::current().record("relay.backend", upstream.addr.as_str());
+            tried.push(upstream.clone());
+            let (result, retryable) = self
+                .attempt(
+                    &upstream,
+                    held,
+                    session,
+                    client,
+                    sender,
+                    mail_params,
+                    recipients,
+                    message_data,
+                )
+                .await;
+            match result {
+                Err(e) if retryable && retry < self.inner.retries => {
+                    let delay = self.inner.retry_backoff.saturating_mul(1 << retry.min(16));
+                    retry += 1;
+                    warn!(
+                        backend = %upstream.addr,
+                        error = %e,
+                        retry = retry,
+                        delay_ms = delay.as_millis() as u64,
+                        "backend relay failed, retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => break result,
+            }
+        };
+        let span = tracing::Span::current();
+        match &result {
+            Ok(outcome) if outcome.rejected.is_empty() => {
+                span.record("relay.outcome", "relayed");
+            }
+            Ok(_) => {
+                span.record("relay.outcome", "partial");
+            }
+            Err(e) => {
+                span.record("relay.outcome", e.kind());
+                span.record("relay.error", tracing::field::display(e));
+            }
+        }
+        result
+    }
+
+    /// The session held in `session`, if it still answers RSET and was not
+    /// handed over (XCLIENT) to a client other than `client`.
+    async fn resume(
+        &self,
+        session: &mut BackendSession,
+        client: Option<&ClientInfo>,
+    ) -> Option<(Arc<Upstream>, Connection)> {
+        let (upstream, mut conn) = session.held.take()?;
+        if conn.impersonating && session.client.as_ref() != client {
+            conn.quit().await;
+            return None;
+        }
+        match conn.command("RSET").await {
+            Ok(reply) if reply.code == 250 => Some((upstream, conn)),
+            _ => {
+                debug!(
+                    backend = %upstream.addr,
+                    "discarding stale held connection"
+                );
+                None
+            }
+        }
+    }
+
+    /// Return the session `session` holds to the pool.
+    pub async fn release(&self, session: BackendSession) {
+        if let Some((upstream, conn)) = session.held {
+            self.checkin(&upstream, conn).await;
+        }
+    }
+
+    /// One relay attempt on `upstream`, on `held` if given. A session
+    /// that completed the message is kept in `session`. The flag tells
+    /// whether the failure (if any) is safe to retry.
+    #[allow(clippy::too_many_arguments)]
+    async fn attempt<'a>(
+        &self,
+        upstream: &Arc<Upstream>,
+        held: Option<Connection>,
+        session: &mut BackendSession,
+        client: Option<&ClientInfo>,
+        sender: &str,
+        mail_params: &EsmtpParams,
+        recipients: &[Recipient<'a>],
+        message_data: &[u8],
+    ) -> (Result<RelayOutcome<'a>, RelayError>, bool) {
+        let conn = match held {
+            Some(conn) => Ok(conn),
+            None => self.checkout(upstream).await,
+        };
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                let retryable = !e.is_permanent();
+                return (Err(e), retryable);
+            }
+        };
+        let result = self
+            .transaction(
+                &mut conn,
+                client,
+                sender,
+                mail_params,
+                recipients,
+                message_data,
+            )
+            .await;
+        let retryable = match &result {
+            Ok(_) => false,
+            Err(e) => {
+                !e.is_permanent() && (!conn.body_sent || matches!(e, RelayError::Rejected { .. }))
+            }
+        };
+        if result.is_ok() {
+            session.held = Some((upstream.clone(), conn));
+            session.client = client.cloned();
+        } else {
+            conn.quit().await;
+        }
+        (result, retryable)
+    }
+
+    /// One mail transaction on an open session.
+    async fn transaction<'a>(
+        &self,
+        conn: &mut Connection,
+        client: Option<&ClientInfo>,
+        sender: &str,
+        mail_params: &EsmtpParams,
+        recipients: &[Recipient<'a>],
+        message_data: &[u8],
+    ) -> Result<RelayOutcome<'a>, RelayError> {
+        conn.body_sent = false;
+        // A held session already speaks for this client
+        if let Some(client) = client.filter(|_| !conn.impersonating) {
+            conn.forward_client(client, self.inner.lmtp).await?;
+        }
+
+        let mail_from = format!("MAIL FROM:<{}>{}", sender, mail_params.to_wire(&conn.caps));
+        let rcpt_to: Vec<String> = recipients
+            .iter()
+            .map(|rcpt| {
+                format!(
+                    "RCPT TO:<{}>{}",
+                    rcpt.address,
+                    rcpt.params.to_wire(&conn.caps)
+                )
+            })
+            .collect();
+        let mut outcome = if conn.caps.contains("PIPELINING") {
+            conn.pipelined_envelope(&mail_from, &rcpt_to, recipients)
+                .await?
+        } else {
+            conn.envelope(&mail_from, &rcpt_to, recipients).await?
+        };
+
+        // Let downstream processors (Ratatoskr) continue this trace
+        if self.inner.trace_context {
+            let trace = headers::trace_context();
+            if trace.is_empty() {
+                // OTel not configured
+            } else if headers::is_header_block(message_data) {
+                conn.stream.write_all(trace.as_bytes()).await?;
+            } else {
+                debug!("message has no header section, trace context not added");
+            }
+        }
+
+        // Send message body
+        conn.stream.write_all(message_data).await?;
+
+        // Ensure message ends with \r\n.\r\n
+        if !message_data.ends_with(b"\r\n") {
+            conn.stream.write_all(b"\r\n").await?;
+        }
+        conn.body_sent = true;
+        conn.stream.write_all(b".\r\n").await?;
+
+        if self.inner.lmtp {
+            // One reply per accepted recipient, in RCPT order (RFC 2033 §4.2)
+            let accepted = std::mem::take(&mut outcome.delivered);
+            for address in accepted {
+                let reply = conn.read_reply().await?;
+                if reply.code == 250 {
+                    outcome.delivered.push(address);
+                } else {
+                    error!(recipient = %address, response = %reply, "backend did not deliver to recipient");
+                    outcome.rejected.push((address, reply));
+                }
+            }
+            if outcome.delivered.is_empty() {
+                return Err(RelayError::all_rejected(
+                    "message not delivered to any recipient",
+                    outcome.rejected,
+                ));
+            }
+        } else {
+            let reply = conn.read_reply().await?;
+            if reply.code != 250 {
+                return Err(RelayError::Rejected {
+                    stage: "message not accepted",
+                    reply,
+                });
+            }
+        }
+
+        info!(
+            sender = sender,
+            recipients = %AddressList(outcome.delivered.iter()),
+            size = message_data.len(),
+            "message relayed to backend"
+        );
+
+        Ok(outcome)
+    }
+
+    /// Check that the next backend answers with a banner and EHLO (LHLO
+    /// over LMTP) on a new connection, without sending mail.
+    pub async fn probe(&self) -> Result<(), RelayError> {
+        let upstream = self
+            .next(&[])
+            .ok_or_else(|| RelayError::Connect("no backend servers known".to_string()))?;
+        let stream = self.connect(&upstream).await?;
+        let conn = Connection::open(
+            stream,
+            &upstream.addr,
+            self.inner.lmtp,
+            self.inner.starttls.as_ref(),
+        )
+        .await?;
+        conn.quit().await;
+        Ok(())
+    }
+}
+
+/// Host part of a `host:port` address, without IPv6 brackets.
+fn host_part(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Parse a comma-separated backend list. Each entry is `host:port`,
+/// optionally followed by `*weight` (default 1).
+pub fn parse_targets(list: &str) -> Result<Vec<(String, u32)>, String> {
+    let targets = list
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| match entry.rsplit_once('*') {
+            Some((addr, weight)) => match weight.trim().parse() {
+                Ok(weight) if weight > 0 => Ok((addr.trim().to_string(), weight)),
+                _ => Err(format!("invalid weight in {:?}", entry)),
+            },
+            None => Ok((entry.to_string(), 1)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if targets.is_empty() {
+        return Err("no backend configured".to_string());
+    }
+    Ok(targets)
+}
+
+/// Extension keyword from an EHLO response line (`250-DSN` -> `DSN`).
+pub fn ehlo_keyword(line: &str) -> Option<String> {
+    line.get(4..)?
+        .split_ascii_whitespace()
+        .next()
+        .map(|k| k.to_ascii_uppercase())
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -150,4 +1234,45 @@ pub enum RelayError {
     Io(#[from] std::io::Error),
     #[error("protocol error: {0}")]
     Protocol(String),
+    /// The backend refused the message with `reply`.
+    #[error("protocol error: {stage}: {reply}")]
+    Rejected {
+        stage: &'static str,
+        reply: SmtpReply,
+    },
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("timed out")]
+    Timeout,
+}
+
+impl RelayError {
+    /// Short machine-readable error class for span attributes.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RelayError::Connect(_) => "connect_error",
+            RelayError::Io(_) => "io_error",
+            RelayError::Protocol(_) | RelayError::Rejected { .. } => "protocol_error",
+            RelayError::Tls(_) => "tls_error",
+            RelayError::Timeout => "timeout",
+        }
+    }
+
+    /// The backend refused the message with a 5xx; retrying won't help.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, RelayError::Rejected { reply, .. } if !reply.is_positive() && !reply.is_transient())
+    }
+
+    /// Every recipient was refused: permanent unless any refusal was
+    /// transient.
+    fn all_rejected(stage: &'static str, rejected: Vec<(&str, SmtpReply)>) -> Self {
+        let (_, reply) = match rejected.iter().find(|(_, reply)| reply.is_transient()) {
+            Some(transient) => transient.clone(),
+            None => rejected
+                .last()
+                .cloned()
+                .expect("at least one recipient was refused"),
+        };
+        RelayError::Rejected { stage, reply }
+    }
 }
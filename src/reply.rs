@@ -0,0 +1,429 @@
+use std::borrow::Cow;
+use std::fmt::{self, Write as _};
+use std::net::IpAddr;
+
+use arrayvec::ArrayString;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::config::HeloCheck;
+
+/// RFC 3463 enhanced status code (`5.1.1`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnhancedStatus {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedStatus {
+    pub const fn new(class: u8, subject: u16, detail: u16) -> Self {
+        Self {
+            class,
+            subject,
+            detail,
+        }
+    }
+
+    /// Parse `class.subject.detail`; the class must be 2, 4 or 5.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(3, '.');
+        let class = parts
+            .next()?
+            .parse()
+            .ok()
+            .filter(|c| matches!(c, 2 | 4 | 5))?;
+        let subject = parts.next()?.parse().ok()?;
+        let detail = parts.next()?.parse().ok()?;
+        Some(Self::new(class, subject, detail))
+    }
+}
+
+impl fmt::Display for EnhancedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// An SMTP reply: a three-digit code, an optional enhanced status repeated
+/// on every line, and text. Text containing `\n` is sent as a multiline
+/// reply (`250-first`, `250 last`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmtpReply {
+    pub code: u16,
+    pub status: Option<EnhancedStatus>,
+    text: Cow<'static, str>,
+}
+
+impl SmtpReply {
+    /// A fixed reply with an enhanced status.
+    pub const fn new(code: u16, status: EnhancedStatus, text: &'static str) -> Self {
+        Self {
+            code,
+            status: Some(status),
+            text: Cow::Borrowed(text),
+        }
+    }
+
+    /// A fixed reply without an enhanced status (banner, 354).
+    pub const fn without_status(code: u16, text: &'static str) -> Self {
+        Self {
+            code,
+            status: None,
+            text: Cow::Borrowed(text),
+        }
+    }
+
+    /// A reply with text built at runtime.
+    pub fn formatted(code: u16, status: Option<EnhancedStatus>, text: String) -> Self {
+        Self {
+            code,
+            status,
+            text: Cow::Owned(text),
+        }
+    }
+
+    /// Parse the lines of a received reply. Returns `None` when the first
+    /// line does not start with a three-digit code.
+    pub fn parse<S: AsRef<str>>(lines: &[S]) -> Option<Self> {
+        let first = lines.first()?.as_ref();
+        let code = first
+            .get(..3)
+            .filter(|c| c.bytes().all(|b| b.is_ascii_digit()))?
+            .parse()
+            .ok()?;
+        let status = first
+            .get(4..)
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(EnhancedStatus::parse)
+            .filter(|s| u16::from(s.class) == code / 100);
+        let text: Vec<&str> = lines
+            .iter()
+            .map(|line| {
+                let rest = line.as_ref().get(4..).unwrap_or("");
+                match status {
+                    Some(_) => rest.split_once(' ').map_or("", |(_, text)| text),
+                    None => rest,
+                }
+            })
+            .collect();
+        Some(Self::formatted(code, status, text.join("\n")))
+    }
+
+    /// Reply text, one entry per line.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.text.split('\n')
+    }
+
+    /// 2xx or 3xx.
+    pub fn is_positive(&self) -> bool {
+        self.code < 400
+    }
+
+    /// 4xx: the client may retry later.
+    pub fn is_transient(&self) -> bool {
+        (400..500).contains(&self.code)
+    }
+
+    /// Code, separator and status for line `last`/not last, e.g. `550 5.1.1 `.
+    fn prefix(&self, last: bool) -> ArrayString<24> {
+        let mut prefix = ArrayString::new();
+        let sep = if last { ' ' } else { '-' };
+        let _ = write!(prefix, "{}{}", self.code, sep);
+        if let Some(status) = self.status {
+            let _ = write!(prefix, "{} ", status);
+        }
+        prefix
+    }
+
+    /// Write the reply with CRLF line endings, without building it as a
+    /// String first.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut lines = self.lines().peekable();
+        while let Some(line) = lines.next() {
+            let prefix = self.prefix(lines.peek().is_none());
+            writer.write_all(prefix.as_bytes()).await?;
+            writer.write_all(line.as_bytes()).await?;
+            writer.write_all(b"\r\n").await?;
+        }
+        Ok(())
+    }
+}
+
+/// Wire format without the final CRLF.
+impl fmt::Display for SmtpReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = self.lines().peekable();
+        while let Some(line) = lines.next() {
+            let last = lines.peek().is_none();
+            write!(f, "{}{}", self.prefix(last), line)?;
+            if !last {
+                f.write_str("\r\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Operator overrides for rejection texts. `{address}` and `{domain}` are
+/// filled in from the recipient; the code and enhanced status stay fixed.
+#[derive(Clone, Debug, Default)]
+pub struct ReplyTemplates {
+    /// Replaces the text of [`USER_UNKNOWN`].
+    pub user_unknown: Option<String>,
+    /// Replaces the text of [`MAILBOX_UNAVAILABLE`].
+    pub mailbox_unavailable: Option<String>,
+    /// Replaces the text of [`UNKNOWN_DOMAIN`].
+    pub unknown_domain: Option<String>,
+    /// Replaces the text of [`SENDER_BLOCKED`].
+    pub sender_blocked: Option<String>,
+}
+
+impl ReplyTemplates {
+    /// 550 5.1.1 for `address`.
+    pub fn user_unknown(&self, address: &str) -> SmtpReply {
+        render(&USER_UNKNOWN, self.user_unknown.as_deref(), address)
+    }
+
+    /// 450 4.2.1 for `address`.
+    pub fn mailbox_unavailable(&self, address: &str) -> SmtpReply {
+        render(
+            &MAILBOX_UNAVAILABLE,
+            self.mailbox_unavailable.as_deref(),
+            address,
+        )
+    }
+
+    /// 550 5.1.2 for `address`.
+    pub fn unknown_domain(&self, address: &str) -> SmtpReply {
+        render(&UNKNOWN_DOMAIN, self.unknown_domain.as_deref(), address)
+    }
+
+    /// 550 5.7.1 for `address`.
+    pub fn sender_blocked(&self, address: &str) -> SmtpReply {
+        render(&SENDER_BLOCKED, self.sender_blocked.as_deref(), address)
+    }
+}
+
+/// `base` with its text replaced by `template`, or `base` unchanged.
+fn render(base: &SmtpReply, template: Option<&str>, address: &str) -> SmtpReply {
+    let Some(template) = template else {
+        return base.clone();
+    };
+    let domain = address.rsplit_once('@').map_or("", |(_, domain)| domain);
+    let text = template
+        .replace("{address}", address)
+        .replace("{domain}", domain);
+    SmtpReply::formatted(base.code, base.status, text)
+}
+
+/// 554 (at connect) or 550 (to MAIL) 5.7.1 for a client on the DNS
+/// blocklist `zone`.
+pub fn dnsbl_listed(code: u16, ip: IpAddr, zone: &str) -> SmtpReply {
+    SmtpReply::formatted(
+        code,
+        Some(status(5, 7, 1)),
+        format!("Client host {} blocked using {}", ip, zone),
+    )
+}
+
+/// 554 (at connect) 5.7.25 for a client without the reverse DNS
+/// `RDNS_CHECK` requires (RFC 7372).
+pub fn rdns_failed(code: u16, ip: IpAddr) -> SmtpReply {
+    SmtpReply::formatted(
+        code,
+        Some(status(5, 7, 25)),
+        format!("Client host {} has no valid reverse DNS", ip),
+    )
+}
+
+/// 550 5.7.1 to a greeting whose name fails a `HELO_CHECKS` check that
+/// rejects, e.g. `Helo command rejected: need fully-qualified hostname`.
+pub fn helo_rejected(check: HeloCheck) -> SmtpReply {
+    let reason = match check {
+        HeloCheck::BareIp => "IP address must be an address literal",
+        HeloCheck::LiteralMismatch => "address literal does not match your address",
+        HeloCheck::OwnName => "that is my name",
+        HeloCheck::NonFqdn => "need fully-qualified hostname",
+    };
+    SmtpReply::formatted(
+        550,
+        Some(status(5, 7, 1)),
+        format!("Helo command rejected: {}", reason),
+    )
+}
+
+const fn status(class: u8, subject: u16, detail: u16) -> EnhancedStatus {
+    EnhancedStatus::new(class, subject, detail)
+}
+
+// -- Connection --
+
+pub const READY_FOR_TLS: SmtpReply = SmtpReply::new(220, status(2, 0, 0), "Ready to start TLS");
+pub const BYE: SmtpReply = SmtpReply::new(221, status(2, 0, 0), "Bye");
+pub const TOO_MANY_CONNECTIONS: SmtpReply = SmtpReply::new(
+    421,
+    status(4, 3, 2),
+    "Too many connections, try again later",
+);
+pub const VIRUS_FOUND: SmtpReply = SmtpReply::new(550, status(5, 7, 1), "Message contains a virus");
+pub const VIRUS_SCAN_UNAVAILABLE: SmtpReply = SmtpReply::new(
+    451,
+    status(4, 3, 0),
+    "Virus scan unavailable, try again later",
+);
+pub const CONTENT_REJECTED: SmtpReply =
+    SmtpReply::new(550, status(5, 7, 1), "Message rejected by content rule");
+pub const SPAM_REJECTED: SmtpReply =
+    SmtpReply::new(554, status(5, 7, 1), "Message rejected as spam");
+pub const SPAM_DEFERRED: SmtpReply =
+    SmtpReply::new(451, status(4, 7, 1), "Message deferred, try again later");
+pub const NETWORK_BLOCKED: SmtpReply =
+    SmtpReply::new(554, status(5, 7, 1), "Access denied for your network");
+pub const GEOIP_BLOCKED: SmtpReply =
+    SmtpReply::new(554, status(5, 7, 1), "Access denied for your country");
+pub const REPUTATION_REJECTED: SmtpReply = SmtpReply::new(
+    554,
+    status(5, 7, 1),
+    "Your IP has a poor reputation, try again later",
+);
+pub const GREYLISTED: SmtpReply =
+    SmtpReply::new(451, status(4, 7, 1), "Greylisted, please try again later");
+pub const AUTO_BLOCKED: SmtpReply = SmtpReply::new(
+    554,
+    status(5, 7, 1),
+    "Too many unknown recipients from your IP, try again later",
+);
+pub const TOO_MANY_CONNECTIONS_FROM_IP: SmtpReply =
+    SmtpReply::new(421, status(4, 7, 0), "Too many connections from your IP");
+pub const TIMEOUT: SmtpReply =
+    SmtpReply::new(421, status(4, 4, 2), "Timeout exceeded, closing connection");
+pub const TOO_MANY_MESSAGES: SmtpReply = SmtpReply::new(
+    421,
+    status(4, 7, 0),
+    "Too many messages in this session, reconnect to send more",
+);
+pub const TOO_MANY_ERRORS: SmtpReply =
+    SmtpReply::new(421, status(4, 7, 0), "Too many errors, closing connection");
+pub const EARLY_TALKER: SmtpReply = SmtpReply::new(
+    554,
+    status(5, 5, 1),
+    "Protocol error: data sent before greeting",
+);
+
+// -- Commands --
+
+pub const OK: SmtpReply = SmtpReply::new(250, status(2, 0, 0), "OK");
+pub const BARE_LINE_ENDING_COMMAND: SmtpReply =
+    SmtpReply::new(500, status(5, 5, 2), "Bare CR or LF not allowed");
+pub const USE_LHLO: SmtpReply = SmtpReply::new(500, status(5, 5, 1), "Use LHLO for LMTP");
+pub const NOT_RECOGNIZED: SmtpReply =
+    SmtpReply::new(502, status(5, 5, 2), "Command not recognized");
+pub const TLS_NOT_AVAILABLE: SmtpReply =
+    SmtpReply::new(502, status(5, 5, 1), "STARTTLS not available");
+pub const TLS_ALREADY_ACTIVE: SmtpReply =
+    SmtpReply::new(554, status(5, 5, 1), "TLS already active");
+pub const SEND_EHLO_FIRST: SmtpReply = SmtpReply::new(503, status(5, 5, 1), "Send EHLO/HELO first");
+pub const SEND_LHLO_FIRST: SmtpReply = SmtpReply::new(503, status(5, 5, 1), "Send LHLO first");
+pub const TLS_REQUIRED: SmtpReply =
+    SmtpReply::new(530, status(5, 7, 0), "Must issue a STARTTLS command first");
+pub const CANNOT_VRFY: SmtpReply = SmtpReply::new(252, status(2, 5, 2), "Cannot verify user");
+pub const VRFY_SYNTAX: SmtpReply = SmtpReply::new(501, status(5, 5, 4), "Syntax: VRFY <address>");
+pub const PARAM_SYNTAX: SmtpReply =
+    SmtpReply::new(501, status(5, 5, 4), "Syntax error in parameters");
+pub const PARAM_UNRECOGNIZED: SmtpReply = SmtpReply::new(
+    555,
+    status(5, 5, 4),
+    "Parameter not recognized or not implemented",
+);
+
+// -- MAIL --
+
+pub const SENDER_OK: SmtpReply = SmtpReply::new(250, status(2, 1, 0), "OK");
+pub const NESTED_MAIL: SmtpReply = SmtpReply::new(503, status(5, 5, 1), "Nested MAIL command");
+pub const BAD_SENDER_SYNTAX: SmtpReply =
+    SmtpReply::new(501, status(5, 1, 7), "Bad sender address syntax");
+pub const SIZE_EXCEEDS_MAXIMUM: SmtpReply = SmtpReply::new(
+    552,
+    status(5, 3, 4),
+    "Message size exceeds fixed maximum message size",
+);
+pub const SENDER_NULL_MX: SmtpReply =
+    SmtpReply::new(550, status(5, 7, 27), "Sender domain does not accept mail");
+pub const SENDER_DOMAIN_NOT_FOUND: SmtpReply =
+    SmtpReply::new(550, status(5, 1, 8), "Sender domain not found");
+pub const SENDER_RATE_LIMITED: SmtpReply = SmtpReply::new(
+    450,
+    status(4, 7, 1),
+    "Sender rate limit exceeded, try again later",
+);
+
+// -- RCPT --
+
+pub const RECIPIENT_OK: SmtpReply = SmtpReply::new(250, status(2, 1, 5), "OK");
+pub const NEED_MAIL: SmtpReply = SmtpReply::new(503, status(5, 5, 1), "Need MAIL command");
+pub const BAD_RECIPIENT_SYNTAX: SmtpReply =
+    SmtpReply::new(501, status(5, 1, 3), "Bad recipient address syntax");
+pub const TOO_MANY_RECIPIENTS: SmtpReply =
+    SmtpReply::new(452, status(4, 5, 3), "Too many recipients");
+pub const TOO_MANY_SESSION_RECIPIENTS: SmtpReply =
+    SmtpReply::new(452, status(4, 5, 3), "Too many recipients for this session");
+pub const LOOKUP_UNAVAILABLE: SmtpReply =
+    SmtpReply::new(451, status(4, 3, 0), "Mailbox lookup unavailable");
+pub const UNKNOWN_DOMAIN: SmtpReply = SmtpReply::new(550, status(5, 1, 2), "Unknown domain");
+pub const USER_UNKNOWN: SmtpReply = SmtpReply::new(550, status(5, 1, 1), "User unknown");
+pub const MAILBOX_UNAVAILABLE: SmtpReply =
+    SmtpReply::new(450, status(4, 2, 1), "Mailbox temporarily unavailable");
+pub const ALIAS_LOOP: SmtpReply = SmtpReply::new(550, status(5, 4, 6), "Alias loop detected");
+pub const SENDER_BLOCKED: SmtpReply =
+    SmtpReply::new(550, status(5, 7, 1), "Sender blocked by recipient");
+
+pub const RECIPIENT_RATE_LIMITED: SmtpReply = SmtpReply::new(
+    452,
+    status(4, 2, 1),
+    "Mailbox is receiving too many messages, try again later",
+);
+
+// -- DATA --
+
+pub const START_MAIL_INPUT: SmtpReply =
+    SmtpReply::without_status(354, "Start mail input; end with <CRLF>.<CRLF>");
+pub const NO_VALID_RECIPIENTS: SmtpReply =
+    SmtpReply::new(503, status(5, 5, 1), "No valid recipients");
+pub const MESSAGE_TOO_LARGE: SmtpReply = SmtpReply::new(552, status(5, 3, 4), "Message too large");
+pub const BARE_LINE_ENDING_MESSAGE: SmtpReply =
+    SmtpReply::new(550, status(5, 6, 0), "Message contains bare CR or LF");
+pub const AMBIGUOUS_END_OF_DATA: SmtpReply = SmtpReply::new(
+    554,
+    status(5, 6, 0),
+    "Ambiguous end-of-data sequence, closing connection",
+);
+pub const NOT_DOT_STUFFED: SmtpReply =
+    SmtpReply::new(550, status(5, 6, 0), "Message is not dot-stuffed");
+pub const MESSAGE_ACCEPTED: SmtpReply = SmtpReply::new(250, status(2, 0, 0), "OK message accepted");
+/// Reply to pass on a refusal from the backend: its code and enhanced status
+/// with our own text, so backend internals are not echoed to the client.
+pub fn downstream(backend: &SmtpReply) -> SmtpReply {
+    let class = (backend.code / 100) as u8;
+    let text = if backend.is_transient() {
+        "Temporarily refused by backend, try again later"
+    } else {
+        "Refused by backend"
+    };
+    SmtpReply::formatted(
+        backend.code,
+        Some(backend.status.unwrap_or(status(class, 0, 0))),
+        text.to_string(),
+    )
+}
+
+pub const MESSAGE_QUEUED: SmtpReply =
+    SmtpReply::new(250, status(2, 0, 0), "OK message queued for delivery");
+pub const SELF_TEST_DISCARDED: SmtpReply =
+    SmtpReply::new(250, status(2, 0, 0), "OK self-test message discarded");
+pub const BACKEND_UNAVAILABLE: SmtpReply =
+    SmtpReply::new(451, status(4, 4, 1), "Backend unavailable");
+pub const RELAY_FAILED: SmtpReply = SmtpReply::new(
+    451,
+    status(4, 3, 0),
+    "Temporary relay failure, try again later",
+);
@@ -0,0 +1,196 @@
+//! Per-IP reputation (`REPUTATION_ENABLED`): a score kept in the store that
+//! bad behaviour raises, clean deliveries lower, and time decays toward
+//! zero. Clients with a high score are tarpitted, greylisted or refused at
+//! connect.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::audit::unix_now;
+use crate::config::Config;
+use crate::store::SharedStore;
+
+/// Lowest score clean deliveries can earn, so a long good history does not
+/// hide a sudden burst of spam.
+const MIN_SCORE: f64 = -10.0;
+
+/// How long a greylisted triplet is remembered.
+const GREYLIST_TTL_SECS: u64 = 2 * 86_400;
+
+/// What a client did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A recipient was refused as unknown domain or mailbox.
+    UnknownRecipient,
+    /// A recipient was a spamtrap.
+    Spamtrap,
+    /// The client talked before the banner.
+    EarlyTalker,
+    /// A message was relayed.
+    Delivered,
+}
+
+impl Event {
+    /// Points added to the score.
+    pub fn points(self) -> f64 {
+        match self {
+            Self::UnknownRecipient => 1.0,
+            Self::Spamtrap => 10.0,
+            Self::EarlyTalker => 5.0,
+            Self::Delivered => -1.0,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UnknownRecipient => "unknown_recipient",
+            Self::Spamtrap => "spamtrap",
+            Self::EarlyTalker => "early_talker",
+            Self::Delivered => "delivered",
+        }
+    }
+}
+
+/// `score` after `elapsed_secs` of halving every `half_life_secs`.
+pub fn decayed(score: f64, elapsed_secs: u64, half_life_secs: u64) -> f64 {
+    if half_life_secs == 0 {
+        return score;
+    }
+    score * 0.5f64.powf(elapsed_secs as f64 / half_life_secs as f64)
+}
+
+#[derive(Clone)]
+pub struct Reputation {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: SharedStore,
+    /// Scores are `{prefix}{ip}`, greylisted triplets `{prefix}grey:...`.
+    key_prefix: String,
+    half_life_secs: u64,
+    /// Thresholds, 0 = off.
+    reject_score: u32,
+    greylist_score: u32,
+    tarpit_score: u32,
+    greylist_delay_secs: u64,
+    tarpit: Duration,
+}
+
+impl Reputation {
+    /// `None` unless `REPUTATION_ENABLED` is set.
+    pub fn from_config(store: SharedStore, config: &Config) -> Option<Self> {
+        if !config.reputation_enabled {
+            return None;
+        }
+        Some(Self {
+            inner: Arc::new(Inner {
+                store,
+                key_prefix: config.reputation_key_prefix.clone(),
+                half_life_secs: config.reputation_half_life_secs,
+                reject_score: config.reputation_reject_score,
+                greylist_score: config.reputation_greylist_score,
+                tarpit_score: config.reputation_tarpit_score,
+                greylist_delay_secs: config.reputation_greylist_delay_secs,
+                tarpit: Duration::from_millis(config.reputation_tarpit_ms),
+            }),
+        })
+    }
+
+    fn key(&self, ip: IpAddr) -> String {
+        format!("{}{}", self.inner.key_prefix, ip)
+    }
+
+    /// The decayed score of `ip`; 0 if it has none or the store fails.
+    pub async fn score(&self, ip: IpAddr) -> f64 {
+        match self.inner.store.get(&self.key(ip)).await {
+            Ok(value) => value.as_deref().and_then(parse).map_or(0.0, |(score, at)| {
+                decayed(
+                    score,
+                    unix_now().saturating_sub(at),
+                    self.inner.half_life_secs,
+                )
+            }),
+            Err(e) => {
+                warn!(ip = %ip, error = %e, "reputation lookup failed, using 0");
+                0.0
+            }
+        }
+    }
+
+    /// Add `event` to the score of `ip` and return the new score. The
+    /// update is not atomic; concurrent events may lose one of them.
+    pub async fn record(&self, ip: IpAddr, event: Event) -> f64 {
+        let score = (self.score(ip).await + event.points()).max(MIN_SCORE);
+        // Kept until the score has decayed to under a thousandth
+        let ttl_secs = self.inner.half_life_secs.max(1).saturating_mul(10);
+        let value = format!("{:.3}:{}", score, unix_now());
+        match self
+            .inner
+            .store
+            .set_ex(&self.key(ip), &value, ttl_secs)
+            .await
+        {
+            Ok(()) => debug!(ip = %ip, event = event.as_str(), score, "reputation updated"),
+            Err(e) => warn!(ip = %ip, error = %e, "reputation not updated"),
+        }
+        score
+    }
+
+    pub fn refuses(&self, score: f64) -> bool {
+        reaches(score, self.inner.reject_score)
+    }
+
+    pub fn greylists(&self, score: f64) -> bool {
+        reaches(score, self.inner.greylist_score)
+    }
+
+    /// How long to hold back the banner and each RCPT reply.
+    pub fn tarpit(&self, score: f64) -> Option<Duration> {
+        reaches(score, self.inner.tarpit_score).then_some(self.inner.tarpit)
+    }
+
+    /// Whether to defer this triplet: true the first time it is seen and
+    /// until `REPUTATION_GREYLIST_DELAY_SECS` have passed. A store error
+    /// lets it through.
+    pub async fn greylisted(&self, ip: IpAddr, sender: &str, recipient: &str) -> bool {
+        let key = format!(
+            "{}grey:{}:{}:{}",
+            self.inner.key_prefix, ip, sender, recipient
+        );
+        let now = unix_now();
+        let first_seen = match self.inner.store.get(&key).await {
+            Ok(Some(value)) => value.parse().unwrap_or(now),
+            Ok(None) => {
+                if let Err(e) = self
+                    .inner
+                    .store
+                    .set_ex(&key, &now.to_string(), GREYLIST_TTL_SECS)
+                    .await
+                {
+                    warn!(ip = %ip, error = %e, "greylist entry not stored, allowing");
+                    return false;
+                }
+                return true;
+            }
+            Err(e) => {
+                warn!(ip = %ip, error = %e, "greylist check failed, allowing");
+                return false;
+            }
+        };
+        now.saturating_sub(first_seen) < self.inner.greylist_delay_secs
+    }
+}
+
+fn reaches(score: f64, threshold: u32) -> bool {
+    threshold > 0 && score >= f64::from(threshold)
+}
+
+/// `score:updated_at`.
+fn parse(value: &str) -> Option<(f64, u64)> {
+    let (score, at) = value.split_once(':')?;
+    Some((score.parse().ok()?, at.parse().ok()?))
+}
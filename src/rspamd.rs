@@ -0,0 +1,189 @@
+//! Content scanning with Rspamd: each message is posted to `RSPAMD_URL`'s
+//! `/checkv2` endpoint after DATA, and the action Rspamd returns decides
+//! whether it is relayed, tagged, deferred or refused.
+
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio_rustls::TlsConnector;
+
+use crate::config::Config;
+use crate::http::{self, HttpUrl, Request};
+use crate::tls;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RspamdError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("timed out")]
+    Timeout,
+    #[error("HTTP {0}")]
+    Status(u16),
+    #[error("invalid reply: {0}")]
+    Invalid(String),
+}
+
+/// What Rspamd says to do with a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// `no action`
+    Accept,
+    /// `add header` or `rewrite subject`: relay it marked as spam.
+    AddHeaders,
+    /// `greylist`
+    Greylist,
+    /// `soft reject`
+    SoftReject,
+    /// `reject`
+    Reject,
+}
+
+impl Action {
+    fn parse(action: &str) -> Option<Self> {
+        Some(match action {
+            "no action" => Self::Accept,
+            "add header" | "rewrite subject" => Self::AddHeaders,
+            "greylist" => Self::Greylist,
+            "soft reject" => Self::SoftReject,
+            "reject" => Self::Reject,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Accept => "accept",
+            Self::AddHeaders => "add_headers",
+            Self::Greylist => "greylist",
+            Self::SoftReject => "soft_reject",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+/// Rspamd's reply to a scan.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Verdict {
+    pub action: Action,
+    pub score: f64,
+    pub required_score: f64,
+    /// Fields to add to the message: Rspamd's own `milter.add_headers`,
+    /// and `X-Spam` for `add header`.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Verdict {
+    /// Parse a `/checkv2` JSON reply.
+    pub fn parse(body: &[u8]) -> Result<Self, RspamdError> {
+        let reply: Value =
+            serde_json::from_slice(body).map_err(|e| RspamdError::Invalid(e.to_string()))?;
+        let action = reply["action"]
+            .as_str()
+            .ok_or_else(|| RspamdError::Invalid("no action".to_string()))?;
+        let action = Action::parse(action)
+            .ok_or_else(|| RspamdError::Invalid(format!("unknown action {action:?}")))?;
+
+        let mut headers = Vec::new();
+        if let Some(fields) = reply["milter"]["add_headers"].as_object() {
+            for (name, value) in fields {
+                // "X-Foo": "bar" or "X-Foo": {"value": "bar", "order": 0}
+                let value = value.as_str().or_else(|| value["value"].as_str());
+                if let Some(value) = value {
+                    headers.push((name.clone(), value.to_string()));
+                }
+            }
+        }
+        if action == Action::AddHeaders && !headers.iter().any(|(name, _)| name == "X-Spam") {
+            headers.push(("X-Spam".to_string(), "Yes".to_string()));
+        }
+        Ok(Self {
+            action,
+            score: reply["score"].as_f64().unwrap_or_default(),
+            required_score: reply["required_score"].as_f64().unwrap_or_default(),
+            headers,
+        })
+    }
+}
+
+/// The envelope Rspamd is told about alongside the message.
+pub struct Envelope<'a> {
+    pub ip: IpAddr,
+    pub helo: Option<&'a str>,
+    /// Confirmed reverse DNS name of the client.
+    pub hostname: Option<&'a str>,
+    pub sender: &'a str,
+    pub recipients: &'a [&'a str],
+    /// The session ID, for matching Rspamd's history to our logs.
+    pub queue_id: &'a str,
+}
+
+#[derive(Clone)]
+pub struct Rspamd {
+    url: HttpUrl,
+    /// Sent as the `Password` header.
+    password: Option<String>,
+    /// Only for `https` URLs.
+    tls: Option<TlsConnector>,
+    timeout: Duration,
+}
+
+impl Rspamd {
+    /// Panics on an invalid `RSPAMD_URL` or `RSPAMD_CA_PATH`, like other
+    /// startup configuration errors.
+    pub fn new(url: &str, config: &Config) -> Self {
+        let mut url: HttpUrl = url.parse().unwrap_or_else(|e| panic!("RSPAMD_URL: {}", e));
+        url.path = format!("{}/checkv2", url.path.trim_end_matches('/'));
+        let tls = url.https.then(|| {
+            tls::verifying_connector(&config.rspamd_ca_path)
+                .unwrap_or_else(|e| panic!("RSPAMD_CA_PATH: {}", e))
+        });
+        Self {
+            url,
+            password: config.rspamd_password.clone(),
+            tls,
+            timeout: Duration::from_millis(config.rspamd_timeout_ms),
+        }
+    }
+
+    /// Scan `message`, giving up after `RSPAMD_TIMEOUT_MS`.
+    pub async fn check(
+        &self,
+        envelope: &Envelope<'_>,
+        message: &[u8],
+    ) -> Result<Verdict, RspamdError> {
+        let ip = envelope.ip.to_string();
+        let mut headers = vec![
+            ("IP", ip.as_str()),
+            ("From", envelope.sender),
+            ("Queue-Id", envelope.queue_id),
+        ];
+        if let Some(helo) = envelope.helo {
+            headers.push(("Helo", helo));
+        }
+        if let Some(hostname) = envelope.hostname {
+            headers.push(("Hostname", hostname));
+        }
+        if let Some(password) = &self.password {
+            headers.push(("Password", password));
+        }
+        headers.extend(envelope.recipients.iter().map(|rcpt| ("Rcpt", *rcpt)));
+        let request = Request {
+            method: "POST",
+            path: &self.url.path,
+            token: None,
+            headers: &headers,
+            body: Some(("message/rfc822", message)),
+        };
+
+        let fetch = http::fetch(&self.url, self.tls.as_ref(), &request);
+        let (status, body) = tokio::time::timeout(self.timeout, fetch)
+            .await
+            .map_err(|_| RspamdError::Timeout)??;
+        if status != 200 {
+            return Err(RspamdError::Status(status));
+        }
+        Verdict::parse(&body)
+    }
+}
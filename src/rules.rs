@@ -0,0 +1,293 @@
+//! Operator-managed content rules from `RULES_FILE`, checked against each
+//! message after DATA and reloaded when the file changes.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use regex_automata::meta::Regex;
+use tracing::{info, warn};
+
+use crate::headers::{self, HeaderInjection};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RulesError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: {reason}")]
+    Invalid { line: usize, reason: String },
+}
+
+/// What part of the message a rule looks at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// The names of all header fields.
+    HeaderName,
+    /// The values of every field with this name.
+    Header(String),
+    /// The body as sent, without decoding.
+    Body,
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    /// ASCII case-insensitive substring, kept lowercased.
+    Contains(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn is_match(&self, haystack: &[u8]) -> bool {
+        match self {
+            Self::Contains(needle) => haystack
+                .to_ascii_lowercase()
+                .windows(needle.len().max(1))
+                .any(|window| window == needle.as_bytes()),
+            Self::Regex(regex) => regex.is_match(haystack),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Refuse the message with this code and text.
+    Reject {
+        code: u16,
+        text: String,
+    },
+    /// Add to the client's score; the message is refused once the total
+    /// reaches `DNSBL_SCORE_THRESHOLD`.
+    Score(u32),
+    AddHeader {
+        name: String,
+        value: String,
+    },
+    /// Answer 250 but drop the message.
+    Discard,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// Line in the rules file, to name the rule in logs.
+    pub line: usize,
+    pub target: Target,
+    matcher: Matcher,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn matches(&self, message: &[u8]) -> bool {
+        match &self.target {
+            Target::HeaderName => headers::fields(message)
+                .iter()
+                .any(|(name, _)| self.matcher.is_match(name.as_bytes())),
+            Target::Header(wanted) => headers::fields(message)
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .any(|(_, value)| self.matcher.is_match(value.as_bytes())),
+            Target::Body => self.matcher.is_match(headers::body(message)),
+        }
+    }
+}
+
+/// The rules of a file, in order.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Parse one rule per line, with `#` comments:
+    /// `<target> <contains|regex> <pattern> <action>`, where the target is
+    /// `subject`, `header:<name>`, `header-name` or `body`, and the action
+    /// is `reject [code] [text]`, `score <n>`, `add-header <name> <value>`
+    /// or `discard`. Tokens with spaces go in double quotes.
+    pub fn parse(text: &str) -> Result<Self, RulesError> {
+        let mut rules = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let invalid = |reason: String| RulesError::Invalid {
+                line: line_no,
+                reason,
+            };
+            let tokens = tokenize(line).map_err(|e| invalid(e.to_string()))?;
+            let Some((target, rest)) = tokens.split_first() else {
+                continue;
+            };
+            let [op, pattern, action, args @ ..] = rest else {
+                return Err(invalid(
+                    "expected <target> <op> <pattern> <action>".to_string(),
+                ));
+            };
+
+            let target = match target.to_lowercase().as_str() {
+                "subject" => Target::Header("Subject".to_string()),
+                "header-name" => Target::HeaderName,
+                "body" => Target::Body,
+                other => match other.strip_prefix("header:") {
+                    Some(name) if !name.is_empty() => Target::Header(name.to_string()),
+                    _ => return Err(invalid(format!("unknown target {target:?}"))),
+                },
+            };
+            let matcher = match op.to_lowercase().as_str() {
+                "contains" => Matcher::Contains(pattern.to_ascii_lowercase()),
+                "regex" => Matcher::Regex(
+                    Regex::new(pattern).map_err(|e| invalid(format!("bad regex: {e}")))?,
+                ),
+                _ => return Err(invalid(format!("unknown match {op:?}"))),
+            };
+            let action = match (action.to_lowercase().as_str(), args) {
+                ("reject", args) if args.len() <= 2 => {
+                    let code = match args.first() {
+                        Some(code) => code
+                            .parse()
+                            .ok()
+                            .filter(|code| (400..600).contains(code))
+                            .ok_or_else(|| invalid(format!("bad reply code {code:?}")))?,
+                        None => 550,
+                    };
+                    let text = args
+                        .get(1)
+                        .cloned()
+                        .unwrap_or_else(|| "Message rejected by content rule".to_string());
+                    Action::Reject { code, text }
+                }
+                ("score", [points]) => Action::Score(
+                    points
+                        .parse()
+                        .map_err(|_| invalid(format!("bad score {points:?}")))?,
+                ),
+                ("add-header", [name, value]) => {
+                    if !HeaderInjection::new().push(name, value) {
+                        return Err(invalid(format!("bad header {name:?}")));
+                    }
+                    Action::AddHeader {
+                        name: name.clone(),
+                        value: value.clone(),
+                    }
+                }
+                ("discard", []) => Action::Discard,
+                _ => return Err(invalid(format!("bad action {action:?}"))),
+            };
+            rules.rules.push(Rule {
+                line: line_no,
+                target,
+                matcher,
+                action,
+            });
+        }
+        Ok(rules)
+    }
+
+    /// The rules that match `message`, in file order.
+    pub fn matching<'a>(&'a self, message: &'a [u8]) -> impl Iterator<Item = &'a Rule> + 'a {
+        self.rules.iter().filter(move |rule| rule.matches(message))
+    }
+}
+
+/// Split `line` at whitespace, keeping double-quoted tokens (with `\"`
+/// and `\\` escapes) whole and stopping at an unquoted `#`.
+fn tokenize(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.peek() {
+            None | Some('#') => return Ok(tokens),
+            Some('"') => {
+                chars.next();
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => token.push(chars.next().ok_or("unterminated quote")?),
+                        Some(c) => token.push(c),
+                        None => return Err("unterminated quote"),
+                    }
+                }
+                tokens.push(token);
+            }
+            Some(_) => {
+                let mut token = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+}
+
+/// What tells a changed file apart: modification time and size.
+type Stamp = Option<(SystemTime, u64)>;
+
+/// The rules of a rules file, swapped whole on reload.
+pub struct RuleSet {
+    path: PathBuf,
+    rules: RwLock<Arc<Rules>>,
+    /// The file as of the last read, successful or not.
+    stamp: Mutex<Stamp>,
+}
+
+impl RuleSet {
+    /// Read and parse the file at `path`.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, RulesError> {
+        let path = path.into();
+        let stamp = std::fs::metadata(&path).ok().and_then(|m| stamp(&m));
+        let rules = Rules::parse(&std::fs::read_to_string(&path)?)?;
+        Ok(Self {
+            path,
+            rules: RwLock::new(Arc::new(rules)),
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    pub fn rules(&self) -> Arc<Rules> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Read the file again. On error the current rules are kept.
+    pub async fn reload(&self) -> Result<(), RulesError> {
+        *self.stamp.lock().unwrap() = self.current_stamp().await;
+        let rules = Rules::parse(&tokio::fs::read_to_string(&self.path).await?)?;
+        info!(
+            path = %self.path.display(),
+            rules = rules.rules.len(),
+            "content rules reloaded"
+        );
+        *self.rules.write().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    /// Every `interval`, reload the file if its modification time or size
+    /// changed since it was last read. Runs until the runtime shuts down.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let current = self.current_stamp().await;
+            if current.is_none() || current == *self.stamp.lock().unwrap() {
+                continue;
+            }
+            if let Err(e) = self.reload().await {
+                warn!(
+                    path = %self.path.display(),
+                    error = %e,
+                    "[RULES-ERROR] content rules not reloaded, keeping previous rules"
+                );
+            }
+        }
+    }
+
+    async fn current_stamp(&self) -> Stamp {
+        tokio::fs::metadata(&self.path)
+            .await
+            .ok()
+            .and_then(|m| stamp(&m))
+    }
+}
+
+fn stamp(metadata: &std::fs::Metadata) -> Stamp {
+    Some((metadata.modified().ok()?, metadata.len()))
+}
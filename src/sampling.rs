@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+
+/// Most distinct (event, IP) pairs tracked per window. Beyond this, new
+/// pairs are suppressed and only counted in aggregate.
+const MAX_KEYS: usize = 10_000;
+
+static SAMPLER: OnceLock<LogSampler> = OnceLock::new();
+
+/// Suppressed occurrences of one event from one client in the last window.
+#[derive(Debug, PartialEq)]
+pub struct Suppressed {
+    pub event: &'static str,
+    pub ip: IpAddr,
+    pub count: u64,
+}
+
+/// Rate limiter for repetitive log lines.
+///
+/// Within each window the first `burst` occurrences of an event from a
+/// client IP are logged; the rest are only counted, and reported as one
+/// aggregate line per event and IP when the window closes.
+pub struct LogSampler {
+    burst: u32,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    seen: HashMap<(&'static str, IpAddr), u64>,
+    /// Occurrences dropped because `seen` was full.
+    overflow: u64,
+}
+
+impl LogSampler {
+    pub fn new(burst: u32) -> Self {
+        Self {
+            burst,
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    /// Record an occurrence and report whether it should be logged.
+    pub fn allow(&self, event: &'static str, ip: IpAddr) -> bool {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if counts.seen.len() >= MAX_KEYS && !counts.seen.contains_key(&(event, ip)) {
+            counts.overflow += 1;
+            return false;
+        }
+        let seen = counts.seen.entry((event, ip)).or_insert(0);
+        *seen += 1;
+        *seen <= u64::from(self.burst)
+    }
+
+    /// Close the current window: return what was suppressed (plus the
+    /// overflow count) and start counting from zero.
+    pub fn drain(&self) -> (Vec<Suppressed>, u64) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let burst = u64::from(self.burst);
+        let suppressed = counts
+            .seen
+            .drain()
+            .filter(|(_, seen)| *seen > burst)
+            .map(|((event, ip), seen)| Suppressed {
+                event,
+                ip,
+                count: seen - burst,
+            })
+            .collect();
+        (suppressed, std::mem::take(&mut counts.overflow))
+    }
+}
+
+/// Install the process-wide sampler. Returns `None` if one is already set.
+pub fn install(sampler: LogSampler) -> Option<&'static LogSampler> {
+    SAMPLER.set(sampler).ok()?;
+    SAMPLER.get()
+}
+
+/// Whether to log this occurrence of a hot-path event. Always true until a
+/// sampler is installed.
+pub fn sampled(event: &'static str, ip: IpAddr) -> bool {
+    SAMPLER.get().is_none_or(|sampler| sampler.allow(event, ip))
+}
@@ -0,0 +1,159 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::tls;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SelfTestError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{step}: unexpected reply: {reply}")]
+    Unexpected { step: &'static str, reply: String },
+}
+
+/// Address the self-test connects to for a listener bound to `listen`.
+///
+/// A wildcard bind (`0.0.0.0`, `::`) is reached through the loopback address
+/// of the same family.
+pub fn loopback_target(listen: SocketAddr) -> SocketAddr {
+    let ip = match listen.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, listen.port())
+}
+
+/// PROXY v1 header describing a direct connection from `local` to `peer`.
+pub fn proxy_v1_header(local: SocketAddr, peer: SocketAddr) -> String {
+    let family = if local.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        local.ip(),
+        peer.ip(),
+        local.port(),
+        peer.port()
+    )
+}
+
+/// Run one SMTP transaction against our own listener.
+///
+/// Connects to `target`, upgrades with STARTTLS when `use_tls` is set, and
+/// delivers a short message to `recipient`, which the session accepts without
+/// a mailbox and discards after probing the backend. With `send_proxy` set,
+/// a PROXY v1 header is sent first so the listener accepts the connection
+/// when the PROXY protocol is required. With `lmtp` set, the session greets
+/// with LHLO instead of EHLO.
+pub async fn run(
+    target: SocketAddr,
+    recipient: &str,
+    use_tls: bool,
+    send_proxy: bool,
+    lmtp: bool,
+) -> Result<(), SelfTestError> {
+    let (hello, step) = if lmtp {
+        ("LHLO selftest", "LHLO")
+    } else {
+        ("EHLO selftest", "EHLO")
+    };
+    let mut stream = TcpStream::connect(target).await?;
+    if send_proxy {
+        let header = proxy_v1_header(stream.local_addr()?, target);
+        stream.write_all(header.as_bytes()).await?;
+    }
+
+    let mut reader = BufReader::new(stream);
+    expect(&mut reader, "banner", 220).await?;
+    let caps = command(&mut reader, hello, step, 250).await?;
+
+    if !use_tls {
+        return transaction(&mut reader, recipient).await;
+    }
+
+    if !caps.iter().any(|line| line.get(4..) == Some("STARTTLS")) {
+        return Err(SelfTestError::Unexpected {
+            step,
+            reply: "STARTTLS not advertised".to_string(),
+        });
+    }
+    command(&mut reader, "STARTTLS", "STARTTLS", 220).await?;
+    let server_name = ServerName::IpAddress(target.ip().into());
+    let tls_stream = tls::loopback_connector()
+        .connect(server_name, reader.into_inner())
+        .await?;
+    debug!("self-test TLS handshake completed");
+
+    let mut reader = BufReader::new(tls_stream);
+    command(&mut reader, hello, step, 250).await?;
+    transaction(&mut reader, recipient).await
+}
+
+/// MAIL, RCPT, DATA and QUIT on an established session.
+async fn transaction<S: AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<S>,
+    recipient: &str,
+) -> Result<(), SelfTestError> {
+    command(reader, "MAIL FROM:<>", "MAIL", 250).await?;
+    command(reader, &format!("RCPT TO:<{}>", recipient), "RCPT", 250).await?;
+    command(reader, "DATA", "DATA", 354).await?;
+    command(
+        reader,
+        "Subject: burngate self-test\r\n\r\nself-test\r\n.",
+        "message",
+        250,
+    )
+    .await?;
+    command(reader, "QUIT", "QUIT", 221).await?;
+    Ok(())
+}
+
+/// Send a command line and expect a reply with `code`.
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<S>,
+    line: &str,
+    step: &'static str,
+    code: u16,
+) -> Result<Vec<String>, SelfTestError> {
+    let writer = reader.get_mut();
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await?;
+    expect(reader, step, code).await
+}
+
+/// Read a (possibly multi-line) reply and check its code.
+async fn expect<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    step: &'static str,
+    code: u16,
+) -> Result<Vec<String>, SelfTestError> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(SelfTestError::Unexpected {
+                step,
+                reply: "connection closed".to_string(),
+            });
+        }
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            break;
+        }
+    }
+    let got: u16 = lines[0].get(..3).and_then(|c| c.parse().ok()).unwrap_or(0);
+    if got != code {
+        return Err(SelfTestError::Unexpected {
+            step,
+            reply: lines.join(" | "),
+        });
+    }
+    Ok(lines)
+}
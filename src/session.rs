@@ -1,15 +1,49 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher, RandomState};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use arrayvec::ArrayString;
-use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
-use crate::config::Config;
-use crate::lookup::MailboxLookup;
-use crate::relay;
+use crate::address::{self, AddressList};
+use crate::audit::{
+    unix_now, AuditEntry, AuditLog, AuditTransaction, ByteCounters, CountingStream,
+    MAX_TRANSACTIONS,
+};
+use crate::autoblock::AutoBlocklist;
+use crate::clamav::{ClamAv, Scan};
+use crate::config::{
+    Config, DnsblAction, DnsblCheckAt, HeloCheck, LookupErrorPolicy, RateLimitBy, RdnsCheck,
+};
+use crate::deadletter::{DeadLetter, DeadMessage};
+use crate::dns::{MailDomain, Rdns, Resolver};
+use crate::dnsbl::{Dnsbl, DnsblVerdict};
+use crate::esmtp::{self, EsmtpParams, MailParams, ParamError, RcptParams};
+use crate::flags::FeatureFlags;
+use crate::geoip::Location;
+use crate::headers::{self, HeaderInjection, Received};
+use crate::helo;
+use crate::lookup::{AliasError, LookupBackend, LookupOutcome, SharedLookup};
+use crate::mirror::{Mirror, MirrorMessage};
+use crate::network;
+use crate::ratelimit::StoreRateLimiter;
+use crate::rejectlog;
+use crate::relay::{self, Backend, BackendSession, ClientInfo};
+use crate::reply::{self, EnhancedStatus, SmtpReply};
+use crate::reputation::{Event, Reputation};
+use crate::rspamd::{Action, Envelope, Rspamd};
+use crate::rules::{self, RuleSet};
+use crate::sampling;
+use crate::spamd::Spamd;
+use crate::spamtrap::Spamtraps;
+use crate::spool::Spool;
+use crate::stats::DeliveryStats;
 use crate::tls::TlsConfig;
+use crate::webhook::{DeliveryEvent, Webhook};
 
 /// Global counters for monitoring.
 pub struct Metrics {
@@ -17,6 +51,89 @@ pub struct Metrics {
     pub rejected: AtomicU64,
     pub connections: AtomicU64,
     pub relay_errors: AtomicU64,
+    /// Clients disconnected for sending before the greeting.
+    pub early_talker_rejected: AtomicU64,
+    /// Clients turned away because no connection slot was free.
+    pub pool_exhausted: AtomicU64,
+    /// Mirror copies that failed or were dropped.
+    pub mirror_errors: AtomicU64,
+    /// Rejections skipped because `SHADOW_MODE` is on.
+    pub shadow_rejected: AtomicU64,
+    /// Messages written to the spool because the backend was unreachable.
+    pub spooled: AtomicU64,
+    /// Delivery events that could not be posted to the webhook.
+    pub webhook_errors: AtomicU64,
+    /// Messages the backend refused that were kept in the dead-letter sink.
+    pub dead_lettered: AtomicU64,
+    /// Mailbox lookups answered from the in-process cache.
+    pub lookup_cache_hits: AtomicU64,
+    /// Mailbox lookups that went to the lookup backend past the cache.
+    pub lookup_cache_misses: AtomicU64,
+    /// Recipients whose mailbox lookup failed, whatever the policy.
+    pub lookup_errors: AtomicU64,
+    /// Recipients accepted despite a failed lookup (`LOOKUP_ERROR_POLICY=accept`).
+    pub lookup_fail_open: AtomicU64,
+    /// Recipients rejected because the Bloom filter has never seen them.
+    pub bloom_rejected: AtomicU64,
+    /// Clients refused because DNS blocklists have them.
+    pub dnsbl_rejected: AtomicU64,
+    /// Clients refused for missing or unconfirmed reverse DNS.
+    pub rdns_rejected: AtomicU64,
+    /// Greetings refused by `HELO_CHECKS`.
+    pub helo_rejected: AtomicU64,
+    /// MAIL commands refused by `SENDER_DOMAIN_CHECK`.
+    pub sender_domain_rejected: AtomicU64,
+    /// MAIL commands refused by `SENDER_RATE_LIMIT`.
+    pub sender_rate_limited: AtomicU64,
+    /// Recipients refused by `RECIPIENT_RATE_LIMIT_PER_*`.
+    pub recipient_rate_limited: AtomicU64,
+    /// Connections refused because a `block` network has the client.
+    pub network_blocked: AtomicU64,
+    /// IPs added to the temporary blocklist (`AUTO_BLOCK_THRESHOLD`).
+    pub auto_blocked: AtomicU64,
+    /// IPs this instance blocked whose block has since lapsed or been lifted.
+    pub auto_unblocked: AtomicU64,
+    /// Connections refused because the client IP is temporarily blocked.
+    pub auto_block_refused: AtomicU64,
+    /// Messages Rspamd said to reject.
+    pub rspamd_rejected: AtomicU64,
+    /// Messages Rspamd said to greylist or soft-reject.
+    pub rspamd_deferred: AtomicU64,
+    /// Messages relayed with Rspamd's spam headers.
+    pub rspamd_tagged: AtomicU64,
+    /// Scans that failed or timed out; the message was let in.
+    pub rspamd_errors: AtomicU64,
+    /// Messages refused for reaching the spamd score threshold.
+    pub spamd_rejected: AtomicU64,
+    /// spamd scans that failed or timed out; the message was let in.
+    pub spamd_errors: AtomicU64,
+    /// Messages refused because ClamAV found a virus.
+    pub viruses_detected: AtomicU64,
+    /// ClamAV scans that failed or timed out.
+    pub clamav_errors: AtomicU64,
+    /// Messages refused by a content rule or by the score rules added.
+    pub rules_rejected: AtomicU64,
+    /// Messages accepted and dropped by a `discard` rule.
+    pub rules_discarded: AtomicU64,
+    /// Recipients that were spamtrap addresses.
+    pub spamtrap_hits: AtomicU64,
+    /// Connections refused for their country (`GEOIP_POLICY`).
+    pub geoip_blocked: AtomicU64,
+    /// `BAN_HOOK_COMMAND` runs and `BAN_HOOK_URL` POSTs that failed or
+    /// timed out.
+    pub ban_hook_errors: AtomicU64,
+    /// Connections refused at `REPUTATION_REJECT_SCORE`.
+    pub reputation_rejected: AtomicU64,
+    /// Recipients deferred at `REPUTATION_GREYLIST_SCORE`.
+    pub reputation_greylisted: AtomicU64,
+    /// Sessions slowed down at `REPUTATION_TARPIT_SCORE`.
+    pub reputation_tarpitted: AtomicU64,
+    /// Redis commands in a row that found no node reachable (0 = healthy).
+    pub redis_consecutive_errors: AtomicU64,
+    /// Attempts to reach Redis again after a backoff.
+    pub redis_reconnects: AtomicU64,
+    /// Unix time of the last Redis reply, 0 if none yet.
+    pub redis_last_success: AtomicU64,
 }
 
 impl Default for Metrics {
@@ -26,6 +143,47 @@ impl Default for Metrics {
             rejected: AtomicU64::new(0),
             connections: AtomicU64::new(0),
             relay_errors: AtomicU64::new(0),
+            early_talker_rejected: AtomicU64::new(0),
+            pool_exhausted: AtomicU64::new(0),
+            mirror_errors: AtomicU64::new(0),
+            shadow_rejected: AtomicU64::new(0),
+            spooled: AtomicU64::new(0),
+            webhook_errors: AtomicU64::new(0),
+            dead_lettered: AtomicU64::new(0),
+            lookup_cache_hits: AtomicU64::new(0),
+            lookup_cache_misses: AtomicU64::new(0),
+            lookup_errors: AtomicU64::new(0),
+            lookup_fail_open: AtomicU64::new(0),
+            bloom_rejected: AtomicU64::new(0),
+            dnsbl_rejected: AtomicU64::new(0),
+            rdns_rejected: AtomicU64::new(0),
+            helo_rejected: AtomicU64::new(0),
+            sender_domain_rejected: AtomicU64::new(0),
+            sender_rate_limited: AtomicU64::new(0),
+            recipient_rate_limited: AtomicU64::new(0),
+            network_blocked: AtomicU64::new(0),
+            auto_blocked: AtomicU64::new(0),
+            auto_unblocked: AtomicU64::new(0),
+            auto_block_refused: AtomicU64::new(0),
+            rspamd_rejected: AtomicU64::new(0),
+            rspamd_deferred: AtomicU64::new(0),
+            rspamd_tagged: AtomicU64::new(0),
+            rspamd_errors: AtomicU64::new(0),
+            spamd_rejected: AtomicU64::new(0),
+            spamd_errors: AtomicU64::new(0),
+            viruses_detected: AtomicU64::new(0),
+            clamav_errors: AtomicU64::new(0),
+            rules_rejected: AtomicU64::new(0),
+            rules_discarded: AtomicU64::new(0),
+            spamtrap_hits: AtomicU64::new(0),
+            geoip_blocked: AtomicU64::new(0),
+            ban_hook_errors: AtomicU64::new(0),
+            reputation_rejected: AtomicU64::new(0),
+            reputation_greylisted: AtomicU64::new(0),
+            reputation_tarpitted: AtomicU64::new(0),
+            redis_consecutive_errors: AtomicU64::new(0),
+            redis_reconnects: AtomicU64::new(0),
+            redis_last_success: AtomicU64::new(0),
         }
     }
 }
@@ -36,27 +194,189 @@ impl Metrics {
     }
 }
 
+/// Position in the SMTP command sequence (RFC 5321 §4.1.4).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// No EHLO/HELO yet, or STARTTLS just completed.
+    Connected,
+    /// Greeted, no mail transaction open.
+    Greeted,
+    /// MAIL FROM accepted, no recipients yet.
+    Mail,
+    /// At least one RCPT TO accepted; DATA is allowed.
+    Rcpt,
+}
+
+/// Verbs tallied individually in the audit summary.
+const KNOWN_COMMANDS: [&str; 12] = [
+    "EHLO", "HELO", "LHLO", "MAIL", "RCPT", "DATA", "RSET", "NOOP", "QUIT", "VRFY", "HELP",
+    "STARTTLS",
+];
+
+/// Sessions started by this process, for session IDs.
+static SESSION_SEQ: AtomicU64 = AtomicU64::new(0);
+
 /// Shared SMTP session state (preserved across TLS upgrade).
 struct SessionState {
+    /// Session ID for the Received header and the `smtp.session` span.
+    id: String,
+    phase: Phase,
     sender: Option<String>,
-    recipients: HashSet<String>,
-    ehlo_received: bool,
-    /// Running count of RCPT TO commands in this session (not reset per transaction).
+    /// SIZE declared on MAIL FROM, used to presize the DATA buffer.
+    declared_size: Option<usize>,
+    /// ESMTP parameters from MAIL FROM, forwarded to the backend where supported.
+    mail_params: EsmtpParams,
+    /// Accepted recipients with their RCPT TO parameters.
+    recipients: HashMap<String, EsmtpParams>,
+    /// RCPT TO commands in this session, across transactions.
     recipient_count: usize,
+    /// RCPT TO commands in the open transaction.
+    txn_recipient_count: usize,
+    /// A recipient of the open transaction is a spamtrap; the message is
+    /// dropped.
+    spamtrap: bool,
+    /// Session-wide verdict counters for the audit trail.
+    rcpt_accepted: u32,
+    rcpt_rejected: u32,
+    messages_relayed: u32,
+    /// Messages accepted with a final 2xx reply in this session.
+    messages: u32,
+    tls: bool,
+    /// Negotiated TLS version and cipher suite.
+    tls_cipher: Option<(String, String)>,
+    /// Reverse DNS of the client, looked up at connect with `RDNS_CHECK`,
+    /// otherwise with the first message.
+    rdns: Option<Rdns>,
+    /// `RDNS_ACTION` score of a client failing `RDNS_CHECK`, added to the
+    /// DNSBL score.
+    rdns_score: u32,
+    /// `HELO_CHECKS` score of the last greeting name, likewise.
+    helo_score: u32,
+    /// Country of the client from `GEOIP_DATABASE`, with its
+    /// `GEOIP_POLICY` score, likewise added.
+    location: Option<Location>,
+    /// `REPUTATION_ENABLED` score of the client at connect.
+    reputation_score: f64,
+    /// The client is in an `allow` network of `NETWORK_LIST_FILE`: no rate
+    /// limits or DNSBL checks.
+    allowed_network: bool,
+    /// What `DNSBL_LISTS` say about the client, once checked.
+    dnsbl: Option<Arc<DnsblVerdict>>,
+    /// Reply to every MAIL once the lists rejected the client.
+    dnsbl_reply: Option<SmtpReply>,
+    /// Name from the last EHLO/HELO/LHLO.
+    helo: Option<String>,
+    /// RFC 3848 protocol of the last greeting: SMTP, ESMTP or LMTP.
+    protocol: &'static str,
+    /// Error replies sent so far (bad syntax, bad sequence, rejected RCPTs).
+    errors: u32,
+    /// The session ended on the idle, DATA or TLS handshake timeout.
+    timed_out: bool,
+    /// Recipients accepted in the open transaction in RCPT order, counting
+    /// repeats. LMTP answers DATA once for each.
+    txn_accepted: Vec<String>,
+    /// Recipients rejected in the open transaction.
+    txn_rejected: u32,
+    /// Commands received, by verb, for the audit summary.
+    commands: BTreeMap<&'static str, u32>,
+    /// Finished transactions for the audit summary.
+    transactions: Vec<AuditTransaction>,
+    /// Backend session kept open for this client's next message.
+    backend_session: BackendSession,
 }
 
 impl SessionState {
     fn new() -> Self {
+        let seq = SESSION_SEQ.fetch_add(1, Ordering::Relaxed);
         Self {
+            id: format!("{:X}{:05X}", unix_now(), seq & 0xF_FFFF),
+            phase: Phase::Connected,
             sender: None,
-            recipients: HashSet::new(),
-            ehlo_received: false,
+            declared_size: None,
+            mail_params: EsmtpParams::default(),
+            recipients: HashMap::new(),
             recipient_count: 0,
+            txn_recipient_count: 0,
+            spamtrap: false,
+            rcpt_accepted: 0,
+            rcpt_rejected: 0,
+            messages_relayed: 0,
+            messages: 0,
+            tls: false,
+            tls_cipher: None,
+            allowed_network: false,
+            rdns: None,
+            rdns_score: 0,
+            helo_score: 0,
+            location: None,
+            reputation_score: 0.0,
+            dnsbl: None,
+            dnsbl_reply: None,
+            helo: None,
+            protocol: "SMTP",
+            errors: 0,
+            timed_out: false,
+            txn_accepted: Vec::new(),
+            txn_rejected: 0,
+            commands: BTreeMap::new(),
+            transactions: Vec::new(),
+            backend_session: BackendSession::default(),
         }
     }
 
+    fn count_command(&mut self, command: &str) {
+        let verb = KNOWN_COMMANDS
+            .iter()
+            .find(|&&known| known == command)
+            .copied()
+            .unwrap_or("UNKNOWN");
+        *self.commands.entry(verb).or_insert(0) += 1;
+    }
+
+    fn reject_rcpt(&mut self) {
+        self.rcpt_rejected += 1;
+        self.txn_rejected += 1;
+    }
+
+    /// The DNSBL, rDNS, HELO and country scores together, held to
+    /// `DNSBL_SCORE_THRESHOLD`.
+    fn score(&self) -> u32 {
+        let dnsbl = self.dnsbl.as_ref().map_or(0, |verdict| verdict.score);
+        let country = self.location.as_ref().map_or(0, |location| location.score);
+        dnsbl + self.rdns_score + self.helo_score + country
+    }
+
+    /// Abort any open mail transaction; the greeting is kept.
     fn reset_transaction(&mut self) {
+        self.finish_transaction("aborted", None);
+    }
+
+    /// Record the open mail transaction (if any) with its outcome, then
+    /// reset to the greeted state.
+    fn finish_transaction(&mut self, outcome: &'static str, size: Option<usize>) {
+        if matches!(self.phase, Phase::Mail | Phase::Rcpt)
+            && self.transactions.len() < MAX_TRANSACTIONS
+        {
+            let mut recipients: Vec<String> = self.recipients.keys().cloned().collect();
+            recipients.sort();
+            self.transactions.push(AuditTransaction {
+                sender: self.sender.clone().unwrap_or_default(),
+                recipients,
+                rcpt_rejected: self.txn_rejected,
+                size,
+                outcome,
+            });
+        }
+        if self.phase != Phase::Connected {
+            self.phase = Phase::Greeted;
+        }
+        self.txn_accepted.clear();
+        self.txn_rejected = 0;
+        self.txn_recipient_count = 0;
+        self.spamtrap = false;
         self.sender = None;
+        self.declared_size = None;
+        self.mail_params = EsmtpParams::default();
         self.recipients.clear();
     }
 }
@@ -65,102 +385,368 @@ impl SessionState {
 struct SmtpContext<'a> {
     peer_addr: std::net::SocketAddr,
     config: &'a Config,
-    lookup: &'a MailboxLookup,
+    lookup: &'a dyn LookupBackend,
     tls_config: &'a Option<TlsConfig>,
     metrics: &'a Metrics,
+    backend: &'a Backend,
+    mirror: Option<&'a Mirror>,
+    spool: Option<&'a Spool>,
+    webhook: Option<&'a Webhook>,
+    dead_letter: Option<&'a DeadLetter>,
+    stats: Option<&'a DeliveryStats>,
+    resolver: &'a Resolver,
+    dnsbl: Option<&'a Dnsbl>,
+    sender_limiter: Option<&'a StoreRateLimiter>,
+    /// `RECIPIENT_RATE_LIMIT_PER_*` limiters, checked in order.
+    recipient_limiters: &'a [StoreRateLimiter],
+    auto_block: Option<&'a AutoBlocklist>,
+    rspamd: Option<&'a Rspamd>,
+    spamd: Option<&'a Spamd>,
+    clamav: Option<&'a ClamAv>,
+    rules: Option<&'a RuleSet>,
+    spamtraps: Option<&'a Spamtraps>,
+    reputation: Option<&'a Reputation>,
     tls_active: bool,
+    /// Reject bare CR/LF and accept only `<CRLF>.<CRLF>` to end DATA.
+    strict_crlf: bool,
+    /// Admitted to a reserved slot on condition of upgrading to TLS; MAIL is
+    /// refused until STARTTLS completes.
+    require_tls: bool,
 }
 
 /// Handle a single SMTP session.
-#[tracing::instrument(skip_all, fields(peer = %peer_addr))]
+///
+/// The `smtp.*`, `lookup.*` and `relay.*` span fields start empty and are
+/// filled in as the session progresses, so an exported trace carries the
+/// verdict and rejection reason without cross-referencing logs.
+#[tracing::instrument(
+    name = "smtp.session",
+    skip_all,
+    fields(
+        peer = %peer_addr,
+        smtp.session_id = tracing::field::Empty,
+        smtp.verdict = tracing::field::Empty,
+        smtp.reason = tracing::field::Empty,
+        smtp.rcpt_domain = tracing::field::Empty,
+        smtp.rdns = tracing::field::Empty,
+        smtp.country = tracing::field::Empty,
+        lookup.result = tracing::field::Empty,
+        lookup.cache = tracing::field::Empty,
+        relay.outcome = tracing::field::Empty,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_session(
     stream: tokio::net::TcpStream,
     peer_addr: std::net::SocketAddr,
     config: Arc<Config>,
-    lookup: MailboxLookup,
+    lookup: SharedLookup,
     tls_config: Option<TlsConfig>,
     metrics: Arc<Metrics>,
+    audit: Option<AuditLog>,
+    backend: Backend,
+    mirror: Option<Mirror>,
+    spool: Option<Spool>,
+    webhook: Option<Webhook>,
+    dead_letter: Option<DeadLetter>,
+    stats: Option<DeliveryStats>,
+    resolver: Resolver,
+    dnsbl: Option<Dnsbl>,
+    sender_limiter: Option<StoreRateLimiter>,
+    recipient_limiters: Vec<StoreRateLimiter>,
+    auto_block: Option<AutoBlocklist>,
+    rspamd: Option<Rspamd>,
+    spamd: Option<Spamd>,
+    clamav: Option<ClamAv>,
+    rules: Option<Arc<RuleSet>>,
+    spamtraps: Option<Spamtraps>,
+    reputation: Option<Reputation>,
+    flags: FeatureFlags,
+    require_tls: bool,
+    allowed_network: bool,
+    location: Option<Location>,
+    reputation_score: f64,
 ) {
     metrics.connections.fetch_add(1, Ordering::Relaxed);
-    info!(peer = %peer_addr, "new connection");
-
-    let timeout = tokio::time::Duration::from_secs(config.connection_timeout_secs);
-
-    let result = tokio::time::timeout(timeout, async {
-        run_session(
-            stream,
-            peer_addr,
-            config,
-            lookup,
-            tls_config,
-            metrics.clone(),
-        )
-        .await
-    })
+    let country = location.as_ref().map(|location| location.country.as_str());
+    info!(peer = %peer_addr, country = country.unwrap_or_default(), "new connection");
+
+    let started_at = unix_now();
+    let started = Instant::now();
+    let counters = Arc::new(ByteCounters::default());
+    let stream = CountingStream::new(stream, counters.clone());
+    let mut state = SessionState::new();
+    state.allowed_network = allowed_network;
+    tracing::Span::current().record("smtp.session_id", state.id.as_str());
+    if let Some(country) = country {
+        tracing::Span::current().record("smtp.country", country);
+    }
+    state.location = location;
+    state.reputation_score = reputation_score;
+    let strict_crlf =
+        config.strict_crlf || flags.is_enabled("strict_crlf", &peer_addr.ip().to_string(), None);
+
+    let result = run_session(
+        stream,
+        &mut state,
+        peer_addr,
+        config,
+        lookup,
+        tls_config,
+        metrics.clone(),
+        backend.clone(),
+        mirror,
+        spool,
+        webhook,
+        dead_letter,
+        stats,
+        resolver,
+        dnsbl,
+        sender_limiter,
+        recipient_limiters,
+        auto_block,
+        rspamd,
+        spamd,
+        clamav,
+        rules,
+        spamtraps,
+        reputation,
+        strict_crlf,
+        require_tls,
+    )
     .await;
 
-    match result {
-        Ok(Ok(())) => debug!(peer = %peer_addr, "session completed"),
-        Ok(Err(e)) => debug!(peer = %peer_addr, error = %e, "session error"),
-        Err(_) => debug!(peer = %peer_addr, "session timed out"),
+    backend
+        .release(std::mem::take(&mut state.backend_session))
+        .await;
+
+    // Anything still open was abandoned by the client
+    let abandoned = if state.timed_out {
+        "timeout"
+    } else {
+        "aborted"
+    };
+    state.finish_transaction(abandoned, None);
+
+    let outcome = match result {
+        _ if state.timed_out => "timeout",
+        Ok(()) => {
+            debug!(peer = %peer_addr, "session completed");
+            "completed"
+        }
+        Err(e) => {
+            debug!(peer = %peer_addr, error = %e, "session error");
+            "error"
+        }
+    };
+
+    if let Some(audit) = audit {
+        let entry = AuditEntry {
+            peer: peer_addr,
+            started_at,
+            duration_ms: started.elapsed().as_millis() as u64,
+            bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+            bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+            tls: state.tls,
+            rcpt_accepted: state.rcpt_accepted,
+            rcpt_rejected: state.rcpt_rejected,
+            messages_relayed: state.messages_relayed,
+            outcome,
+            commands: state.commands,
+            transactions: state.transactions,
+        };
+        audit.record(&entry).await;
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_session(
-    stream: tokio::net::TcpStream,
+    stream: CountingStream<tokio::net::TcpStream>,
+    state: &mut SessionState,
     peer_addr: std::net::SocketAddr,
     config: Arc<Config>,
-    lookup: MailboxLookup,
+    lookup: SharedLookup,
     tls_config: Option<TlsConfig>,
     metrics: Arc<Metrics>,
+    backend: Backend,
+    mirror: Option<Mirror>,
+    spool: Option<Spool>,
+    webhook: Option<Webhook>,
+    dead_letter: Option<DeadLetter>,
+    stats: Option<DeliveryStats>,
+    resolver: Resolver,
+    dnsbl: Option<Dnsbl>,
+    sender_limiter: Option<StoreRateLimiter>,
+    recipient_limiters: Vec<StoreRateLimiter>,
+    auto_block: Option<AutoBlocklist>,
+    rspamd: Option<Rspamd>,
+    spamd: Option<Spamd>,
+    clamav: Option<ClamAv>,
+    rules: Option<Arc<RuleSet>>,
+    spamtraps: Option<Spamtraps>,
+    reputation: Option<Reputation>,
+    strict_crlf: bool,
+    require_tls: bool,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut reader = BufReader::new(stream);
-    let mut state = SessionState::new();
+    // Replies are buffered in the BufWriter and flushed once the pipelined
+    // command group in the BufReader has been processed (RFC 2920).
+    let mut reader = BufReader::new(BufWriter::new(stream));
+
+    // Hold the banner back; bots that talk before it are dropped
+    if let Some(delay) = greet_delay(&config, peer_addr.ip()) {
+        if talks_early(&mut reader, delay).await?
+            && !shadow_pass(&config, &metrics, peer_addr, "early_talker")
+        {
+            if sampling::sampled("early_talker", peer_addr.ip()) {
+                info!(peer = %peer_addr, "[CONN-REJECTED] client sent data before greeting");
+            }
+            metrics
+                .early_talker_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            if let Some(reputation) = reputation.as_ref().filter(|_| !state.allowed_network) {
+                reputation.record(peer_addr.ip(), Event::EarlyTalker).await;
+            }
+            record_rejection(peer_addr.ip(), "early_talker");
+            send_reply(reader.get_mut(), &reply::EARLY_TALKER).await?;
+            return Ok(());
+        }
+    }
+
+    if config.rdns_check != RdnsCheck::Off {
+        if let Some(reply) = rdns_rejection(state, peer_addr, &config, &metrics, &resolver).await {
+            send_reply(reader.get_mut(), &reply).await?;
+            return Ok(());
+        }
+    }
+
+    if config.dnsbl_check_at == DnsblCheckAt::Connect {
+        if let Some(reply) =
+            dnsbl_rejection(state, peer_addr, &config, &metrics, dnsbl.as_ref(), 554).await
+        {
+            send_reply(reader.get_mut(), &reply).await?;
+            return Ok(());
+        }
+    }
+
+    // Slow down clients with a poor reputation
+    if let Some(delay) = reputation
+        .as_ref()
+        .and_then(|reputation| reputation.tarpit(state.reputation_score))
+    {
+        metrics.reputation_tarpitted.fetch_add(1, Ordering::Relaxed);
+        if sampling::sampled("tarpit", peer_addr.ip()) {
+            info!(
+                peer = %peer_addr,
+                score = state.reputation_score,
+                "[REPUTATION-TARPIT] slowing down client"
+            );
+        }
+        tokio::time::sleep(delay).await;
+    }
 
     // Send banner
-    send_line(
-        reader.get_mut(),
-        &format!("220 {} ESMTP burngate", config.server_name),
-    )
-    .await?;
+    let banner = format!(
+        "{} {} burngate",
+        config.server_name,
+        if config.lmtp { "LMTP" } else { "ESMTP" }
+    );
+    send_reply(reader.get_mut(), &SmtpReply::formatted(220, None, banner)).await?;
 
     // Run SMTP loop on plain connection
     let ctx = SmtpContext {
         peer_addr,
         config: &config,
-        lookup: &lookup,
+        lookup: lookup.as_ref(),
         tls_config: &tls_config,
         metrics: &metrics,
+        backend: &backend,
+        mirror: mirror.as_ref(),
+        spool: spool.as_ref(),
+        webhook: webhook.as_ref(),
+        dead_letter: dead_letter.as_ref(),
+        stats: stats.as_ref(),
+        resolver: &resolver,
+        dnsbl: dnsbl.as_ref(),
+        sender_limiter: sender_limiter.as_ref(),
+        recipient_limiters: &recipient_limiters,
+        auto_block: auto_block.as_ref(),
+        rspamd: rspamd.as_ref(),
+        spamd: spamd.as_ref(),
+        clamav: clamav.as_ref(),
+        rules: rules.as_deref(),
+        spamtraps: spamtraps.as_ref(),
+        reputation: reputation.as_ref(),
         tls_active: false,
+        strict_crlf,
+        require_tls,
     };
-    let result = smtp_loop(&mut reader, &mut state, &ctx).await;
+    let result = smtp_loop(&mut reader, state, &ctx).await;
 
     match result {
         LoopResult::Done(r) => r,
         LoopResult::StartTls => {
             let tls_cfg = tls_config.as_ref().unwrap();
 
-            // Recover the raw TcpStream for TLS handshake
-            let tcp_stream = reader.into_inner();
-            let tls_stream = tls_cfg.accept(tcp_stream).await?;
+            // Recover the raw TcpStream for TLS handshake. Any commands
+            // pipelined after STARTTLS are discarded with the read buffer.
+            let tcp_stream = reader.into_inner().into_inner();
+            let handshake_timeout = Duration::from_secs(config.idle_timeout_secs);
+            let Ok(tls_stream) =
+                tokio::time::timeout(handshake_timeout, tls_cfg.accept(tcp_stream)).await
+            else {
+                debug!(peer = %peer_addr, "STARTTLS handshake timed out");
+                state.timed_out = true;
+                return Ok(());
+            };
+            let tls_stream = tls_stream?;
             info!(peer = %peer_addr, "STARTTLS handshake completed");
+            state.tls = true;
+            let (_, tls_conn) = tls_stream.get_ref();
+            if let (Some(version), Some(suite)) = (
+                tls_conn.protocol_version(),
+                tls_conn.negotiated_cipher_suite(),
+            ) {
+                state.tls_cipher = Some((
+                    version.as_str().unwrap_or("TLS").replace('_', "."),
+                    suite.suite().as_str().unwrap_or("unknown").to_string(),
+                ));
+            }
 
             // Reset EHLO state per RFC 3207 — client must re-EHLO after STARTTLS
-            state.ehlo_received = false;
             state.reset_transaction();
+            state.phase = Phase::Connected;
 
-            let mut tls_reader = BufReader::new(tls_stream);
+            let mut tls_reader = BufReader::new(BufWriter::new(tls_stream));
 
             // Continue SMTP on the TLS connection
             let ctx = SmtpContext {
                 peer_addr,
                 config: &config,
-                lookup: &lookup,
+                lookup: lookup.as_ref(),
                 tls_config: &tls_config,
                 metrics: &metrics,
+                backend: &backend,
+                mirror: mirror.as_ref(),
+                spool: spool.as_ref(),
+                webhook: webhook.as_ref(),
+                dead_letter: dead_letter.as_ref(),
+                stats: stats.as_ref(),
+                resolver: &resolver,
+                dnsbl: dnsbl.as_ref(),
+                sender_limiter: sender_limiter.as_ref(),
+                recipient_limiters: &recipient_limiters,
+                auto_block: auto_block.as_ref(),
+                rspamd: rspamd.as_ref(),
+                spamd: spamd.as_ref(),
+                clamav: clamav.as_ref(),
+                rules: rules.as_deref(),
+                spamtraps: spamtraps.as_ref(),
+                reputation: reputation.as_ref(),
                 tls_active: true,
+                strict_crlf,
+                require_tls: false,
             };
-            let result = smtp_loop(&mut tls_reader, &mut state, &ctx).await;
+            let result = smtp_loop(&mut tls_reader, state, &ctx).await;
 
             match result {
                 LoopResult::Done(r) => r,
@@ -178,538 +764,3644 @@ enum LoopResult {
     StartTls,
 }
 
-/// Write an SMTP response line.
-async fn send_line<W: AsyncWrite + Unpin>(
-    writer: &mut W,
-    line: &str,
-) -> Result<(), std::io::Error> {
-    writer.write_all(line.as_bytes()).await?;
-    writer.write_all(b"\r\n").await?;
-    writer.flush().await?;
-    Ok(())
+/// The client's reverse DNS, forward-confirmed with `verify`. `None` when
+/// the lookup fails.
+async fn reverse_dns(
+    resolver: &Resolver,
+    peer_addr: std::net::SocketAddr,
+    verify: bool,
+) -> Option<Rdns> {
+    match resolver.reverse_check(peer_addr.ip(), verify).await {
+        Ok(rdns) => {
+            tracing::Span::current().record("smtp.rdns", rdns.as_str());
+            Some(rdns)
+        }
+        Err(e) => {
+            debug!(peer = %peer_addr, error = %e, "reverse DNS lookup failed");
+            None
+        }
+    }
 }
 
-/// Send an SMTP response line, returning from the loop on write error.
-macro_rules! send_or_return {
-    ($reader:expr, $line:expr) => {
-        if let Err(e) = send_line($reader.get_mut(), $line).await {
-            return LoopResult::Done(Err(e.into()));
-        }
+/// The `Received:` and `X-Original-To:` fields, as configured, for a
+/// message in this session.
+fn gateway_headers(
+    state: &SessionState,
+    ctx: &SmtpContext<'_>,
+    recipients: &[relay::Recipient<'_>],
+) -> HeaderInjection {
+    let single = match recipients {
+        [only] => Some(only.address),
+        _ => None,
     };
+    let mut fields = HeaderInjection::new();
+    if ctx.config.received_header {
+        fields.push("Received", &received(state, ctx, single));
+    }
+    if let Some(rcpt) = single.filter(|_| ctx.config.x_original_to_header) {
+        fields.push("X-Original-To", rcpt);
+    }
+    if let Some(tag) = state.dnsbl.as_ref().and_then(|verdict| verdict.tag()) {
+        fields.push("X-DNSBL", &tag);
+    }
+    fields
 }
 
-/// Read a single line from the SMTP client with a hard byte limit.
-///
-/// Reads in buffered chunks via `fill_buf()`/`consume()` instead of
-/// byte-by-byte, avoiding per-byte overhead while still enforcing the
-/// size limit.
-///
-/// Returns an error if the line exceeds `max_len` bytes before a newline is
-/// found. This prevents memory exhaustion from newline-less input.
-async fn read_line<R: tokio::io::AsyncRead + Unpin>(
-    reader: &mut BufReader<R>,
-    buf: &mut Vec<u8>,
-    max_len: usize,
-) -> Result<Option<String>, std::io::Error> {
-    buf.clear();
-    loop {
-        let available = reader.fill_buf().await?;
-        if available.is_empty() {
-            // EOF
-            if buf.is_empty() {
-                return Ok(None);
-            }
-            let s = String::from_utf8_lossy(buf).trim_end().to_string();
-            return Ok(Some(s));
-        }
+fn received(state: &SessionState, ctx: &SmtpContext<'_>, recipient: Option<&str>) -> String {
+    let protocol = format!("{}{}", state.protocol, if state.tls { "S" } else { "" });
+    Received {
+        helo: state.helo.as_deref(),
+        rdns: state.rdns.as_ref().and_then(Rdns::name),
+        rdns_forged: matches!(state.rdns, Some(Rdns::Forged(_))),
+        ip: ctx.peer_addr.ip(),
+        by: &ctx.config.server_name,
+        protocol: &protocol,
+        tls: state
+            .tls_cipher
+            .as_ref()
+            .map(|(version, cipher)| (version.as_str(), cipher.as_str())),
+        id: &state.id,
+        recipient,
+        date: SystemTime::now(),
+    }
+    .to_string()
+}
 
-        if let Some(pos) = available.iter().position(|&b| b == b'\n') {
-            // Found newline — take everything before it, consume through the \n
-            buf.extend_from_slice(&available[..pos]);
-            reader.consume(pos + 1);
-            if buf.len() > max_len {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "line exceeds maximum length",
-                ));
+/// Check the client against `DNSBL_LISTS`, unless it is in
+/// `TRUSTED_NETWORKS` or an `allow` network, and keep the verdict for the `X-DNSBL` field.
+/// Returns the `code` reply to refuse a listed client with, unless
+/// `SHADOW_MODE` lets it through.
+async fn dnsbl_rejection(
+    state: &mut SessionState,
+    peer_addr: std::net::SocketAddr,
+    config: &Config,
+    metrics: &Metrics,
+    dnsbl: Option<&Dnsbl>,
+    code: u16,
+) -> Option<SmtpReply> {
+    let dnsbl = dnsbl?;
+    if state.allowed_network || network::contains(&config.trusted_networks, peer_addr.ip()) {
+        return None;
+    }
+    let verdict = dnsbl.check(peer_addr.ip()).await;
+    state.dnsbl = Some(verdict.clone());
+    let threshold = config.dnsbl_score_threshold;
+    let zone = match verdict.rejected_by.as_deref() {
+        Some(zone) => zone,
+        // rDNS and HELO scores can take a scored listing over the threshold
+        None if threshold > 0 && verdict.score > 0 => {
+            if state.score() < threshold {
+                return None;
             }
-            let s = String::from_utf8_lossy(buf).trim_end().to_string();
-            return Ok(Some(s));
+            let scored = verdict
+                .listings
+                .iter()
+                .rev()
+                .find(|listing| matches!(listing.action, DnsblAction::Score(_)))?;
+            scored.zone.as_str()
         }
+        None => return None,
+    };
+    if shadow_pass(config, metrics, peer_addr, "dnsbl") {
+        return None;
+    }
+    if sampling::sampled("dnsbl_rejected", peer_addr.ip()) {
+        info!(
+            peer = %peer_addr,
+            zone = zone,
+            score = verdict.score,
+            "[DNSBL-REJECTED] client is on a DNS blocklist"
+        );
+    }
+    metrics.dnsbl_rejected.fetch_add(1, Ordering::Relaxed);
+    record_rejection(peer_addr.ip(), "dnsbl");
+    Some(reply::dnsbl_listed(code, peer_addr.ip(), zone))
+}
 
-        // No newline in this chunk — consume it all and keep reading
-        let len = available.len();
-        buf.extend_from_slice(available);
-        reader.consume(len);
-        if buf.len() > max_len {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "line exceeds maximum length",
-            ));
+/// Look up the client's reverse DNS and hold it to `RDNS_CHECK`, unless
+/// it is in `TRUSTED_NETWORKS`. A failed lookup is not held against it.
+/// Returns the 554 reply to refuse the client with when `RDNS_ACTION`
+/// rejects it, unless `SHADOW_MODE` lets it through.
+async fn rdns_rejection(
+    state: &mut SessionState,
+    peer_addr: std::net::SocketAddr,
+    config: &Config,
+    metrics: &Metrics,
+    resolver: &Resolver,
+) -> Option<SmtpReply> {
+    let rdns = reverse_dns(resolver, peer_addr, config.rdns_check == RdnsCheck::Fcrdns).await;
+    state.rdns = Some(rdns.clone().unwrap_or(Rdns::Missing));
+    let rdns = rdns?;
+    let valid = match rdns {
+        Rdns::Confirmed(_) => true,
+        Rdns::Unconfirmed(_) => config.rdns_check == RdnsCheck::Ptr,
+        Rdns::Missing | Rdns::Forged(_) => false,
+    };
+    if valid || network::contains(&config.trusted_networks, peer_addr.ip()) {
+        return None;
+    }
+    let threshold = config.dnsbl_score_threshold;
+    let reject = match config.rdns_action {
+        DnsblAction::Reject => true,
+        DnsblAction::Tag => false,
+        DnsblAction::Score(score) => {
+            state.rdns_score = score;
+            threshold > 0 && score >= threshold
         }
+    };
+    if !reject || shadow_pass(config, metrics, peer_addr, "rdns") {
+        if sampling::sampled("rdns_failed", peer_addr.ip()) {
+            info!(
+                peer = %peer_addr,
+                rdns = rdns.name().unwrap_or(""),
+                result = rdns.as_str(),
+                score = state.rdns_score,
+                "[RDNS-FAILED] client has no valid reverse DNS"
+            );
+        }
+        return None;
+    }
+    if sampling::sampled("rdns_rejected", peer_addr.ip()) {
+        info!(
+            peer = %peer_addr,
+            rdns = rdns.name().unwrap_or(""),
+            result = rdns.as_str(),
+            "[RDNS-REJECTED] client has no valid reverse DNS"
+        );
     }
+    metrics.rdns_rejected.fetch_add(1, Ordering::Relaxed);
+    record_rejection(peer_addr.ip(), "rdns");
+    Some(reply::rdns_failed(554, peer_addr.ip()))
 }
 
-/// Read the DATA portion of an SMTP message until a lone ".".
-///
-/// Passes raw wire format through to the backend — no dot-unstuffing.
-/// The backend (or MDA) is responsible for dot-unstuffing per RFC 5321 §4.5.2.
-async fn read_data<R: tokio::io::AsyncRead + Unpin>(
-    reader: &mut BufReader<R>,
-    max_size: usize,
-) -> Result<Vec<u8>, std::io::Error> {
-    let mut data = Vec::with_capacity(8192);
-    let mut line_buf = Vec::with_capacity(1024);
-
-    loop {
-        line_buf.clear();
-        let n = reader.read_until(b'\n', &mut line_buf).await?;
-        if n == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "connection closed during DATA",
-            ));
+/// Hold the greeting name to `HELO_CHECKS`, unless the client is in
+/// `TRUSTED_NETWORKS`. Returns the reply to refuse the greeting with when a
+/// failed check rejects, or its score takes the client to
+/// `DNSBL_SCORE_THRESHOLD`, unless `SHADOW_MODE` lets it through.
+fn helo_rejection(
+    state: &mut SessionState,
+    ctx: &SmtpContext<'_>,
+    helo: &str,
+) -> Option<SmtpReply> {
+    let config = ctx.config;
+    state.helo_score = 0;
+    if config.helo_checks.is_empty()
+        || network::contains(&config.trusted_networks, ctx.peer_addr.ip())
+    {
+        return None;
+    }
+    let failed: Vec<(HeloCheck, DnsblAction)> = config
+        .helo_checks
+        .iter()
+        .copied()
+        .filter(|(check, _)| helo::fails(*check, helo, ctx.peer_addr.ip(), &config.server_name))
+        .collect();
+    let mut rejected_by = None;
+    for (check, action) in &failed {
+        match action {
+            DnsblAction::Reject => rejected_by = rejected_by.or(Some(*check)),
+            DnsblAction::Score(score) => state.helo_score += score,
+            DnsblAction::Tag => {}
+        }
+    }
+    let threshold = config.dnsbl_score_threshold;
+    if rejected_by.is_none() && state.helo_score > 0 && threshold > 0 && state.score() >= threshold
+    {
+        rejected_by = failed
+            .iter()
+            .rev()
+            .find(|(_, action)| matches!(action, DnsblAction::Score(_)))
+            .map(|(check, _)| *check);
+    }
+    let checks = failed
+        .iter()
+        .map(|(check, _)| check.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let Some(check) =
+        rejected_by.filter(|_| !shadow_pass(config, ctx.metrics, ctx.peer_addr, "helo"))
+    else {
+        if !failed.is_empty() && sampling::sampled("helo_failed", ctx.peer_addr.ip()) {
+            info!(
+                peer = %ctx.peer_addr,
+                helo = helo,
+                checks = %checks,
+                score = state.helo_score,
+                "[HELO-FAILED] suspicious greeting name"
+            );
         }
+        return None;
+    };
+    if sampling::sampled("helo_rejected", ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            helo = helo,
+            checks = %checks,
+            "[HELO-REJECTED] greeting name refused"
+        );
+    }
+    ctx.metrics.helo_rejected.fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), "helo");
+    Some(reply::helo_rejected(check))
+}
 
-        // Check for lone "." terminator (with optional \r before \n)
-        let trimmed = if line_buf.ends_with(b"\r\n") {
-            &line_buf[..line_buf.len() - 2]
-        } else if line_buf.ends_with(b"\n") {
-            &line_buf[..line_buf.len() - 1]
-        } else {
-            &line_buf[..]
-        };
-        if trimmed == b"." {
-            break;
+/// Check with `SENDER_DOMAIN_CHECK` that bounces to `sender` could be
+/// delivered. Returns the 550 reply for a domain with a null MX or no mail
+/// route at all, unless the client is in `TRUSTED_NETWORKS` or an `allow`
+/// network, or `SHADOW_MODE` lets it through. The null sender and address
+/// literals are not checked, and a lookup that fails lets the sender in.
+async fn sender_domain_rejection(
+    ctx: &SmtpContext<'_>,
+    sender: Option<&str>,
+    allowed_network: bool,
+) -> Option<SmtpReply> {
+    if !ctx.config.sender_domain_check
+        || allowed_network
+        || network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip())
+    {
+        return None;
+    }
+    let domain = address::domain(sender.filter(|sender| !sender.is_empty())?);
+    if domain.is_empty() || domain.starts_with('[') {
+        return None;
+    }
+    // DNS wants the A-label spelling of internationalized domains
+    let domain = &address::normalize_domain(domain).ok()?;
+    let verdict = match ctx.resolver.mail_domain(domain).await {
+        Ok(verdict) if verdict.accepts_mail() => return None,
+        Ok(verdict) => verdict,
+        Err(e) => {
+            debug!(peer = %ctx.peer_addr, domain = domain, error = %e, "sender domain lookup failed, allowing");
+            return None;
         }
+    };
+    if shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "sender_domain") {
+        return None;
+    }
+    if sampling::sampled("sender_domain", ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            domain = domain,
+            result = verdict.as_str(),
+            "[SENDER-DOMAIN-REJECTED] sender domain cannot receive bounces"
+        );
+    }
+    ctx.metrics
+        .sender_domain_rejected
+        .fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), "sender_domain");
+    Some(match verdict {
+        MailDomain::NullMx => reply::SENDER_NULL_MX,
+        _ => reply::SENDER_DOMAIN_NOT_FOUND,
+    })
+}
 
-        // Relay raw wire format — no dot-unstuffing
-        data.extend_from_slice(&line_buf);
+/// Count a new message from `sender` against `SENDER_RATE_LIMIT`. Returns
+/// the 450 reply for a sender over the limit, unless the client is in
+/// `TRUSTED_NETWORKS` or an `allow` network, or `SHADOW_MODE` lets it
+/// through. The null sender is
+/// never limited, and a count that fails lets the message in.
+async fn sender_rate_limited(
+    ctx: &SmtpContext<'_>,
+    sender: Option<&str>,
+    allowed_network: bool,
+) -> Option<SmtpReply> {
+    let limiter = ctx.sender_limiter?;
+    let sender = sender.filter(|sender| !sender.is_empty())?;
+    if allowed_network || network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip()) {
+        return None;
+    }
+    let sender = address::lookup_form(sender);
+    let key = match ctx.config.sender_rate_by {
+        RateLimitBy::Address => &*sender,
+        RateLimitBy::Domain => address::domain(&sender),
+    };
+    match limiter.check_and_increment(key).await {
+        Ok(true) => return None,
+        Ok(false) => {}
+        Err(e) => {
+            warn!(peer = %ctx.peer_addr, error = %e, "sender rate limit check failed, allowing");
+            return None;
+        }
+    }
+    if shadow_pass(
+        ctx.config,
+        ctx.metrics,
+        ctx.peer_addr,
+        "sender_rate_limited",
+    ) {
+        return None;
+    }
+    if sampling::sampled("sender_rate_limited", ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            sender = key,
+            "[SENDER-RATE-LIMITED] sender exceeded its message rate"
+        );
+    }
+    ctx.metrics
+        .sender_rate_limited
+        .fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), "sender_rate_limited");
+    Some(reply::SENDER_RATE_LIMITED)
+}
 
-        if data.len() > max_size {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "message exceeds maximum size",
-            ));
+/// Count a message to `mailbox` against `RECIPIENT_RATE_LIMIT_PER_*`.
+/// Returns the 452 reply for a mailbox over a limit, unless the client is
+/// in `TRUSTED_NETWORKS` or an `allow` network, or `SHADOW_MODE` lets it
+/// through. A count that fails lets the recipient in.
+async fn recipient_rate_limited(
+    ctx: &SmtpContext<'_>,
+    mailbox: &str,
+    allowed_network: bool,
+) -> Option<SmtpReply> {
+    if ctx.recipient_limiters.is_empty()
+        || allowed_network
+        || network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip())
+    {
+        return None;
+    }
+    for limiter in ctx.recipient_limiters {
+        match limiter.check_and_increment(mailbox).await {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                warn!(peer = %ctx.peer_addr, error = %e, "mailbox rate limit check failed, allowing");
+                return None;
+            }
+        }
+        if shadow_pass(
+            ctx.config,
+            ctx.metrics,
+            ctx.peer_addr,
+            "recipient_rate_limited",
+        ) {
+            return None;
+        }
+        if sampling::sampled("recipient_rate_limited", ctx.peer_addr.ip()) {
+            info!(
+                peer = %ctx.peer_addr,
+                address = mailbox,
+                limit = limiter.limit(),
+                window_secs = limiter.window().as_secs(),
+                "[RCPT-RATE-LIMITED] mailbox is receiving too many messages"
+            );
         }
+        ctx.metrics
+            .recipient_rate_limited
+            .fetch_add(1, Ordering::Relaxed);
+        record_rejection(ctx.peer_addr.ip(), "recipient_rate_limited");
+        return Some(reply::RECIPIENT_RATE_LIMITED);
     }
+    None
+}
 
-    Ok(data)
+/// Count a user-unknown rejection towards `AUTO_BLOCK_THRESHOLD`, unless the
+/// client is in `TRUSTED_NETWORKS` or an `allow` network.
+async fn auto_block_strike(ctx: &SmtpContext<'_>, allowed_network: bool) {
+    let Some(auto_block) = ctx.auto_block else {
+        return;
+    };
+    if allowed_network || network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip()) {
+        return;
+    }
+    auto_block.strike(ctx.peer_addr.ip()).await;
 }
 
-/// Main SMTP command loop, generic over the stream type.
-///
-/// Uses `BufReader<S>` where S implements both AsyncRead and AsyncWrite.
-/// Writes go through `reader.get_mut()` since SMTP is half-duplex.
-async fn smtp_loop<S: tokio::io::AsyncRead + AsyncWrite + Unpin>(
-    reader: &mut BufReader<S>,
-    state: &mut SessionState,
+/// Add `event` to the client's reputation, unless it is in
+/// `TRUSTED_NETWORKS` or an `allow` network.
+async fn reputation_event(ctx: &SmtpContext<'_>, allowed_network: bool, event: Event) {
+    let Some(reputation) = ctx.reputation else {
+        return;
+    };
+    if allowed_network || network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip()) {
+        return;
+    }
+    reputation.record(ctx.peer_addr.ip(), event).await;
+}
+
+/// Defer a new sender and recipient pair from a client whose reputation
+/// reached `REPUTATION_GREYLIST_SCORE`, unless `SHADOW_MODE` lets it through.
+/// Exempt clients connect with a score of 0.
+async fn reputation_greylisted(
     ctx: &SmtpContext<'_>,
-) -> LoopResult {
-    let mut line_buf = Vec::with_capacity(1024);
+    sender: &str,
+    recipient: &str,
+    score: f64,
+) -> Option<SmtpReply> {
+    let reputation = ctx.reputation?;
+    if !reputation.greylists(score)
+        || !reputation
+            .greylisted(ctx.peer_addr.ip(), sender, recipient)
+            .await
+        || shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "greylisted")
+    {
+        return None;
+    }
+    if sampling::sampled("greylisted", ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            sender = sender,
+            address = recipient,
+            score,
+            "[GREYLISTED] recipient deferred for the client's reputation"
+        );
+    }
+    ctx.metrics
+        .reputation_greylisted
+        .fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), "greylisted");
+    Some(reply::GREYLISTED)
+}
 
-    loop {
-        let line = match read_line(reader, &mut line_buf, ctx.config.max_line_length).await {
-            Ok(Some(line)) => line,
-            Ok(None) => return LoopResult::Done(Ok(())),
-            Err(e) => {
-                debug!(peer = %ctx.peer_addr, error = %e, "read error");
-                return LoopResult::Done(Ok(()));
+/// Scan the message with ClamAV. Returns the reason and reply to refuse an
+/// infected message with, unless `SHADOW_MODE` lets it through, or to defer
+/// one that could not be scanned unless `CLAMAV_FAIL_OPEN` is set.
+async fn virus_check(ctx: &SmtpContext<'_>, data: &[u8]) -> Option<(&'static str, SmtpReply)> {
+    let clamav = ctx.clamav?;
+    let signature = match clamav.scan(data).await {
+        Ok(Some(Scan::Clean)) => return None,
+        Ok(Some(Scan::Infected(signature))) => signature,
+        Ok(None) => {
+            debug!(peer = %ctx.peer_addr, size = data.len(), "message too large for ClamAV, not scanned");
+            return None;
+        }
+        Err(e) => {
+            ctx.metrics.clamav_errors.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                peer = %ctx.peer_addr,
+                error = %e,
+                fail_open = ctx.config.clamav_fail_open,
+                "[CLAMAV-ERROR] virus scan failed"
+            );
+            if ctx.config.clamav_fail_open {
+                return None;
             }
-        };
+            record_rejection(ctx.peer_addr.ip(), "virus_scan_error");
+            return Some(("virus_scan_error", reply::VIRUS_SCAN_UNAVAILABLE));
+        }
+    };
+    if shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "virus") {
+        return None;
+    }
+    warn!(
+        peer = %ctx.peer_addr,
+        virus = %signature,
+        "[VIRUS-FOUND] infected message refused"
+    );
+    ctx.metrics.viruses_detected.fetch_add(1, Ordering::Relaxed);
+    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), "virus");
+    Some(("virus", reply::VIRUS_FOUND))
+}
 
-        let (command, args) = parse_command(&line);
+/// Count and log a spamtrap recipient, and block the client with the
+/// auto-blocklist unless it is in `TRUSTED_NETWORKS` or an `allow` network,
+/// or `SHADOW_MODE` lets it through.
+async fn spamtrap_hit(ctx: &SmtpContext<'_>, address: &str, allowed_network: bool) {
+    ctx.metrics.spamtrap_hits.fetch_add(1, Ordering::Relaxed);
+    if sampling::sampled("spamtrap", ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            address = address,
+            "[SPAMTRAP-HIT] mail to a spamtrap address, message will be dropped"
+        );
+    }
+    let Some(auto_block) = ctx.auto_block else {
+        return;
+    };
+    if allowed_network
+        || network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip())
+        || shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "spamtrap")
+    {
+        return;
+    }
+    auto_block.block(ctx.peer_addr.ip(), "spamtrap").await;
+}
 
-        match command.as_str() {
-            "EHLO" | "HELO" => {
-                state.ehlo_received = true;
-                let mut caps = vec![
-                    format!("250-{} Hello {}", ctx.config.server_name, args),
-                    "250-SIZE 10485760".to_string(),
-                    "250-8BITMIME".to_string(),
-                    "250-PIPELINING".to_string(),
-                    "250-ENHANCEDSTATUSCODES".to_string(),
-                ];
-                if ctx.tls_config.is_some() && !ctx.tls_active {
-                    caps.push("250-STARTTLS".to_string());
-                }
-                if let Some(last) = caps.last_mut() {
-                    *last = last.replacen("250-", "250 ", 1);
-                }
-                for cap in &caps {
-                    send_or_return!(reader, cap);
-                }
+/// Check the message against `RULES_FILE`, unless the client is in
+/// `TRUSTED_NETWORKS`. Returns the fields the matching rules add, or the
+/// reason and reply to refuse the message with, unless `SHADOW_MODE` lets it
+/// through. Rule scores are added to the client's `score` and refuse the
+/// message once the total reaches `DNSBL_SCORE_THRESHOLD`. A `discard` rule
+/// answers as if the message was accepted, and it is not relayed.
+fn content_rules(
+    ctx: &SmtpContext<'_>,
+    data: &[u8],
+    score: u32,
+) -> Result<Vec<(String, String)>, (&'static str, SmtpReply)> {
+    let mut fields = Vec::new();
+    let Some(rule_set) = ctx.rules else {
+        return Ok(fields);
+    };
+    if network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip()) {
+        return Ok(fields);
+    }
+    let rules = rule_set.rules();
+    let mut matched = Vec::new();
+    let mut rule_score = 0u32;
+    let mut reject = None;
+    let mut discard = false;
+    for rule in rules.matching(data) {
+        matched.push(rule.line);
+        match &rule.action {
+            rules::Action::Reject { code, text } => {
+                let status = EnhancedStatus::new((code / 100) as u8, 7, 1);
+                reject = Some(SmtpReply::formatted(*code, Some(status), text.clone()));
+                break;
             }
-
-            "STARTTLS" => {
-                if ctx.tls_active {
-                    send_or_return!(reader, "554 5.5.1 TLS already active");
-                } else if ctx.tls_config.is_some() {
-                    send_or_return!(reader, "220 2.0.0 Ready to start TLS");
-                    return LoopResult::StartTls;
-                } else {
-                    send_or_return!(reader, "502 5.5.1 STARTTLS not available");
-                }
+            rules::Action::Score(points) => rule_score = rule_score.saturating_add(*points),
+            rules::Action::AddHeader { name, value } => fields.push((name.clone(), value.clone())),
+            rules::Action::Discard => discard = true,
+        }
+    }
+    let threshold = ctx.config.dnsbl_score_threshold;
+    let score = score.saturating_add(rule_score);
+    let reply = match reject {
+        Some(reply) => reply,
+        None if discard => {
+            if shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "content_discard") {
+                return Ok(fields);
             }
-
-            "MAIL" => {
-                state.sender = extract_address(args);
-                state.recipients.clear();
-                send_or_return!(reader, "250 2.1.0 OK");
+            if sampling::sampled("content_discard", ctx.peer_addr.ip()) {
+                info!(
+                    peer = %ctx.peer_addr,
+                    rules = ?matched,
+                    "[RULE-DISCARDED] message dropped by content rule"
+                );
             }
+            ctx.metrics.rules_discarded.fetch_add(1, Ordering::Relaxed);
+            record_verdict("discarded", "content_discard");
+            return Err(("discarded", reply::MESSAGE_ACCEPTED));
+        }
+        None if rule_score > 0 && threshold > 0 && score >= threshold => reply::CONTENT_REJECTED,
+        None => return Ok(fields),
+    };
+    if shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "content_rule") {
+        return Ok(fields);
+    }
+    if sampling::sampled("content_rule", ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            rules = ?matched,
+            score = score,
+            "[RULE-REJECTED] message refused by content rule"
+        );
+    }
+    ctx.metrics.rules_rejected.fetch_add(1, Ordering::Relaxed);
+    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), "content_rule");
+    Err(("content_rule", reply))
+}
 
-            "RCPT" => {
-                let address = match extract_address(args) {
-                    Some(addr) => addr,
-                    None => {
-                        send_or_return!(reader, "501 5.1.3 Bad recipient address syntax");
-                        continue;
-                    }
-                };
+/// Scan the message with Rspamd and then spamd, unless the client is in
+/// `TRUSTED_NETWORKS`. Returns the fields the scanners want added, or the
+/// reason and reply to refuse or defer the message with, unless
+/// `SHADOW_MODE` lets it through. A scan that fails or times out lets the
+/// message in.
+async fn spam_check(
+    ctx: &SmtpContext<'_>,
+    envelope: &Envelope<'_>,
+    data: &[u8],
+) -> Result<Vec<(String, String)>, (&'static str, SmtpReply)> {
+    let mut fields = Vec::new();
+    if network::contains(&ctx.config.trusted_networks, ctx.peer_addr.ip()) {
+        return Ok(fields);
+    }
+    if let Some(rspamd) = ctx.rspamd {
+        fields.extend(rspamd_check(ctx, rspamd, envelope, data).await?);
+    }
+    if let Some(spamd) = ctx.spamd {
+        fields.extend(spamd_check(ctx, spamd, data).await?);
+    }
+    Ok(fields)
+}
 
-                // Enforce per-session RCPT TO limit
-                state.recipient_count += 1;
-                if state.recipient_count > ctx.config.max_recipients {
-                    warn!(
-                        peer = %ctx.peer_addr,
-                        count = state.recipient_count,
-                        max = ctx.config.max_recipients,
-                        "RCPT TO limit exceeded"
-                    );
-                    send_or_return!(reader, "452 4.5.3 Too many recipients");
-                    continue;
-                }
+async fn rspamd_check(
+    ctx: &SmtpContext<'_>,
+    rspamd: &Rspamd,
+    envelope: &Envelope<'_>,
+    data: &[u8],
+) -> Result<Vec<(String, String)>, (&'static str, SmtpReply)> {
+    let verdict = match rspamd.check(envelope, data).await {
+        Ok(verdict) => verdict,
+        Err(e) => {
+            ctx.metrics.rspamd_errors.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                peer = %ctx.peer_addr,
+                error = %e,
+                "[RSPAMD-ERROR] spam scan failed, accepting"
+            );
+            return Ok(Vec::new());
+        }
+    };
+    let (reason, reply, tag, counter) = match verdict.action {
+        Action::Accept => return Ok(verdict.headers),
+        Action::AddHeaders => {
+            ctx.metrics.rspamd_tagged.fetch_add(1, Ordering::Relaxed);
+            if sampling::sampled("spam_tagged", ctx.peer_addr.ip()) {
+                info!(
+                    peer = %ctx.peer_addr,
+                    score = verdict.score,
+                    required = verdict.required_score,
+                    "[SPAM-TAGGED] message marked as spam"
+                );
+            }
+            return Ok(verdict.headers);
+        }
+        Action::Reject => (
+            "spam",
+            reply::SPAM_REJECTED,
+            "[SPAM-REJECTED]",
+            &ctx.metrics.rspamd_rejected,
+        ),
+        Action::Greylist | Action::SoftReject => (
+            "spam_deferred",
+            reply::SPAM_DEFERRED,
+            "[SPAM-DEFERRED]",
+            &ctx.metrics.rspamd_deferred,
+        ),
+    };
+    if shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, reason) {
+        return Ok(verdict.headers);
+    }
+    if sampling::sampled(reason, ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            scanner = "rspamd",
+            action = verdict.action.as_str(),
+            score = verdict.score,
+            required = verdict.required_score,
+            "{} message refused by Rspamd",
+            tag
+        );
+    }
+    counter.fetch_add(1, Ordering::Relaxed);
+    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), reason);
+    Err((reason, reply))
+}
+
+async fn spamd_check(
+    ctx: &SmtpContext<'_>,
+    spamd: &Spamd,
+    data: &[u8],
+) -> Result<Vec<(String, String)>, (&'static str, SmtpReply)> {
+    let report = match spamd.check(data).await {
+        Ok(Some(report)) => report,
+        Ok(None) => {
+            debug!(peer = %ctx.peer_addr, size = data.len(), "message too large for spamd, not scanned");
+            return Ok(Vec::new());
+        }
+        Err(e) => {
+            ctx.metrics.spamd_errors.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                peer = %ctx.peer_addr,
+                error = %e,
+                "[SPAMD-ERROR] spam scan failed, accepting"
+            );
+            return Ok(Vec::new());
+        }
+    };
+    let fields = if ctx.config.spamd_headers {
+        vec![("X-Spam-Status".to_string(), report.status())]
+    } else {
+        Vec::new()
+    };
+    let threshold = ctx.config.spamd_reject_score.unwrap_or(report.required);
+    if report.score < threshold || shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "spam") {
+        return Ok(fields);
+    }
+    if sampling::sampled("spam", ctx.peer_addr.ip()) {
+        info!(
+            peer = %ctx.peer_addr,
+            scanner = "spamd",
+            score = report.score,
+            threshold = threshold,
+            tests = %report.tests.join(","),
+            "[SPAM-REJECTED] message refused by SpamAssassin"
+        );
+    }
+    ctx.metrics.spamd_rejected.fetch_add(1, Ordering::Relaxed);
+    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+    record_rejection(ctx.peer_addr.ip(), "spam");
+    Err(("spam", reply::SPAM_REJECTED))
+}
 
-                let address_lower = address.to_lowercase();
-                let domain = address_lower.rsplit('@').next().unwrap_or("");
+/// Slow down a "user unknown" reply to make address enumeration expensive.
+/// Clients from `TRUSTED_NETWORKS` are answered immediately.
+async fn rejection_delay(ctx: &SmtpContext<'_>) {
+    let config = ctx.config;
+    if config.reject_delay_ms == 0 && config.reject_delay_jitter_ms == 0
+        || network::contains(&config.trusted_networks, ctx.peer_addr.ip())
+    {
+        return;
+    }
+    let jitter = match config.reject_delay_jitter_ms {
+        0 => 0,
+        max => RandomState::new().build_hasher().finish() % (max + 1),
+    };
+    tokio::time::sleep(Duration::from_millis(config.reject_delay_ms + jitter)).await;
+}
 
-                if !is_domain_accepted(domain, &ctx.config.accepted_domains) {
-                    info!(
-                        peer = %ctx.peer_addr,
-                        address = %address_lower,
-                        domain = domain,
-                        "[MAIL-REJECTED] unknown domain"
-                    );
-                    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
-                    send_or_return!(reader, "550 5.1.2 Unknown domain");
-                    continue;
-                }
+/// How long to hold the banner back: `GREET_DELAY_MS`, or not at all for
+/// `TRUSTED_NETWORKS`.
+fn greet_delay(config: &Config, ip: std::net::IpAddr) -> Option<Duration> {
+    if config.greet_delay_ms == 0 || network::contains(&config.trusted_networks, ip) {
+        return None;
+    }
+    Some(Duration::from_millis(config.greet_delay_ms))
+}
 
-                // Check Redis for mailbox existence — the key spam-filtering step
-                if !ctx.lookup.should_accept(&address_lower).await {
-                    info!(
-                        peer = %ctx.peer_addr,
-                        address = %address_lower,
-                        "[MAIL-REJECTED] mailbox not found"
-                    );
-                    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
-                    send_or_return!(reader, "550 5.1.1 User unknown");
-                    continue;
-                }
+/// Wait up to `delay` for the client to send anything. A compliant client
+/// waits for the 220 banner, so any byte here marks an early talker.
+async fn talks_early<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    delay: Duration,
+) -> Result<bool, std::io::Error> {
+    match tokio::time::timeout(delay, reader.fill_buf()).await {
+        Ok(buf) => Ok(!buf?.is_empty()),
+        Err(_) => Ok(false),
+    }
+}
 
-                info!(
-                    peer = %ctx.peer_addr,
-                    address = %address_lower,
-                    "[RCPT-ACCEPTED] mailbox verified"
+/// Whether `address` is the reserved self-test recipient, sent from loopback.
+fn is_self_test_recipient(ctx: &SmtpContext<'_>, address: &str) -> bool {
+    ctx.config.self_test
+        && ctx.peer_addr.ip().is_loopback()
+        && address::lookup_form(address) == ctx.config.self_test_address
+}
+
+/// In `SHADOW_MODE`, count and log as `[SHADOW-REJECTED]` a rejection a
+/// policy check (`reason`) would have made, and return true so the caller
+/// lets the client, sender or recipient through as if the check passed.
+/// Protocol errors and resource limits are never shadowed.
+pub fn shadow_pass(
+    config: &Config,
+    metrics: &Metrics,
+    peer_addr: std::net::SocketAddr,
+    reason: &str,
+) -> bool {
+    if !config.shadow_mode {
+        return false;
+    }
+    metrics.shadow_rejected.fetch_add(1, Ordering::Relaxed);
+    if sampling::sampled("shadow_rejected", peer_addr.ip()) {
+        info!(
+            peer = %peer_addr,
+            reason = reason,
+            "[SHADOW-REJECTED] would reject, accepting in shadow mode"
+        );
+    }
+    true
+}
+
+/// Record a verdict and its reason on the current session span.
+fn record_verdict(verdict: &str, reason: &str) {
+    let span = tracing::Span::current();
+    span.record("smtp.verdict", verdict);
+    span.record("smtp.reason", reason);
+}
+
+/// Record a rejection on the span and in `REJECT_LOG_FILE`.
+fn record_rejection(ip: std::net::IpAddr, reason: &str) {
+    record_verdict("rejected", reason);
+    rejectlog::rejected(ip, reason);
+}
+
+/// SMTP reply for a rejected MAIL/RCPT parameter list.
+fn param_error_reply(e: &ParamError) -> SmtpReply {
+    let status = Some(EnhancedStatus::new(5, 5, 4));
+    match e {
+        ParamError::Malformed(_) => reply::PARAM_SYNTAX,
+        ParamError::Invalid(key) => {
+            SmtpReply::formatted(501, status, format!("Invalid {} parameter", key))
+        }
+        ParamError::Unrecognized(_) => reply::PARAM_UNRECOGNIZED,
+        ParamError::Unsupported(key) => {
+            SmtpReply::formatted(555, status, format!("Unsupported {} value", key))
+        }
+    }
+}
+
+/// Write an SMTP reply and flush it (with any queued replies).
+async fn send_reply<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    reply: &SmtpReply,
+) -> Result<(), std::io::Error> {
+    reply.write_to(writer).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Flush queued replies unless more pipelined commands are already buffered.
+///
+/// Called before every blocking read, so a client waiting on replies is
+/// never left hanging, while a pipelined group gets its replies in one write.
+async fn flush_if_idle<S: tokio::io::AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<S>,
+) -> Result<(), std::io::Error> {
+    if reader.buffer().is_empty() {
+        reader.get_mut().flush().await?;
+    }
+    Ok(())
+}
+
+/// Send a 421 to a client that let a timeout expire, and end the session.
+async fn close_timed_out<S: tokio::io::AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<S>,
+    state: &mut SessionState,
+    ctx: &SmtpContext<'_>,
+    waiting_for: &str,
+) -> LoopResult {
+    debug!(peer = %ctx.peer_addr, waiting_for, "session timed out");
+    state.timed_out = true;
+    record_rejection(ctx.peer_addr.ip(), "timeout");
+    let grace = Duration::from_secs(ctx.config.idle_timeout_secs);
+    let _ = tokio::time::timeout(grace, send_reply(reader.get_mut(), &reply::TIMEOUT)).await;
+    LoopResult::Done(Ok(()))
+}
+
+/// Queue an SMTP reply without flushing, returning from the loop on write
+/// error.
+macro_rules! send_or_return {
+    ($reader:expr, $reply:expr) => {
+        if let Err(e) = $reply.write_to($reader.get_mut()).await {
+            return LoopResult::Done(Err(e.into()));
+        }
+    };
+}
+
+/// Queue an error reply and count it against the session's error budget.
+///
+/// Once `MAX_SESSION_ERRORS` is reached the client gets a 421 and the
+/// session ends, which caps address harvesting and protocol fuzzing.
+macro_rules! send_error_or_return {
+    ($reader:expr, $state:expr, $ctx:expr, $reply:expr) => {
+        send_or_return!($reader, $reply);
+        $state.errors += 1;
+        let max = $ctx.config.max_session_errors;
+        if max > 0 && $state.errors >= max {
+            if sampling::sampled("too_many_errors", $ctx.peer_addr.ip()) {
+                warn!(
+                    peer = %$ctx.peer_addr,
+                    errors = $state.errors,
+                    "too many errors, closing connection"
                 );
-                state.recipients.insert(address_lower);
-                send_or_return!(reader, "250 2.1.5 OK");
             }
+            record_rejection($ctx.peer_addr.ip(), "too_many_errors");
+            let _ = send_reply($reader.get_mut(), &reply::TOO_MANY_ERRORS).await;
+            return LoopResult::Done(Ok(()));
+        }
+    };
+}
 
-            "DATA" => {
-                if state.recipients.is_empty() {
-                    send_or_return!(reader, "503 5.5.1 No valid recipients");
-                    continue;
+/// Queue the final reply to DATA: once for SMTP, once per accepted recipient
+/// for LMTP (RFC 2033 §4.2). An accepted message counts toward
+/// `MAX_SESSION_MESSAGES`.
+macro_rules! send_data_reply_or_return {
+    ($reader:expr, $state:expr, $replies:expr, $reply:expr) => {
+        for _ in 0..$replies {
+            send_or_return!($reader, $reply);
+        }
+        if $reply.is_positive() {
+            $state.messages += 1;
+        }
+    };
+}
+
+/// Read a single line from the SMTP client with a hard byte limit.
+///
+/// Reads in buffered chunks via `fill_buf()`/`consume()` instead of
+/// byte-by-byte, avoiding per-byte overhead while still enforcing the
+/// size limit.
+///
+/// Returns an error if the line exceeds `max_len` bytes before a newline is
+/// found. This prevents memory exhaustion from newline-less input.
+async fn read_line<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<Option<String>, std::io::Error> {
+    buf.clear();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            // EOF
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            let s = String::from_utf8_lossy(buf).trim_end().to_string();
+            return Ok(Some(s));
+        }
+
+        if let Some(pos) = available.iter().position(|&b| b == b'\n') {
+            // Found newline — take everything before it, consume through the \n
+            buf.extend_from_slice(&available[..pos]);
+            reader.consume(pos + 1);
+            if buf.len() > max_len {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "line exceeds maximum length",
+                ));
+            }
+            let s = String::from_utf8_lossy(buf).trim_end().to_string();
+            return Ok(Some(s));
+        }
+
+        // No newline in this chunk — consume it all and keep reading
+        let len = available.len();
+        buf.extend_from_slice(available);
+        reader.consume(len);
+        if buf.len() > max_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "line exceeds maximum length",
+            ));
+        }
+    }
+}
+
+/// Longest line fragment buffered at once while reading DATA.
+const DATA_CHUNK: u64 = 8192;
+
+/// Upper bound on the buffer preallocated from a declared MAIL SIZE.
+const MAX_PREALLOC: usize = 1024 * 1024;
+
+/// Why a DATA body was not returned.
+#[derive(Debug, thiserror::Error)]
+enum DataError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The body exceeded the size limit; it was drained to the terminator.
+    #[error("message exceeds maximum size")]
+    TooLarge,
+    /// Strict mode saw a bare CR or LF; the body was drained to the terminator.
+    #[error("bare CR or LF in message")]
+    BareLineEnding,
+    /// An end-of-data look-alike that other MTAs may read differently. The
+    /// client's next "command" may be part of the message, so the session
+    /// cannot continue.
+    #[error("ambiguous end-of-data sequence")]
+    TerminatorConfusion,
+    /// A line starts with a single "."; the body was drained to the terminator.
+    #[error("message is not dot-stuffed")]
+    NotDotStuffed,
+}
+
+/// Whether a raw command line (the bytes before its `\n`) was not terminated
+/// by a proper CRLF or contains a stray CR.
+fn has_bare_line_ending(raw: &[u8]) -> bool {
+    match raw.split_last() {
+        Some((b'\r', rest)) => rest.contains(&b'\r'),
+        _ => true,
+    }
+}
+
+/// Read the DATA portion of an SMTP message until a lone ".".
+///
+/// Passes raw wire format through to the backend — no dot-unstuffing.
+/// The backend (or MDA) is responsible for dot-unstuffing per RFC 5321 §4.5.2.
+///
+/// `size_hint` is the SIZE declared on MAIL FROM and only sizes the initial
+/// buffer. Once the running byte count crosses `max_size`, the buffered body
+/// is dropped and the rest is discarded up to the terminator, so the client
+/// stays in sync and sees the 552 as the reply to its final ".".
+///
+/// In `strict` mode only `<CRLF>.<CRLF>` terminates the body, so `<LF>.<LF>`
+/// and similar cannot split one message into two (SMTP smuggling), and any
+/// bare CR or LF makes the whole message fail with `BareLineEnding`.
+///
+/// The lenient mode still ends on `<LF>.<LF>` for LF-only clients, but fails
+/// with `TerminatorConfusion` when a message that otherwise uses CRLF ends on
+/// anything else, or when a dot sits next to a lone CR. With `check_stuffing`,
+/// a line starting with a single "." fails with `NotDotStuffed`.
+async fn read_data<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    max_size: usize,
+    size_hint: Option<usize>,
+    strict: bool,
+    check_stuffing: bool,
+) -> Result<Vec<u8>, DataError> {
+    let capacity = size_hint.unwrap_or(0).clamp(8192, MAX_PREALLOC);
+    let mut data = Vec::with_capacity(capacity.min(max_size));
+    let mut line_buf = Vec::with_capacity(1024);
+    let mut received: usize = 0;
+    let mut mid_line = false;
+    // The last three bytes seen; the DATA command itself ended with CRLF
+    let mut recent = [b' ', b'\r', b'\n'];
+    let mut prev_line_crlf = true;
+    let mut saw_crlf = false;
+    let mut bare = false;
+    let mut confusion = false;
+    let mut not_stuffed = false;
+
+    loop {
+        line_buf.clear();
+        let n = (&mut *reader)
+            .take(DATA_CHUNK)
+            .read_until(b'\n', &mut line_buf)
+            .await?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed during DATA",
+            )
+            .into());
+        }
+
+        // Check for lone "." terminator (with optional \r before \n). A
+        // fragment that continues an over-long line can never be one.
+        let complete = line_buf.ends_with(b"\n");
+        if strict {
+            if !mid_line && prev_line_crlf && line_buf == b".\r\n" {
+                break;
+            }
+        } else if !mid_line && complete {
+            let crlf = line_buf.ends_with(b"\r\n");
+            let trimmed = &line_buf[..line_buf.len() - if crlf { 2 } else { 1 }];
+            if trimmed == b"." {
+                if saw_crlf && !(crlf && prev_line_crlf) {
+                    confusion = true;
+                }
+                break;
+            }
+        }
+        if check_stuffing && !mid_line && line_buf.starts_with(b".") && !line_buf.starts_with(b"..")
+        {
+            not_stuffed = true;
+        }
+        for &b in &line_buf {
+            let [p2, p1, p0] = recent;
+            if strict {
+                if (p0 == b'\r') != (b == b'\n') {
+                    bare = true;
                 }
+            } else if p1 == b'\r' && p0 == b'.' && matches!(b, b'\r' | b'\n')
+                || matches!(p2, b'\r' | b'\n') && p1 == b'.' && p0 == b'\r' && b != b'\n'
+            {
+                // "<CR>.<CR>", "<CR>.<LF>" or "<LF>.<CR>x": end-of-data to
+                // some MTAs, ordinary text to others
+                confusion = true;
+            }
+            if b == b'\n' {
+                prev_line_crlf = p0 == b'\r';
+                saw_crlf |= prev_line_crlf;
+            }
+            recent = [p1, p0, b];
+        }
+        mid_line = !complete;
 
-                send_or_return!(reader, "354 Start mail input; end with <CRLF>.<CRLF>");
+        received = received.saturating_add(n);
+        if received > max_size {
+            // Discard mode: free the buffer and keep draining to the terminator
+            if !data.is_empty() {
+                data = Vec::new();
+            }
+            continue;
+        }
 
-                let data = match read_data(reader, ctx.config.max_message_size).await {
-                    Ok(data) => data,
-                    Err(e) => {
-                        let _ = send_line(reader.get_mut(), "552 5.3.4 Message too large").await;
-                        debug!(peer = %ctx.peer_addr, error = %e, "data read error");
-                        continue;
-                    }
+        // Relay raw wire format — no dot-unstuffing
+        data.extend_from_slice(&line_buf);
+    }
+
+    if confusion {
+        return Err(DataError::TerminatorConfusion);
+    }
+    if received > max_size {
+        return Err(DataError::TooLarge);
+    }
+    if bare {
+        return Err(DataError::BareLineEnding);
+    }
+    if not_stuffed {
+        return Err(DataError::NotDotStuffed);
+    }
+    Ok(data)
+}
+
+/// Main SMTP command loop, generic over the stream type.
+///
+/// Uses `BufReader<S>` where S implements both AsyncRead and AsyncWrite.
+/// Writes go through `reader.get_mut()` since SMTP is half-duplex.
+async fn smtp_loop<S: tokio::io::AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<S>,
+    state: &mut SessionState,
+    ctx: &SmtpContext<'_>,
+) -> LoopResult {
+    let mut line_buf = Vec::with_capacity(1024);
+    let idle_timeout = Duration::from_secs(ctx.config.idle_timeout_secs);
+    let data_timeout = Duration::from_secs(ctx.config.data_timeout_secs);
+
+    loop {
+        // The idle clock covers both delivering our queued replies and
+        // receiving the next command.
+        let deadline = Instant::now() + idle_timeout;
+        match tokio::time::timeout_at(deadline, flush_if_idle(reader)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return LoopResult::Done(Err(e.into())),
+            Err(_) => return close_timed_out(reader, state, ctx, "reply flush").await,
+        }
+        let read = read_line(reader, &mut line_buf, ctx.config.max_line_length);
+        let line = match tokio::time::timeout_at(deadline, read).await {
+            Ok(Ok(Some(line))) => line,
+            Ok(Ok(None)) => return LoopResult::Done(Ok(())),
+            Ok(Err(e)) => {
+                debug!(peer = %ctx.peer_addr, error = %e, "read error");
+                return LoopResult::Done(Ok(()));
+            }
+            Err(_) => return close_timed_out(reader, state, ctx, "command").await,
+        };
+
+        if ctx.strict_crlf && has_bare_line_ending(&line_buf) {
+            debug!(peer = %ctx.peer_addr, "bare CR or LF in command");
+            send_error_or_return!(reader, state, ctx, reply::BARE_LINE_ENDING_COMMAND);
+            continue;
+        }
+
+        let (command, args) = parse_command(&line);
+        if !command.is_empty() {
+            state.count_command(&command);
+        }
+
+        match command.as_str() {
+            "EHLO" | "HELO" | "LHLO" => {
+                if (command.as_str() == "LHLO") != ctx.config.lmtp {
+                    let reply = if ctx.config.lmtp {
+                        reply::USE_LHLO
+                    } else {
+                        reply::NOT_RECOGNIZED
+                    };
+                    send_error_or_return!(reader, state, ctx, reply);
+                    continue;
+                }
+                if let Some(reply) = helo_rejection(state, ctx, args) {
+                    send_error_or_return!(reader, state, ctx, reply);
+                    continue;
+                }
+                // A new greeting implies RSET (RFC 5321 §4.1.4)
+                state.reset_transaction();
+                state.phase = Phase::Greeted;
+                state.helo = Some(args.to_string()).filter(|name| !name.is_empty());
+                state.protocol = match command.as_str() {
+                    "HELO" => "SMTP",
+                    "LHLO" => "LMTP",
+                    _ => "ESMTP",
                 };
+                // HELO gets a plain one-line reply without extensions (RFC 5321 §4.1.1.1)
+                let mut text = format!("{} Hello {}", ctx.config.server_name, args);
+                if command.as_str() == "HELO" {
+                    send_or_return!(reader, SmtpReply::formatted(250, None, text));
+                    continue;
+                }
+                if ctx.config.advertise_size {
+                    text.push_str(&format!("\nSIZE {}", ctx.config.max_message_size));
+                }
+                text.push_str("\n8BITMIME\nPIPELINING\nENHANCEDSTATUSCODES");
+                if ctx.tls_config.is_some() && !ctx.tls_active {
+                    text.push_str("\nSTARTTLS");
+                }
+                send_or_return!(reader, SmtpReply::formatted(250, None, text));
+            }
+
+            "STARTTLS" => {
+                if ctx.tls_active {
+                    send_error_or_return!(reader, state, ctx, reply::TLS_ALREADY_ACTIVE);
+                } else if ctx.tls_config.is_some() {
+                    if let Err(e) = send_reply(reader.get_mut(), &reply::READY_FOR_TLS).await {
+                        return LoopResult::Done(Err(e.into()));
+                    }
+                    return LoopResult::StartTls;
+                } else {
+                    send_error_or_return!(reader, state, ctx, reply::TLS_NOT_AVAILABLE);
+                }
+            }
+
+            "MAIL" => {
+                match state.phase {
+                    Phase::Connected => {
+                        let reply = if ctx.config.lmtp {
+                            reply::SEND_LHLO_FIRST
+                        } else {
+                            reply::SEND_EHLO_FIRST
+                        };
+                        send_error_or_return!(reader, state, ctx, reply);
+                        continue;
+                    }
+                    Phase::Mail | Phase::Rcpt => {
+                        send_error_or_return!(reader, state, ctx, reply::NESTED_MAIL);
+                        continue;
+                    }
+                    Phase::Greeted => {}
+                }
+                if ctx.require_tls {
+                    send_error_or_return!(reader, state, ctx, reply::TLS_REQUIRED);
+                    continue;
+                }
+                let max_messages = ctx.config.max_session_messages;
+                if max_messages > 0 && state.messages >= max_messages {
+                    if sampling::sampled("too_many_messages", ctx.peer_addr.ip()) {
+                        info!(
+                            peer = %ctx.peer_addr,
+                            messages = state.messages,
+                            "[SESSION-LIMIT] message limit reached, closing connection"
+                        );
+                    }
+                    record_rejection(ctx.peer_addr.ip(), "too_many_messages");
+                    let _ = send_reply(reader.get_mut(), &reply::TOO_MANY_MESSAGES).await;
+                    return LoopResult::Done(Ok(()));
+                }
+                if ctx.config.dnsbl_check_at == DnsblCheckAt::Mail && state.dnsbl.is_none() {
+                    state.dnsbl_reply = dnsbl_rejection(
+                        state,
+                        ctx.peer_addr,
+                        ctx.config,
+                        ctx.metrics,
+                        ctx.dnsbl,
+                        550,
+                    )
+                    .await;
+                }
+                if let Some(reply) = state.dnsbl_reply.clone() {
+                    send_error_or_return!(reader, state, ctx, reply);
+                    continue;
+                }
+                let sender = extract_address(args);
+                if let Some(Err(e)) = sender.as_deref().map(address::validate) {
+                    debug!(peer = %ctx.peer_addr, error = %e, "MAIL FROM address rejected");
+                    send_error_or_return!(reader, state, ctx, reply::BAD_SENDER_SYNTAX);
+                    continue;
+                }
+                let params = match MailParams::parse(esmtp::params_part(args)) {
+                    Ok(params) => params,
+                    Err(e) => {
+                        debug!(peer = %ctx.peer_addr, error = %e, "MAIL FROM parameters rejected");
+                        send_error_or_return!(reader, state, ctx, &param_error_reply(&e));
+                        continue;
+                    }
+                };
+                let declared_size = params.size;
+
+                // Reject declared oversized messages before the body is sent
+                if let Some(size) = declared_size {
+                    if size > ctx.config.max_message_size {
+                        info!(
+                            peer = %ctx.peer_addr,
+                            size = size,
+                            max = ctx.config.max_message_size,
+                            "[MAIL-REJECTED] declared size exceeds limit"
+                        );
+                        ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                        record_rejection(ctx.peer_addr.ip(), "message_too_large");
+                        send_or_return!(reader, reply::SIZE_EXCEEDS_MAXIMUM);
+                        continue;
+                    }
+                }
+                if let Some(reply) =
+                    sender_domain_rejection(ctx, sender.as_deref(), state.allowed_network).await
+                {
+                    send_or_return!(reader, reply);
+                    continue;
+                }
+                if let Some(reply) =
+                    sender_rate_limited(ctx, sender.as_deref(), state.allowed_network).await
+                {
+                    send_or_return!(reader, reply);
+                    continue;
+                }
+                state.sender = sender;
+                state.declared_size = declared_size;
+                state.mail_params = params.params;
+                state.recipients.clear();
+                state.phase = Phase::Mail;
+                send_or_return!(reader, reply::SENDER_OK);
+            }
+
+            "RCPT" => {
+                if !matches!(state.phase, Phase::Mail | Phase::Rcpt) {
+                    send_error_or_return!(reader, state, ctx, reply::NEED_MAIL);
+                    continue;
+                }
+                if let Some(delay) = ctx
+                    .reputation
+                    .and_then(|reputation| reputation.tarpit(state.reputation_score))
+                {
+                    tokio::time::sleep(delay).await;
+                }
+                let address = match extract_address(args) {
+                    Some(addr) if address::validate(&addr).is_ok() => addr,
+                    _ => {
+                        state.reject_rcpt();
+                        record_rejection(ctx.peer_addr.ip(), "bad_address");
+                        send_error_or_return!(reader, state, ctx, reply::BAD_RECIPIENT_SYNTAX);
+                        continue;
+                    }
+                };
+                let rcpt_params = match RcptParams::parse(esmtp::params_part(args)) {
+                    Ok(params) => params.params,
+                    Err(e) => {
+                        debug!(peer = %ctx.peer_addr, error = %e, "RCPT TO parameters rejected");
+                        state.reject_rcpt();
+                        record_rejection(ctx.peer_addr.ip(), "bad_parameters");
+                        send_error_or_return!(reader, state, ctx, &param_error_reply(&e));
+                        continue;
+                    }
+                };
+
+                let Ok(address) = address::normalize(&address, ctx.config.preserve_local_case)
+                else {
+                    state.reject_rcpt();
+                    record_rejection(ctx.peer_addr.ip(), "bad_address");
+                    send_error_or_return!(reader, state, ctx, reply::BAD_RECIPIENT_SYNTAX);
+                    continue;
+                };
+
+                // A repeat of an accepted recipient is acknowledged again
+                // (LMTP owes it a DATA reply) without another lookup
+                if let Some(params) = state.recipients.get_mut(&address) {
+                    *params = rcpt_params;
+                    state.txn_accepted.push(address);
+                    send_or_return!(reader, reply::RECIPIENT_OK);
+                    continue;
+                }
+
+                // Enforce the per-transaction and per-session RCPT TO limits.
+                // Only well-formed, new recipients count; unknown and blocked
+                // ones do, so address harvesting still runs into the limit.
+                state.recipient_count += 1;
+                state.txn_recipient_count += 1;
+                let max_session = ctx.config.max_session_recipients;
+                let limit = if state.txn_recipient_count > ctx.config.max_recipients {
+                    Some(("transaction", &reply::TOO_MANY_RECIPIENTS))
+                } else if max_session > 0 && state.recipient_count > max_session {
+                    Some(("session", &reply::TOO_MANY_SESSION_RECIPIENTS))
+                } else {
+                    None
+                };
+                if let Some((scope, limit_reply)) = limit {
+                    if sampling::sampled("rcpt_limit", ctx.peer_addr.ip()) {
+                        warn!(
+                            peer = %ctx.peer_addr,
+                            scope = scope,
+                            count = state.txn_recipient_count,
+                            session_count = state.recipient_count,
+                            "RCPT TO limit exceeded"
+                        );
+                    }
+                    state.reject_rcpt();
+                    record_rejection(ctx.peer_addr.ip(), "too_many_recipients");
+                    send_error_or_return!(reader, state, ctx, limit_reply);
+                    continue;
+                }
+
+                let address_lower = address::lookup_form(&address);
+                let domain = address::domain(&address_lower);
+                tracing::Span::current().record("smtp.rcpt_domain", domain);
+
+                if is_self_test_recipient(ctx, &address_lower) {
+                    // Exercise the lookup path, but accept without a mailbox
+                    if ctx.lookup.check(&address_lower).await == LookupOutcome::Error {
+                        send_or_return!(reader, reply::LOOKUP_UNAVAILABLE);
+                        continue;
+                    }
+                    debug!(peer = %ctx.peer_addr, "self-test recipient accepted");
+                    state.txn_accepted.push(address.clone());
+                    state.recipients.insert(address, rcpt_params);
+                    state.phase = Phase::Rcpt;
+                    send_or_return!(reader, reply::RECIPIENT_OK);
+                    continue;
+                }
+
+                // Spamtraps look like any mailbox to the client
+                if let Some(spamtraps) = ctx.spamtraps {
+                    if spamtraps.contains(&address_lower).await {
+                        spamtrap_hit(ctx, &address_lower, state.allowed_network).await;
+                        reputation_event(ctx, state.allowed_network, Event::Spamtrap).await;
+                        state.spamtrap = true;
+                        state.txn_accepted.push(address.clone());
+                        record_verdict("accepted", "spamtrap");
+                        state.recipients.insert(address, rcpt_params);
+                        state.phase = Phase::Rcpt;
+                        send_or_return!(reader, reply::RECIPIENT_OK);
+                        continue;
+                    }
+                }
+
+                if !is_domain_accepted(domain, &ctx.config.accepted_domains)
+                    && !shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "unknown_domain")
+                {
+                    if sampling::sampled("rcpt_unknown_domain", ctx.peer_addr.ip()) {
+                        info!(
+                            peer = %ctx.peer_addr,
+                            address = %address_lower,
+                            domain = domain,
+                            "[MAIL-REJECTED] unknown domain"
+                        );
+                    }
+                    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    state.reject_rcpt();
+                    record_rejection(ctx.peer_addr.ip(), "unknown_domain");
+                    auto_block_strike(ctx, state.allowed_network).await;
+                    reputation_event(ctx, state.allowed_network, Event::UnknownRecipient).await;
+                    send_error_or_return!(
+                        reader,
+                        state,
+                        ctx,
+                        ctx.config.reply_templates.unknown_domain(&address)
+                    );
+                    continue;
+                }
+
+                // An alias is checked and relayed as the mailbox it stands for
+                let alias = match ctx.lookup.resolve_alias(&address_lower).await {
+                    Ok(alias) => alias,
+                    Err(e) => {
+                        let (reason, reply) = match e {
+                            AliasError::Loop => ("alias_loop", &reply::ALIAS_LOOP),
+                            AliasError::Store(_) => ("alias_error", &reply::LOOKUP_UNAVAILABLE),
+                        };
+                        if sampling::sampled("rcpt_alias", ctx.peer_addr.ip()) {
+                            info!(
+                                peer = %ctx.peer_addr,
+                                address = %address_lower,
+                                error = %e,
+                                "[MAIL-REJECTED] alias not resolved"
+                            );
+                        }
+                        ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                        state.reject_rcpt();
+                        record_rejection(ctx.peer_addr.ip(), reason);
+                        send_error_or_return!(reader, state, ctx, reply);
+                        continue;
+                    }
+                };
+                let (address, address_lower) = match alias {
+                    Some(target) => {
+                        debug!(peer = %ctx.peer_addr, alias = %address_lower, target = %target, "recipient rewritten");
+                        (target.clone(), target)
+                    }
+                    None => {
+                        let lower = address_lower.into_owned();
+                        (address, lower)
+                    }
+                };
+                let domain = address::domain(&address_lower);
+
+                // Aliases of an accepted mailbox, and other spellings of it
+                // kept by PRESERVE_LOCAL_CASE, are repeats of it, so the
+                // message is relayed to it once
+                let accepted = state
+                    .recipients
+                    .keys()
+                    .find(|key| *address::lookup_form(key) == *address_lower)
+                    .cloned();
+                if let Some(accepted) = accepted {
+                    state.recipients.insert(accepted.clone(), rcpt_params);
+                    state.recipient_count -= 1;
+                    state.txn_recipient_count -= 1;
+                    state.txn_accepted.push(accepted);
+                    send_or_return!(reader, reply::RECIPIENT_OK);
+                    continue;
+                }
+
+                // Check Redis for mailbox existence — the key spam-filtering step
+                let outcome = ctx.lookup.check(&address_lower).await;
+                tracing::Span::current().record("lookup.result", outcome.as_str());
+                let mut fail_open = false;
+                if outcome == LookupOutcome::Error {
+                    ctx.metrics.lookup_errors.fetch_add(1, Ordering::Relaxed);
+                    match ctx.config.lookup_error_policy {
+                        LookupErrorPolicy::Reject => {}
+                        LookupErrorPolicy::TempFail
+                            if !shadow_pass(
+                                ctx.config,
+                                ctx.metrics,
+                                ctx.peer_addr,
+                                "lookup_error",
+                            ) =>
+                        {
+                            if sampling::sampled("rcpt_lookup_error", ctx.peer_addr.ip()) {
+                                info!(
+                                    peer = %ctx.peer_addr,
+                                    address = %address_lower,
+                                    "[MAIL-REJECTED] mailbox lookup failed, asking sender to retry"
+                                );
+                            }
+                            ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                            state.reject_rcpt();
+                            record_rejection(ctx.peer_addr.ip(), "lookup_error");
+                            send_error_or_return!(reader, state, ctx, reply::LOOKUP_UNAVAILABLE);
+                            continue;
+                        }
+                        LookupErrorPolicy::TempFail => fail_open = true,
+                        LookupErrorPolicy::Accept => {
+                            if sampling::sampled("rcpt_lookup_error", ctx.peer_addr.ip()) {
+                                warn!(
+                                    peer = %ctx.peer_addr,
+                                    address = %address_lower,
+                                    "[LOOKUP-FAIL-OPEN] mailbox lookup failed, accepting"
+                                );
+                            }
+                            ctx.metrics.lookup_fail_open.fetch_add(1, Ordering::Relaxed);
+                            fail_open = true;
+                        }
+                    }
+                }
+                // Under `reject`, a failed lookup is answered as unknown but
+                // logged as the gateway's failure, not the client's
+                let reason = if outcome == LookupOutcome::Error {
+                    "lookup_error"
+                } else {
+                    "mailbox_not_found"
+                };
+                if !outcome.is_hit()
+                    && !fail_open
+                    && !shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, reason)
+                {
+                    let soft_fail = is_soft_fail_domain(ctx.config, domain);
+                    if sampling::sampled("rcpt_unknown_user", ctx.peer_addr.ip()) {
+                        info!(
+                            peer = %ctx.peer_addr,
+                            address = %address_lower,
+                            lookup = outcome.as_str(),
+                            soft_fail,
+                            "[MAIL-REJECTED] mailbox not found"
+                        );
+                    }
+                    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    state.reject_rcpt();
+                    record_rejection(ctx.peer_addr.ip(), reason);
+                    // A failed lookup is not held against the client
+                    if outcome == LookupOutcome::Miss {
+                        auto_block_strike(ctx, state.allowed_network).await;
+                        reputation_event(ctx, state.allowed_network, Event::UnknownRecipient).await;
+                    }
+                    rejection_delay(ctx).await;
+                    let templates = &ctx.config.reply_templates;
+                    let reply = if soft_fail {
+                        templates.mailbox_unavailable(&address)
+                    } else {
+                        templates.user_unknown(&address)
+                    };
+                    send_error_or_return!(reader, state, ctx, reply);
+                    continue;
+                }
+
+                // Per-mailbox sender blocklist set by the mailbox owner
+                let sender = state.sender.as_deref().unwrap_or("");
+                if ctx.lookup.is_sender_blocked(&address_lower, sender).await
+                    && !shadow_pass(ctx.config, ctx.metrics, ctx.peer_addr, "sender_blocked")
+                {
+                    if sampling::sampled("rcpt_sender_blocked", ctx.peer_addr.ip()) {
+                        info!(
+                            peer = %ctx.peer_addr,
+                            address = %address_lower,
+                            sender = sender,
+                            "[MAIL-REJECTED] sender blocked by mailbox owner"
+                        );
+                    }
+                    ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    state.reject_rcpt();
+                    record_rejection(ctx.peer_addr.ip(), "sender_blocked");
+                    send_error_or_return!(
+                        reader,
+                        state,
+                        ctx,
+                        ctx.config.reply_templates.sender_blocked(&address)
+                    );
+                    continue;
+                }
+
+                if let Some(reply) =
+                    reputation_greylisted(ctx, sender, &address_lower, state.reputation_score).await
+                {
+                    state.reject_rcpt();
+                    send_error_or_return!(reader, state, ctx, reply);
+                    continue;
+                }
+
+                if let Some(reply) =
+                    recipient_rate_limited(ctx, &address_lower, state.allowed_network).await
+                {
+                    state.reject_rcpt();
+                    send_error_or_return!(reader, state, ctx, reply);
+                    continue;
+                }
+
+                if sampling::sampled("rcpt_accepted", ctx.peer_addr.ip()) {
+                    info!(
+                        peer = %ctx.peer_addr,
+                        address = %address_lower,
+                        "[RCPT-ACCEPTED] mailbox verified"
+                    );
+                }
+                state.rcpt_accepted += 1;
+                state.txn_accepted.push(address.clone());
+                record_verdict("accepted", outcome.as_str());
+                state.recipients.insert(address, rcpt_params);
+                state.phase = Phase::Rcpt;
+                send_or_return!(reader, reply::RECIPIENT_OK);
+            }
+
+            "DATA" => {
+                match state.phase {
+                    Phase::Rcpt => {}
+                    Phase::Mail => {
+                        send_error_or_return!(reader, state, ctx, reply::NO_VALID_RECIPIENTS);
+                        continue;
+                    }
+                    Phase::Connected | Phase::Greeted => {
+                        send_error_or_return!(reader, state, ctx, reply::NEED_MAIL);
+                        continue;
+                    }
+                }
+
+                let replies = if ctx.config.lmtp {
+                    state.txn_accepted.len()
+                } else {
+                    1
+                };
+
+                // 354 must reach the client before it sends the body
+                if let Err(e) = send_reply(reader.get_mut(), &reply::START_MAIL_INPUT).await {
+                    return LoopResult::Done(Err(e.into()));
+                }
+
+                let read = read_data(
+                    reader,
+                    ctx.config.max_message_size,
+                    state.declared_size,
+                    ctx.strict_crlf,
+                    ctx.config.check_dot_stuffing,
+                );
+                let Ok(data) = tokio::time::timeout(data_timeout, read).await else {
+                    state.finish_transaction("timeout", None);
+                    return close_timed_out(reader, state, ctx, "message data").await;
+                };
+                let data = match data {
+                    Ok(data) => data,
+                    Err(DataError::TooLarge) => {
+                        // Body was drained to the terminator, so the session
+                        // is still in sync and can start a new transaction.
+                        info!(
+                            peer = %ctx.peer_addr,
+                            max = ctx.config.max_message_size,
+                            "[MAIL-REJECTED] message exceeds maximum size"
+                        );
+                        ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                        record_rejection(ctx.peer_addr.ip(), "message_too_large");
+                        state.finish_transaction("too_large", None);
+                        send_data_reply_or_return!(
+                            reader,
+                            state,
+                            replies,
+                            reply::MESSAGE_TOO_LARGE
+                        );
+                        continue;
+                    }
+                    Err(DataError::BareLineEnding) => {
+                        info!(
+                            peer = %ctx.peer_addr,
+                            "[MAIL-REJECTED] bare CR or LF in message"
+                        );
+                        ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                        record_rejection(ctx.peer_addr.ip(), "bare_line_ending");
+                        state.finish_transaction("bare_line_ending", None);
+                        send_data_reply_or_return!(
+                            reader,
+                            state,
+                            replies,
+                            reply::BARE_LINE_ENDING_MESSAGE
+                        );
+                        continue;
+                    }
+                    Err(DataError::NotDotStuffed) => {
+                        info!(
+                            peer = %ctx.peer_addr,
+                            "[MAIL-REJECTED] message is not dot-stuffed"
+                        );
+                        ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                        record_rejection(ctx.peer_addr.ip(), "not_dot_stuffed");
+                        state.finish_transaction("not_dot_stuffed", None);
+                        send_data_reply_or_return!(reader, state, replies, reply::NOT_DOT_STUFFED);
+                        continue;
+                    }
+                    Err(DataError::TerminatorConfusion) => {
+                        warn!(
+                            peer = %ctx.peer_addr,
+                            "[MAIL-REJECTED] ambiguous end-of-data sequence, closing connection"
+                        );
+                        ctx.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                        record_rejection(ctx.peer_addr.ip(), "terminator_confusion");
+                        state.finish_transaction("terminator_confusion", None);
+                        for _ in 0..replies {
+                            let _ =
+                                send_reply(reader.get_mut(), &reply::AMBIGUOUS_END_OF_DATA).await;
+                        }
+                        return LoopResult::Done(Ok(()));
+                    }
+                    Err(e) => {
+                        debug!(peer = %ctx.peer_addr, error = %e, "data read error");
+                        return LoopResult::Done(Ok(()));
+                    }
+                };
+
+                if state.spamtrap {
+                    debug!(peer = %ctx.peer_addr, size = data.len(), "message to a spamtrap dropped");
+                    state.finish_transaction("spamtrap", Some(data.len()));
+                    send_data_reply_or_return!(reader, state, replies, reply::MESSAGE_ACCEPTED);
+                    continue;
+                }
+
+                if ctx.config.received_header && state.rdns.is_none() {
+                    let rdns = reverse_dns(ctx.resolver, ctx.peer_addr, true).await;
+                    state.rdns = Some(rdns.unwrap_or(Rdns::Missing));
+                }
+                let sender = state.sender.as_deref().unwrap_or("");
+                let recipients: Vec<relay::Recipient> = state
+                    .recipients
+                    .iter()
+                    .filter(|(address, _)| !is_self_test_recipient(ctx, address))
+                    .map(|(address, params)| relay::Recipient { address, params })
+                    .collect();
+
+                if recipients.is_empty() {
+                    // Self-test only: discard the message, but check the backend
+                    let probe = ctx.backend.probe();
+                    let probe = tokio::time::timeout(data_timeout, probe).await;
+                    let reply = match probe.unwrap_or(Err(relay::RelayError::Timeout)) {
+                        Ok(()) => reply::SELF_TEST_DISCARDED,
+                        Err(e) => {
+                            warn!(peer = %ctx.peer_addr, error = %e, "self-test backend probe failed");
+                            reply::BACKEND_UNAVAILABLE
+                        }
+                    };
+                    state.finish_transaction("discarded", Some(data.len()));
+                    send_data_reply_or_return!(reader, state, replies, reply);
+                    continue;
+                }
+
+                let size = data.len();
+                let addresses: Vec<&str> = recipients.iter().map(|r| r.address).collect();
+                let envelope = Envelope {
+                    ip: ctx.peer_addr.ip(),
+                    helo: state.helo.as_deref(),
+                    hostname: match &state.rdns {
+                        Some(Rdns::Confirmed(name)) => Some(name),
+                        _ => None,
+                    },
+                    sender,
+                    recipients: &addresses,
+                    queue_id: &state.id,
+                };
+                let rule_fields = match content_rules(ctx, &data, state.score()) {
+                    Ok(fields) => fields,
+                    Err((reason, reply)) => {
+                        state.finish_transaction(reason, Some(size));
+                        send_data_reply_or_return!(reader, state, replies, reply);
+                        continue;
+                    }
+                };
+                if let Some((reason, reply)) = virus_check(ctx, &data).await {
+                    state.finish_transaction(reason, Some(size));
+                    send_data_reply_or_return!(reader, state, replies, reply);
+                    continue;
+                }
+                let spam_fields = match spam_check(ctx, &envelope, &data).await {
+                    Ok(fields) => fields,
+                    Err((reason, reply)) => {
+                        state.finish_transaction(reason, Some(size));
+                        send_data_reply_or_return!(reader, state, replies, reply);
+                        continue;
+                    }
+                };
+                let mut data = data;
+                let mut fields = gateway_headers(state, ctx, &recipients);
+                for (name, value) in rule_fields.iter().chain(&spam_fields) {
+                    fields.push(name, value);
+                }
+                if !fields.is_empty() && !fields.prepend_to(&mut data) {
+                    warn!(
+                        peer = %ctx.peer_addr,
+                        "message has no header section, trace headers not added"
+                    );
+                }
+                let client = ctx.config.backend_forward_client.then(|| ClientInfo {
+                    addr: ctx.peer_addr,
+                    helo: state.helo.clone(),
+                    proto: format!("{}{}", state.protocol, if state.tls { "S" } else { "" }),
+                });
+                let relay = ctx.backend.relay_in(
+                    &mut state.backend_session,
+                    client.as_ref(),
+                    sender,
+                    &state.mail_params,
+                    &recipients,
+                    &data,
+                );
+                let relayed = tokio::time::timeout(data_timeout, relay).await;
+                // LMTP clients get the backend's verdict for each recipient
+                let mut refused = HashMap::new();
+                let (outcome, reply) = match relayed.unwrap_or(Err(relay::RelayError::Timeout)) {
+                    Ok(relayed) => {
+                        refused.extend(
+                            relayed
+                                .rejected
+                                .iter()
+                                .map(|(address, refusal)| (*address, reply::downstream(refusal))),
+                        );
+                        tracing::Span::current().record("relay.outcome", "relayed");
+                        record_verdict("relayed", "ok");
+                        state.messages_relayed += 1;
+                        reputation_event(ctx, state.allowed_network, Event::Delivered).await;
+                        ctx.metrics
+                            .accepted
+                            .fetch_add(relayed.delivered.len() as u64, Ordering::Relaxed);
+                        info!(
+                            peer = %ctx.peer_addr,
+                            sender = sender,
+                            recipients = %AddressList(relayed.delivered.iter()),
+                            refused = %AddressList(relayed.rejected.iter().map(|(a, _)| a)),
+                            size = size,
+                            "[MAIL-RELAYED] forwarded to backend"
+                        );
+                        for address in &relayed.delivered {
+                            ctx.lookup.delivered(address).await;
+                        }
+                        if let Some(stats) = ctx.stats {
+                            stats.submit(relayed.delivered.iter().map(|a| a.to_string()).collect());
+                        }
+                        if let Some(webhook) = ctx.webhook {
+                            webhook.submit(DeliveryEvent {
+                                session_id: Some(state.id.clone()),
+                                sender: sender.to_string(),
+                                recipients: relayed
+                                    .delivered
+                                    .iter()
+                                    .map(|a| a.to_string())
+                                    .collect(),
+                                size: data.len(),
+                                message_id: headers::field(&data, "Message-ID"),
+                            });
+                        }
+                        if let Some(mirror) = ctx.mirror {
+                            mirror.submit(MirrorMessage {
+                                client,
+                                sender: sender.to_string(),
+                                mail_params: state.mail_params.clone(),
+                                recipients: recipients
+                                    .iter()
+                                    .map(|r| (r.address.to_string(), r.params.clone()))
+                                    .collect(),
+                                data,
+                            });
+                        }
+                        ("relayed", reply::MESSAGE_ACCEPTED)
+                    }
+                    Err(e) => {
+                        ctx.metrics.relay_errors.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            peer = %ctx.peer_addr,
+                            error = %e,
+                            "[RELAY-ERROR] failed to forward to backend"
+                        );
+                        if let Some(dead_letter) = ctx.dead_letter.filter(|_| e.is_permanent()) {
+                            dead_letter
+                                .store(&DeadMessage {
+                                    session_id: Some(&state.id),
+                                    client: client.as_ref(),
+                                    sender,
+                                    mail_params: &state.mail_params,
+                                    recipients: &recipients,
+                                    error: &e,
+                                    data: &data,
+                                })
+                                .await;
+                        }
+                        let spooled = match ctx.spool {
+                            Some(spool) if !e.is_permanent() => {
+                                let spooled = spool
+                                    .enqueue(
+                                        client.as_ref(),
+                                        sender,
+                                        &state.mail_params,
+                                        &recipients,
+                                        &data,
+                                    )
+                                    .await;
+                                if let Err(se) = &spooled {
+                                    warn!(peer = %ctx.peer_addr, error = %se, "[SPOOL-ERROR] message not spooled");
+                                }
+                                spooled.is_ok()
+                            }
+                            _ => false,
+                        };
+                        if spooled {
+                            tracing::Span::current().record("relay.outcome", "spooled");
+                            record_verdict("spooled", e.kind());
+                            state.messages_relayed += 1;
+                            ctx.metrics.spooled.fetch_add(1, Ordering::Relaxed);
+                            ctx.metrics
+                                .accepted
+                                .fetch_add(recipients.len() as u64, Ordering::Relaxed);
+                            info!(
+                                peer = %ctx.peer_addr,
+                                sender = sender,
+                                recipients = %AddressList(recipients.iter().map(|r| r.address)),
+                                size = size,
+                                "[MAIL-SPOOLED] backend unavailable, queued for retry"
+                            );
+                            ("spooled", reply::MESSAGE_QUEUED)
+                        } else if let relay::RelayError::Rejected { reply: refusal, .. } = &e {
+                            // No recipient was taken: pass the refusal on
+                            tracing::Span::current().record("relay.outcome", "rejected");
+                            record_verdict("relay_rejected", e.kind());
+                            ("relay_rejected", reply::downstream(refusal))
+                        } else {
+                            tracing::Span::current().record("relay.outcome", "failed");
+                            record_verdict("relay_failed", e.kind());
+                            ("relay_failed", reply::RELAY_FAILED)
+                        }
+                    }
+                };
+
+                if ctx.config.lmtp {
+                    let mut accepted = false;
+                    for address in &state.txn_accepted {
+                        let reply = refused.get(address.as_str()).unwrap_or(&reply);
+                        send_or_return!(reader, reply);
+                        accepted |= reply.is_positive();
+                    }
+                    if accepted {
+                        state.messages += 1;
+                    }
+                } else {
+                    send_data_reply_or_return!(reader, state, 1, reply);
+                }
+                state.finish_transaction(outcome, Some(size));
+            }
+
+            "RSET" => {
+                state.reset_transaction();
+                send_or_return!(reader, reply::OK);
+            }
+
+            "NOOP" => {
+                send_or_return!(reader, reply::OK);
+            }
+
+            "QUIT" => {
+                let _ = send_reply(reader.get_mut(), &reply::BYE).await;
+                return LoopResult::Done(Ok(()));
+            }
+
+            "HELP" => {
+                let greeting = if ctx.config.lmtp { "LHLO" } else { "EHLO HELO" };
+                let mut commands = format!("{} MAIL RCPT DATA RSET NOOP QUIT VRFY HELP", greeting);
+                if ctx.tls_config.is_some() && !ctx.tls_active {
+                    commands.push_str(" STARTTLS");
+                }
+                let mut text = format!("burngate supported commands:\n  {}", commands);
+                if let Some(url) = &ctx.config.help_url {
+                    text.push_str(&format!("\nFor more information see {}", url));
+                }
+                let status = Some(EnhancedStatus::new(2, 0, 0));
+                send_or_return!(reader, SmtpReply::formatted(214, status, text));
+            }
+
+            "VRFY" => {
+                if !ctx.config.vrfy_lookup {
+                    send_or_return!(reader, reply::CANNOT_VRFY);
+                    continue;
+                }
+
+                // Accept both `VRFY <user@domain>` and bare `VRFY user@domain`
+                let address = extract_address(args).unwrap_or_else(|| args.to_string());
+                let normalized =
+                    address::validate(&address).and_then(|()| address::normalize(&address, false));
+                let address = match normalized {
+                    Ok(address) => address,
+                    _ => {
+                        send_error_or_return!(reader, state, ctx, reply::VRFY_SYNTAX);
+                        continue;
+                    }
+                };
+
+                let domain = address::domain(&address);
+                let exists = is_domain_accepted(domain, &ctx.config.accepted_domains)
+                    && ctx.lookup.should_accept(&address).await;
+                debug!(peer = %ctx.peer_addr, address = %address, exists, "VRFY lookup");
+                if exists {
+                    let text = format!("<{}>", address);
+                    let status = Some(EnhancedStatus::new(2, 1, 5));
+                    send_or_return!(reader, SmtpReply::formatted(250, status, text));
+                } else {
+                    rejection_delay(ctx).await;
+                    send_error_or_return!(
+                        reader,
+                        state,
+                        ctx,
+                        ctx.config.reply_templates.user_unknown(&address)
+                    );
+                }
+            }
+
+            "" => {}
+
+            _ => {
+                send_error_or_return!(reader, state, ctx, reply::NOT_RECOGNIZED);
+            }
+        }
+    }
+}
+
+/// Check if a domain (or its parent) is in the accepted set.
+/// Supports subdomain matching: `abc.tempy.email` matches if `tempy.email` is accepted.
+pub fn is_domain_accepted(domain: &str, accepted: &std::collections::HashSet<String>) -> bool {
+    accepted.contains(domain)
+        || domain
+            .find('.')
+            .and_then(|i| domain.get(i + 1..))
+            .map(|parent| accepted.contains(parent))
+            .unwrap_or(false)
+}
+
+/// Whether unknown mailboxes in `domain` get a 450 instead of a 550.
+fn is_soft_fail_domain(config: &Config, domain: &str) -> bool {
+    config.soft_fail_unknown || is_domain_accepted(domain, &config.soft_fail_domains)
+}
+
+/// Parse the first word (command) and the rest (arguments) from an SMTP line.
+/// The command is uppercased for case-insensitive matching per RFC 5321.
+/// Uses a stack-allocated `ArrayString<8>` since SMTP commands are at most 8 bytes.
+pub fn parse_command(line: &str) -> (ArrayString<8>, &str) {
+    let trimmed = line.trim();
+    match trimmed.find(' ') {
+        Some(pos) => {
+            let mut cmd = ArrayString::<8>::new();
+            for ch in trimmed[..pos].chars().take(8) {
+                let _ = cmd.try_push(ch.to_ascii_uppercase());
+            }
+            (cmd, trimmed[pos + 1..].trim())
+        }
+        None => {
+            let mut cmd = ArrayString::<8>::new();
+            for ch in trimmed.chars().take(8) {
+                let _ = cmd.try_push(ch.to_ascii_uppercase());
+            }
+            (cmd, "")
+        }
+    }
+}
+
+/// Extract an email address from SMTP arguments like `FROM:<addr>` or `TO:<addr>`.
+///
+/// Quoted local parts may contain `>` (`TO:<"a>b"@example.com>`), and a
+/// source route (`TO:<@relay.example:user@example.com>`) is dropped.
+pub fn extract_address(args: &str) -> Option<String> {
+    let path = address::find_path(args)?;
+    let inner = address::strip_source_route(&args[path.start + 1..path.end - 1]);
+    if inner.is_empty() {
+        None
+    } else {
+        Some(inner.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryStore, Store};
+    use tokio::io::BufReader;
+
+    fn test_config() -> Config {
+        std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+        Config::from_env()
+    }
+
+    /// Drive the command loop with `input`, closing the client side after
+    /// it unless `keep_open`. Returns the raw replies and the final state.
+    /// `alice@example.com` is the only existing mailbox.
+    async fn run_loop(config: Config, input: &str, keep_open: bool) -> (String, SessionState) {
+        run_loop_in(test_store().await, config, input, keep_open).await
+    }
+
+    async fn test_store() -> Arc<MemoryStore> {
+        let store = Arc::new(MemoryStore::new());
+        store
+            .set_ex("mb:alice@example.com", "1", 3600)
+            .await
+            .unwrap();
+        // With REDIS_SET_NAME=broken, lookups of unknown mailboxes fail
+        store.hash_set("broken", "f", "v");
+        store.hash_set("aliases", "info@example.com", "sales@example.com");
+        store.hash_set("aliases", "sales@example.com", "alice@example.com");
+        store.hash_set("aliases", "ping@example.com", "pong@example.com");
+        store.hash_set("aliases", "pong@example.com", "ping@example.com");
+        store
+    }
+
+    /// `run_loop` against `store`, to look at what the session left in it.
+    async fn run_loop_in(
+        store: Arc<MemoryStore>,
+        config: Config,
+        input: &str,
+        keep_open: bool,
+    ) -> (String, SessionState) {
+        let metrics = Arc::new(Metrics::new());
+        let lookup = crate::lookup::from_config(store.clone(), &config, metrics.clone());
+        let dead_letter = DeadLetter::from_config(&config, store.clone(), metrics.clone()).unwrap();
+        let tls_config = None;
+        let backend = Backend::from_config(&config);
+        let resolver = Resolver::new(Vec::new(), Duration::from_secs(1));
+        let sender_limiter = StoreRateLimiter::senders(store.clone(), &config);
+        let recipient_limiters = StoreRateLimiter::recipients(store.clone(), &config);
+        let auto_block = AutoBlocklist::from_config(store.clone(), &config, metrics.clone());
+        let rspamd = config
+            .rspamd_url
+            .as_deref()
+            .map(|url| Rspamd::new(url, &config));
+        let spamd = config
+            .spamd_addr
+            .as_deref()
+            .map(|addr| Spamd::new(addr, &config));
+        let clamav = config
+            .clamav_addr
+            .as_deref()
+            .map(|addr| ClamAv::new(addr, &config));
+        let rules = config
+            .rules_file
+            .as_deref()
+            .map(|path| RuleSet::load(path).unwrap());
+        let spamtraps = Spamtraps::from_config(store.clone(), &config);
+        let reputation = Reputation::from_config(store.clone(), &config);
+        let ctx = SmtpContext {
+            peer_addr: "192.0.2.1:40000".parse().unwrap(),
+            config: &config,
+            lookup: lookup.as_ref(),
+            tls_config: &tls_config,
+            metrics: &metrics,
+            backend: &backend,
+            mirror: None,
+            spool: None,
+            webhook: None,
+            dead_letter: dead_letter.as_ref(),
+            stats: None,
+            resolver: &resolver,
+            dnsbl: None,
+            sender_limiter: sender_limiter.as_ref(),
+            recipient_limiters: &recipient_limiters,
+            auto_block: auto_block.as_ref(),
+            rspamd: rspamd.as_ref(),
+            spamd: spamd.as_ref(),
+            clamav: clamav.as_ref(),
+            rules: rules.as_ref(),
+            spamtraps: spamtraps.as_ref(),
+            reputation: reputation.as_ref(),
+            tls_active: false,
+            strict_crlf: false,
+            require_tls: false,
+        };
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(input.as_bytes()).await.unwrap();
+        if !keep_open {
+            client.shutdown().await.unwrap();
+        }
+        let mut reader = BufReader::new(BufWriter::new(server));
+        let mut state = SessionState::new();
+        let _ = smtp_loop(&mut reader, &mut state, &ctx).await;
+        drop(reader);
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        (out, state)
+    }
+
+    /// Run `input` through the command loop and return the final line of
+    /// each reply.
+    async fn converse(input: &str) -> Vec<String> {
+        let (out, _) = run_loop(test_config(), input, false).await;
+        out.lines()
+            .filter(|line| line.as_bytes().get(3) != Some(&b'-'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    // -- read_line (bounded) --
+
+    #[tokio::test]
+    async fn read_line_normal() {
+        let input = b"EHLO example.com\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(result, Some("EHLO example.com".to_string()));
+    }
+
+    #[tokio::test]
+    async fn read_line_lf_only() {
+        let input = b"QUIT\n";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(result, Some("QUIT".to_string()));
+    }
+
+    #[tokio::test]
+    async fn read_line_eof_empty() {
+        let input = b"";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[tokio::test]
+    async fn read_line_eof_with_partial_data() {
+        let input = b"PARTIAL";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(result, Some("PARTIAL".to_string()));
+    }
+
+    #[tokio::test]
+    async fn read_line_exceeds_limit() {
+        // 10 bytes of 'A' without a newline, limit of 5
+        let input = b"AAAAAAAAAA";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+        let result = read_line(&mut reader, &mut buf, 5).await;
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn read_line_exactly_at_limit() {
+        // 5 bytes + newline, limit of 5 — should succeed
+        let input = b"ABCDE\n";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+        let result = read_line(&mut reader, &mut buf, 5).await.unwrap();
+        assert_eq!(result, Some("ABCDE".to_string()));
+    }
+
+    #[tokio::test]
+    async fn read_line_one_over_limit() {
+        // 6 bytes without newline, limit of 5 — should fail
+        let input = b"ABCDEF\n";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+        let result = read_line(&mut reader, &mut buf, 5).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_line_multiple_lines() {
+        let input = b"LINE1\r\nLINE2\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let mut buf = Vec::new();
+
+        let r1 = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(r1, Some("LINE1".to_string()));
+
+        let r2 = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(r2, Some("LINE2".to_string()));
+
+        let r3 = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(r3, None);
+    }
+
+    // -- read_data (no dot-unstuffing, raw wire format) --
+
+    #[tokio::test]
+    async fn read_data_simple_message() {
+        let input = b"Subject: test\r\n\r\nHello world\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, false)
+            .await
+            .unwrap();
+        assert_eq!(data, b"Subject: test\r\n\r\nHello world\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_preserves_dot_stuffing() {
+        // ".." lines should be passed through raw (no unstuffing)
+        let input = b"..leading dot\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, false)
+            .await
+            .unwrap();
+        // Raw wire format: the ".." is preserved
+        assert_eq!(data, b"..leading dot\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_dot_only_terminates() {
+        let input = b"line1\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, false)
+            .await
+            .unwrap();
+        assert_eq!(data, b"line1\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_dot_lf_terminates() {
+        // Lone "." with just LF (no CR)
+        let input = b"line1\n.\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, false)
+            .await
+            .unwrap();
+        assert_eq!(data, b"line1\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_exceeds_max_size() {
+        // 100 bytes of data, max_size of 10
+        let mut input = Vec::new();
+        for _ in 0..20 {
+            input.extend_from_slice(b"AAAAA\r\n");
+        }
+        input.extend_from_slice(b".\r\n");
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10, None, false, false).await;
+        assert!(matches!(result, Err(DataError::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn read_data_oversized_drains_to_terminator() {
+        // After the limit is crossed the rest of the body is discarded, so the
+        // next read sees the command following the terminator.
+        let mut input = Vec::new();
+        for _ in 0..20 {
+            input.extend_from_slice(b"AAAAA\r\n");
+        }
+        input.extend_from_slice(b".\r\nQUIT\r\n");
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10, Some(5), false, false).await;
+        assert!(matches!(result, Err(DataError::TooLarge)));
+
+        let mut buf = Vec::new();
+        let next = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(next, Some("QUIT".to_string()));
+    }
+
+    #[tokio::test]
+    async fn read_data_long_line_split_into_chunks() {
+        // A line longer than DATA_CHUNK is read in fragments; a fragment
+        // boundary must not be mistaken for a line start.
+        let mut input = vec![b'A'; DATA_CHUNK as usize - 1];
+        input.extend_from_slice(b".\r\n.\r\n");
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 100_000, None, false, false)
+            .await
+            .unwrap();
+        assert_eq!(data.len(), DATA_CHUNK as usize + 2);
+    }
+
+    #[tokio::test]
+    async fn read_data_size_hint_does_not_change_content() {
+        let input = b"line1\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, Some(usize::MAX), false, false)
+            .await
+            .unwrap();
+        assert_eq!(data, b"line1\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_eof_before_terminator() {
+        let input = b"line1\r\nline2\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10_000, None, false, false).await;
+        assert!(matches!(
+            result,
+            Err(DataError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_data_empty_message() {
+        // Just a terminator, no body
+        let input = b".\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, false)
+            .await
+            .unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_data_dot_in_middle_of_line_not_terminator() {
+        // A line with "." in it but not alone
+        let input = b".not-a-terminator\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, false)
+            .await
+            .unwrap();
+        // ".not-a-terminator" is not a lone ".", so it's included in data
+        assert_eq!(data, b".not-a-terminator\r\n");
+    }
+
+    // -- strict CRLF (SMTP smuggling) --
+
+    #[test]
+    fn bare_line_ending_detection() {
+        assert!(!has_bare_line_ending(b"QUIT\r"));
+        assert!(has_bare_line_ending(b"QUIT"));
+        assert!(has_bare_line_ending(
+            b"MAIL FROM:<a@b.c>\rRCPT TO:<d@e.f>\r"
+        ));
+        assert!(has_bare_line_ending(b""));
+    }
+
+    #[tokio::test]
+    async fn read_data_strict_accepts_crlf_message() {
+        let input = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, true, false)
+            .await
+            .unwrap();
+        assert_eq!(data, b"Subject: hi\r\n\r\nbody\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_strict_ignores_lf_dot_lf() {
+        // "<LF>.<LF>" must not end the message; the smuggled MAIL stays in the
+        // body and the message is rejected once the real terminator arrives.
+        let input = b"body\n.\nMAIL FROM:<spoof@x.y>\r\n.\r\nQUIT\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10_000, None, true, false).await;
+        assert!(matches!(result, Err(DataError::BareLineEnding)));
+
+        let mut buf = Vec::new();
+        let next = read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(next, Some("QUIT".to_string()));
+    }
+
+    #[tokio::test]
+    async fn read_data_strict_dot_after_bare_lf_not_terminator() {
+        let input = b"body\n.\r\nmore\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10_000, None, true, false).await;
+        assert!(matches!(result, Err(DataError::BareLineEnding)));
+        assert!(reader.buffer().is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_data_strict_rejects_bare_cr() {
+        let input = b"body\rmore\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10_000, None, true, false).await;
+        assert!(matches!(result, Err(DataError::BareLineEnding)));
+    }
+
+    #[tokio::test]
+    async fn read_data_strict_crlf_split_across_chunks() {
+        let mut input = vec![b'x'; DATA_CHUNK as usize - 1];
+        input.extend_from_slice(b"\r\n.\r\n");
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 100_000, None, true, false)
+            .await
+            .unwrap();
+        assert_eq!(data.len(), DATA_CHUNK as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn read_data_lf_terminator_in_crlf_message_is_confusion() {
+        let input = b"Subject: x\r\n\r\nbody\r\n.\nMAIL FROM:<spoof@x.y>\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10_000, None, false, false).await;
+        assert!(matches!(result, Err(DataError::TerminatorConfusion)));
+    }
+
+    #[tokio::test]
+    async fn read_data_dot_next_to_lone_cr_is_confusion() {
+        for input in [
+            &b"a\r.\rb\r\n.\r\n"[..],
+            b"a\r.\nb\r\n.\r\n",
+            b"a\n.\rb\r\n.\r\n",
+        ] {
+            let mut reader = BufReader::new(input);
+            let result = read_data(&mut reader, 10_000, None, false, false).await;
+            assert!(
+                matches!(result, Err(DataError::TerminatorConfusion)),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn read_data_lone_cr_without_dot_is_lenient() {
+        let input = b"a\rb\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, false)
+            .await
+            .unwrap();
+        assert_eq!(data, b"a\rb\r\n");
+    }
+
+    #[tokio::test]
+    async fn read_data_checks_dot_stuffing() {
+        let input = b"..stuffed\r\n.unstuffed\r\n.\r\nQUIT\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let result = read_data(&mut reader, 10_000, None, false, true).await;
+        assert!(matches!(result, Err(DataError::NotDotStuffed)));
+        assert_eq!(reader.buffer(), b"QUIT\r\n");
+
+        let input = b"..stuffed\r\n.\r\n";
+        let mut reader = BufReader::new(&input[..]);
+        let data = read_data(&mut reader, 10_000, None, false, true)
+            .await
+            .unwrap();
+        assert_eq!(data, b"..stuffed\r\n");
+    }
+
+    // -- pipelining --
+
+    #[tokio::test]
+    async fn replies_to_pipelined_group_flushed_once() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut client_rx, mut client_tx) = tokio::io::split(client);
+        client_tx
+            .write_all(b"MAIL FROM:<a@b.c>\r\nRCPT TO:<d@e.f>\r\n")
+            .await
+            .unwrap();
+
+        let mut reader = BufReader::new(BufWriter::new(server));
+        let mut buf = Vec::new();
+
+        read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        SmtpReply::without_status(250, "first")
+            .write_to(reader.get_mut())
+            .await
+            .unwrap();
+        flush_if_idle(&mut reader).await.unwrap();
+        // Second command is still buffered, so nothing has been written yet
+        assert!(reader.get_ref().buffer().starts_with(b"250 first"));
+
+        read_line(&mut reader, &mut buf, 1024).await.unwrap();
+        SmtpReply::without_status(250, "second")
+            .write_to(reader.get_mut())
+            .await
+            .unwrap();
+        flush_if_idle(&mut reader).await.unwrap();
+        assert!(reader.get_ref().buffer().is_empty());
+
+        let mut out = [0u8; 23];
+        client_rx.read_exact(&mut out).await.unwrap();
+        assert_eq!(&out, b"250 first\r\n250 second\r\n");
+    }
+
+    // -- pre-greet delay --
+
+    #[tokio::test(start_paused = true)]
+    async fn early_talker_detected() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"EHLO bot\r\n").await.unwrap();
+        let mut reader = BufReader::new(server);
+        assert!(talks_early(&mut reader, Duration::from_secs(3))
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn trusted_networks_are_greeted_at_once() {
+        let mut config = test_config();
+        config.greet_delay_ms = 2000;
+        config.trusted_networks = crate::network::parse_list("10.0.0.0/8").unwrap();
+        assert_eq!(
+            greet_delay(&config, "192.0.2.1".parse().unwrap()),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(greet_delay(&config, "10.1.2.3".parse().unwrap()), None);
+        config.greet_delay_ms = 0;
+        assert_eq!(greet_delay(&config, "192.0.2.1".parse().unwrap()), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn patient_client_passes() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let mut reader = BufReader::new(server);
+        let started = Instant::now();
+        assert!(!talks_early(&mut reader, Duration::from_secs(3))
+            .await
+            .unwrap());
+        assert_eq!(started.elapsed().as_secs(), 3);
+    }
+
+    // -- command sequencing --
+
+    #[tokio::test]
+    async fn mail_requires_greeting() {
+        let replies = converse("MAIL FROM:<a@b.c>\r\nEHLO x\r\nMAIL FROM:<a@b.c>\r\n").await;
+        assert!(replies[0].starts_with("503 5.5.1"));
+        assert!(replies[1].starts_with("250 "));
+        assert!(replies[2].starts_with("250 2.1.0"));
+    }
+
+    #[tokio::test]
+    async fn rcpt_requires_mail() {
+        let replies = converse("EHLO x\r\nRCPT TO:<alice@example.com>\r\n").await;
+        assert_eq!(replies[1], "503 5.5.1 Need MAIL command");
+    }
+
+    #[tokio::test]
+    async fn data_requires_recipient() {
+        let replies = converse(
+            "EHLO x\r\nDATA\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<nobody@example.com>\r\nDATA\r\n",
+        )
+        .await;
+        assert_eq!(replies[1], "503 5.5.1 Need MAIL command");
+        assert!(replies[3].starts_with("550 "));
+        assert_eq!(replies[4], "503 5.5.1 No valid recipients");
+    }
+
+    #[tokio::test]
+    async fn nested_mail_rejected_until_rset() {
+        let replies = converse(
+            "EHLO x\r\nMAIL FROM:<a@b.c>\r\nMAIL FROM:<d@e.f>\r\nRSET\r\nMAIL FROM:<d@e.f>\r\n",
+        )
+        .await;
+        assert_eq!(replies[2], "503 5.5.1 Nested MAIL command");
+        assert!(replies[4].starts_with("250 2.1.0"));
+    }
+
+    #[tokio::test]
+    async fn ehlo_aborts_transaction() {
+        let replies = converse(
+            "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nEHLO y\r\nDATA\r\n",
+        )
+        .await;
+        assert!(replies[2].starts_with("250 2.1.5"));
+        assert_eq!(replies[4], "503 5.5.1 Need MAIL command");
+    }
+
+    // -- timeouts --
+
+    /// Send `input` without closing the connection; returns the raw
+    /// replies and whether the session ended on a timeout.
+    async fn converse_open(input: &str, idle_secs: u64, data_secs: u64) -> (String, bool) {
+        let mut config = test_config();
+        config.idle_timeout_secs = idle_secs;
+        config.data_timeout_secs = data_secs;
+        let (out, state) = run_loop(config, input, true).await;
+        (out, state.timed_out)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_client_gets_421() {
+        let started = Instant::now();
+        let (out, timed_out) = converse_open("EHLO x\r\n", 30, 600).await;
+        assert!(timed_out);
+        assert!(out.ends_with("421 4.4.2 Timeout exceeded, closing connection\r\n"));
+        assert_eq!(started.elapsed().as_secs(), 30);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_data_uses_data_timeout() {
+        let started = Instant::now();
+        let input =
+            "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nDATA\r\nSubject: hi\r\n";
+        let (out, timed_out) = converse_open(input, 30, 600).await;
+        assert!(timed_out);
+        assert!(out.contains("354 "));
+        assert!(out.ends_with("421 4.4.2 Timeout exceeded, closing connection\r\n"));
+        assert_eq!(started.elapsed().as_secs(), 600);
+    }
+
+    // -- LOOKUP_ERROR_POLICY --
+
+    fn lookup_error_config(policy: LookupErrorPolicy) -> Config {
+        let mut config = test_config();
+        config.redis_set_name = "broken".to_string();
+        config.lookup_error_policy = policy;
+        config
+    }
+
+    const TWO_RCPTS: &str = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nRCPT TO:<bob@example.com>\r\n";
+
+    #[tokio::test]
+    async fn lookup_errors_reject_by_default() {
+        let (out, state) = run_loop(
+            lookup_error_config(LookupErrorPolicy::Reject),
+            TWO_RCPTS,
+            false,
+        )
+        .await;
+        assert!(out.ends_with("550 5.1.1 User unknown\r\n"));
+        assert_eq!(state.recipients.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lookup_errors_are_not_auto_block_strikes() {
+        let mut config = lookup_error_config(LookupErrorPolicy::Reject);
+        config.auto_block_threshold = 1;
+        let store = test_store().await;
+        let (out, _) = run_loop_in(store.clone(), config.clone(), TWO_RCPTS, false).await;
+        assert!(out.ends_with("550 5.1.1 User unknown\r\n"));
+        let auto_block =
+            AutoBlocklist::from_config(store, &config, Arc::new(Metrics::new())).unwrap();
+        assert!(!auto_block.is_blocked("192.0.2.1".parse().unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn lookup_errors_do_not_hurt_reputation() {
+        let mut config = lookup_error_config(LookupErrorPolicy::Reject);
+        config.reputation_enabled = true;
+        let store = test_store().await;
+        run_loop_in(store.clone(), config.clone(), TWO_RCPTS, false).await;
+        let reputation = Reputation::from_config(store, &config).unwrap();
+        assert_eq!(reputation.score("192.0.2.1".parse().unwrap()).await, 0.0);
+    }
+
+    #[tokio::test]
+    async fn lookup_errors_can_ask_for_a_retry() {
+        let (out, state) = run_loop(
+            lookup_error_config(LookupErrorPolicy::TempFail),
+            TWO_RCPTS,
+            false,
+        )
+        .await;
+        assert!(out.ends_with("451 4.3.0 Mailbox lookup unavailable\r\n"));
+        assert_eq!(state.recipients.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lookup_errors_can_fail_open() {
+        let (out, state) = run_loop(
+            lookup_error_config(LookupErrorPolicy::Accept),
+            TWO_RCPTS,
+            false,
+        )
+        .await;
+        assert!(out.ends_with("250 2.1.5 OK\r\n"));
+        assert_eq!(state.recipients.len(), 2);
+    }
+
+    // -- aliases --
+
+    #[tokio::test]
+    async fn alias_chain_is_relayed_to_its_mailbox() {
+        let mut config = test_config();
+        config.alias_hash = "aliases".to_string();
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<Info@example.com>\r\nRCPT TO:<ping@example.com>\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(replies[2], "250 2.1.5 OK");
+        assert_eq!(replies[3], "550 5.4.6 Alias loop detected");
+        assert_eq!(
+            state.recipients.keys().collect::<Vec<_>>(),
+            ["alice@example.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases_of_one_mailbox_are_one_recipient() {
+        for (preserve_local_case, first) in
+            [(false, "alice@example.com"), (true, "Alice@example.com")]
+        {
+            let mut config = test_config();
+            config.alias_hash = "aliases".to_string();
+            config.preserve_local_case = preserve_local_case;
+            let input = format!(
+                "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<{first}>\r\n\
+                 RCPT TO:<info@example.com>\r\nRCPT TO:<sales@example.com>\r\nQUIT\r\n"
+            );
+            let (out, state) = run_loop(config, &input, false).await;
+            let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+            assert!(replies[2..5].iter().all(|r| *r == "250 2.1.5 OK"));
+            assert_eq!(state.recipients.keys().collect::<Vec<_>>(), [first]);
+            assert_eq!(state.recipient_count, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn aliases_are_ignored_unless_configured() {
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<info@example.com>\r\n";
+        let (out, state) = run_loop(test_config(), input, false).await;
+        assert!(out.ends_with("550 5.1.1 User unknown\r\n"));
+        assert!(state.recipients.is_empty());
+    }
+
+    // -- audit summary --
+
+    #[tokio::test]
+    async fn transactions_recorded_with_outcomes() {
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nRCPT TO:<nobody@example.com>\r\nRSET\r\nMAIL FROM:<>\r\nRCPT TO:<alice@example.com>\r\nBOGUS\r\n";
+        let (_, mut state) = run_loop(test_config(), input, false).await;
+        state.finish_transaction("aborted", None);
+
+        assert_eq!(state.commands["RCPT"], 3);
+        assert_eq!(state.commands["UNKNOWN"], 1);
+        assert_eq!(state.transactions.len(), 2);
+        let first = &state.transactions[0];
+        assert_eq!(first.sender, "a@b.c");
+        assert_eq!(first.recipients, ["alice@example.com"]);
+        assert_eq!(first.rcpt_rejected, 1);
+        assert_eq!(first.outcome, "aborted");
+        assert_eq!(state.transactions[1].sender, "");
+        assert_eq!(state.transactions[1].rcpt_rejected, 0);
+    }
+
+    #[tokio::test]
+    async fn oversized_message_recorded() {
+        let mut config = test_config();
+        config.max_message_size = 4;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nDATA\r\ntoo long\r\n.\r\n";
+        let (_, state) = run_loop(config, input, false).await;
+        assert_eq!(state.transactions.len(), 1);
+        assert_eq!(state.transactions[0].outcome, "too_large");
+    }
+
+    // -- address normalization --
+
+    #[tokio::test]
+    async fn rcpt_matches_regardless_of_spelling() {
+        let replies = converse(
+            "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<ALICE@Example.COM.>\r\nRCPT TO:<\"alice\"@example.com>\r\n",
+        )
+        .await;
+        assert!(replies[2].starts_with("250 2.1.5"));
+        assert!(replies[3].starts_with("250 2.1.5"));
+    }
+
+    #[tokio::test]
+    async fn idn_domain_accepted_in_either_form() {
+        let mut config = test_config();
+        config.accepted_domains = ["xn--bcher-kva.example".to_string()].into();
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<bob@Bücher.example>\r\nRCPT TO:<bob@mail.xn--bcher-kva.example>\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| l.starts_with("550")).collect();
+        assert_eq!(replies, ["550 5.1.1 User unknown"; 2]);
+    }
+
+    #[tokio::test]
+    async fn malformed_addresses_rejected() {
+        let replies = converse(
+            "EHLO x\r\nMAIL FROM:<a b@c.d>\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<ali ce@example.com>\r\nRCPT TO:<alice@exa_mple.com>\r\n",
+        )
+        .await;
+        assert_eq!(replies[1], "501 5.1.7 Bad sender address syntax");
+        assert!(replies[2].starts_with("250 2.1.0"));
+        assert_eq!(replies[3], "501 5.1.3 Bad recipient address syntax");
+        assert_eq!(replies[4], "501 5.1.3 Bad recipient address syntax");
+    }
+
+    #[tokio::test]
+    async fn rcpt_invalid_idn_rejected() {
+        let replies =
+            converse("EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@xn--a.example.com>\r\n").await;
+        assert_eq!(replies[2], "501 5.1.3 Bad recipient address syntax");
+    }
+
+    // -- ESMTP parameters --
+
+    #[tokio::test]
+    async fn invalid_parameter_values_rejected() {
+        let replies = converse(
+            "EHLO x\r\nMAIL FROM:<a@b.c> BODY=BINARYMIME\r\nMAIL FROM:<a@b.c> SIZE=abc\r\nMAIL FROM:<a@b.c> BODY=8BITMIME\r\nRCPT TO:<alice@example.com> NOTIFY=NEVER,DELAY\r\n",
+        )
+        .await;
+        assert_eq!(replies[1], "555 5.5.4 Unsupported BODY value");
+        assert_eq!(replies[2], "501 5.5.4 Invalid SIZE parameter");
+        assert!(replies[3].starts_with("250 2.1.0"));
+        assert_eq!(replies[4], "501 5.5.4 Invalid NOTIFY parameter");
+    }
+
+    // -- rejection delay --
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_user_rejection_delayed() {
+        let mut config = test_config();
+        config.reject_delay_ms = 2000;
+        config.reject_delay_jitter_ms = 500;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nRCPT TO:<nobody@example.com>\r\n";
+        let started = Instant::now();
+        let (out, _) = run_loop(config, input, false).await;
+        let elapsed = started.elapsed().as_millis();
+        assert!(out.contains("550 5.1.1 User unknown"));
+        assert!((2000..=2500).contains(&elapsed), "{elapsed}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trusted_network_not_delayed() {
+        let mut config = test_config();
+        config.reject_delay_ms = 2000;
+        config.trusted_networks = crate::network::parse_list("192.0.2.0/24").unwrap();
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<nobody@example.com>\r\n";
+        let started = Instant::now();
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("550 5.1.1 User unknown"));
+        assert_eq!(started.elapsed().as_millis(), 0);
+    }
+
+    // -- error budget --
+
+    #[tokio::test]
+    async fn too_many_errors_closes_session() {
+        let mut input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\n".to_string();
+        for i in 0..9 {
+            input.push_str(&format!("RCPT TO:<nobody{}@example.com>\r\n", i));
+        }
+        input.push_str("BOGUS\r\nNOOP\r\n");
+        let replies = converse(&input).await;
+        assert_eq!(replies[11], "502 5.5.2 Command not recognized");
+        assert_eq!(replies[12], "421 4.7.0 Too many errors, closing connection");
+        assert_eq!(replies.len(), 13);
+    }
+
+    #[tokio::test]
+    async fn accepted_commands_do_not_count() {
+        let mut input = "EHLO x\r\n".to_string();
+        for _ in 0..20 {
+            input.push_str("NOOP\r\n");
+        }
+        input.push_str("BOGUS\r\n");
+        let replies = converse(&input).await;
+        assert_eq!(replies.last().unwrap(), "502 5.5.2 Command not recognized");
+    }
+
+    // -- greeting --
+
+    #[tokio::test]
+    async fn helo_reply_is_single_line() {
+        let (out, _) = run_loop(test_config(), "HELO x\r\n", false).await;
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.starts_with("250 ") && out.ends_with(" Hello x\r\n"));
+    }
+
+    #[tokio::test]
+    async fn helo_checks_reject_or_score_greeting_names() {
+        let mut config = test_config();
+        config.helo_checks = vec![
+            (HeloCheck::BareIp, DnsblAction::Reject),
+            (HeloCheck::NonFqdn, DnsblAction::Score(3)),
+            (HeloCheck::LiteralMismatch, DnsblAction::Score(3)),
+        ];
+        config.dnsbl_score_threshold = 5;
+        let input = "EHLO 192.0.2.1\r\nEHLO [198.51.100.7]\r\nHELO x\r\nMAIL FROM:<a@b.c>\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(
+            replies[0],
+            "550 5.7.1 Helo command rejected: IP address must be an address literal"
+        );
+        // A score below the threshold only logs
+        assert!(replies[1].starts_with("250 "));
+        assert!(replies[2].starts_with("250 ") && replies[2].ends_with(" Hello x"));
+        assert_eq!(replies[3], "250 2.1.0 OK");
+        assert_eq!(state.helo_score, 3);
 
-                let sender = state.sender.as_deref().unwrap_or("");
-                let recipients: Vec<String> = state.recipients.iter().cloned().collect();
+        let mut config = test_config();
+        config.helo_checks = vec![
+            (HeloCheck::NonFqdn, DnsblAction::Score(3)),
+            (HeloCheck::OwnName, DnsblAction::Score(3)),
+        ];
+        config.server_name = "mx".to_string();
+        let (out, state) = run_loop(config, "EHLO mx\r\nMAIL FROM:<a@b.c>\r\n", false).await;
+        assert!(out.starts_with("550 5.7.1 Helo command rejected: that is my name\r\n"));
+        assert!(out.ends_with("503 5.5.1 Send EHLO/HELO first\r\n"));
+        assert_eq!(state.phase, Phase::Connected);
+    }
 
-                match relay::relay_message(&ctx.config.backend_addr, sender, &recipients, &data)
-                    .await
-                {
-                    Ok(()) => {
-                        ctx.metrics
-                            .accepted
-                            .fetch_add(recipients.len() as u64, Ordering::Relaxed);
-                        info!(
-                            peer = %ctx.peer_addr,
-                            sender = sender,
-                            recipients = ?recipients,
-                            size = data.len(),
-                            "[MAIL-RELAYED] forwarded to backend"
-                        );
-                        send_or_return!(reader, "250 2.0.0 OK message accepted");
-                    }
-                    Err(e) => {
-                        ctx.metrics.relay_errors.fetch_add(1, Ordering::Relaxed);
-                        warn!(
-                            peer = %ctx.peer_addr,
-                            error = %e,
-                            "[RELAY-ERROR] failed to forward to backend"
-                        );
-                        send_or_return!(
-                            reader,
-                            "451 4.3.0 Temporary relay failure, try again later"
-                        );
-                    }
-                }
+    // -- sender rate limit --
 
-                state.reset_transaction();
-            }
+    #[tokio::test]
+    async fn senders_over_their_rate_are_deferred() {
+        let mut config = test_config();
+        config.sender_rate_limit = 2;
+        config.sender_rate_by = RateLimitBy::Domain;
+        let input = "EHLO x\r\nMAIL FROM:<a@Flood.example>\r\nRSET\r\n\
+                     MAIL FROM:<b@flood.example>\r\nRSET\r\n\
+                     MAIL FROM:<c@flood.example>\r\nMAIL FROM:<>\r\nRSET\r\n\
+                     MAIL FROM:<a@other.example>\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out
+            .lines()
+            .filter(|l| !l.starts_with("250-") && *l != "250 2.0.0 OK")
+            .collect();
+        assert_eq!(replies[1], "250 2.1.0 OK");
+        assert_eq!(replies[2], "250 2.1.0 OK");
+        assert_eq!(
+            replies[3],
+            "450 4.7.1 Sender rate limit exceeded, try again later"
+        );
+        assert_eq!(replies[4], "250 2.1.0 OK");
+        assert_eq!(replies[5], "250 2.1.0 OK");
+    }
 
-            "RSET" => {
-                state.reset_transaction();
-                send_or_return!(reader, "250 2.0.0 OK");
-            }
+    #[tokio::test]
+    async fn busy_mailboxes_are_deferred() {
+        let mut config = test_config();
+        config.recipient_rate_limit_per_minute = 5;
+        config.recipient_rate_limit_per_hour = 2;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nRSET\r\n\
+                     MAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nRSET\r\n\
+                     MAIL FROM:<a@b.c>\r\nRCPT TO:<Alice@example.com>\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out
+            .lines()
+            .filter(|l| !l.starts_with("250-") && *l != "250 2.0.0 OK")
+            .collect();
+        assert_eq!(replies[2], "250 2.1.5 OK");
+        assert_eq!(replies[4], "250 2.1.5 OK");
+        assert_eq!(
+            replies[6],
+            "452 4.2.1 Mailbox is receiving too many messages, try again later"
+        );
+        assert!(state.recipients.is_empty());
+    }
 
-            "NOOP" => {
-                send_or_return!(reader, "250 2.0.0 OK");
-            }
+    // -- LMTP --
 
-            "QUIT" => {
-                let _ = send_line(reader.get_mut(), "221 2.0.0 Bye").await;
-                return LoopResult::Done(Ok(()));
-            }
+    async fn converse_lmtp(config: Config, input: &str) -> Vec<String> {
+        let (out, _) = run_loop(
+            Config {
+                lmtp: true,
+                ..config
+            },
+            input,
+            false,
+        )
+        .await;
+        out.lines()
+            .filter(|line| line.as_bytes().get(3) != Some(&b'-'))
+            .map(str::to_string)
+            .collect()
+    }
 
-            "VRFY" => {
-                send_or_return!(reader, "252 2.5.2 Cannot verify user");
-            }
+    #[tokio::test]
+    async fn lmtp_requires_lhlo() {
+        let replies =
+            converse_lmtp(test_config(), "EHLO x\r\nMAIL FROM:<a@b.c>\r\nLHLO x\r\n").await;
+        assert_eq!(replies[0], "500 5.5.1 Use LHLO for LMTP");
+        assert_eq!(replies[1], "503 5.5.1 Send LHLO first");
+        assert!(replies[2].starts_with("250 "));
+    }
 
-            "" => {}
+    #[tokio::test]
+    async fn smtp_rejects_lhlo() {
+        let replies = converse("LHLO x\r\n").await;
+        assert_eq!(replies[0], "502 5.5.2 Command not recognized");
+    }
 
-            _ => {
-                send_or_return!(reader, "502 5.5.2 Command not recognized");
-            }
-        }
+    #[tokio::test]
+    async fn lmtp_replies_once_per_recipient() {
+        let mut config = test_config();
+        config.max_message_size = 4;
+        let input = "LHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     RCPT TO:<nobody@example.com>\r\nRCPT TO:<Alice@example.com>\r\n\
+                     DATA\r\ntoo long\r\n.\r\nQUIT\r\n";
+        let replies = converse_lmtp(config, input).await;
+        assert_eq!(replies[3], "550 5.1.1 User unknown");
+        assert_eq!(replies[5], "354 Start mail input; end with <CRLF>.<CRLF>");
+        assert_eq!(replies[6], "552 5.3.4 Message too large");
+        assert_eq!(replies[7], "552 5.3.4 Message too large");
+        assert_eq!(replies[8], "221 2.0.0 Bye");
     }
-}
 
-/// Check if a domain (or its parent) is in the accepted set.
-/// Supports subdomain matching: `abc.tempy.email` matches if `tempy.email` is accepted.
-pub fn is_domain_accepted(domain: &str, accepted: &std::collections::HashSet<String>) -> bool {
-    accepted.contains(domain)
-        || domain
-            .find('.')
-            .and_then(|i| domain.get(i + 1..))
-            .map(|parent| accepted.contains(parent))
-            .unwrap_or(false)
-}
+    #[tokio::test]
+    async fn rejection_uses_reply_template() {
+        let mut config = test_config();
+        config.reply_templates.user_unknown =
+            Some("{address} expired, see https://{domain}/expired".to_string());
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<gone@example.com>\r\nQUIT\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(
+            out.contains("550 5.1.1 gone@example.com expired, see https://example.com/expired\r\n")
+        );
+    }
 
-/// Parse the first word (command) and the rest (arguments) from an SMTP line.
-/// The command is uppercased for case-insensitive matching per RFC 5321.
-/// Uses a stack-allocated `ArrayString<8>` since SMTP commands are at most 8 bytes.
-pub fn parse_command(line: &str) -> (ArrayString<8>, &str) {
-    let trimmed = line.trim();
-    match trimmed.find(' ') {
-        Some(pos) => {
-            let mut cmd = ArrayString::<8>::new();
-            for ch in trimmed[..pos].chars().take(8) {
-                let _ = cmd.try_push(ch.to_ascii_uppercase());
-            }
-            (cmd, trimmed[pos + 1..].trim())
-        }
-        None => {
-            let mut cmd = ArrayString::<8>::new();
-            for ch in trimmed.chars().take(8) {
-                let _ = cmd.try_push(ch.to_ascii_uppercase());
-            }
-            (cmd, "")
-        }
+    #[tokio::test]
+    async fn unknown_mailbox_soft_fails_globally() {
+        let mut config = test_config();
+        config.soft_fail_unknown = true;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<gone@example.com>\r\nQUIT\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("450 4.2.1 Mailbox temporarily unavailable\r\n"));
     }
-}
 
-/// Extract an email address from SMTP arguments like `FROM:<addr>` or `TO:<addr>`.
-pub fn extract_address(args: &str) -> Option<String> {
-    let start = args.find('<')?;
-    let end = args.find('>')?;
-    if end > start + 1 {
-        Some(args[start + 1..end].to_string())
-    } else {
-        None
+    #[tokio::test]
+    async fn unknown_mailbox_soft_fails_per_domain() {
+        let mut config = test_config();
+        config.soft_fail_domains = ["sub.example.com".to_string()].into();
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<gone@sub.example.com>\r\n\
+                     RCPT TO:<gone@example.com>\r\nQUIT\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(replies[2], "450 4.2.1 Mailbox temporarily unavailable");
+        assert_eq!(replies[3], "550 5.1.1 User unknown");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::io::BufReader;
+    #[tokio::test]
+    async fn shadow_mode_accepts_what_policy_rejects() {
+        let mut config = test_config();
+        config.shadow_mode = true;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<gone@example.com>\r\n\
+                     RCPT TO:<bob@other.test>\r\nQUIT\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(replies[2], "250 2.1.5 OK");
+        assert_eq!(replies[3], "250 2.1.5 OK");
+        assert_eq!(state.recipients.len(), 2);
+    }
 
-    // -- read_line (bounded) --
+    #[tokio::test]
+    async fn recipient_limit_resets_per_transaction() {
+        let mut config = test_config();
+        config.max_recipients = 1;
+        config.max_session_recipients = 3;
+        let rcpt = "RCPT TO:<alice@example.com>\r\n";
+        let input = format!(
+            "EHLO x\r\nMAIL FROM:<a@b.c>\r\n{rcpt}RCPT TO:<gone@example.com>\r\nRSET\r\n\
+             MAIL FROM:<a@b.c>\r\n{rcpt}RSET\r\nMAIL FROM:<a@b.c>\r\n{rcpt}QUIT\r\n"
+        );
+        let (out, _) = run_loop(config, &input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(replies[2], "250 2.1.5 OK");
+        assert_eq!(replies[3], "452 4.5.3 Too many recipients");
+        assert_eq!(replies[6], "250 2.1.5 OK");
+        assert_eq!(replies[9], "452 4.5.3 Too many recipients for this session");
+    }
 
     #[tokio::test]
-    async fn read_line_normal() {
-        let input = b"EHLO example.com\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
-        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
-        assert_eq!(result, Some("EHLO example.com".to_string()));
+    async fn message_limit_ends_the_session() {
+        let mut config = test_config();
+        config.backend_addrs = vec![(refusing_backend("250 OK\r\n").await, 1)];
+        config.max_session_messages = 2;
+        let message = "MAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                       DATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\n";
+        let input = format!("EHLO x\r\n{message}{message}{message}QUIT\r\n");
+        let (out, state) = run_loop(config, &input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(state.messages, 2);
+        assert_eq!(
+            *replies.last().unwrap(),
+            "421 4.7.0 Too many messages in this session, reconnect to send more"
+        );
+        assert_eq!(replies.iter().filter(|r| r.starts_with("354 ")).count(), 2);
+        assert!(!out.contains("221 "));
     }
 
     #[tokio::test]
-    async fn read_line_lf_only() {
-        let input = b"QUIT\n";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
-        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
-        assert_eq!(result, Some("QUIT".to_string()));
+    async fn failed_messages_do_not_count_toward_the_message_limit() {
+        let mut config = test_config();
+        config.backend_addrs = vec![(refusing_backend("250 OK\r\n").await, 1)];
+        config.max_session_messages = 1;
+        config.max_message_size = 100;
+        let envelope = "MAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nDATA\r\n";
+        let large = format!("{envelope}Subject: hi\r\n\r\n{}\r\n.\r\n", "x".repeat(200));
+        let small = format!("{envelope}Subject: hi\r\n\r\nhi\r\n.\r\n");
+        let input = format!("EHLO x\r\n{large}{small}MAIL FROM:<a@b.c>\r\nQUIT\r\n");
+        let (out, state) = run_loop(config, &input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert!(replies[4].starts_with("552 "));
+        assert!(replies[8].starts_with("250 "));
+        assert_eq!(state.messages, 1);
+        assert_eq!(
+            *replies.last().unwrap(),
+            "421 4.7.0 Too many messages in this session, reconnect to send more"
+        );
     }
 
     #[tokio::test]
-    async fn read_line_eof_empty() {
-        let input = b"";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
-        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
-        assert_eq!(result, None);
+    async fn recipient_limit_skips_duplicates_and_bad_syntax() {
+        let mut config = test_config();
+        config.max_recipients = 2;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     RCPT TO:<alice@example.com>\r\nRCPT TO:<bad..dots@example.com>\r\n\
+                     RCPT TO:<gone@example.com>\r\nRCPT TO:<other@example.com>\r\nQUIT\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(replies[3], "250 2.1.5 OK");
+        assert!(replies[4].starts_with("501 "));
+        assert_eq!(replies[5], "550 5.1.1 User unknown");
+        assert_eq!(replies[6], "452 4.5.3 Too many recipients");
+        assert_eq!(state.recipient_count, 3);
     }
 
     #[tokio::test]
-    async fn read_line_eof_with_partial_data() {
-        let input = b"PARTIAL";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
-        let result = read_line(&mut reader, &mut buf, 1024).await.unwrap();
-        assert_eq!(result, Some("PARTIAL".to_string()));
+    async fn terminator_confusion_closes_session() {
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nDATA\r\n\
+                     Subject: x\r\n\r\nbody\r\n.\nMAIL FROM:<spoof@x.y>\r\n";
+        let (out, _) = run_loop(test_config(), input, true).await;
+        assert!(out.ends_with("554 5.6.0 Ambiguous end-of-data sequence, closing connection\r\n"));
+    }
+
+    /// Backend that answers every RCPT TO with `rcpt_reply`.
+    async fn refusing_backend(rcpt_reply: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    let _ = reader.get_mut().write_all(b"220 backend\r\n").await;
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let reply = if line.starts_with("RCPT") {
+                            rcpt_reply
+                        } else {
+                            "250 OK\r\n"
+                        };
+                        let _ = reader.get_mut().write_all(reply.as_bytes()).await;
+                    }
+                });
+            }
+        });
+        addr
     }
 
     #[tokio::test]
-    async fn read_line_exceeds_limit() {
-        // 10 bytes of 'A' without a newline, limit of 5
-        let input = b"AAAAAAAAAA";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
-        let result = read_line(&mut reader, &mut buf, 5).await;
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    async fn backend_refusing_every_recipient_fails_data() {
+        let mut config = test_config();
+        config.backend_addrs = vec![(refusing_backend("550 5.1.1 no such user\r\n").await, 1)];
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nhi\r\n.\r\nQUIT\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        assert!(out.contains("550 5.1.1 Refused by backend\r\n"));
+        assert_eq!(state.transactions[0].outcome, "relay_rejected");
     }
 
     #[tokio::test]
-    async fn read_line_exactly_at_limit() {
-        // 5 bytes + newline, limit of 5 — should succeed
-        let input = b"ABCDE\n";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
-        let result = read_line(&mut reader, &mut buf, 5).await.unwrap();
-        assert_eq!(result, Some("ABCDE".to_string()));
+    async fn lmtp_passes_backend_refusal_to_each_recipient() {
+        let mut config = test_config();
+        config.backend_addrs = vec![(refusing_backend("452 4.2.2 over quota\r\n").await, 1)];
+        let input = "LHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     RCPT TO:<Alice@example.com>\r\nDATA\r\nhi\r\n.\r\nQUIT\r\n";
+        let replies = converse_lmtp(config, input).await;
+        let refusal = "452 4.2.2 Temporarily refused by backend, try again later";
+        assert_eq!(replies[5], refusal);
+        assert_eq!(replies[6], refusal);
+        assert_eq!(replies[7], "221 2.0.0 Bye");
+    }
+
+    /// Backend that accepts everything and sends each message body line
+    /// down the channel.
+    async fn recording_backend() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let _ = reader.get_mut().write_all(b"220 backend\r\n").await;
+            let mut in_data = false;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                let reply: &[u8] = match line.as_str() {
+                    ".\r\n" if in_data => {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    }
+                    _ if in_data => {
+                        let _ = tx.send(line.clone());
+                        continue;
+                    }
+                    "DATA\r\n" => {
+                        in_data = true;
+                        b"354 go\r\n"
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                let _ = reader.get_mut().write_all(reply).await;
+            }
+        });
+        (addr, rx)
     }
 
     #[tokio::test]
-    async fn read_line_one_over_limit() {
-        // 6 bytes without newline, limit of 5 — should fail
-        let input = b"ABCDEF\n";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
-        let result = read_line(&mut reader, &mut buf, 5).await;
-        assert!(result.is_err());
+    async fn relayed_message_starts_with_received_header() {
+        let mut config = test_config();
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.server_name = "mx.example.com".to_string();
+        let input = "EHLO client.example.org\r\nMAIL FROM:<a@b.c>\r\n\
+                     RCPT TO:<alice@example.com>\r\nDATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\nQUIT\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        assert!(out.contains("250 2.0.0"), "{out}");
+        assert_eq!(
+            body.recv().await.unwrap(),
+            "Received: from client.example.org ([192.0.2.1])\r\n"
+        );
+        assert_eq!(
+            body.recv().await.unwrap(),
+            format!(
+                "\tby mx.example.com (burngate) with ESMTP id {}\r\n",
+                state.id
+            )
+        );
+        assert!(body
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("\tfor <alice@example.com>; "));
+        assert_eq!(body.recv().await.unwrap(), "Subject: hi\r\n");
+    }
+
+    /// Rspamd that answers every scan with `verdict`.
+    async fn rspamd_answering(verdict: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).await.unwrap_or(0) > 2 {
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                let _ = reader.read_exact(&mut body).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    verdict.len(),
+                    verdict
+                );
+                let _ = reader.get_mut().write_all(response.as_bytes()).await;
+            }
+        });
+        url
     }
 
     #[tokio::test]
-    async fn read_line_multiple_lines() {
-        let input = b"LINE1\r\nLINE2\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let mut buf = Vec::new();
+    async fn rspamd_rejects_or_tags_spam() {
+        let mut config = test_config();
+        config.rspamd_url = Some(rspamd_answering(r#"{"action": "reject", "score": 18.5}"#).await);
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\nQUIT\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        assert!(
+            out.contains("554 5.7.1 Message rejected as spam\r\n"),
+            "{out}"
+        );
+        assert_eq!(state.transactions[0].outcome, "spam");
 
-        let r1 = read_line(&mut reader, &mut buf, 1024).await.unwrap();
-        assert_eq!(r1, Some("LINE1".to_string()));
+        let mut config = test_config();
+        config.rspamd_url = Some(rspamd_answering(r#"{"action": "greylist", "score": 4.0}"#).await);
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("451 4.7.1 Message deferred, try again later\r\n"));
 
-        let r2 = read_line(&mut reader, &mut buf, 1024).await.unwrap();
-        assert_eq!(r2, Some("LINE2".to_string()));
+        let mut config = test_config();
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.received_header = false;
+        config.rspamd_url =
+            Some(rspamd_answering(r#"{"action": "add header", "score": 7.2}"#).await);
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("250 2.0.0"), "{out}");
+        assert_eq!(body.recv().await.unwrap(), "X-Spam: Yes\r\n");
+        assert_eq!(body.recv().await.unwrap(), "Subject: hi\r\n");
+    }
 
-        let r3 = read_line(&mut reader, &mut buf, 1024).await.unwrap();
-        assert_eq!(r3, None);
+    /// clamd that answers every scan with `reply`.
+    async fn clamd_answering(reply: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut command = [0; 10];
+                let _ = stream.read_exact(&mut command).await;
+                while let Ok(len @ 1..) = stream.read_u32().await {
+                    let mut chunk = vec![0; len as usize];
+                    let _ = stream.read_exact(&mut chunk).await;
+                }
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        addr
     }
 
-    // -- read_data (no dot-unstuffing, raw wire format) --
+    #[tokio::test]
+    async fn infected_messages_are_refused() {
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\nQUIT\r\n";
+        let mut config = test_config();
+        config.clamav_addr = Some(clamd_answering("stream: Eicar-Test-Signature FOUND\0").await);
+        let (out, state) = run_loop(config, input, false).await;
+        assert!(
+            out.contains("550 5.7.1 Message contains a virus\r\n"),
+            "{out}"
+        );
+        assert_eq!(state.transactions[0].outcome, "virus");
+
+        // A failed scan defers the message unless CLAMAV_FAIL_OPEN is set
+        let mut config = test_config();
+        config.clamav_addr = Some(clamd_answering("stream: Can't allocate memory ERROR\0").await);
+        let (out, _) = run_loop(config.clone(), input, false).await;
+        assert!(out.contains("451 4.3.0 Virus scan unavailable, try again later\r\n"));
+
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.received_header = false;
+        config.clamav_fail_open = true;
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("250 2.0.0"), "{out}");
+        assert_eq!(body.recv().await.unwrap(), "Subject: hi\r\n");
+    }
 
     #[tokio::test]
-    async fn read_data_simple_message() {
-        let input = b"Subject: test\r\n\r\nHello world\r\n.\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let data = read_data(&mut reader, 10_000).await.unwrap();
-        assert_eq!(data, b"Subject: test\r\n\r\nHello world\r\n");
+    async fn spamtrap_messages_are_accepted_and_dropped() {
+        let mut config = test_config();
+        config.spamtrap_addresses = vec!["trap@example.com".to_string()];
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     RCPT TO:<Trap@example.com>\r\nDATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\n\
+                     MAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\nQUIT\r\n";
+        let (out, state) = run_loop(config, input, false).await;
+        let replies: Vec<&str> = out.lines().filter(|l| !l.starts_with("250-")).collect();
+        assert_eq!(replies[3], "250 2.1.5 OK");
+        // Not relayed: the test backend is unreachable
+        assert_eq!(replies[5], "250 2.0.0 OK message accepted");
+        assert_eq!(state.transactions[0].outcome, "spamtrap");
+        // The next transaction starts clean
+        assert!(!state.spamtrap);
     }
 
     #[tokio::test]
-    async fn read_data_preserves_dot_stuffing() {
-        // ".." lines should be passed through raw (no unstuffing)
-        let input = b"..leading dot\r\n.\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let data = read_data(&mut reader, 10_000).await.unwrap();
-        // Raw wire format: the ".." is preserved
-        assert_eq!(data, b"..leading dot\r\n");
+    async fn content_rules_reject_discard_or_tag() {
+        let path =
+            std::env::temp_dir().join(format!("burngate-rules-session-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "subject contains casino reject 554 \"No gambling\"\n\
+             subject contains prize discard\n\
+             body contains unsubscribe score 3\n\
+             body contains unsubscribe add-header X-Bulk yes\n",
+        )
+        .unwrap();
+        let message = |subject: &str| {
+            format!(
+                "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                 DATA\r\nSubject: {subject}\r\n\r\nunsubscribe\r\n.\r\nQUIT\r\n"
+            )
+        };
+        let mut config = test_config();
+        config.rules_file = Some(path.to_str().unwrap().to_string());
+        let (out, state) = run_loop(config.clone(), &message("casino"), false).await;
+        assert!(out.contains("554 5.7.1 No gambling\r\n"), "{out}");
+        assert_eq!(state.transactions[0].outcome, "content_rule");
+
+        // Discarded messages are answered as accepted
+        let (out, state) = run_loop(config.clone(), &message("a prize"), false).await;
+        assert!(out.contains("250 2.0.0 OK message accepted\r\n"), "{out}");
+        assert_eq!(state.transactions[0].outcome, "discarded");
+
+        // Below the score threshold the rule fields are added
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.received_header = false;
+        let (out, _) = run_loop(config.clone(), &message("news"), false).await;
+        assert!(out.contains("250 2.0.0"), "{out}");
+        assert_eq!(body.recv().await.unwrap(), "X-Bulk: yes\r\n");
+        assert_eq!(body.recv().await.unwrap(), "Subject: news\r\n");
+
+        config.dnsbl_score_threshold = 3;
+        let (out, _) = run_loop(config, &message("news"), false).await;
+        assert!(
+            out.contains("550 5.7.1 Message rejected by content rule\r\n"),
+            "{out}"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// spamd that answers every scan with `score / 5.0`.
+    async fn spamd_scoring(score: f64) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let _ = stream.read_to_end(&mut request).await;
+                let reply = format!(
+                    "SPAMD/1.1 0 EX_OK\r\nSpam: {} ; {score} / 5.0\r\n\r\nBAYES_99\r\n",
+                    score >= 5.0
+                );
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        addr
     }
 
     #[tokio::test]
-    async fn read_data_dot_only_terminates() {
-        let input = b"line1\r\n.\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let data = read_data(&mut reader, 10_000).await.unwrap();
-        assert_eq!(data, b"line1\r\n");
+    async fn spamd_score_threshold_rejects_or_tags() {
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\nQUIT\r\n";
+        let mut config = test_config();
+        config.spamd_addr = Some(spamd_scoring(6.5).await);
+        let (out, state) = run_loop(config, input, false).await;
+        assert!(
+            out.contains("554 5.7.1 Message rejected as spam\r\n"),
+            "{out}"
+        );
+        assert_eq!(state.transactions[0].outcome, "spam");
+
+        // A higher SPAMD_REJECT_SCORE only marks it
+        let mut config = test_config();
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.received_header = false;
+        config.spamd_addr = Some(spamd_scoring(6.5).await);
+        config.spamd_reject_score = Some(10.0);
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("250 2.0.0"), "{out}");
+        assert_eq!(
+            body.recv().await.unwrap(),
+            "X-Spam-Status: Yes, score=6.5 required=5.0 tests=BAYES_99\r\n"
+        );
+        assert_eq!(body.recv().await.unwrap(), "Subject: hi\r\n");
     }
 
     #[tokio::test]
-    async fn read_data_dot_lf_terminates() {
-        // Lone "." with just LF (no CR)
-        let input = b"line1\n.\n";
-        let mut reader = BufReader::new(&input[..]);
-        let data = read_data(&mut reader, 10_000).await.unwrap();
-        assert_eq!(data, b"line1\n");
+    async fn unreachable_rspamd_lets_mail_through() {
+        let mut config = test_config();
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.received_header = false;
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.rspamd_url = Some(format!("http://{}", closed.local_addr().unwrap()));
+        drop(closed);
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\nQUIT\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("250 2.0.0"), "{out}");
+        assert_eq!(body.recv().await.unwrap(), "Subject: hi\r\n");
     }
 
     #[tokio::test]
-    async fn read_data_exceeds_max_size() {
-        // 100 bytes of data, max_size of 10
-        let mut input = Vec::new();
-        for _ in 0..20 {
-            input.extend_from_slice(b"AAAAA\r\n");
-        }
-        input.extend_from_slice(b".\r\n");
-        let mut reader = BufReader::new(&input[..]);
-        let result = read_data(&mut reader, 10).await;
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    async fn received_header_can_be_turned_off() {
+        let mut config = test_config();
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.received_header = false;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\nQUIT\r\n";
+        run_loop(config, input, false).await;
+        assert_eq!(body.recv().await.unwrap(), "Subject: hi\r\n");
     }
 
     #[tokio::test]
-    async fn read_data_eof_before_terminator() {
-        let input = b"line1\r\nline2\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let result = read_data(&mut reader, 10_000).await;
-        assert!(result.is_err());
+    async fn x_original_to_names_a_single_recipient() {
+        let mut config = test_config();
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        config.received_header = false;
+        config.x_original_to_header = true;
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<Alice@example.com>\r\n\
+                     DATA\r\nSubject: hi\r\n\r\nhi\r\n.\r\nQUIT\r\n";
+        run_loop(config, input, false).await;
         assert_eq!(
-            result.unwrap_err().kind(),
-            std::io::ErrorKind::UnexpectedEof
+            body.recv().await.unwrap(),
+            "X-Original-To: alice@example.com\r\n"
         );
+        assert_eq!(body.recv().await.unwrap(), "Subject: hi\r\n");
     }
 
     #[tokio::test]
-    async fn read_data_empty_message() {
-        // Just a terminator, no body
-        let input = b".\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let data = read_data(&mut reader, 10_000).await.unwrap();
-        assert!(data.is_empty());
+    async fn message_without_header_section_is_relayed_untouched() {
+        let mut config = test_config();
+        let (addr, mut body) = recording_backend().await;
+        config.backend_addrs = vec![(addr, 1)];
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nno headers here\r\n.\r\nQUIT\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("250 2.0.0"), "{out}");
+        assert_eq!(body.recv().await.unwrap(), "no headers here\r\n");
     }
 
     #[tokio::test]
-    async fn read_data_dot_in_middle_of_line_not_terminator() {
-        // A line with "." in it but not alone
-        let input = b".not-a-terminator\r\n.\r\n";
-        let mut reader = BufReader::new(&input[..]);
-        let data = read_data(&mut reader, 10_000).await.unwrap();
-        // ".not-a-terminator" is not a lone ".", so it's included in data
-        assert_eq!(data, b".not-a-terminator\r\n");
+    async fn refused_message_goes_to_dead_letters() {
+        let dir =
+            std::env::temp_dir().join(format!("burngate-dead-session-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = test_config();
+        config.backend_addrs = vec![(refusing_backend("550 5.1.1 no such user\r\n").await, 1)];
+        config.dead_letter_dir = Some(dir.to_str().unwrap().to_string());
+        let input = "EHLO x\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<alice@example.com>\r\n\
+                     DATA\r\nSubject: hi\r\n\r\nbody\r\n.\r\nQUIT\r\n";
+        let (out, _) = run_loop(config, input, false).await;
+        assert!(out.contains("550 5.1.1 Refused by backend\r\n"));
+        let eml = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|ext| ext == "eml"))
+            .unwrap();
+        let kept = std::fs::read_to_string(eml).unwrap();
+        assert!(kept.ends_with("Subject: hi\r\n\r\nbody\r\n"));
     }
 }
@@ -0,0 +1,151 @@
+//! Content scanning with SpamAssassin: each message is sent to spamd at
+//! `SPAMD_ADDR` over the spamc protocol after DATA, and refused when its
+//! score reaches the rejection threshold.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpamdError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("timed out")]
+    Timeout,
+    #[error("invalid reply: {0}")]
+    Invalid(String),
+}
+
+/// spamd's reply to `SYMBOLS`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    /// Whether spamd itself calls it spam (score over its `required_score`).
+    pub spam: bool,
+    pub score: f64,
+    pub required: f64,
+    /// Names of the rules that hit.
+    pub tests: Vec<String>,
+}
+
+impl Report {
+    /// Parse a spamd reply:
+    ///
+    /// ```text
+    /// SPAMD/1.1 0 EX_OK
+    /// Content-length: 33
+    /// Spam: True ; 15.3 / 5.0
+    ///
+    /// BAYES_99,HTML_MESSAGE,URIBL_BLACK
+    /// ```
+    pub fn parse(reply: &[u8]) -> Result<Self, SpamdError> {
+        let reply = String::from_utf8_lossy(reply);
+        let (head, body) = reply
+            .split_once("\r\n\r\n")
+            .or_else(|| reply.split_once("\n\n"))
+            .unwrap_or((&reply, ""));
+        let mut lines = head.lines();
+        let status = lines.next().unwrap_or_default();
+        let mut words = status.split_whitespace();
+        if !words
+            .next()
+            .is_some_and(|proto| proto.starts_with("SPAMD/"))
+        {
+            return Err(SpamdError::Invalid(format!("status line {status:?}")));
+        }
+        if words.next() != Some("0") {
+            return Err(SpamdError::Invalid(status.to_string()));
+        }
+
+        // Spam: True ; 15.3 / 5.0
+        let spam = lines
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim().eq_ignore_ascii_case("Spam").then_some(value)
+            })
+            .ok_or_else(|| SpamdError::Invalid("no Spam header".to_string()))?;
+        let invalid = || SpamdError::Invalid(format!("Spam header {spam:?}"));
+        let (flag, scores) = spam.split_once(';').ok_or_else(invalid)?;
+        let (score, required) = scores.split_once('/').ok_or_else(invalid)?;
+        let flag = flag.trim();
+        Ok(Self {
+            spam: flag.eq_ignore_ascii_case("true") || flag.eq_ignore_ascii_case("yes"),
+            score: score.trim().parse().map_err(|_| invalid())?,
+            required: required.trim().parse().map_err(|_| invalid())?,
+            tests: body
+                .split(',')
+                .map(str::trim)
+                .filter(|test| !test.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// The `X-Spam-Status` value, in SpamAssassin's own format:
+    /// `Yes, score=15.3 required=5.0 tests=BAYES_99,URIBL_BLACK`.
+    pub fn status(&self) -> String {
+        format!(
+            "{}, score={:.1} required={:.1} tests={}",
+            if self.spam { "Yes" } else { "No" },
+            self.score,
+            self.required,
+            if self.tests.is_empty() {
+                "none".to_string()
+            } else {
+                self.tests.join(",")
+            }
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct Spamd {
+    addr: String,
+    /// Whose preferences spamd applies (`User` header).
+    user: Option<String>,
+    timeout: Duration,
+    /// Larger messages are not scanned.
+    max_size: usize,
+}
+
+impl Spamd {
+    pub fn new(addr: &str, config: &Config) -> Self {
+        Self {
+            addr: addr.to_string(),
+            user: config.spamd_user.clone(),
+            timeout: Duration::from_millis(config.spamd_timeout_ms),
+            max_size: config.spamd_max_size,
+        }
+    }
+
+    /// Scan `message`, giving up after `SPAMD_TIMEOUT_MS`. `None` when the
+    /// message is larger than `SPAMD_MAX_SIZE` and was not scanned.
+    pub async fn check(&self, message: &[u8]) -> Result<Option<Report>, SpamdError> {
+        if self.max_size > 0 && message.len() > self.max_size {
+            return Ok(None);
+        }
+        let scan = self.scan(message);
+        let reply = tokio::time::timeout(self.timeout, scan)
+            .await
+            .map_err(|_| SpamdError::Timeout)??;
+        Report::parse(&reply).map(Some)
+    }
+
+    async fn scan(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        let mut head = format!("SYMBOLS SPAMC/1.5\r\nContent-length: {}\r\n", message.len());
+        if let Some(user) = &self.user {
+            head.push_str(&format!("User: {user}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(message).await?;
+        stream.shutdown().await?;
+        let mut reply = Vec::new();
+        stream.take(64 * 1024).read_to_end(&mut reply).await?;
+        Ok(reply)
+    }
+}
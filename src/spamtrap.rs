@@ -0,0 +1,59 @@
+//! Spamtrap addresses (`SPAMTRAP_ADDRESSES`, `SPAMTRAP_REDIS_SET`): mail to
+//! them is accepted and then dropped, and the client that sent it is put
+//! on the automatic blocklist.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use tracing::warn;
+
+use crate::config::Config;
+use crate::store::SharedStore;
+
+#[derive(Clone)]
+pub struct Spamtraps {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Lookup forms of `SPAMTRAP_ADDRESSES`.
+    addresses: HashSet<String>,
+    store: SharedStore,
+    /// Set of further trap addresses in the store.
+    set: Option<String>,
+}
+
+impl Spamtraps {
+    /// `None` when no trap addresses are configured.
+    pub fn from_config(store: SharedStore, config: &Config) -> Option<Self> {
+        if config.spamtrap_addresses.is_empty() && config.spamtrap_redis_set.is_none() {
+            return None;
+        }
+        Some(Self {
+            inner: Arc::new(Inner {
+                addresses: config.spamtrap_addresses.iter().cloned().collect(),
+                store,
+                set: config.spamtrap_redis_set.clone(),
+            }),
+        })
+    }
+
+    /// Whether `address` (in lookup form) is a trap. A store error counts
+    /// as not a trap.
+    pub async fn contains(&self, address: &str) -> bool {
+        let inner = &self.inner;
+        if inner.addresses.contains(address) {
+            return true;
+        }
+        let Some(set) = &inner.set else {
+            return false;
+        };
+        match inner.store.set_contains(set, &[address]).await {
+            Ok(found) => found.first().copied().unwrap_or(false),
+            Err(e) => {
+                warn!(address = address, error = %e, "spamtrap check failed");
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::address::AddressList;
+use crate::config::Config;
+use crate::deadletter::{DeadLetter, DeadMessage};
+use crate::esmtp::EsmtpParams;
+use crate::headers;
+use crate::relay::{Backend, ClientInfo, Recipient, RelayError};
+use crate::session::Metrics;
+use crate::stats::DeliveryStats;
+use crate::webhook::{DeliveryEvent, Webhook};
+
+/// On-disk queue for messages the backend could not take.
+///
+/// When relaying fails transiently, the message is written to `SPOOL_DIR`
+/// before the client gets its 250, and a background worker retries it with
+/// exponential backoff until the backend accepts or permanently refuses it.
+/// Messages still on disk are queued again at startup.
+#[derive(Clone)]
+pub struct Spool {
+    inner: Arc<SpoolInner>,
+}
+
+struct SpoolInner {
+    dir: PathBuf,
+    max_messages: usize,
+    backend: Backend,
+    timeout: Duration,
+    retry_initial: Duration,
+    retry_max: Duration,
+    /// Messages on disk or being written, bounded by `max_messages`.
+    count: AtomicUsize,
+    /// Suffix that keeps file names unique within the same nanosecond.
+    seq: AtomicU64,
+    queue: Mutex<Vec<Entry>>,
+    wake: Notify,
+    metrics: Arc<Metrics>,
+    /// Told about each message delivered from the spool.
+    webhook: Option<Webhook>,
+    /// Takes messages the backend refuses; without it they stay on disk
+    /// as `.failed` files.
+    dead_letter: Option<DeadLetter>,
+    /// Counts messages delivered from the spool.
+    stats: Option<DeliveryStats>,
+}
+
+/// A spooled message waiting for its next attempt.
+struct Entry {
+    path: PathBuf,
+    attempts: u32,
+    due: Instant,
+}
+
+/// A message read back from the spool.
+struct SpooledMessage {
+    client: Option<ClientInfo>,
+    sender: String,
+    mail_params: EsmtpParams,
+    recipients: Vec<(String, EsmtpParams)>,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpoolError {
+    #[error("spool is full")]
+    Full,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl Spool {
+    /// Open (creating if needed) the spool directory and queue the messages
+    /// already in it for immediate delivery.
+    pub fn open(
+        dir: &str,
+        config: &Config,
+        backend: Backend,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let now = Instant::now();
+        let mut queue = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "msg") {
+                queue.push(Entry {
+                    path,
+                    attempts: 0,
+                    due: now,
+                });
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(SpoolInner {
+                dir: PathBuf::from(dir),
+                max_messages: config.spool_max_messages,
+                backend,
+                timeout: Duration::from_secs(config.data_timeout_secs),
+                retry_initial: Duration::from_secs(config.spool_retry_initial_secs),
+                retry_max: Duration::from_secs(config.spool_retry_max_secs),
+                count: AtomicUsize::new(queue.len()),
+                seq: AtomicU64::new(0),
+                queue: Mutex::new(queue),
+                wake: Notify::new(),
+                metrics,
+                webhook: None,
+                dead_letter: None,
+                stats: None,
+            }),
+        })
+    }
+
+    /// Report messages delivered from the spool to `webhook`.
+    ///
+    /// Must be called before the spool is cloned.
+    pub fn with_webhook(mut self, webhook: Option<Webhook>) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("the webhook is set before the spool is shared")
+            .webhook = webhook;
+        self
+    }
+
+    /// Hand messages the backend refuses to `dead_letter`.
+    ///
+    /// Must be called before the spool is cloned.
+    pub fn with_dead_letter(mut self, dead_letter: Option<DeadLetter>) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("the dead-letter sink is set before the spool is shared")
+            .dead_letter = dead_letter;
+        self
+    }
+
+    /// Count messages delivered from the spool in `stats`.
+    ///
+    /// Must be called before the spool is cloned.
+    pub fn with_stats(mut self, stats: Option<DeliveryStats>) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("the delivery counters are set before the spool is shared")
+            .stats = stats;
+        self
+    }
+
+    /// Messages waiting for delivery.
+    pub fn len(&self) -> usize {
+        self.inner.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write a message to disk and queue it. Returns once the file is synced,
+    /// so the caller may acknowledge the message.
+    pub async fn enqueue(
+        &self,
+        client: Option<&ClientInfo>,
+        sender: &str,
+        mail_params: &EsmtpParams,
+        recipients: &[Recipient<'_>],
+        data: &[u8],
+    ) -> Result<(), SpoolError> {
+        let max = self.inner.max_messages;
+        if self
+            .inner
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_err()
+        {
+            return Err(SpoolError::Full);
+        }
+
+        let header = envelope_json(client, sender, mail_params, recipients);
+        let path = self.inner.dir.join(format!("{}.msg", self.next_id()));
+        if let Err(e) = write_file(&path, header.to_string().as_bytes(), data).await {
+            self.inner.count.fetch_sub(1, Ordering::Relaxed);
+            return Err(e.into());
+        }
+
+        self.inner.queue.lock().unwrap().push(Entry {
+            path,
+            attempts: 0,
+            due: Instant::now() + self.inner.retry_initial,
+        });
+        self.inner.wake.notify_one();
+        Ok(())
+    }
+
+    /// Deliver queued messages as they come due. Runs until the task is
+    /// dropped.
+    pub async fn run(self) {
+        loop {
+            let (due, wait) = {
+                let mut queue = self.inner.queue.lock().unwrap();
+                let now = Instant::now();
+                match queue.iter().enumerate().min_by_key(|(_, e)| e.due) {
+                    Some((i, entry)) if entry.due <= now => (Some(queue.swap_remove(i)), None),
+                    Some((_, entry)) => (None, Some(entry.due)),
+                    None => (None, None),
+                }
+            };
+            match (due, wait) {
+                (Some(entry), _) => self.attempt(entry).await,
+                (None, Some(until)) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(until) => {}
+                        _ = self.inner.wake.notified() => {}
+                    }
+                }
+                (None, None) => self.inner.wake.notified().await,
+            }
+        }
+    }
+
+    async fn attempt(&self, mut entry: Entry) {
+        let message = match read_file(&entry.path).await {
+            Ok(message) => message,
+            Err(e) => {
+                warn!(path = %entry.path.display(), error = %e, "[SPOOL-FAILED] unreadable spool file, skipping");
+                self.inner.count.fetch_sub(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        let recipients: Vec<Recipient> = message
+            .recipients
+            .iter()
+            .map(|(address, params)| Recipient { address, params })
+            .collect();
+        let relay = self.inner.backend.relay(
+            message.client.as_ref(),
+            &message.sender,
+            &message.mail_params,
+            &recipients,
+            &message.data,
+        );
+        match tokio::time::timeout(self.inner.timeout, relay)
+            .await
+            .unwrap_or(Err(RelayError::Timeout))
+        {
+            Ok(outcome) => {
+                info!(
+                    sender = %message.sender,
+                    recipients = %AddressList(outcome.delivered.iter()),
+                    refused = %AddressList(outcome.rejected.iter().map(|(a, _)| a)),
+                    attempts = entry.attempts + 1,
+                    "[SPOOL-DELIVERED] spooled message relayed to backend"
+                );
+                if let Some(webhook) = &self.inner.webhook {
+                    webhook.submit(DeliveryEvent {
+                        session_id: None,
+                        sender: message.sender.clone(),
+                        recipients: outcome.delivered.iter().map(|a| a.to_string()).collect(),
+                        size: message.data.len(),
+                        message_id: headers::field(&message.data, "Message-ID"),
+                    });
+                }
+                if let Some(stats) = &self.inner.stats {
+                    stats.submit(outcome.delivered.iter().map(|a| a.to_string()).collect());
+                }
+                if let Err(e) = tokio::fs::remove_file(&entry.path).await {
+                    warn!(path = %entry.path.display(), error = %e, "failed to remove delivered spool file");
+                }
+                self.inner.count.fetch_sub(1, Ordering::Relaxed);
+            }
+            Err(e) if e.is_permanent() => {
+                self.inner
+                    .metrics
+                    .relay_errors
+                    .fetch_add(1, Ordering::Relaxed);
+                if let Some(dead_letter) = &self.inner.dead_letter {
+                    let dead = DeadMessage {
+                        session_id: None,
+                        client: message.client.as_ref(),
+                        sender: &message.sender,
+                        mail_params: &message.mail_params,
+                        recipients: &recipients,
+                        error: &e,
+                        data: &message.data,
+                    };
+                    if dead_letter.store(&dead).await {
+                        warn!(
+                            sender = %message.sender,
+                            error = %e,
+                            "[SPOOL-FAILED] backend refused spooled message, moved to dead letters"
+                        );
+                        let _ = tokio::fs::remove_file(&entry.path).await;
+                        self.inner.count.fetch_sub(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+                let failed = entry.path.with_extension("failed");
+                let _ = tokio::fs::rename(&entry.path, &failed).await;
+                warn!(
+                    sender = %message.sender,
+                    error = %e,
+                    path = %failed.display(),
+                    "[SPOOL-FAILED] backend refused spooled message, kept for inspection"
+                );
+                self.inner.count.fetch_sub(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                entry.attempts += 1;
+                let delay = self.backoff(entry.attempts);
+                debug!(
+                    path = %entry.path.display(),
+                    error = %e,
+                    attempts = entry.attempts,
+                    retry_in_secs = delay.as_secs(),
+                    "[SPOOL-RETRY] spooled message not delivered"
+                );
+                entry.due = Instant::now() + delay;
+                self.inner.queue.lock().unwrap().push(entry);
+            }
+        }
+    }
+
+    /// Delay after `attempts` failures: doubling from the initial delay,
+    /// capped at the maximum.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.inner
+            .retry_initial
+            .saturating_mul(factor)
+            .min(self.inner.retry_max)
+    }
+
+    fn next_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let seq = self.inner.seq.fetch_add(1, Ordering::Relaxed);
+        format!("{:x}-{:x}", nanos, seq)
+    }
+}
+
+/// The envelope of a message as stored in spool files: client, sender,
+/// MAIL parameters and recipients with their RCPT parameters.
+pub(crate) fn envelope_json(
+    client: Option<&ClientInfo>,
+    sender: &str,
+    mail_params: &EsmtpParams,
+    recipients: &[Recipient<'_>],
+) -> Value {
+    json!({
+        "client": client.map(|c| json!({
+            "addr": c.addr.to_string(),
+            "helo": c.helo,
+            "proto": c.proto,
+        })),
+        "sender": sender,
+        "mail_params": mail_params.to_string(),
+        "recipients": recipients
+            .iter()
+            .map(|r| json!([r.address, r.params.to_string()]))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Write the header line and data to a temporary file, sync it, then rename
+/// it into place so the worker never sees a partial message.
+async fn write_file(path: &Path, header: &[u8], data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(header).await?;
+    file.write_all(b"\n").await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+async fn read_file(path: &Path) -> io::Result<SpooledMessage> {
+    let bytes = tokio::fs::read(path).await?;
+    let split = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| invalid("missing header"))?;
+    let header: Value =
+        serde_json::from_slice(&bytes[..split]).map_err(|e| invalid(&e.to_string()))?;
+    let params = |v: &Value| {
+        EsmtpParams::parse(v.as_str().unwrap_or("")).map_err(|e| invalid(&e.to_string()))
+    };
+
+    let client = match &header["client"] {
+        Value::Null => None,
+        c => Some(ClientInfo {
+            addr: c["addr"]
+                .as_str()
+                .and_then(|a| a.parse().ok())
+                .ok_or_else(|| invalid("bad client address"))?,
+            helo: c["helo"].as_str().map(str::to_string),
+            proto: c["proto"].as_str().unwrap_or("ESMTP").to_string(),
+        }),
+    };
+    let recipients = header["recipients"]
+        .as_array()
+        .ok_or_else(|| invalid("missing recipients"))?
+        .iter()
+        .map(|r| {
+            let address = r[0].as_str().ok_or_else(|| invalid("bad recipient"))?;
+            Ok((address.to_string(), params(&r[1])?))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok(SpooledMessage {
+        client,
+        sender: header["sender"].as_str().unwrap_or("").to_string(),
+        mail_params: params(&header["mail_params"])?,
+        recipients,
+        data: bytes[split + 1..].to_vec(),
+    })
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
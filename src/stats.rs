@@ -0,0 +1,64 @@
+use tracing::warn;
+
+use crate::address;
+use crate::audit::unix_now;
+use crate::config::Config;
+use crate::store::SharedStore;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Daily delivery counters in Redis, for dashboards that should not have to
+/// parse logs.
+///
+/// Each relayed recipient increments `{prefix}:{domain}:accepted:{day}` and
+/// `{prefix}:{address}:received:{day}`, where `day` counts UTC days since the
+/// epoch as in the audit trail. Counters expire after the retention period.
+#[derive(Clone)]
+pub struct DeliveryStats {
+    store: SharedStore,
+    key_prefix: String,
+    retention_days: u64,
+}
+
+impl DeliveryStats {
+    pub fn new(store: SharedStore, config: &Config) -> Self {
+        Self {
+            store,
+            key_prefix: config.stats_key_prefix.clone(),
+            retention_days: config.stats_retention_days,
+        }
+    }
+
+    /// Count a delivery in the background, so the client's reply does not
+    /// wait for Redis.
+    pub fn submit(&self, recipients: Vec<String>) {
+        let stats = self.clone();
+        tokio::spawn(async move {
+            let recipients: Vec<&str> = recipients.iter().map(String::as_str).collect();
+            stats.record(&recipients).await;
+        });
+    }
+
+    /// Count a delivery to `recipients`. Errors are logged, never propagated.
+    pub async fn record(&self, recipients: &[&str]) {
+        let day = unix_now() / SECS_PER_DAY;
+        let ttl = self.retention_days.max(1) * SECS_PER_DAY;
+        for recipient in recipients {
+            let address = address::lookup_form(recipient);
+            let keys = [
+                format!(
+                    "{}:{}:accepted:{}",
+                    self.key_prefix,
+                    address::domain(&address),
+                    day
+                ),
+                format!("{}:{}:received:{}", self.key_prefix, address, day),
+            ];
+            for key in keys {
+                if let Err(e) = self.store.incr(&key, ttl).await {
+                    warn!(error = %e, key = %key, "failed to count delivery");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1117 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use aws_lc_rs::digest;
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, StreamExt};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig, PubSub};
+use redis::{AsyncCommands, Client};
+use tokio::sync::{mpsc, OnceCell};
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::session::Metrics;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("wrong type for key {0}")]
+    WrongType(String),
+    /// The script is not loaded, e.g. after a restart or SCRIPT FLUSH.
+    #[error("no script with SHA1 {0}")]
+    NoScript(String),
+    /// No reply within the caller's deadline.
+    #[error("timed out")]
+    Timeout,
+    /// No Redis node was reachable recently; not retried until the
+    /// backoff runs out.
+    #[error("redis unavailable, backing off")]
+    Unavailable,
+}
+
+/// Key-value storage behind every shared-state feature (mailbox lookups,
+/// blocklists, feature flag overrides, audit trail).
+///
+/// Operations are named after what callers need rather than Redis commands,
+/// so a backend only has to provide strings with expiry, sets, lists, hashes
+/// and counters.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Whether `key` exists.
+    async fn exists(&self, key: &str) -> Result<bool, StoreError>;
+
+    /// Membership of each of `members` in the set at `key`, in order.
+    async fn set_contains(&self, key: &str, members: &[&str]) -> Result<Vec<bool>, StoreError>;
+
+    /// Whether `key` exists and whether `member` is in the set at `set`, in
+    /// one round trip.
+    async fn exists_and_contains(
+        &self,
+        key: &str,
+        set: &str,
+        member: &str,
+    ) -> Result<(bool, bool), StoreError>;
+
+    /// Every member of the set at `key`, read in batches.
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError>;
+
+    /// Every key starting with `prefix` and ending with `suffix`, read in
+    /// batches. Keys created or deleted meanwhile may be missed.
+    async fn keys_between(&self, prefix: &str, suffix: &str) -> Result<Vec<String>, StoreError>;
+
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError>;
+
+    async fn set_ex(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError>;
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError>;
+
+    /// Increment a counter, starting its `ttl_secs` expiry when it is created.
+    async fn incr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError>;
+
+    /// Take back an `incr`; the counter keeps its expiry.
+    async fn decr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError>;
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError>;
+
+    async fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StoreError>;
+
+    /// Append to a list and reset its expiry to `ttl_secs`.
+    async fn list_push(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError>;
+
+    async fn list_all(&self, key: &str) -> Result<Vec<String>, StoreError>;
+
+    /// Atomically replace a list's contents, with a new `ttl_secs` expiry.
+    async fn list_replace(
+        &self,
+        key: &str,
+        values: &[String],
+        ttl_secs: u64,
+    ) -> Result<(), StoreError>;
+
+    /// Seconds until `key` expires; `None` if it is missing or never expires.
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, StoreError>;
+
+    /// Make `key` expire `ttl_secs` from now. Returns whether it exists.
+    async fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StoreError>;
+
+    /// Messages published to `channel` from now on. A lost subscription is
+    /// renewed, but messages published meanwhile are missed. Dropping the
+    /// receiver ends the subscription at the next message.
+    async fn subscribe(&self, channel: &str)
+        -> Result<mpsc::UnboundedReceiver<String>, StoreError>;
+
+    /// Load a Lua script, returning the SHA1 it is run by.
+    async fn script_load(&self, script: &str) -> Result<String, StoreError>;
+
+    /// Run a loaded script. Its reply must be an integer; nil (or Lua
+    /// `false`) counts as 0.
+    async fn eval_sha(&self, sha: &str, keys: &[&str], args: &[&str]) -> Result<i64, StoreError>;
+}
+
+/// The SHA1 that Redis loads `script` under.
+pub fn script_sha(script: &str) -> String {
+    let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, script.as_bytes());
+    digest.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Keys or members asked for per SCAN/SSCAN round trip.
+const SCAN_BATCH: usize = 1000;
+
+/// `literal` with Redis glob characters escaped.
+fn glob_escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Shared handle to the configured store.
+pub type SharedStore = Arc<dyn Store>;
+
+/// Forward the messages of `pubsub` to the returned receiver, subscribing
+/// again with `resubscribe` whenever the subscription is lost.
+fn forward(
+    channel: &str,
+    mut pubsub: PubSub,
+    resubscribe: impl Fn(String) -> BoxFuture<'static, Result<PubSub, StoreError>> + Send + 'static,
+) -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let channel = channel.to_string();
+    tokio::spawn(async move {
+        loop {
+            let mut messages = pubsub.into_on_message();
+            while let Some(message) = messages.next().await {
+                let Ok(payload) = message.get_payload::<String>() else {
+                    continue;
+                };
+                if tx.send(payload).is_err() {
+                    return;
+                }
+            }
+            pubsub = loop {
+                if tx.is_closed() {
+                    return;
+                }
+                warn!(channel = %channel, "redis subscription lost, resubscribing");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                match resubscribe(channel.clone()).await {
+                    Ok(pubsub) => break pubsub,
+                    Err(e) => warn!(channel = %channel, error = %e, "redis subscribe failed"),
+                }
+            };
+        }
+    });
+    rx
+}
+
+/// Redis-backed store (one node).
+#[derive(Clone)]
+pub struct RedisStore {
+    /// For subscriptions, which need a connection of their own.
+    client: Client,
+    conn: ConnectionManager,
+}
+
+impl RedisStore {
+    pub fn new(client: Client, conn: ConnectionManager) -> Self {
+        Self { client, conn }
+    }
+
+    /// Add `delta` to a counter. It is created with its expiry in the same
+    /// transaction, so a counter is never left without one.
+    async fn add(&self, key: &str, delta: i64, ttl_secs: u64) -> Result<i64, StoreError> {
+        let mut conn = self.conn.clone();
+        let (count,): (i64,) = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(key)
+            .arg(0)
+            .arg("EX")
+            .arg(ttl_secs.max(1))
+            .arg("NX")
+            .ignore()
+            .incr(key, delta)
+            .query_async(&mut conn)
+            .await?;
+        Ok(count)
+    }
+
+    async fn pubsub(&self, channel: &str) -> Result<PubSub, StoreError> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(channel).await?;
+        Ok(pubsub)
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        let mut conn = self.conn.clone();
+        redis::cmd("PING").query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+}
+
+/// Redis nodes in order of preference, the production backend.
+///
+/// Commands go to the current node. When it cannot be reached, the next
+/// node that connects becomes current and the command is retried there;
+/// other errors are returned as they are. [`fail_back`](Self::fail_back)
+/// returns to a preferred node once it answers again.
+#[derive(Clone)]
+pub struct FailoverStore {
+    inner: Arc<FailoverInner>,
+}
+
+struct FailoverInner {
+    nodes: Vec<Node>,
+    current: AtomicUsize,
+    timeout: Duration,
+    backoff: Backoff,
+    metrics: Arc<Metrics>,
+}
+
+/// Exponential backoff once no node can be reached.
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    state: Mutex<BackoffState>,
+}
+
+#[derive(Default)]
+struct BackoffState {
+    /// Attempts in a row that reached no node.
+    failures: u32,
+    /// Until when commands fail without trying.
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32 << failures.saturating_sub(1).min(20);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+struct Node {
+    /// Host and port, for logs (the URL may hold a password).
+    addr: String,
+    client: Client,
+    store: OnceCell<RedisStore>,
+}
+
+impl FailoverStore {
+    /// Nodes for `urls`, connected on first use, with the timeout and
+    /// backoff from `config`. Health is reported in `metrics`.
+    pub fn new(
+        urls: &[String],
+        config: &Config,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, StoreError> {
+        let nodes = urls
+            .iter()
+            .map(|url| {
+                let client = Client::open(url.as_str())?;
+                Ok(Node {
+                    addr: client.get_connection_info().addr.to_string(),
+                    client,
+                    store: OnceCell::new(),
+                })
+            })
+            .collect::<Result<_, StoreError>>()?;
+        Ok(Self {
+            inner: Arc::new(FailoverInner {
+                nodes,
+                current: AtomicUsize::new(0),
+                timeout: Duration::from_millis(config.redis_timeout_ms),
+                backoff: Backoff {
+                    initial: Duration::from_millis(config.redis_backoff_initial_ms),
+                    max: Duration::from_millis(config.redis_backoff_max_ms),
+                    state: Mutex::new(BackoffState::default()),
+                },
+                metrics,
+            }),
+        })
+    }
+
+    /// Connect to the first node that answers. Returns its address.
+    pub async fn connect(&self) -> Result<String, StoreError> {
+        self.run(|store| async move { store.ping().await }.boxed())
+            .await?;
+        Ok(self.current_addr())
+    }
+
+    pub fn current_addr(&self) -> String {
+        let current = self.inner.current.load(Ordering::Relaxed);
+        self.inner.nodes[current].addr.clone()
+    }
+
+    /// Every `interval`, make the most preferred node that answers PING
+    /// current again.
+    pub async fn fail_back(self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let current = self.inner.current.load(Ordering::Relaxed);
+            for (i, node) in self.inner.nodes[..current].iter().enumerate() {
+                let Ok(store) = self.inner.connect(node).await else {
+                    continue;
+                };
+                if store.ping().await.is_ok() {
+                    self.inner.switch(current, i);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Run `op` on the current node, failing over to the following nodes
+    /// (wrapping around) while they cannot be reached. When none could be
+    /// reached, commands fail with [`StoreError::Unavailable`] until the
+    /// backoff runs out, then one command tries again.
+    async fn run<'a, T>(
+        &self,
+        op: impl Fn(RedisStore) -> BoxFuture<'a, Result<T, StoreError>>,
+    ) -> Result<T, StoreError> {
+        let inner = &self.inner;
+        inner.wait_for_backoff()?;
+        let start = inner.current.load(Ordering::Relaxed);
+        let count = inner.nodes.len();
+        let mut last_error = None;
+        for i in (0..count).map(|k| (start + k) % count) {
+            let node = &inner.nodes[i];
+            let result = match inner.connect(node).await {
+                Ok(store) => op(store).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if unreachable(&e) => {
+                    warn!(node = %node.addr, error = %e, "redis node unreachable");
+                    last_error = Some(e);
+                }
+                result => {
+                    if i != start {
+                        inner.switch(start, i);
+                    }
+                    inner.reached();
+                    return result;
+                }
+            }
+        }
+        inner.unreached();
+        Err(last_error.expect("at least one Redis node"))
+    }
+}
+
+impl FailoverInner {
+    /// Fail while backing off. Once the backoff has run out, let this
+    /// caller try, keeping the others waiting for one more delay.
+    fn wait_for_backoff(&self) -> Result<(), StoreError> {
+        let mut state = self.backoff.state.lock().unwrap();
+        let Some(retry_at) = state.retry_at else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < retry_at {
+            return Err(StoreError::Unavailable);
+        }
+        state.retry_at = Some(now + self.backoff.delay(state.failures));
+        self.metrics
+            .redis_reconnects
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reached(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.metrics
+            .redis_last_success
+            .store(now, Ordering::Relaxed);
+        let mut state = self.backoff.state.lock().unwrap();
+        if state.failures > 0 {
+            info!(failures = state.failures, "redis reachable again");
+            *state = BackoffState::default();
+            self.metrics
+                .redis_consecutive_errors
+                .store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn unreached(&self) {
+        let mut state = self.backoff.state.lock().unwrap();
+        state.failures += 1;
+        self.metrics
+            .redis_consecutive_errors
+            .store(state.failures.into(), Ordering::Relaxed);
+        if self.backoff.initial.is_zero() {
+            return;
+        }
+        let delay = self.backoff.delay(state.failures);
+        state.retry_at = Some(Instant::now() + delay);
+        warn!(
+            failures = state.failures,
+            retry_ms = delay.as_millis() as u64,
+            "[REDIS-DOWN] no redis node reachable, backing off"
+        );
+    }
+
+    async fn connect(&self, node: &Node) -> Result<RedisStore, StoreError> {
+        node.store
+            .get_or_try_init(|| async {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(self.timeout)
+                    .set_response_timeout(self.timeout)
+                    .set_number_of_retries(1);
+                let conn = ConnectionManager::new_with_config(node.client.clone(), config).await?;
+                Ok(RedisStore::new(node.client.clone(), conn))
+            })
+            .await
+            .cloned()
+    }
+
+    fn switch(&self, from: usize, to: usize) {
+        if self
+            .current
+            .compare_exchange(from, to, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            let (from_addr, to_addr) = (&self.nodes[from].addr, &self.nodes[to].addr);
+            if to < from {
+                info!(from = %from_addr, to = %to_addr, "[REDIS-FAILBACK] back on a preferred redis node");
+            } else {
+                warn!(from = %from_addr, to = %to_addr, "[REDIS-FAILOVER] switched redis node");
+            }
+        }
+    }
+}
+
+/// Whether `e` means the node is down rather than the command failed.
+fn unreachable(e: &StoreError) -> bool {
+    match e {
+        StoreError::Redis(e) => {
+            e.is_io_error()
+                || e.is_connection_refusal()
+                || e.is_connection_dropped()
+                || e.is_timeout()
+        }
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl Store for FailoverStore {
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        self.run(|s| async move { s.exists(key).await }.boxed())
+            .await
+    }
+
+    async fn set_contains(&self, key: &str, members: &[&str]) -> Result<Vec<bool>, StoreError> {
+        self.run(|s| async move { s.set_contains(key, members).await }.boxed())
+            .await
+    }
+
+    async fn exists_and_contains(
+        &self,
+        key: &str,
+        set: &str,
+        member: &str,
+    ) -> Result<(bool, bool), StoreError> {
+        self.run(|s| async move { s.exists_and_contains(key, set, member).await }.boxed())
+            .await
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        self.run(|s| async move { s.set_members(key).await }.boxed())
+            .await
+    }
+
+    async fn keys_between(&self, prefix: &str, suffix: &str) -> Result<Vec<String>, StoreError> {
+        self.run(|s| async move { s.keys_between(prefix, suffix).await }.boxed())
+            .await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        self.run(|s| async move { s.get(key).await }.boxed()).await
+    }
+
+    async fn set_ex(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        self.run(|s| async move { s.set_ex(key, value, ttl_secs).await }.boxed())
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.run(|s| async move { s.delete(key).await }.boxed())
+            .await
+    }
+
+    async fn incr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        self.run(|s| async move { s.incr(key, ttl_secs).await }.boxed())
+            .await
+    }
+
+    async fn decr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        self.run(|s| async move { s.decr(key, ttl_secs).await }.boxed())
+            .await
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        self.run(|s| async move { s.hash_get(key, field).await }.boxed())
+            .await
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StoreError> {
+        self.run(|s| async move { s.hash_get_all(key).await }.boxed())
+            .await
+    }
+
+    async fn list_push(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        self.run(|s| async move { s.list_push(key, value, ttl_secs).await }.boxed())
+            .await
+    }
+
+    async fn list_all(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        self.run(|s| async move { s.list_all(key).await }.boxed())
+            .await
+    }
+
+    async fn list_replace(
+        &self,
+        key: &str,
+        values: &[String],
+        ttl_secs: u64,
+    ) -> Result<(), StoreError> {
+        self.run(|s| async move { s.list_replace(key, values, ttl_secs).await }.boxed())
+            .await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        self.run(|s| async move { s.ttl(key).await }.boxed()).await
+    }
+
+    async fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StoreError> {
+        self.run(|s| async move { s.expire(key, ttl_secs).await }.boxed())
+            .await
+    }
+
+    /// Subscribes on the current node; a lost subscription is renewed on
+    /// whichever node is current then.
+    async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> Result<mpsc::UnboundedReceiver<String>, StoreError> {
+        let pubsub = self
+            .run(|s| async move { s.pubsub(channel).await }.boxed())
+            .await?;
+        let store = self.clone();
+        Ok(forward(channel, pubsub, move |channel| {
+            let store = store.clone();
+            async move {
+                store
+                    .run(|s| {
+                        let channel = channel.clone();
+                        async move { s.pubsub(&channel).await }.boxed()
+                    })
+                    .await
+            }
+            .boxed()
+        }))
+    }
+
+    async fn script_load(&self, script: &str) -> Result<String, StoreError> {
+        self.run(|s| async move { s.script_load(script).await }.boxed())
+            .await
+    }
+
+    async fn eval_sha(&self, sha: &str, keys: &[&str], args: &[&str]) -> Result<i64, StoreError> {
+        self.run(|s| async move { s.eval_sha(sha, keys, args).await }.boxed())
+            .await
+    }
+}
+
+#[async_trait]
+impl Store for RedisStore {
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(conn.exists(key).await?)
+    }
+
+    async fn set_contains(&self, key: &str, members: &[&str]) -> Result<Vec<bool>, StoreError> {
+        let mut pipe = redis::pipe();
+        for member in members {
+            pipe.sismember(key, *member);
+        }
+        let mut conn = self.conn.clone();
+        Ok(pipe.query_async(&mut conn).await?)
+    }
+
+    async fn exists_and_contains(
+        &self,
+        key: &str,
+        set: &str,
+        member: &str,
+    ) -> Result<(bool, bool), StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(redis::pipe()
+            .exists(key)
+            .sismember(set, member)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.conn.clone();
+        let mut cmd = redis::cmd("SSCAN");
+        cmd.arg(key).cursor_arg(0).arg("COUNT").arg(SCAN_BATCH);
+        let mut members = cmd.iter_async::<String>(&mut conn).await?;
+        let mut all = Vec::new();
+        while let Some(member) = members.next_item().await {
+            all.push(member);
+        }
+        Ok(all)
+    }
+
+    async fn keys_between(&self, prefix: &str, suffix: &str) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.conn.clone();
+        let pattern = format!("{}*{}", glob_escape(prefix), glob_escape(suffix));
+        let mut cmd = redis::cmd("SCAN");
+        cmd.cursor_arg(0)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(SCAN_BATCH);
+        let mut keys = cmd.iter_async::<String>(&mut conn).await?;
+        let mut all = Vec::new();
+        while let Some(key) = keys.next_item().await {
+            all.push(key);
+        }
+        Ok(all)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(conn.get(key).await?)
+    }
+
+    async fn set_ex(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        let mut conn = self.conn.clone();
+        conn.set_ex::<_, _, ()>(key, value, ttl_secs.max(1)).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn.clone();
+        conn.del::<_, ()>(key).await?;
+        Ok(())
+    }
+
+    async fn incr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        self.add(key, 1, ttl_secs).await
+    }
+
+    async fn decr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        self.add(key, -1, ttl_secs).await
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(conn.hget(key, field).await?)
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(conn.hgetall(key).await?)
+    }
+
+    async fn list_push(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        let mut conn = self.conn.clone();
+        redis::pipe()
+            .rpush(key, value)
+            .ignore()
+            .expire(key, ttl_secs.max(1) as i64)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_all(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(conn.lrange(key, 0, -1).await?)
+    }
+
+    async fn list_replace(
+        &self,
+        key: &str,
+        values: &[String],
+        ttl_secs: u64,
+    ) -> Result<(), StoreError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(key).ignore();
+        if !values.is_empty() {
+            pipe.rpush(key, values)
+                .ignore()
+                .expire(key, ttl_secs.max(1) as i64)
+                .ignore();
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        let mut conn = self.conn.clone();
+        let ttl: i64 = conn.ttl(key).await?;
+        Ok((ttl > 0).then_some(ttl as u64))
+    }
+
+    async fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(conn.expire(key, ttl_secs.max(1) as i64).await?)
+    }
+
+    async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> Result<mpsc::UnboundedReceiver<String>, StoreError> {
+        let pubsub = self.pubsub(channel).await?;
+        let store = self.clone();
+        Ok(forward(channel, pubsub, move |channel| {
+            let store = store.clone();
+            async move { store.pubsub(&channel).await }.boxed()
+        }))
+    }
+
+    async fn script_load(&self, script: &str) -> Result<String, StoreError> {
+        let mut conn = self.conn.clone();
+        Ok(redis::cmd("SCRIPT")
+            .arg("LOAD")
+            .arg(script)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn eval_sha(&self, sha: &str, keys: &[&str], args: &[&str]) -> Result<i64, StoreError> {
+        let mut conn = self.conn.clone();
+        let reply: Option<i64> = redis::cmd("EVALSHA")
+            .arg(sha)
+            .arg(keys.len())
+            .arg(keys)
+            .arg(args)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| match e.kind() {
+                redis::ErrorKind::NoScriptError => StoreError::NoScript(sha.to_string()),
+                _ => StoreError::Redis(e),
+            })?;
+        Ok(reply.unwrap_or(0))
+    }
+}
+
+enum Value {
+    String(String),
+    Set(HashSet<String>),
+    List(Vec<String>),
+    Hash(HashMap<String, String>),
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+fn expiry(ttl_secs: u64) -> Option<Instant> {
+    Some(Instant::now() + Duration::from_secs(ttl_secs.max(1)))
+}
+
+/// In-process store, used by tests in place of Redis.
+///
+/// Expiry follows tokio's clock, so paused-time tests can advance past TTLs.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    subscribers: Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<String>>>>>,
+    scripts: Arc<Mutex<Scripts>>,
+}
+
+/// Stand-in for a Lua script: gets KEYS and ARGV, returns the reply.
+type ScriptFn = Arc<dyn Fn(&[&str], &[&str]) -> i64 + Send + Sync>;
+
+#[derive(Default)]
+struct Scripts {
+    /// By source, for `script_load`.
+    defined: HashMap<String, ScriptFn>,
+    /// By SHA1, for `eval_sha`.
+    loaded: HashMap<String, ScriptFn>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&self, key: &str, delta: i64, ttl_secs: u64) -> Result<i64, StoreError> {
+        let mut entries = self.live(key);
+        let entry = entries.entry(key.to_string()).or_insert_with(|| Entry {
+            value: Value::String("0".to_string()),
+            expires_at: expiry(ttl_secs),
+        });
+        let Value::String(current) = &mut entry.value else {
+            return Err(StoreError::WrongType(key.to_string()));
+        };
+        let count = current
+            .parse::<i64>()
+            .map_err(|_| StoreError::WrongType(key.to_string()))?
+            + delta;
+        *current = count.to_string();
+        Ok(count)
+    }
+
+    /// Add a member to a set (seeding helper for tests).
+    pub fn set_add(&self, key: &str, member: &str) {
+        self.with_entry(
+            key,
+            || Value::Set(HashSet::new()),
+            |value| {
+                if let Value::Set(set) = value {
+                    set.insert(member.to_string());
+                }
+            },
+        );
+    }
+
+    /// Set a hash field (seeding helper for tests).
+    pub fn hash_set(&self, key: &str, field: &str, value: &str) {
+        self.with_entry(
+            key,
+            || Value::Hash(HashMap::new()),
+            |current| {
+                if let Value::Hash(hash) = current {
+                    hash.insert(field.to_string(), value.to_string());
+                }
+            },
+        );
+    }
+
+    /// Deliver `message` to the subscribers of `channel` (test helper, like
+    /// PUBLISH). Returns how many received it.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(senders) = subscribers.get_mut(channel) else {
+            return 0;
+        };
+        senders.retain(|tx| tx.send(message.to_string()).is_ok());
+        senders.len()
+    }
+
+    /// Make `script` loadable, running `run` in place of the Lua source
+    /// (test helper).
+    pub fn define_script(
+        &self,
+        script: &str,
+        run: impl Fn(&[&str], &[&str]) -> i64 + Send + Sync + 'static,
+    ) {
+        self.scripts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .defined
+            .insert(script.to_string(), Arc::new(run));
+    }
+
+    /// Forget every loaded script (test helper, like SCRIPT FLUSH).
+    pub fn flush_scripts(&self) {
+        self.scripts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .loaded
+            .clear();
+    }
+
+    /// Lock the map, dropping `key` first if it has expired.
+    fn live(&self, key: &str) -> MutexGuard<'_, HashMap<String, Entry>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.get(key).is_some_and(Entry::expired) {
+            entries.remove(key);
+        }
+        entries
+    }
+
+    /// Run `f` on the value at `key`, creating it with `empty` if missing.
+    fn with_entry(&self, key: &str, empty: impl FnOnce() -> Value, f: impl FnOnce(&mut Value)) {
+        let mut entries = self.live(key);
+        let entry = entries.entry(key.to_string()).or_insert_with(|| Entry {
+            value: empty(),
+            expires_at: None,
+        });
+        f(&mut entry.value)
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        Ok(self.live(key).contains_key(key))
+    }
+
+    async fn set_contains(&self, key: &str, members: &[&str]) -> Result<Vec<bool>, StoreError> {
+        match self.live(key).get(key).map(|e| &e.value) {
+            None => Ok(vec![false; members.len()]),
+            Some(Value::Set(set)) => Ok(members.iter().map(|m| set.contains(*m)).collect()),
+            Some(_) => Err(StoreError::WrongType(key.to_string())),
+        }
+    }
+
+    async fn exists_and_contains(
+        &self,
+        key: &str,
+        set: &str,
+        member: &str,
+    ) -> Result<(bool, bool), StoreError> {
+        let exists = self.exists(key).await?;
+        let contains = self.set_contains(set, &[member]).await?;
+        Ok((exists, contains[0]))
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        match self.live(key).get(key).map(|e| &e.value) {
+            None => Ok(Vec::new()),
+            Some(Value::Set(set)) => Ok(set.iter().cloned().collect()),
+            Some(_) => Err(StoreError::WrongType(key.to_string())),
+        }
+    }
+
+    async fn keys_between(&self, prefix: &str, suffix: &str) -> Result<Vec<String>, StoreError> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(entries
+            .iter()
+            .filter(|(key, entry)| {
+                !entry.expired()
+                    && key.len() >= prefix.len() + suffix.len()
+                    && key.starts_with(prefix)
+                    && key.ends_with(suffix)
+            })
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        match self.live(key).get(key).map(|e| &e.value) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(StoreError::WrongType(key.to_string())),
+        }
+    }
+
+    async fn set_ex(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        self.live(key).insert(
+            key.to_string(),
+            Entry {
+                value: Value::String(value.to_string()),
+                expires_at: expiry(ttl_secs),
+            },
+        );
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.live(key).remove(key);
+        Ok(())
+    }
+
+    async fn incr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        self.add(key, 1, ttl_secs)
+    }
+
+    async fn decr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        self.add(key, -1, ttl_secs)
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        match self.live(key).get(key).map(|e| &e.value) {
+            None => Ok(None),
+            Some(Value::Hash(hash)) => Ok(hash.get(field).cloned()),
+            Some(_) => Err(StoreError::WrongType(key.to_string())),
+        }
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StoreError> {
+        match self.live(key).get(key).map(|e| &e.value) {
+            None => Ok(HashMap::new()),
+            Some(Value::Hash(hash)) => Ok(hash.clone()),
+            Some(_) => Err(StoreError::WrongType(key.to_string())),
+        }
+    }
+
+    async fn list_push(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        let mut entries = self.live(key);
+        let entry = entries.entry(key.to_string()).or_insert_with(|| Entry {
+            value: Value::List(Vec::new()),
+            expires_at: None,
+        });
+        let Value::List(list) = &mut entry.value else {
+            return Err(StoreError::WrongType(key.to_string()));
+        };
+        list.push(value.to_string());
+        entry.expires_at = expiry(ttl_secs);
+        Ok(())
+    }
+
+    async fn list_all(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        match self.live(key).get(key).map(|e| &e.value) {
+            None => Ok(Vec::new()),
+            Some(Value::List(list)) => Ok(list.clone()),
+            Some(_) => Err(StoreError::WrongType(key.to_string())),
+        }
+    }
+
+    async fn list_replace(
+        &self,
+        key: &str,
+        values: &[String],
+        ttl_secs: u64,
+    ) -> Result<(), StoreError> {
+        let mut entries = self.live(key);
+        if values.is_empty() {
+            entries.remove(key);
+        } else {
+            entries.insert(
+                key.to_string(),
+                Entry {
+                    value: Value::List(values.to_vec()),
+                    expires_at: expiry(ttl_secs),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        let remaining = self
+            .live(key)
+            .get(key)
+            .and_then(|e| e.expires_at)
+            .map(|at| at.saturating_duration_since(Instant::now()).as_secs());
+        Ok(remaining.filter(|&secs| secs > 0))
+    }
+
+    async fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StoreError> {
+        let mut entries = self.live(key);
+        let Some(entry) = entries.get_mut(key) else {
+            return Ok(false);
+        };
+        entry.expires_at = expiry(ttl_secs);
+        Ok(true)
+    }
+
+    async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> Result<mpsc::UnboundedReceiver<String>, StoreError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(channel.to_string())
+            .or_default()
+            .push(tx);
+        Ok(rx)
+    }
+
+    async fn script_load(&self, script: &str) -> Result<String, StoreError> {
+        let sha = script_sha(script);
+        let mut scripts = self.scripts.lock().unwrap_or_else(|e| e.into_inner());
+        let run = scripts
+            .defined
+            .get(script)
+            .cloned()
+            .ok_or_else(|| StoreError::NoScript(sha.clone()))?;
+        scripts.loaded.insert(sha.clone(), run);
+        Ok(sha)
+    }
+
+    async fn eval_sha(&self, sha: &str, keys: &[&str], args: &[&str]) -> Result<i64, StoreError> {
+        let run = self
+            .scripts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .loaded
+            .get(sha)
+            .cloned()
+            .ok_or_else(|| StoreError::NoScript(sha.to_string()))?;
+        Ok(run(keys, args))
+    }
+}
@@ -2,8 +2,12 @@ use std::fs::File;
 use std::io::BufReader;
 use std::sync::Arc;
 
-use rustls::ServerConfig;
-use tokio_rustls::TlsAcceptor;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 use tracing::info;
 
 /// TLS configuration wrapper for STARTTLS support.
@@ -40,11 +44,114 @@ impl TlsConfig {
         })
     }
 
-    /// Perform TLS handshake on a plain TCP stream.
-    pub async fn accept(
+    /// Perform TLS handshake on a plain stream.
+    pub async fn accept<IO: AsyncRead + AsyncWrite + Unpin>(
         &self,
-        stream: tokio::net::TcpStream,
-    ) -> Result<tokio_rustls::server::TlsStream<tokio::net::TcpStream>, std::io::Error> {
+        stream: IO,
+    ) -> Result<tokio_rustls::server::TlsStream<IO>, std::io::Error> {
         self.acceptor.accept(stream).await
     }
 }
+
+/// Certificate verifier that checks handshake signatures but trusts any
+/// certificate. For connecting to our own listener, whose certificate may
+/// not match the loopback address, and to backends without a configured CA.
+#[derive(Debug)]
+struct LoopbackVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for LoopbackVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn crypto_provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+/// TLS client for connecting to our own listener (startup self-test and
+/// conformance suite) and to Postgres below `sslmode=verify-full`; the
+/// certificate is not verified.
+pub fn loopback_connector() -> TlsConnector {
+    let provider = crypto_provider();
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions are supported")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(LoopbackVerifier(provider)))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// TLS client for STARTTLS to the relay backend. With `ca_path`, the
+/// backend certificate must chain to one of the PEM certificates in that
+/// file; without it, the certificate is not verified.
+pub fn backend_connector(
+    ca_path: Option<&str>,
+) -> Result<TlsConnector, Box<dyn std::error::Error>> {
+    let Some(ca_path) = ca_path else {
+        return Ok(loopback_connector());
+    };
+    let connector = verifying_connector(ca_path)?;
+    info!(ca = ca_path, "backend TLS verification enabled");
+    Ok(connector)
+}
+
+/// Client TLS that verifies servers against the PEM certificates in
+/// `ca_path`.
+pub fn verifying_connector(ca_path: &str) -> Result<TlsConnector, Box<dyn std::error::Error>> {
+    let mut roots = RootCertStore::empty();
+    let mut reader = BufReader::new(File::open(ca_path)?);
+    for cert in rustls_pemfile::certs(&mut reader) {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        return Err("no certificates found in CA file".into());
+    }
+    let config = ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
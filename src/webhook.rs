@@ -0,0 +1,165 @@
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, warn};
+
+use crate::audit::unix_now;
+use crate::config::Config;
+use crate::http::{self, HttpUrl, Request};
+use crate::session::Metrics;
+use crate::tls;
+
+/// POSTs a JSON event to `WEBHOOK_URL` for every relayed message, so the
+/// frontend learns about new mail without polling.
+///
+/// Like the mirror, events are sent after the client has its reply and
+/// never hold up delivery. Failed POSTs are retried with doubling backoff;
+/// events beyond `WEBHOOK_MAX_PENDING` in flight are dropped. Both count as
+/// webhook errors.
+#[derive(Clone)]
+pub struct Webhook {
+    inner: Arc<WebhookInner>,
+}
+
+struct WebhookInner {
+    url: HttpUrl,
+    /// Sent as `Authorization: Bearer <token>`.
+    token: Option<String>,
+    /// Only for `https` URLs.
+    tls: Option<TlsConnector>,
+    pending: Arc<Semaphore>,
+    retries: u32,
+    retry_backoff: Duration,
+    timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+/// A delivered message, as reported to the webhook.
+#[derive(Clone, Debug)]
+pub struct DeliveryEvent {
+    /// `None` for messages delivered from the spool.
+    pub session_id: Option<String>,
+    pub sender: String,
+    /// Recipients the backend accepted.
+    pub recipients: Vec<String>,
+    pub size: usize,
+    pub message_id: Option<String>,
+}
+
+impl DeliveryEvent {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "event": "delivered",
+            "session_id": self.session_id,
+            "sender": self.sender,
+            "recipients": self.recipients,
+            "size": self.size,
+            "message_id": self.message_id,
+            "timestamp": unix_now(),
+        })
+    }
+}
+
+impl Webhook {
+    /// Panics on an invalid `WEBHOOK_URL` or `WEBHOOK_CA_PATH`, like other
+    /// startup configuration errors.
+    pub fn new(url: &str, config: &Config, metrics: Arc<Metrics>) -> Self {
+        let url: HttpUrl = url.parse().unwrap_or_else(|e| panic!("WEBHOOK_URL: {}", e));
+        let tls = url.https.then(|| {
+            tls::verifying_connector(&config.webhook_ca_path)
+                .unwrap_or_else(|e| panic!("WEBHOOK_CA_PATH: {}", e))
+        });
+        Self {
+            inner: Arc::new(WebhookInner {
+                url,
+                token: config.webhook_token.clone(),
+                tls,
+                pending: Arc::new(Semaphore::new(config.webhook_max_pending)),
+                retries: config.webhook_retries,
+                retry_backoff: Duration::from_millis(config.webhook_retry_backoff_ms),
+                timeout: Duration::from_secs(config.webhook_timeout_secs),
+                metrics,
+            }),
+        }
+    }
+
+    /// Queue an event without waiting for it. Returns false when too many
+    /// events are already in flight and this one was dropped.
+    pub fn submit(&self, event: DeliveryEvent) -> bool {
+        let Ok(permit) = self.inner.pending.clone().try_acquire_owned() else {
+            self.inner
+                .metrics
+                .webhook_errors
+                .fetch_add(1, Ordering::Relaxed);
+            warn!("[WEBHOOK-ERROR] too many events in flight, event dropped");
+            return false;
+        };
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            webhook.deliver(&event).await;
+            drop(permit);
+        });
+        true
+    }
+
+    async fn deliver(&self, event: &DeliveryEvent) {
+        let body = event.to_json().to_string();
+        let mut retry = 0;
+        loop {
+            let post = tokio::time::timeout(self.inner.timeout, self.post(body.as_bytes()));
+            let (error, retryable) = match post.await {
+                Ok(Ok(status)) if (200..300).contains(&status) => {
+                    debug!(status = status, "webhook event delivered");
+                    return;
+                }
+                // Other 4xx: the endpoint will not take this event however
+                // often it is sent
+                Ok(Ok(status)) => (
+                    format!("HTTP {status}"),
+                    !(400..500).contains(&status) || matches!(status, 408 | 429),
+                ),
+                Ok(Err(e)) => (e.to_string(), true),
+                Err(_) => ("timed out".to_string(), true),
+            };
+            if !retryable || retry >= self.inner.retries {
+                self.fail(&error, retry);
+                return;
+            }
+            let delay = self.inner.retry_backoff.saturating_mul(1 << retry.min(16));
+            retry += 1;
+            debug!(error = %error, retry = retry, "webhook POST failed, retrying");
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn fail(&self, error: &str, retries: u32) {
+        self.inner
+            .metrics
+            .webhook_errors
+            .fetch_add(1, Ordering::Relaxed);
+        warn!(
+            host = %self.inner.url.host,
+            error = error,
+            retries = retries,
+            "[WEBHOOK-ERROR] failed to post delivery event"
+        );
+    }
+
+    /// One POST; returns the HTTP status.
+    async fn post(&self, body: &[u8]) -> io::Result<u16> {
+        let url = &self.inner.url;
+        let request = Request {
+            method: "POST",
+            path: &url.path,
+            token: self.inner.token.as_deref(),
+            headers: &[],
+            body: Some(("application/json", body)),
+        };
+        http::send(url, self.inner.tls.as_ref(), &request).await
+    }
+}
@@ -0,0 +1,295 @@
+use std::borrow::Cow;
+
+use burngate::address::{
+    domain, domain_to_unicode, find_path, lookup_form, normalize, normalize_domain, split,
+    strip_source_route, validate, validate_domain, with_unicode_domain, AddressError, AddressList,
+};
+
+// -- split --
+
+#[test]
+fn split_at_last_at() {
+    assert_eq!(split("\"a@b\"@example.com"), ("\"a@b\"", "example.com"));
+}
+
+#[test]
+fn split_without_domain() {
+    assert_eq!(split("postmaster"), ("postmaster", ""));
+    assert_eq!(domain("postmaster"), "");
+}
+
+// -- find_path --
+
+#[test]
+fn path_ends_at_first_unquoted_bracket() {
+    assert_eq!(find_path("TO:<a@b.c> X=<y>"), Some(3..10));
+    assert_eq!(find_path("TO:<\"a>b\"@c.d>"), Some(3..14));
+    assert_eq!(find_path("TO:<a@b.c"), None);
+    assert_eq!(find_path("TO:a@b.c"), None);
+}
+
+// -- strip_source_route --
+
+#[test]
+fn source_route_dropped() {
+    assert_eq!(strip_source_route("@relay.example:user@c.d"), "user@c.d");
+    assert_eq!(
+        strip_source_route("@a.example,@[192.0.2.1]:user@c.d"),
+        "user@c.d"
+    );
+    assert_eq!(strip_source_route("user@c.d"), "user@c.d");
+}
+
+#[test]
+fn malformed_source_route_kept() {
+    for path in [
+        "@relay.example",
+        "@:user@c.d",
+        "@a.example,b.example:user@c.d",
+    ] {
+        assert_eq!(strip_source_route(path), path);
+    }
+}
+
+// -- validate --
+
+#[test]
+fn valid_addresses() {
+    for address in [
+        "user@example.com",
+        "first.last+tag@sub.example.com",
+        "o'brien@example.com",
+        "\"john doe\"@example.com",
+        "\"quote\\\"inside\"@example.com",
+        "user@[192.0.2.1]",
+        "user@[IPv6:2001:db8::1]",
+        "用户@例子.example",
+        "user@example.com.",
+        "Postmaster",
+    ] {
+        assert_eq!(validate(address), Ok(()), "{address}");
+    }
+}
+
+#[test]
+fn invalid_local_parts() {
+    for address in [
+        "@example.com",
+        "a b@example.com",
+        ".user@example.com",
+        "user.@example.com",
+        "us..er@example.com",
+        "us\ter@example.com",
+        "\"unterminated@example.com",
+        "\"ctl\u{7}\"@example.com",
+    ] {
+        assert!(
+            matches!(validate(address), Err(AddressError::InvalidLocalPart(_))),
+            "{address}"
+        );
+    }
+}
+
+#[test]
+fn invalid_domains() {
+    for address in [
+        "user@",
+        "user@exa mple.com",
+        "user@-example.com",
+        "user@example..com",
+        "user@exam_ple.com",
+        "user@[192.0.2.300]",
+        "user@[IPv6:nope]",
+        "user@[192.0.2.1",
+    ] {
+        assert!(
+            matches!(validate(address), Err(AddressError::InvalidDomain(_))),
+            "{address}"
+        );
+    }
+}
+
+#[test]
+fn missing_domain() {
+    assert_eq!(validate("alice"), Err(AddressError::MissingDomain));
+}
+
+#[test]
+fn length_limits() {
+    let local = "a".repeat(64);
+    assert_eq!(validate(&format!("{local}@example.com")), Ok(()));
+    assert_eq!(
+        validate(&format!("a{local}@example.com")),
+        Err(AddressError::TooLong)
+    );
+    let label = "b".repeat(63);
+    let long_domain = [label.as_str(); 4].join(".");
+    assert_eq!(validate_domain(&long_domain), Ok(()));
+    assert_eq!(
+        validate(&format!("user@{long_domain}")),
+        Err(AddressError::TooLong)
+    );
+    assert!(validate_domain(&format!("{}.com", "c".repeat(64))).is_err());
+}
+
+// -- normalize_domain --
+
+#[test]
+fn domain_lowercased() {
+    assert_eq!(normalize_domain("Tempy.EMAIL").unwrap(), "tempy.email");
+}
+
+#[test]
+fn domain_trailing_dot_dropped() {
+    assert_eq!(normalize_domain("tempy.email.").unwrap(), "tempy.email");
+}
+
+#[test]
+fn unicode_domain_becomes_punycode() {
+    assert_eq!(
+        normalize_domain("Bücher.example").unwrap(),
+        "xn--bcher-kva.example"
+    );
+}
+
+#[test]
+fn punycode_domain_unchanged() {
+    assert_eq!(
+        normalize_domain("XN--BCHER-KVA.example").unwrap(),
+        "xn--bcher-kva.example"
+    );
+}
+
+#[test]
+fn address_literal_lowercased_only() {
+    assert_eq!(
+        normalize_domain("[IPv6:2001:DB8::1]").unwrap(),
+        "[ipv6:2001:db8::1]"
+    );
+}
+
+#[test]
+fn invalid_idn_rejected() {
+    assert!(matches!(
+        normalize_domain("xn--a.example"),
+        Err(AddressError::InvalidDomain(_))
+    ));
+}
+
+// -- domain_to_unicode --
+
+#[test]
+fn punycode_decoded() {
+    assert_eq!(domain_to_unicode("xn--bcher-kva.example"), "bücher.example");
+}
+
+#[test]
+fn ascii_domain_unchanged() {
+    assert!(matches!(
+        domain_to_unicode("tempy.email"),
+        Cow::Borrowed("tempy.email")
+    ));
+}
+
+#[test]
+fn address_domain_decoded() {
+    assert_eq!(
+        with_unicode_domain("anna@xn--bcher-kva.example"),
+        "anna@bücher.example"
+    );
+    assert!(matches!(
+        with_unicode_domain("anna@tempy.email"),
+        Cow::Borrowed("anna@tempy.email")
+    ));
+}
+
+// -- normalize --
+
+#[test]
+fn address_lowercased() {
+    assert_eq!(
+        normalize("Alice@Example.COM", false).unwrap(),
+        "alice@example.com"
+    );
+}
+
+#[test]
+fn local_case_preserved_when_asked() {
+    assert_eq!(
+        normalize("Alice@Example.COM", true).unwrap(),
+        "Alice@example.com"
+    );
+}
+
+#[test]
+fn needless_quotes_removed() {
+    assert_eq!(
+        normalize("\"john.doe\"@example.com", false).unwrap(),
+        "john.doe@example.com"
+    );
+}
+
+#[test]
+fn needed_quotes_kept() {
+    assert_eq!(
+        normalize("\"John Doe\"@example.com", false).unwrap(),
+        "\"john doe\"@example.com"
+    );
+    assert_eq!(
+        normalize("\"a..b\"@example.com", false).unwrap(),
+        "\"a..b\"@example.com"
+    );
+}
+
+#[test]
+fn unicode_domain_in_address() {
+    assert_eq!(
+        normalize("user@пример.example", false).unwrap(),
+        "user@xn--e1afmkfd.example"
+    );
+}
+
+#[test]
+fn bare_local_part() {
+    assert_eq!(normalize("Postmaster", false).unwrap(), "postmaster");
+}
+
+// -- lookup_form --
+
+#[test]
+fn lookup_form_folds_preserved_case() {
+    let relay = normalize("Alice@Bücher.example", true).unwrap();
+    assert_eq!(lookup_form(&relay), "alice@xn--bcher-kva.example");
+}
+
+#[test]
+fn lookup_form_falls_back_to_lowercase() {
+    assert_eq!(lookup_form("A@XN--A.example"), "a@xn--a.example");
+}
+
+#[test]
+fn lookup_form_borrows_canonical_address() {
+    assert!(matches!(lookup_form("alice@example.com"), Cow::Borrowed(_)));
+    assert!(matches!(
+        lookup_form("xn--a@xn--bcher-kva.example"),
+        Cow::Borrowed(_)
+    ));
+    assert!(matches!(lookup_form("Alice@example.com"), Cow::Owned(_)));
+    assert!(matches!(
+        lookup_form("\"alice\"@example.com"),
+        Cow::Owned(_)
+    ));
+    assert!(matches!(lookup_form("alice@example.com."), Cow::Owned(_)));
+}
+
+// -- AddressList --
+
+#[test]
+fn address_list_display() {
+    let addresses = ["a@example.com", "b@example.com"];
+    assert_eq!(
+        AddressList(addresses.iter()).to_string(),
+        "[a@example.com, b@example.com]"
+    );
+    assert_eq!(AddressList(std::iter::empty::<&str>()).to_string(), "[]");
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use burngate::allowlist::{Allowlist, AllowlistError, Entries};
+use burngate::config::Config;
+use burngate::lookup::{AllowlistLookup, LookupBackend, LookupOutcome, MailboxLookup};
+use burngate::store::MemoryStore;
+
+fn file(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "burngate-allowlist-{}-{}",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn plaintext_and_json_lists_normalize_entries() {
+    let text = "# system mailboxes\nPostmaster@Example.com\n\nbücher.example  # whole domain\n";
+    let entries = Entries::parse(text).unwrap();
+    assert!(entries.addresses.contains("postmaster@example.com"));
+    assert!(entries.domains.contains("xn--bcher-kva.example"));
+
+    let json = r#"{"addresses": ["postmaster@example.com"], "domains": ["Bücher.example"]}"#;
+    assert_eq!(Entries::parse(json).unwrap(), entries);
+
+    assert!(entries.contains("anyone@xn--bcher-kva.example"));
+    assert!(!entries.contains("alice@example.com"));
+}
+
+#[test]
+fn invalid_entries_are_reported_by_line() {
+    assert!(matches!(
+        Entries::parse("ok.example\nbad domain\n"),
+        Err(AllowlistError::Invalid { line: 2, .. })
+    ));
+    assert!(matches!(
+        Entries::parse(r#"{"domains": "example.com"}"#),
+        Err(AllowlistError::Invalid { line: 0, .. })
+    ));
+    assert!(matches!(
+        Entries::parse("{not json"),
+        Err(AllowlistError::Json(_))
+    ));
+}
+
+#[tokio::test]
+async fn allowlisted_recipients_skip_the_backend() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    let backend = Arc::new(MailboxLookup::new(Arc::new(MemoryStore::new()), &config));
+    let path = file("skip", "abuse@example.com\n");
+    let allowlist = Arc::new(Allowlist::load(&path).unwrap());
+    let lookup = AllowlistLookup::new(allowlist.clone(), backend);
+
+    assert_eq!(
+        lookup.check("Abuse@Example.com").await,
+        LookupOutcome::Allowlisted
+    );
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Miss);
+
+    std::fs::write(&path, "example.com\n").unwrap();
+    allowlist.reload().await.unwrap();
+    assert_eq!(
+        lookup.check("bob@example.com").await,
+        LookupOutcome::Allowlisted
+    );
+
+    // A broken file keeps the previous entries
+    std::fs::write(&path, "not a domain!\n").unwrap();
+    assert!(allowlist.reload().await.is_err());
+    assert!(allowlist.contains("bob@example.com"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn changed_file_is_reloaded_by_the_watcher() {
+    let path = file("watch", "a@example.com\n");
+    let allowlist = Arc::new(Allowlist::load(&path).unwrap());
+    tokio::spawn(allowlist.clone().watch(Duration::from_millis(20)));
+
+    std::fs::write(&path, "a@example.com\nb@example.com\n").unwrap();
+    for _ in 0..100 {
+        if allowlist.contains("b@example.com") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(allowlist.contains("b@example.com"));
+    std::fs::remove_file(&path).unwrap();
+}
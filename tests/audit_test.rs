@@ -0,0 +1,158 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use burngate::audit::{
+    anonymize_address, anonymize_entry, anonymize_ip, unix_now, AuditEntry, AuditLog,
+    AuditTransaction, ByteCounters, CountingStream,
+};
+use burngate::config::Config;
+use burngate::store::{MemoryStore, Store};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn entry(ip: IpAddr) -> AuditEntry {
+    AuditEntry {
+        peer: SocketAddr::new(ip, 51234),
+        started_at: 1_700_000_000,
+        duration_ms: 1500,
+        bytes_in: 100,
+        bytes_out: 200,
+        tls: true,
+        rcpt_accepted: 1,
+        rcpt_rejected: 2,
+        messages_relayed: 1,
+        outcome: "completed",
+        commands: [("EHLO", 1), ("MAIL", 1), ("RCPT", 3), ("DATA", 1)].into(),
+        transactions: vec![AuditTransaction {
+            sender: "sender@example.org".to_string(),
+            recipients: vec!["alice@example.com".to_string()],
+            rcpt_rejected: 2,
+            size: Some(42),
+            outcome: "relayed",
+        }],
+    }
+}
+
+// -- anonymize_ip --
+
+#[test]
+fn anonymize_ipv4_truncates_last_octet() {
+    let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 77));
+    assert_eq!(anonymize_ip(ip), IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)));
+}
+
+#[test]
+fn anonymize_ipv6_truncates_to_48() {
+    let ip: IpAddr = "2001:db8:abcd:1234:5678::1".parse().unwrap();
+    let expected: IpAddr = "2001:db8:abcd::".parse().unwrap();
+    assert_eq!(anonymize_ip(ip), expected);
+}
+
+// -- AuditEntry::to_json --
+
+#[test]
+fn to_json_full_ip() {
+    let json = entry(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 9))).to_json(false);
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["ip"], "198.51.100.9");
+    assert_eq!(value["bytes_in"], 100);
+    assert_eq!(value["rcpt_rejected"], 2);
+    assert_eq!(value["tls"], true);
+    assert_eq!(value["outcome"], "completed");
+}
+
+#[test]
+fn to_json_anonymized() {
+    let json = entry(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 9))).to_json(true);
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["ip"], "198.51.100.0");
+    assert_eq!(value["transactions"][0]["sender"], "*@example.org");
+    assert_eq!(value["transactions"][0]["recipients"][0], "*@example.com");
+}
+
+#[test]
+fn to_json_summarizes_session() {
+    let json = entry(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 9))).to_json(false);
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["commands"]["RCPT"], 3);
+    let txn = &value["transactions"][0];
+    assert_eq!(txn["sender"], "sender@example.org");
+    assert_eq!(txn["recipients"][0], "alice@example.com");
+    assert_eq!(txn["rcpt_rejected"], 2);
+    assert_eq!(txn["size"], 42);
+    assert_eq!(txn["outcome"], "relayed");
+}
+
+// -- anonymize_address --
+
+#[test]
+fn anonymize_address_keeps_domain() {
+    assert_eq!(anonymize_address("alice@example.com"), "*@example.com");
+    assert_eq!(anonymize_address("postmaster"), "*");
+    assert_eq!(anonymize_address(""), "");
+}
+
+// -- anonymize_entry --
+
+#[test]
+fn anonymize_entry_rewrites_ip_and_addresses() {
+    let json = entry(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6))).to_json(false);
+    let value: serde_json::Value = serde_json::from_str(&anonymize_entry(&json)).unwrap();
+    assert_eq!(value["ip"], "2001:db8:1::");
+    assert_eq!(value["duration_ms"], 1500);
+    assert_eq!(value["transactions"][0]["sender"], "*@example.org");
+    assert_eq!(value["transactions"][0]["recipients"][0], "*@example.com");
+    assert_eq!(value["transactions"][0]["outcome"], "relayed");
+}
+
+#[test]
+fn anonymize_entry_leaves_garbage_untouched() {
+    assert_eq!(anonymize_entry("not json"), "not json");
+}
+
+// -- CountingStream --
+
+#[tokio::test]
+async fn counting_stream_counts_both_directions() {
+    let (client, server) = tokio::io::duplex(1024);
+    let counters = Arc::new(ByteCounters::default());
+    let mut counted = CountingStream::new(server, counters.clone());
+    let mut client = client;
+
+    client.write_all(b"EHLO example.com\r\n").await.unwrap();
+    let mut buf = [0u8; 18];
+    counted.read_exact(&mut buf).await.unwrap();
+    counted.write_all(b"250 OK\r\n").await.unwrap();
+
+    assert_eq!(counters.bytes_in.load(Ordering::Relaxed), 18);
+    assert_eq!(counters.bytes_out.load(Ordering::Relaxed), 8);
+}
+
+// -- AuditLog --
+
+#[tokio::test]
+async fn aged_entries_are_anonymized() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    let store = MemoryStore::new();
+    let audit = AuditLog::new(Arc::new(store.clone()), &config);
+
+    let mut old = entry(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 77)));
+    old.started_at = unix_now() - 10 * 86_400;
+    audit.record(&old).await;
+    let mut recent = entry(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 9)));
+    recent.started_at = unix_now();
+    audit.record(&recent).await;
+
+    audit.anonymize_expired().await.unwrap();
+
+    let old_key = format!("audit:conn:{}", old.started_at / 86_400);
+    let stored = store.list_all(&old_key).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert!(stored[0].contains("\"ip\":\"203.0.113.0\""));
+    assert!(store.exists(&format!("{old_key}:anon")).await.unwrap());
+
+    let recent_key = format!("audit:conn:{}", recent.started_at / 86_400);
+    let stored = store.list_all(&recent_key).await.unwrap();
+    assert!(stored[0].contains("\"ip\":\"198.51.100.9\""));
+}
@@ -0,0 +1,76 @@
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use burngate::autoblock::AutoBlocklist;
+use burngate::config::Config;
+use burngate::session::Metrics;
+use burngate::store::{MemoryStore, SharedStore};
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    Config::from_env()
+}
+
+fn blocklist(threshold: u32) -> (AutoBlocklist, SharedStore, Arc<Metrics>) {
+    let mut config = config();
+    config.auto_block_threshold = threshold;
+    config.auto_block_key_prefix = "ab:".to_string();
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    let metrics = Arc::new(Metrics::new());
+    let blocklist = AutoBlocklist::from_config(store.clone(), &config, metrics.clone()).unwrap();
+    (blocklist, store, metrics)
+}
+
+#[test]
+fn disabled_without_threshold() {
+    let mut config = config();
+    config.auto_block_threshold = 0;
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    assert!(AutoBlocklist::from_config(store, &config, Arc::new(Metrics::new())).is_none());
+}
+
+#[tokio::test]
+async fn ip_is_blocked_on_reaching_the_threshold() {
+    let (blocklist, store, metrics) = blocklist(3);
+    let ip: IpAddr = "192.0.2.7".parse().unwrap();
+    let other: IpAddr = "2001:db8::7".parse().unwrap();
+
+    assert!(!blocklist.strike(ip).await);
+    assert!(!blocklist.strike(ip).await);
+    assert!(!blocklist.strike(other).await);
+    assert!(!blocklist.is_blocked(ip).await);
+
+    assert!(blocklist.strike(ip).await);
+    assert!(blocklist.is_blocked(ip).await);
+    assert!(!blocklist.is_blocked(other).await);
+    assert!(store.ttl("ab:ip:192.0.2.7").await.unwrap().is_some());
+
+    // Further rejections do not block it again
+    assert!(!blocklist.strike(ip).await);
+    assert_eq!(metrics.auto_blocked.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn lifted_blocks_are_counted_by_the_sweep() {
+    let (blocklist, store, metrics) = blocklist(1);
+    let ip: IpAddr = "192.0.2.8".parse().unwrap();
+    assert!(blocklist.strike(ip).await);
+    assert_eq!(blocklist.sweep().await, 0);
+
+    store.delete("ab:ip:192.0.2.8").await.unwrap();
+    assert!(!blocklist.is_blocked(ip).await);
+    assert_eq!(blocklist.sweep().await, 1);
+    assert_eq!(blocklist.sweep().await, 0);
+    assert_eq!(metrics.auto_unblocked.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn spamtrap_hits_block_at_once() {
+    let (blocklist, _, metrics) = blocklist(5);
+    let ip: IpAddr = "192.0.2.9".parse().unwrap();
+    assert!(blocklist.block(ip, "spamtrap").await);
+    assert!(blocklist.is_blocked(ip).await);
+    assert!(!blocklist.block(ip, "spamtrap").await);
+    assert_eq!(metrics.auto_blocked.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,117 @@
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use burngate::banhook::BanHook;
+use burngate::config::Config;
+use burngate::session::Metrics;
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.ban_hook_timeout_ms = 2000;
+    config
+}
+
+/// A shell script at a fresh path, made executable.
+fn script(name: &str, body: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("burngate-{}-{}", name, std::process::id()));
+    std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[test]
+fn unset_hooks_are_none() {
+    assert!(BanHook::from_config(&config(), Arc::new(Metrics::new())).is_none());
+}
+
+#[tokio::test]
+async fn command_gets_the_ip_as_argument_and_environment() {
+    let out = std::env::temp_dir().join(format!("burngate-banhook-out-{}", std::process::id()));
+    let _ = std::fs::remove_file(&out);
+    let path = script(
+        "banhook",
+        r#"out="$1"; shift; echo "$* $BURNGATE_IP $BURNGATE_REASON $BURNGATE_TTL_SECS" > "$out""#,
+    );
+    let mut config = config();
+    config.ban_hook_command = Some(format!("{} {} add blocked", path.display(), out.display()));
+    let metrics = Arc::new(Metrics::new());
+    let hook = BanHook::from_config(&config, metrics.clone()).unwrap();
+
+    assert!(
+        hook.run("192.0.2.9".parse().unwrap(), "spamtrap", 600)
+            .await
+    );
+    assert_eq!(
+        std::fs::read_to_string(&out).unwrap(),
+        "add blocked 192.0.2.9 192.0.2.9 spamtrap 600\n"
+    );
+    assert_eq!(metrics.ban_hook_errors.load(Ordering::Relaxed), 0);
+
+    // Failing and slow commands count as errors
+    config.ban_hook_command = Some(script("banhook-fail", "exit 3").display().to_string());
+    let hook = BanHook::from_config(&config, metrics.clone()).unwrap();
+    assert!(
+        !hook
+            .run("192.0.2.9".parse().unwrap(), "spamtrap", 600)
+            .await
+    );
+    config.ban_hook_command = Some(script("banhook-slow", "sleep 5").display().to_string());
+    config.ban_hook_timeout_ms = 100;
+    let hook = BanHook::from_config(&config, metrics.clone()).unwrap();
+    assert!(
+        !hook
+            .run("192.0.2.9".parse().unwrap(), "spamtrap", 600)
+            .await
+    );
+    assert_eq!(metrics.ban_hook_errors.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn url_gets_a_json_event() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config();
+    config.ban_hook_url = Some(format!("http://{}/ban", listener.local_addr().unwrap()));
+    let hook = BanHook::from_config(&config, Arc::new(Metrics::new())).unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await.unwrap();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        (request_line, body)
+    });
+
+    assert!(
+        hook.run("2001:db8::1".parse().unwrap(), "unknown_recipients", 3600)
+            .await
+    );
+    let (request_line, body) = server.await.unwrap();
+    assert_eq!(request_line, "POST /ban HTTP/1.1\r\n");
+    let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(event["event"], "ip_blocked");
+    assert_eq!(event["ip"], "2001:db8::1");
+    assert_eq!(event["reason"], "unknown_recipients");
+    assert_eq!(event["ttl_secs"], 3600);
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use burngate::bloom::BloomFilter;
+use burngate::config::Config;
+use burngate::lookup::{BloomLookup, LookupBackend, LookupOutcome, MailboxLookup};
+use burngate::session::Metrics;
+use burngate::store::{MemoryStore, Store};
+
+#[test]
+fn filter_never_forgets_and_rarely_guesses() {
+    let mut filter = BloomFilter::new(10_000, 0.01);
+    for i in 0..10_000 {
+        filter.insert(&format!("user{i}@example.com"));
+    }
+    assert!((0..10_000).all(|i| filter.contains(&format!("user{i}@example.com"))));
+    let false_positives = (0..10_000)
+        .filter(|i| filter.contains(&format!("random{i}@example.com")))
+        .count();
+    assert!(false_positives < 200, "{false_positives} false positives");
+}
+
+#[tokio::test]
+async fn unknown_recipients_are_rejected_without_a_lookup() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    let store = MemoryStore::new();
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let shared = Arc::new(store.clone());
+    let backend = Arc::new(MailboxLookup::new(shared.clone(), &config));
+    let lookup = BloomLookup::new(backend, shared, &config, metrics.clone());
+
+    // Before the first build, everything is looked up
+    store.hash_set("addresses", "f", "v");
+    assert_eq!(
+        lookup.check("carol@example.com").await,
+        LookupOutcome::Error
+    );
+    assert!(lookup.rebuild().await.is_err());
+
+    store.delete("addresses").await.unwrap();
+    store.set_add("addresses", "bob@example.com");
+    assert_eq!(lookup.rebuild().await.unwrap(), 2);
+    assert_eq!(
+        lookup.check("Alice@Example.com").await,
+        LookupOutcome::KeyHit
+    );
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::SetHit);
+    assert_eq!(lookup.check("carol@example.com").await, LookupOutcome::Miss);
+    assert_eq!(metrics.bloom_rejected.load(Ordering::Relaxed), 1);
+
+    // A mailbox created after the build is let through once added
+    store.set_ex("mb:carol@example.com", "1", 60).await.unwrap();
+    lookup.add("carol@example.com");
+    assert_eq!(
+        lookup.check("carol@example.com").await,
+        LookupOutcome::KeyHit
+    );
+}
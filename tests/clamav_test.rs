@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use burngate::clamav::{ClamAv, ClamAvError, Scan};
+use burngate::config::Config;
+
+#[test]
+fn replies_are_parsed() {
+    assert_eq!(Scan::parse(b"stream: OK\0").unwrap(), Scan::Clean);
+    assert_eq!(
+        Scan::parse(b"stream: Eicar-Test-Signature FOUND\0").unwrap(),
+        Scan::Infected("Eicar-Test-Signature".to_string())
+    );
+    assert!(matches!(
+        Scan::parse(b"INSTREAM size limit exceeded. ERROR\0"),
+        Err(ClamAvError::Failed(_))
+    ));
+}
+
+/// clamd that decodes the `INSTREAM` chunks it is sent, keeps the
+/// message, and answers `reply` after `delay`.
+async fn clamd(reply: &'static str, delay: Duration) -> (String, Arc<Mutex<Vec<u8>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let last = Arc::new(Mutex::new(Vec::new()));
+    let seen = last.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut message = Vec::new();
+            loop {
+                let len = stream.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let start = message.len();
+                message.resize(start + len, 0);
+                stream.read_exact(&mut message[start..]).await.unwrap();
+            }
+            *seen.lock().unwrap() = message;
+            tokio::time::sleep(delay).await;
+            let _ = stream.write_all(reply.as_bytes()).await;
+        }
+    });
+    (addr, last)
+}
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.clamav_timeout_ms = 200;
+    config.clamav_max_size = 200_000;
+    config
+}
+
+#[tokio::test]
+async fn message_is_streamed_in_chunks() {
+    let (addr, last) = clamd("stream: Eicar-Test-Signature FOUND\0", Duration::ZERO).await;
+    let clamav = ClamAv::new(&addr, &config());
+    // Larger than one chunk
+    let message: Vec<u8> = (0..150_000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(
+        clamav.scan(&message).await.unwrap(),
+        Some(Scan::Infected("Eicar-Test-Signature".to_string()))
+    );
+    assert_eq!(*last.lock().unwrap(), message);
+
+    // Over CLAMAV_MAX_SIZE: not scanned
+    assert_eq!(clamav.scan(&vec![b'x'; 200_001]).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn slow_or_unreachable_clamd_is_an_error() {
+    let (addr, _) = clamd("stream: OK\0", Duration::from_secs(5)).await;
+    let result = ClamAv::new(&addr, &config()).scan(b"hi\r\n").await;
+    assert!(matches!(result, Err(ClamAvError::Timeout)));
+
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = closed.local_addr().unwrap().to_string();
+    drop(closed);
+    let result = ClamAv::new(&addr, &config()).scan(b"hi\r\n").await;
+    assert!(matches!(result, Err(ClamAvError::Io(_))));
+}
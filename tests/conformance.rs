@@ -0,0 +1,143 @@
+//! RFC 5321/3207 conformance run.
+//!
+//! By default the suite runs against an in-process gateway with a stub
+//! backend. Set `CONFORMANCE_TARGET=host:port` (and optionally
+//! `CONFORMANCE_RCPT` to an accepted recipient) to check a running instance
+//! instead, and `CONFORMANCE_REPORT=path` to also write the report as JSON.
+//! Run with `--nocapture` to see the report:
+//!
+//! ```text
+//! cargo test --test conformance -- --nocapture
+//! ```
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use burngate::config::Config;
+use burngate::conformance::{self, Target};
+use burngate::dns::Resolver;
+use burngate::flags::FeatureFlags;
+use burngate::lookup;
+use burngate::relay::Backend;
+use burngate::session::{self, Metrics};
+use burngate::store::{MemoryStore, Store};
+
+/// Backend that accepts every command and message.
+async fn stub_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stream);
+                let _ = reader.get_mut().write_all(b"220 stub\r\n").await;
+                let mut in_data = false;
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let reply: &[u8] = match line.trim_end() {
+                        "." if in_data => {
+                            in_data = false;
+                            b"250 queued\r\n"
+                        }
+                        _ if in_data => continue,
+                        "DATA" => {
+                            in_data = true;
+                            b"354 go\r\n"
+                        }
+                        "QUIT" => b"221 bye\r\n",
+                        _ => b"250 OK\r\n",
+                    };
+                    let _ = reader.get_mut().write_all(reply).await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Gateway on a loopback port, accepting `alice@example.com`.
+async fn gateway() -> SocketAddr {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.backend_addrs = vec![(stub_backend().await.to_string(), 1)];
+    let config = Arc::new(config);
+
+    let store = MemoryStore::new();
+    store
+        .set_ex("mb:alice@example.com", "1", 3600)
+        .await
+        .unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let lookup = lookup::from_config(Arc::new(store), &config, metrics.clone());
+    let flags = FeatureFlags::new(HashMap::new());
+    let backend = Backend::from_config(&config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, peer)) = listener.accept().await {
+            tokio::spawn(session::handle_session(
+                stream,
+                peer,
+                config.clone(),
+                lookup.clone(),
+                None,
+                metrics.clone(),
+                None,
+                backend.clone(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Resolver::new(Vec::new(), Duration::from_secs(1)),
+                None,
+                None,
+                Vec::new(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                flags.clone(),
+                false,
+                false,
+                None,
+                0.0,
+            ));
+        }
+    });
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conformance() {
+    let target = match std::env::var("CONFORMANCE_TARGET") {
+        Ok(addr) => Target {
+            addr: addr.parse().expect("CONFORMANCE_TARGET must be host:port"),
+            recipient: std::env::var("CONFORMANCE_RCPT").ok(),
+        },
+        Err(_) => Target {
+            addr: gateway().await,
+            recipient: Some("alice@example.com".to_string()),
+        },
+    };
+
+    let report = conformance::run_all(&target).await;
+    println!("{report}");
+    if let Ok(path) = std::env::var("CONFORMANCE_REPORT") {
+        std::fs::write(&path, report.to_json().to_string()).unwrap();
+    }
+    assert!(report.passed(), "conformance failures:\n{report}");
+}
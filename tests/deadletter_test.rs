@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use burngate::deadletter::{DeadLetter, DeadMessage};
+use burngate::esmtp::EsmtpParams;
+use burngate::relay::{ClientInfo, Recipient, RelayError};
+use burngate::reply::SmtpReply;
+use burngate::session::Metrics;
+use burngate::store::{MemoryStore, Store};
+
+/// Fresh, empty directory under the system temp dir.
+fn sink_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("burngate-dead-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Store one refused message with a fixed envelope in `dead_letter`.
+async fn store(dead_letter: &DeadLetter, data: &[u8]) -> bool {
+    let params = EsmtpParams::parse("NOTIFY=NEVER").unwrap();
+    let client = ClientInfo {
+        addr: "192.0.2.1:40000".parse().unwrap(),
+        helo: Some("mx.example.org".to_string()),
+        proto: "ESMTP".to_string(),
+    };
+    let recipients = [Recipient {
+        address: "alice@example.com",
+        params: &params,
+    }];
+    let error = RelayError::Rejected {
+        stage: "message not accepted",
+        reply: SmtpReply::parse(&["554 5.6.0 message content rejected".to_string()]).unwrap(),
+    };
+    dead_letter
+        .store(&DeadMessage {
+            session_id: Some("671E2C4000001"),
+            client: Some(&client),
+            sender: "a@b.c",
+            mail_params: &EsmtpParams::default(),
+            recipients: &recipients,
+            error: &error,
+            data,
+        })
+        .await
+}
+
+// -- directory --
+
+#[tokio::test]
+async fn directory_keeps_raw_message_and_envelope() {
+    let dir = sink_dir("files");
+    let metrics = Arc::new(Metrics::new());
+    let dead_letter = DeadLetter::dir(dir.to_str().unwrap(), metrics.clone()).unwrap();
+    let data = b"Subject: caf\xe9\r\n\r\nbody\r\n";
+    assert!(store(&dead_letter, data).await);
+
+    let paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    assert_eq!(paths.len(), 2);
+    let eml = paths
+        .iter()
+        .find(|p| p.extension().is_some_and(|ext| ext == "eml"))
+        .unwrap();
+    assert_eq!(std::fs::read(eml).unwrap(), data);
+
+    let envelope: Value =
+        serde_json::from_slice(&std::fs::read(eml.with_extension("json")).unwrap()).unwrap();
+    assert_eq!(envelope["sender"], "a@b.c");
+    assert_eq!(envelope["session_id"], "671E2C4000001");
+    assert_eq!(envelope["client"]["helo"], "mx.example.org");
+    assert_eq!(envelope["recipients"][0][0], "alice@example.com");
+    assert_eq!(envelope["recipients"][0][1], "NOTIFY=NEVER");
+    assert!(envelope["error"].as_str().unwrap().contains("554"));
+    assert_eq!(metrics.dead_lettered.load(Ordering::Relaxed), 1);
+}
+
+// -- Redis --
+
+#[tokio::test]
+async fn redis_list_gets_one_entry_per_message() {
+    let redis = Arc::new(MemoryStore::new());
+    let metrics = Arc::new(Metrics::new());
+    let dead_letter = DeadLetter::redis(redis.clone(), "deadletter", 7, metrics.clone());
+    assert!(store(&dead_letter, b"Subject: hi\r\n\r\nbody\r\n").await);
+    assert!(store(&dead_letter, b"Subject: again\r\n\r\nbody\r\n").await);
+
+    let entries = redis.list_all("deadletter").await.unwrap();
+    assert_eq!(entries.len(), 2);
+    let entry: Value = serde_json::from_str(&entries[0]).unwrap();
+    assert_eq!(entry["message"], "Subject: hi\r\n\r\nbody\r\n");
+    assert_eq!(entry["sender"], "a@b.c");
+    assert!(redis.ttl("deadletter").await.unwrap().unwrap() > 6 * 86_400);
+    assert_eq!(metrics.dead_lettered.load(Ordering::Relaxed), 2);
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use burngate::dns::{
+    encode_query, parse_answers, parse_resolv_conf, parse_response, reverse_name, MailDomain, Mx,
+    Rdns, Record, RecordType, Resolver, Srv,
+};
+
+/// Nameserver answering from `zone`: (name, type code) to encoded rdata.
+/// Unknown names get NXDOMAIN.
+async fn nameserver(zone: HashMap<(String, u16), Vec<Vec<u8>>>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let query = &buf[..len];
+            let (name, end) = qname(query);
+            let qtype = u16::from_be_bytes([query[end], query[end + 1]]);
+            let answers = zone.get(&(name, qtype)).cloned().unwrap_or_default();
+            let rcode = if answers.is_empty() { 3 } else { 0 };
+            let mut reply = query[..2].to_vec();
+            reply.extend_from_slice(&[
+                0x81,
+                0x80 | rcode,
+                0,
+                1,
+                0,
+                answers.len() as u8,
+                0,
+                0,
+                0,
+                0,
+            ]);
+            reply.extend_from_slice(&query[12..end + 4]);
+            for rdata in answers {
+                // Owner name compressed to the question
+                reply.extend_from_slice(&[0xc0, 12]);
+                reply.extend_from_slice(&qtype.to_be_bytes());
+                reply.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+                reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                reply.extend_from_slice(&rdata);
+            }
+            let _ = socket.send_to(&reply, peer).await;
+        }
+    });
+    addr
+}
+
+fn qname(packet: &[u8]) -> (String, usize) {
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    while packet[pos] != 0 {
+        let len = packet[pos] as usize;
+        labels.push(String::from_utf8_lossy(&packet[pos + 1..pos + 1 + len]).to_string());
+        pos += 1 + len;
+    }
+    (labels.join("."), pos + 1)
+}
+
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out
+}
+
+fn resolver(server: SocketAddr) -> Resolver {
+    Resolver::new(vec![server], Duration::from_secs(2))
+}
+
+// -- wire format --
+
+#[test]
+fn query_encodes_labels() {
+    let packet = encode_query(0x1234, "mx.example.com.", RecordType::A).unwrap();
+    assert_eq!(&packet[..4], &[0x12, 0x34, 0x01, 0x00]);
+    assert_eq!(
+        &packet[12..],
+        b"\x02mx\x07example\x03com\x00\x00\x01\x00\x01"
+    );
+    assert!(encode_query(1, "a..b", RecordType::A).is_err());
+}
+
+#[test]
+fn response_follows_compression_pointers() {
+    let mut packet = vec![0, 1, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+    packet.extend_from_slice(&wire_name("1.2.0.192.in-addr.arpa"));
+    packet.extend_from_slice(&[0, 12, 0, 1]);
+    packet.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1, 0, 0, 0, 60, 0, 7]);
+    // "mail" + pointer to "arpa" is nonsense but well-formed
+    packet.extend_from_slice(b"\x04Mail\xc0\x1e");
+    let records = parse_response(&packet, RecordType::Ptr).unwrap();
+    assert_eq!(records, [Record::Ptr("mail.arpa".to_string())]);
+}
+
+#[test]
+fn pointer_loops_are_malformed() {
+    let mut packet = vec![0, 1, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+    assert!(parse_response(&packet, RecordType::Ptr).is_err());
+}
+
+#[test]
+fn answers_carry_their_ttl() {
+    let mut packet = vec![0, 1, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+    packet.extend_from_slice(&wire_name("mx.example.com"));
+    packet.extend_from_slice(&[0, 1, 0, 1]);
+    packet.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 192, 0, 2, 1]);
+    // Reserved top bit set: treated as 0
+    packet.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0x80, 0, 0, 1, 0, 4, 192, 0, 2, 2]);
+    let answers = parse_answers(&packet, RecordType::A).unwrap();
+    assert_eq!(
+        answers,
+        [
+            (Record::A([192, 0, 2, 1].into()), 3600),
+            (Record::A([192, 0, 2, 2].into()), 0)
+        ]
+    );
+}
+
+#[test]
+fn reverse_names() {
+    assert_eq!(
+        reverse_name("192.0.2.1".parse().unwrap()),
+        "1.2.0.192.in-addr.arpa"
+    );
+    assert_eq!(
+        reverse_name("2001:db8::1".parse().unwrap()),
+        "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+    );
+}
+
+#[test]
+fn resolv_conf_nameservers() {
+    let conf = "# comment\nsearch example.com\nnameserver 10.0.0.53\nnameserver fe80::1%eth0\n";
+    assert_eq!(
+        parse_resolv_conf(conf),
+        [
+            "10.0.0.53:53".parse::<SocketAddr>().unwrap(),
+            "[fe80::1]:53".parse().unwrap()
+        ]
+    );
+}
+
+// -- resolver --
+
+#[tokio::test]
+async fn reverse_lookup_is_forward_confirmed() {
+    let mut zone = HashMap::new();
+    zone.insert(
+        ("1.2.0.192.in-addr.arpa".to_string(), 12),
+        vec![wire_name("mail.example.org")],
+    );
+    zone.insert(
+        ("mail.example.org".to_string(), 1),
+        vec![vec![192, 0, 2, 1]],
+    );
+    zone.insert(
+        ("2.2.0.192.in-addr.arpa".to_string(), 12),
+        vec![wire_name("forged.example.org")],
+    );
+    zone.insert(
+        ("forged.example.org".to_string(), 1),
+        vec![vec![198, 51, 100, 7]],
+    );
+    let resolver = resolver(nameserver(zone).await);
+
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert_eq!(
+        resolver.reverse(ip("192.0.2.1")).await.unwrap().as_deref(),
+        Some("mail.example.org")
+    );
+    assert_eq!(resolver.reverse(ip("192.0.2.2")).await.unwrap(), None);
+    assert_eq!(resolver.reverse(ip("192.0.2.3")).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn reverse_check_reports_missing_unconfirmed_and_forged_names() {
+    let mut zone = HashMap::new();
+    zone.insert(
+        ("1.2.0.192.in-addr.arpa".to_string(), 12),
+        vec![
+            wire_name("forged.example.org"),
+            wire_name("mail.example.org"),
+        ],
+    );
+    zone.insert(
+        ("mail.example.org".to_string(), 1),
+        vec![vec![192, 0, 2, 1]],
+    );
+    zone.insert(
+        ("2.2.0.192.in-addr.arpa".to_string(), 12),
+        vec![wire_name("forged.example.org")],
+    );
+    let resolver = resolver(nameserver(zone).await);
+
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert_eq!(
+        resolver.reverse_check(ip("192.0.2.1"), true).await.unwrap(),
+        Rdns::Confirmed("mail.example.org".to_string())
+    );
+    assert_eq!(
+        resolver.reverse_check(ip("192.0.2.2"), true).await.unwrap(),
+        Rdns::Forged("forged.example.org".to_string())
+    );
+    assert_eq!(
+        resolver
+            .reverse_check(ip("192.0.2.2"), false)
+            .await
+            .unwrap(),
+        Rdns::Unconfirmed("forged.example.org".to_string())
+    );
+    assert_eq!(
+        resolver.reverse_check(ip("192.0.2.3"), true).await.unwrap(),
+        Rdns::Missing
+    );
+}
+
+#[tokio::test]
+async fn ip_lookup_lists_ipv4_before_ipv6() {
+    let mut zone = HashMap::new();
+    zone.insert(
+        ("backend.internal".to_string(), 28),
+        vec![[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1].to_vec()],
+    );
+    zone.insert(
+        ("backend.internal".to_string(), 1),
+        vec![vec![10, 0, 0, 1], vec![10, 0, 0, 2]],
+    );
+    let resolver = resolver(nameserver(zone).await);
+
+    let (addrs, ttl) = resolver.lookup_ip("backend.internal").await.unwrap();
+    let expected: Vec<IpAddr> = ["10.0.0.1", "10.0.0.2", "2001:db8::1"]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+    assert_eq!(addrs, expected);
+    assert_eq!(ttl, Duration::from_secs(60));
+
+    let (addrs, _) = resolver.lookup_ip("missing.internal").await.unwrap();
+    assert!(addrs.is_empty());
+}
+
+#[tokio::test]
+async fn srv_records_name_their_targets() {
+    let mut rdata = vec![0, 10, 0, 5, 0x09, 0xdd];
+    rdata.extend_from_slice(&wire_name("mx1.internal"));
+    let mut zone = HashMap::new();
+    zone.insert(("_smtp-backend._tcp.internal".to_string(), 33), vec![rdata]);
+    let resolver = resolver(nameserver(zone).await);
+
+    let records = resolver
+        .query("_smtp-backend._tcp.internal", RecordType::Srv)
+        .await
+        .unwrap();
+    assert_eq!(
+        records,
+        [Record::Srv(Srv {
+            priority: 10,
+            weight: 5,
+            port: 2525,
+            target: "mx1.internal".to_string(),
+        })]
+    );
+}
+
+#[tokio::test]
+async fn silent_nameserver_times_out() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let resolver = Resolver::new(
+        vec![socket.local_addr().unwrap()],
+        Duration::from_millis(50),
+    );
+    let err = resolver
+        .query("example.com", RecordType::A)
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "DNS query timed out");
+}
+
+#[tokio::test]
+async fn mail_domains_need_an_mx_or_address() {
+    let mut mx = vec![0, 10];
+    mx.extend_from_slice(&wire_name("mx.example.net"));
+    let mut zone = HashMap::new();
+    zone.insert(("example.net".to_string(), 15), vec![mx]);
+    // RFC 7505: a single MX of preference 0 pointing at the root
+    zone.insert(("nomail.example".to_string(), 15), vec![vec![0, 0, 0]]);
+    zone.insert(("host.example".to_string(), 1), vec![vec![192, 0, 2, 1]]);
+    let resolver = resolver(nameserver(zone).await);
+
+    assert_eq!(
+        resolver.query("example.net", RecordType::Mx).await.unwrap(),
+        [Record::Mx(Mx {
+            preference: 10,
+            exchange: "mx.example.net".to_string(),
+        })]
+    );
+    for (domain, expected) in [
+        ("example.net", MailDomain::Mx),
+        ("host.example", MailDomain::Implicit),
+        ("nomail.example", MailDomain::NullMx),
+        ("missing.example", MailDomain::Missing),
+    ] {
+        let verdict = resolver.mail_domain(domain).await.unwrap();
+        assert_eq!(verdict, expected, "{domain}");
+        assert_eq!(
+            verdict.accepts_mail(),
+            expected == MailDomain::Mx || expected == MailDomain::Implicit
+        );
+    }
+}
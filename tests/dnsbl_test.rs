@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use burngate::config::{Config, DnsblAction, DnsblList};
+use burngate::dns::Resolver;
+use burngate::dnsbl::{query_name, Dnsbl};
+
+/// Nameserver answering A queries from `zone`, NXDOMAIN for other names.
+/// Returns its address and the number of queries.
+async fn nameserver(zone: HashMap<String, [u8; 4]>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let count = queries.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            count.fetch_add(1, Ordering::SeqCst);
+            let query = &buf[..len];
+            let mut labels = Vec::new();
+            let mut pos = 12;
+            while query[pos] != 0 {
+                let len = query[pos] as usize;
+                labels.push(String::from_utf8_lossy(&query[pos + 1..pos + 1 + len]).to_string());
+                pos += 1 + len;
+            }
+            let end = pos + 1;
+            let answer = zone.get(&labels.join("."));
+            let mut reply = query[..2].to_vec();
+            let (rcode, count) = if answer.is_some() { (0, 1) } else { (3, 0) };
+            reply.extend_from_slice(&[0x81, 0x80 | rcode, 0, 1, 0, count, 0, 0, 0, 0]);
+            reply.extend_from_slice(&query[12..end + 4]);
+            if let Some(rdata) = answer {
+                reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                reply.extend_from_slice(rdata);
+            }
+            let _ = socket.send_to(&reply, peer).await;
+        }
+    });
+    (addr, queries)
+}
+
+fn list(zone: &str, action: DnsblAction) -> DnsblList {
+    DnsblList {
+        zone: zone.to_string(),
+        action,
+    }
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn query_names_reverse_the_address() {
+    assert_eq!(
+        query_name(ip("192.0.2.99"), "zen.spamhaus.org"),
+        "99.2.0.192.zen.spamhaus.org"
+    );
+    let v6 = query_name(ip("2001:db8::1"), "bl.example");
+    assert!(v6.starts_with("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0."));
+    assert!(v6.ends_with(".8.b.d.0.1.0.0.2.bl.example"));
+}
+
+#[test]
+fn lists_are_parsed_with_their_actions() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    std::env::set_var(
+        "DNSBL_LISTS",
+        "Zen.Spamhaus.org., bl.spamcop.net:tag, b.barracudacentral.org:score=3",
+    );
+    let config = Config::from_env();
+    std::env::remove_var("DNSBL_LISTS");
+    assert_eq!(
+        config.dnsbl_lists,
+        vec![
+            list("zen.spamhaus.org", DnsblAction::Reject),
+            list("bl.spamcop.net", DnsblAction::Tag),
+            list("b.barracudacentral.org", DnsblAction::Score(3)),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn listings_reject_tag_or_score() {
+    let mut zone = HashMap::new();
+    zone.insert("1.2.0.192.block.test".to_string(), [127, 0, 0, 2]);
+    zone.insert("2.2.0.192.tag.test".to_string(), [127, 0, 0, 4]);
+    zone.insert("2.2.0.192.score.test".to_string(), [127, 0, 0, 2]);
+    zone.insert("3.2.0.192.score.test".to_string(), [127, 0, 0, 2]);
+    zone.insert("3.2.0.192.more.test".to_string(), [127, 0, 0, 10]);
+    // A refused query, not a listing
+    zone.insert("4.2.0.192.block.test".to_string(), [127, 255, 255, 254]);
+    let (server, _) = nameserver(zone).await;
+    let dnsbl = Dnsbl::new(
+        vec![
+            list("block.test", DnsblAction::Reject),
+            list("tag.test", DnsblAction::Tag),
+            list("score.test", DnsblAction::Score(2)),
+            list("more.test", DnsblAction::Score(3)),
+        ],
+        5,
+        Duration::from_secs(60),
+        Resolver::new(vec![server], Duration::from_secs(2)),
+    );
+
+    let verdict = dnsbl.check(ip("192.0.2.1")).await;
+    assert_eq!(verdict.rejected_by.as_deref(), Some("block.test"));
+    assert_eq!(verdict.tag(), None);
+
+    let verdict = dnsbl.check(ip("192.0.2.2")).await;
+    assert_eq!(verdict.rejected_by, None);
+    assert_eq!(
+        verdict.tag().as_deref(),
+        Some("tag.test=127.0.0.4, score.test=127.0.0.2; score=2")
+    );
+
+    let verdict = dnsbl.check(ip("::ffff:192.0.2.3")).await;
+    assert_eq!(verdict.score, 5);
+    assert_eq!(verdict.rejected_by.as_deref(), Some("more.test"));
+
+    let verdict = dnsbl.check(ip("192.0.2.4")).await;
+    assert!(verdict.listings.is_empty());
+
+    assert_eq!(
+        dnsbl.hits(),
+        "block.test=1,tag.test=1,score.test=2,more.test=1"
+    );
+}
+
+#[tokio::test]
+async fn verdicts_are_cached_unless_a_list_failed() {
+    let mut zone = HashMap::new();
+    zone.insert("1.2.0.192.block.test".to_string(), [127, 0, 0, 2]);
+    let (server, queries) = nameserver(zone).await;
+    let dnsbl = Dnsbl::new(
+        vec![list("block.test", DnsblAction::Reject)],
+        0,
+        Duration::from_secs(60),
+        Resolver::new(vec![server], Duration::from_secs(2)),
+    );
+    for _ in 0..3 {
+        assert!(dnsbl.check(ip("192.0.2.1")).await.rejected_by.is_some());
+    }
+    assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+    // No nameserver: nothing is listed, and nothing is remembered
+    let dnsbl = Dnsbl::new(
+        vec![list("block.test", DnsblAction::Reject)],
+        0,
+        Duration::from_secs(60),
+        Resolver::new(Vec::new(), Duration::from_secs(2)),
+    );
+    assert!(dnsbl.check(ip("192.0.2.1")).await.rejected_by.is_none());
+}
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+use burngate::esmtp::{
+    encode_xtext, params_part, Body, EsmtpParams, MailParams, Notify, ParamError, RcptParams, Ret,
+    MAIL_PARAMS, RCPT_PARAMS,
+};
+use burngate::relay::ehlo_keyword;
+
+fn caps(list: &[&str]) -> HashSet<String> {
+    list.iter().map(|s| s.to_string()).collect()
+}
+
+// -- params_part --
+
+#[test]
+fn params_after_path() {
+    assert_eq!(
+        params_part("FROM:<a@b.c> SIZE=10 RET=HDRS"),
+        " SIZE=10 RET=HDRS"
+    );
+    assert_eq!(params_part("FROM:<a@b.c>"), "");
+    assert_eq!(params_part("FROM:a@b.c"), "");
+}
+
+#[test]
+fn params_after_quoted_angle_bracket() {
+    assert_eq!(
+        params_part("TO:<\"a>b\"@example.com> NOTIFY=NEVER"),
+        " NOTIFY=NEVER"
+    );
+}
+
+// -- EsmtpParams::parse --
+
+#[test]
+fn parse_keywords_and_values() {
+    let params = EsmtpParams::parse(" size=1024 SMTPUTF8 ENVID=abc+2B123").unwrap();
+    assert_eq!(params.get("SIZE"), Some("1024"));
+    assert!(params.contains("smtputf8"));
+    assert_eq!(params.get("SMTPUTF8"), None);
+    assert_eq!(params.get("ENVID"), Some("abc+2B123"));
+}
+
+#[test]
+fn parse_empty() {
+    assert!(EsmtpParams::parse("").unwrap().is_empty());
+    assert!(EsmtpParams::parse("   ").unwrap().is_empty());
+}
+
+#[test]
+fn parse_malformed() {
+    assert!(matches!(
+        EsmtpParams::parse("SIZE="),
+        Err(ParamError::Malformed(_))
+    ));
+    assert!(matches!(
+        EsmtpParams::parse("=value"),
+        Err(ParamError::Malformed(_))
+    ));
+    assert!(matches!(
+        EsmtpParams::parse("BAD_KEY=1"),
+        Err(ParamError::Malformed(_))
+    ));
+}
+
+#[test]
+fn parse_known_rejects_unrecognized() {
+    assert_eq!(
+        EsmtpParams::parse_known("SIZE=1 XFOO=bar", MAIL_PARAMS),
+        Err(ParamError::Unrecognized("XFOO".to_string()))
+    );
+    assert!(EsmtpParams::parse_known("NOTIFY=SUCCESS,FAILURE", RCPT_PARAMS).is_ok());
+    // MAIL-only parameters are not valid on RCPT
+    assert!(EsmtpParams::parse_known("RET=HDRS", RCPT_PARAMS).is_err());
+}
+
+#[test]
+fn parse_rejects_duplicate_keyword() {
+    assert!(matches!(
+        EsmtpParams::parse("SIZE=1 size=2"),
+        Err(ParamError::Malformed(_))
+    ));
+}
+
+// -- MailParams::parse --
+
+#[test]
+fn mail_params_structured() {
+    let params =
+        MailParams::parse(" SIZE=1024 BODY=8bitmime SMTPUTF8 RET=HDRS ENVID=id+2B1 AUTH=<>")
+            .unwrap();
+    assert_eq!(params.size, Some(1024));
+    assert_eq!(params.body, Some(Body::EightBitMime));
+    assert!(params.smtputf8);
+    assert_eq!(params.ret, Some(Ret::Hdrs));
+    assert_eq!(params.envid.as_deref(), Some("id+2B1"));
+    assert_eq!(params.auth.as_deref(), Some("<>"));
+    assert_eq!(params.params.get("BODY"), Some("8bitmime"));
+}
+
+#[test]
+fn mail_params_empty() {
+    assert_eq!(MailParams::parse("").unwrap(), MailParams::default());
+}
+
+#[test]
+fn mail_params_invalid_values() {
+    for (params, key) in [
+        ("SIZE=12k", "SIZE"),
+        ("SIZE", "SIZE"),
+        ("BODY=9BIT", "BODY"),
+        ("SMTPUTF8=yes", "SMTPUTF8"),
+        ("RET=ALL", "RET"),
+        ("ENVID=bad+zz", "ENVID"),
+        ("AUTH", "AUTH"),
+    ] {
+        assert_eq!(
+            MailParams::parse(params),
+            Err(ParamError::Invalid(key)),
+            "{params}"
+        );
+    }
+}
+
+#[test]
+fn mail_params_binarymime_unsupported() {
+    assert_eq!(
+        MailParams::parse("BODY=BINARYMIME"),
+        Err(ParamError::Unsupported("BODY"))
+    );
+}
+
+#[test]
+fn mail_params_unknown_rejected() {
+    assert_eq!(
+        MailParams::parse("XFOO"),
+        Err(ParamError::Unrecognized("XFOO".to_string()))
+    );
+}
+
+// -- RcptParams::parse --
+
+#[test]
+fn rcpt_params_structured() {
+    let params = RcptParams::parse("NOTIFY=failure,DELAY ORCPT=rfc822;bob+40example.com").unwrap();
+    assert_eq!(
+        params.notify,
+        Some(Notify {
+            success: false,
+            failure: true,
+            delay: true
+        })
+    );
+    assert_eq!(params.orcpt.as_deref(), Some("rfc822;bob+40example.com"));
+}
+
+#[test]
+fn rcpt_params_notify_never() {
+    let params = RcptParams::parse("NOTIFY=NEVER").unwrap();
+    assert_eq!(params.notify, Some(Notify::default()));
+}
+
+#[test]
+fn rcpt_params_invalid_values() {
+    for params in [
+        "NOTIFY=NEVER,SUCCESS",
+        "NOTIFY=SUCCESS,SUCCESS",
+        "NOTIFY=SOMETIMES",
+        "ORCPT=bob@example.com",
+        "ORCPT=;bob",
+    ] {
+        assert!(
+            matches!(RcptParams::parse(params), Err(ParamError::Invalid(_))),
+            "{params}"
+        );
+    }
+}
+
+// -- EsmtpParams::to_wire --
+
+#[test]
+fn to_wire_forwards_supported_only() {
+    let params = EsmtpParams::parse("SIZE=100 RET=HDRS ENVID=x BODY=8BITMIME").unwrap();
+    assert_eq!(
+        params.to_wire(&caps(&["DSN", "8BITMIME", "SIZE"])),
+        " RET=HDRS ENVID=x BODY=8BITMIME"
+    );
+    // Backend without DSN: DSN parameters are dropped
+    assert_eq!(params.to_wire(&caps(&["8BITMIME"])), " BODY=8BITMIME");
+    assert_eq!(params.to_wire(&caps(&[])), "");
+}
+
+#[test]
+fn to_wire_keeps_flag_parameters() {
+    let params = EsmtpParams::parse("SMTPUTF8").unwrap();
+    assert_eq!(params.to_wire(&caps(&["SMTPUTF8"])), " SMTPUTF8");
+}
+
+// -- encode_xtext --
+
+#[test]
+fn xtext_escapes_plus_equals_and_non_printables() {
+    assert_eq!(encode_xtext("mx.example.org"), "mx.example.org");
+    assert_eq!(encode_xtext("a+b=c d"), "a+2Bb+3Dc+20d");
+    assert_eq!(encode_xtext("é"), "+C3+A9");
+}
+
+// -- ehlo_keyword --
+
+#[test]
+fn ehlo_keyword_extraction() {
+    assert_eq!(ehlo_keyword("250-DSN\r\n"), Some("DSN".to_string()));
+    assert_eq!(ehlo_keyword("250 size 1000\r\n"), Some("SIZE".to_string()));
+    assert_eq!(ehlo_keyword("250"), None);
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use burngate::flags::{parse_flags, FeatureFlags, FlagRule};
+use burngate::store::MemoryStore;
+
+// -- FlagRule::parse --
+
+#[test]
+fn parse_on_off() {
+    assert_eq!(FlagRule::parse("on"), Some(FlagRule::On));
+    assert_eq!(FlagRule::parse("TRUE"), Some(FlagRule::On));
+    assert_eq!(FlagRule::parse("off"), Some(FlagRule::Off));
+    assert_eq!(FlagRule::parse("0"), Some(FlagRule::Off));
+}
+
+#[test]
+fn parse_percent() {
+    assert_eq!(FlagRule::parse("25%"), Some(FlagRule::Percent(25)));
+    assert_eq!(FlagRule::parse("250%"), Some(FlagRule::Percent(100)));
+    assert_eq!(FlagRule::parse("abc%"), None);
+}
+
+#[test]
+fn parse_domains() {
+    assert_eq!(
+        FlagRule::parse("Example.com|example.org"),
+        Some(FlagRule::Domains(vec![
+            "example.com".to_string(),
+            "example.org".to_string()
+        ]))
+    );
+}
+
+#[test]
+fn parse_domains_idn_to_punycode() {
+    assert_eq!(
+        FlagRule::parse("bücher.example"),
+        Some(FlagRule::Domains(vec!["xn--bcher-kva.example".to_string()]))
+    );
+}
+
+#[test]
+fn parse_empty() {
+    assert_eq!(FlagRule::parse(""), None);
+    assert_eq!(FlagRule::parse("|"), None);
+}
+
+// -- parse_flags --
+
+#[test]
+fn parse_flag_list() {
+    let flags = parse_flags("strict_crlf=25%, new_filter=example.com|example.org,broken=");
+    assert_eq!(flags.len(), 2);
+    assert_eq!(flags.get("strict_crlf"), Some(&FlagRule::Percent(25)));
+    assert!(matches!(
+        flags.get("new_filter"),
+        Some(FlagRule::Domains(_))
+    ));
+}
+
+#[test]
+fn parse_flag_list_empty() {
+    assert!(parse_flags("").is_empty());
+}
+
+// -- FeatureFlags --
+
+fn flags(spec: &str) -> FeatureFlags {
+    FeatureFlags::new(parse_flags(spec))
+}
+
+#[test]
+fn unknown_flag_disabled() {
+    assert!(!flags("").is_enabled("strict_crlf", "1.2.3.4", None));
+}
+
+#[test]
+fn on_and_off() {
+    let f = flags("a=on,b=off");
+    assert!(f.is_enabled("a", "x", None));
+    assert!(!f.is_enabled("b", "x", None));
+}
+
+#[test]
+fn percent_bounds() {
+    let f = flags("none=0%,all=100%");
+    for i in 0..100 {
+        let key = format!("10.0.0.{i}");
+        assert!(!f.is_enabled("none", &key, None));
+        assert!(f.is_enabled("all", &key, None));
+    }
+}
+
+#[test]
+fn percent_is_stable_and_partial() {
+    let f = flags("half=50%");
+    let enabled = (0..1000)
+        .filter(|i| f.is_enabled("half", &format!("key{i}"), None))
+        .count();
+    assert!(enabled > 350 && enabled < 650, "enabled = {enabled}");
+    // Same key always gets the same answer
+    let first = f.is_enabled("half", "key42", None);
+    for _ in 0..10 {
+        assert_eq!(f.is_enabled("half", "key42", None), first);
+    }
+}
+
+#[test]
+fn domain_scoped() {
+    let f = flags("canary=example.com");
+    assert!(f.is_enabled("canary", "x", Some("example.com")));
+    assert!(f.is_enabled("canary", "x", Some("sub.example.com")));
+    assert!(!f.is_enabled("canary", "x", Some("notexample.com")));
+    assert!(!f.is_enabled("canary", "x", None));
+}
+
+#[test]
+fn overrides_replace_defaults() {
+    let f = flags("a=off");
+    let mut overrides = HashMap::new();
+    overrides.insert("a".to_string(), FlagRule::On);
+    f.set_overrides(overrides);
+    assert!(f.is_enabled("a", "x", None));
+
+    // Clearing overrides falls back to configured rule
+    f.set_overrides(HashMap::new());
+    assert!(!f.is_enabled("a", "x", None));
+}
+
+// -- refresh_from_store --
+
+#[tokio::test]
+async fn refresh_loads_overrides_from_store() {
+    let store = MemoryStore::new();
+    store.hash_set("burngate:flags", "Strict_CRLF", "on");
+    store.hash_set("burngate:flags", "broken", "");
+
+    let f = flags("strict_crlf=off");
+    f.refresh_from_store(&store, "burngate:flags")
+        .await
+        .unwrap();
+    assert!(f.is_enabled("strict_crlf", "x", None));
+    assert!(!f.is_enabled("broken", "x", None));
+}
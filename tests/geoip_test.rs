@@ -0,0 +1,196 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use burngate::config::GeoAction;
+use burngate::geoip::{Database, GeoIp, GeoIpError, Location, Value};
+
+fn string(s: &str) -> Vec<u8> {
+    assert!(s.len() < 29);
+    let mut out = vec![2 << 5 | s.len() as u8];
+    out.extend(s.as_bytes());
+    out
+}
+
+fn map(entries: u8) -> u8 {
+    7 << 5 | entries
+}
+
+fn uint(value: u32) -> Vec<u8> {
+    let mut out = vec![6 << 5 | 4];
+    out.extend(value.to_be_bytes());
+    out
+}
+
+/// `{"country": {"iso_code": cc}}`, or for `registered`,
+/// `{"registered_country": ...}`.
+fn country(cc: &str, registered: bool) -> Vec<u8> {
+    let mut out = vec![map(1)];
+    out.extend(string(if registered {
+        "registered_country"
+    } else {
+        "country"
+    }));
+    out.push(map(1));
+    out.extend(string("iso_code"));
+    out.extend(string(cc));
+    out
+}
+
+#[derive(Clone, Copy)]
+enum Record {
+    Empty,
+    Node(u32),
+    Data(u32),
+}
+
+/// An IPv6 MaxMind DB with 24-bit records holding `networks` (IPv4 network,
+/// prefix length, data section record); IPv4 lives under ::/96.
+fn database(networks: &[(&str, u32, Vec<u8>)]) -> Vec<u8> {
+    let mut nodes = vec![[Record::Empty; 2]];
+    let mut data: Vec<u8> = Vec::new();
+    for (network, len, record) in networks {
+        let offset = data.len() as u32;
+        data.extend(record);
+        let ip: Ipv4Addr = network.parse().unwrap();
+        let bits = u128::from(u32::from(ip));
+        let mut node = 0;
+        for i in 0..96 + len {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            if i == 95 + len {
+                nodes[node][bit] = Record::Data(offset);
+            } else if let Record::Node(next) = nodes[node][bit] {
+                node = next as usize;
+            } else {
+                nodes.push([Record::Empty; 2]);
+                nodes[node][bit] = Record::Node(nodes.len() as u32 - 1);
+                node = nodes.len() - 1;
+            }
+        }
+    }
+
+    let node_count = nodes.len() as u32;
+    let mut out = Vec::new();
+    for record in nodes.iter().flatten() {
+        let value = match *record {
+            Record::Empty => node_count,
+            Record::Node(n) => n,
+            Record::Data(offset) => node_count + 16 + offset,
+        };
+        out.extend(&value.to_be_bytes()[1..]);
+    }
+    out.extend([0; 16]);
+    out.extend(data);
+    out.extend(b"\xab\xcd\xefMaxMind.com");
+    out.push(map(3));
+    out.extend(string("node_count"));
+    out.extend(uint(node_count));
+    out.extend(string("record_size"));
+    out.extend(uint(24));
+    out.extend(string("ip_version"));
+    out.extend(uint(6));
+    out
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn countries_are_found_by_network() {
+    // The second record points back at the first one's "country" key
+    let mut pointed = vec![map(1), 1 << 5, 1, map(1)];
+    pointed.extend(string("iso_code"));
+    pointed.extend(string("FR"));
+    let db = Database::from_bytes(database(&[
+        ("192.0.2.0", 24, country("DE", false)),
+        ("198.51.100.128", 25, pointed),
+        ("203.0.113.0", 24, country("JP", true)),
+    ]))
+    .unwrap();
+
+    assert_eq!(db.country(ip("192.0.2.77")).unwrap().as_deref(), Some("DE"));
+    assert_eq!(
+        db.country(ip("198.51.100.200")).unwrap().as_deref(),
+        Some("FR")
+    );
+    assert_eq!(db.country(ip("198.51.100.1")).unwrap(), None);
+    // Only the registered country is known
+    assert_eq!(
+        db.country(ip("203.0.113.9")).unwrap().as_deref(),
+        Some("JP")
+    );
+    // IPv4-mapped addresses are looked up as IPv4
+    assert_eq!(
+        db.country(ip("::ffff:192.0.2.1")).unwrap().as_deref(),
+        Some("DE")
+    );
+    assert_eq!(db.country(ip("2001:db8::1")).unwrap(), None);
+
+    let record = db.lookup(ip("192.0.2.1")).unwrap().unwrap();
+    assert_eq!(
+        record.get("country").and_then(|c| c.get("iso_code")),
+        Some(&Value::String("DE".to_string()))
+    );
+}
+
+#[test]
+fn files_without_metadata_are_refused() {
+    assert!(matches!(
+        Database::from_bytes(vec![0; 64]),
+        Err(GeoIpError::Invalid(_))
+    ));
+    // Metadata claiming more nodes than the file holds
+    let mut bytes = b"\xab\xcd\xefMaxMind.com".to_vec();
+    bytes.push(map(3));
+    bytes.extend(string("node_count"));
+    bytes.extend(uint(1000));
+    bytes.extend(string("record_size"));
+    bytes.extend(uint(24));
+    bytes.extend(string("ip_version"));
+    bytes.extend(uint(6));
+    assert!(matches!(
+        Database::from_bytes(bytes),
+        Err(GeoIpError::Invalid(_))
+    ));
+}
+
+#[tokio::test]
+async fn policies_apply_by_country() {
+    let db = Database::from_bytes(database(&[
+        ("192.0.2.0", 24, country("DE", false)),
+        ("198.51.100.0", 24, country("FR", false)),
+        ("203.0.113.0", 24, country("JP", false)),
+    ]))
+    .unwrap();
+    let geoip = GeoIp::new(
+        db,
+        &[
+            ("DE".to_string(), GeoAction::Block),
+            ("FR".to_string(), GeoAction::RateLimit(2)),
+            ("JP".to_string(), GeoAction::Score(3)),
+        ],
+    );
+
+    assert_eq!(geoip.action("DE"), Some(GeoAction::Block));
+    assert_eq!(geoip.action("US"), None);
+    assert_eq!(
+        geoip.locate(ip("203.0.113.1")),
+        Some(Location {
+            country: "JP".to_string(),
+            score: 3,
+        })
+    );
+    assert_eq!(geoip.locate(ip("192.0.2.1")).unwrap().score, 0);
+    assert_eq!(geoip.locate(ip("10.0.0.1")), None);
+
+    // FR allows two connections per IP at once; other countries any
+    let peer = ip("198.51.100.7");
+    assert!(geoip.check_rate("FR", peer).await);
+    assert!(geoip.check_rate("FR", peer).await);
+    assert!(!geoip.check_rate("FR", peer).await);
+    assert!(geoip.check_rate("FR", ip("198.51.100.8")).await);
+    for _ in 0..5 {
+        assert!(geoip.check_rate("JP", ip("203.0.113.1")).await);
+    }
+
+    assert_eq!(geoip.connections(), "DE=1,JP=1");
+}
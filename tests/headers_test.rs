@@ -0,0 +1,167 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use burngate::headers::{
+    field, is_header_block, rfc3339_date, rfc5322_date, HeaderInjection, Received,
+};
+
+fn at(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+// -- dates --
+
+#[test]
+fn date_is_rfc5322_in_utc() {
+    assert_eq!(rfc5322_date(at(0)), "Thu, 1 Jan 1970 00:00:00 +0000");
+    assert_eq!(
+        rfc5322_date(at(1_709_164_800)),
+        "Thu, 29 Feb 2024 00:00:00 +0000"
+    );
+    assert_eq!(
+        rfc5322_date(at(1_791_972_245)),
+        "Wed, 14 Oct 2026 10:04:05 +0000"
+    );
+}
+
+#[test]
+fn timestamp_is_rfc3339_in_utc() {
+    assert_eq!(rfc3339_date(at(0)), "1970-01-01T00:00:00Z");
+    assert_eq!(rfc3339_date(at(1_791_972_245)), "2026-10-14T10:04:05Z");
+}
+
+// -- Received --
+
+fn received<'a>() -> Received<'a> {
+    Received {
+        helo: Some("mail.example.org"),
+        rdns: Some("mail.example.org"),
+        rdns_forged: false,
+        ip: "192.0.2.1".parse().unwrap(),
+        by: "mx.example.com",
+        protocol: "ESMTPS",
+        tls: Some(("TLSv1.3", "TLS13_AES_256_GCM_SHA384")),
+        id: "671E2C4000001",
+        recipient: Some("alice@example.com"),
+        date: at(1_791_972_245),
+    }
+}
+
+#[test]
+fn received_carries_client_tls_and_recipient() {
+    assert_eq!(
+        received().to_string(),
+        "from mail.example.org (mail.example.org [192.0.2.1])\r\n\
+         \tby mx.example.com (burngate) with ESMTPS id 671E2C4000001\r\n\
+         \t(using TLSv1.3 with cipher TLS13_AES_256_GCM_SHA384)\r\n\
+         \tfor <alice@example.com>; Wed, 14 Oct 2026 10:04:05 +0000"
+    );
+}
+
+#[test]
+fn received_without_rdns_tls_or_single_recipient() {
+    let header = Received {
+        rdns: None,
+        ip: "2001:db8::1".parse().unwrap(),
+        protocol: "ESMTP",
+        tls: None,
+        recipient: None,
+        ..received()
+    };
+    assert_eq!(
+        header.to_string(),
+        "from mail.example.org ([IPv6:2001:db8::1])\r\n\
+         \tby mx.example.com (burngate) with ESMTP id 671E2C4000001;\
+         \x20Wed, 14 Oct 2026 10:04:05 +0000"
+    );
+}
+
+#[test]
+fn received_marks_unconfirmed_rdns() {
+    let header = Received {
+        rdns: Some("dyn-1-2-0-192.example.net"),
+        rdns_forged: true,
+        ..received()
+    };
+    assert!(header.to_string().starts_with(
+        "from mail.example.org (dyn-1-2-0-192.example.net [192.0.2.1] (may be forged))\r\n"
+    ));
+}
+
+#[test]
+fn received_strips_header_breaking_helo() {
+    let header = Received {
+        helo: Some("evil) (x\r\nBcc: y"),
+        ..received()
+    };
+    assert!(header
+        .to_string()
+        .starts_with("from evilxBcc:y (mail.example.org [192.0.2.1])\r\n"));
+
+    let header = Received {
+        helo: None,
+        ip: "::ffff:192.0.2.1".parse().unwrap(),
+        ..received()
+    };
+    assert!(header
+        .to_string()
+        .starts_with("from unknown (mail.example.org [192.0.2.1])\r\n"));
+}
+
+#[test]
+fn received_value_is_a_valid_folded_field() {
+    let mut fields = HeaderInjection::new();
+    assert!(fields.push("Received", &received().to_string()));
+}
+
+// -- injection --
+
+#[test]
+fn fields_are_prepended_in_order() {
+    let mut fields = HeaderInjection::new();
+    fields.push("Received", "from a\r\n\tby b; date");
+    fields.push("X-Original-To", "alice@example.com");
+    let mut message = b"Subject: hi\r\n\r\nbody\r\n".to_vec();
+    assert!(fields.prepend_to(&mut message));
+    assert_eq!(
+        message,
+        b"Received: from a\r\n\tby b; date\r\n\
+          X-Original-To: alice@example.com\r\n\
+          Subject: hi\r\n\r\nbody\r\n"
+    );
+}
+
+#[test]
+fn invalid_fields_are_dropped() {
+    let mut fields = HeaderInjection::new();
+    assert!(!fields.push("Bad Name", "x"));
+    assert!(!fields.push("", "x"));
+    assert!(!fields.push("X-Test", "a\r\nBcc: evil"));
+    assert!(!fields.push("X-Test", "a\nb"));
+    assert!(fields.is_empty());
+}
+
+#[test]
+fn only_header_sections_get_fields() {
+    assert!(is_header_block(b"Subject: hi\r\n\r\nbody"));
+    assert!(is_header_block(b"\r\nbody only"));
+    assert!(!is_header_block(b"Dear customer: this is body text\r\n"));
+    assert!(!is_header_block(b"From alice@example.com Thu Oct 15\r\n"));
+    assert!(!is_header_block(b" folded: continuation\r\n"));
+    assert!(!is_header_block(b""));
+
+    let mut fields = HeaderInjection::new();
+    fields.push("X-Original-To", "alice@example.com");
+    let mut message = b"just text\r\n".to_vec();
+    assert!(!fields.prepend_to(&mut message));
+    assert_eq!(message, b"just text\r\n");
+}
+
+// -- lookup --
+
+#[test]
+fn field_is_found_case_insensitively_and_unfolded() {
+    let message = b"Subject: hi\r\nmessage-id:\r\n <1@b.c>\r\n\r\nMessage-ID: <body@x>\r\n";
+    assert_eq!(field(message, "Message-ID").as_deref(), Some("<1@b.c>"));
+    assert_eq!(field(message, "subject").as_deref(), Some("hi"));
+    assert_eq!(field(message, "To"), None);
+}
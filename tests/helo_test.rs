@@ -0,0 +1,71 @@
+use std::net::IpAddr;
+
+use burngate::config::HeloCheck;
+use burngate::helo::fails;
+
+fn client() -> IpAddr {
+    "192.0.2.1".parse().unwrap()
+}
+
+fn check(check: HeloCheck, helo: &str) -> bool {
+    fails(check, helo, client(), "mx.example.com")
+}
+
+#[test]
+fn bare_ips_fail() {
+    assert!(check(HeloCheck::BareIp, "192.0.2.1"));
+    assert!(check(HeloCheck::BareIp, "2001:db8::1"));
+    assert!(!check(HeloCheck::BareIp, "[192.0.2.1]"));
+    assert!(!check(HeloCheck::BareIp, "mail.example.org"));
+}
+
+#[test]
+fn literals_must_match_the_client() {
+    assert!(!check(HeloCheck::LiteralMismatch, "[192.0.2.1]"));
+    assert!(check(HeloCheck::LiteralMismatch, "[198.51.100.7]"));
+    assert!(check(HeloCheck::LiteralMismatch, "[not-an-ip]"));
+    assert!(!check(HeloCheck::LiteralMismatch, "mail.example.org"));
+
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    assert!(!fails(
+        HeloCheck::LiteralMismatch,
+        "[IPv6:2001:db8::1]",
+        v6,
+        "mx"
+    ));
+    assert!(fails(
+        HeloCheck::LiteralMismatch,
+        "[IPv6:2001:db8::2]",
+        v6,
+        "mx"
+    ));
+
+    // A dual-stack listener reports IPv4 clients as mapped addresses
+    let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+    assert!(!fails(
+        HeloCheck::LiteralMismatch,
+        "[192.0.2.1]",
+        mapped,
+        "mx"
+    ));
+}
+
+#[test]
+fn our_own_name_fails() {
+    assert!(check(HeloCheck::OwnName, "mx.example.com"));
+    assert!(check(HeloCheck::OwnName, "MX.Example.COM."));
+    assert!(!check(HeloCheck::OwnName, "mx2.example.com"));
+}
+
+#[test]
+fn names_must_be_fully_qualified() {
+    assert!(check(HeloCheck::NonFqdn, "localhost"));
+    assert!(check(HeloCheck::NonFqdn, "WIN-4F2K9"));
+    assert!(check(HeloCheck::NonFqdn, "bad_name.example.org"));
+    assert!(check(HeloCheck::NonFqdn, ""));
+    assert!(!check(HeloCheck::NonFqdn, "mail.example.org"));
+    assert!(!check(HeloCheck::NonFqdn, "mail.example.org."));
+    // Left to bare_ip and literal_mismatch
+    assert!(!check(HeloCheck::NonFqdn, "192.0.2.1"));
+    assert!(!check(HeloCheck::NonFqdn, "[192.0.2.1]"));
+}
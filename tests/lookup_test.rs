@@ -0,0 +1,846 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use burngate::config::{ChainAction, ChainStage, CheckMode, Config, DomainForm, StageKind};
+use burngate::http;
+use burngate::lookup::{
+    self, AliasError, CachedLookup, HttpLookup, LookupBackend, LookupChain, LookupOutcome,
+    MailboxLookup,
+};
+use burngate::session::Metrics;
+use burngate::store::{self, MemoryStore, Store, StoreError};
+
+fn lookup(store: &MemoryStore) -> MailboxLookup {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    MailboxLookup::new(Arc::new(store.clone()), &config)
+}
+
+// -- check --
+
+#[tokio::test]
+async fn active_key_hits() {
+    let store = MemoryStore::new();
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    let outcome = lookup(&store).check("Alice@Example.com").await;
+    assert_eq!(outcome, LookupOutcome::KeyHit);
+}
+
+#[tokio::test]
+async fn known_set_is_fallback() {
+    let store = MemoryStore::new();
+    store.set_add("addresses", "bob@example.com");
+    let outcome = lookup(&store).check("bob@example.com").await;
+    assert_eq!(outcome, LookupOutcome::SetHit);
+}
+
+#[tokio::test]
+async fn unknown_address_misses() {
+    let store = MemoryStore::new();
+    let outcome = lookup(&store).check("nobody@example.com").await;
+    assert_eq!(outcome, LookupOutcome::Miss);
+    assert!(!outcome.is_hit());
+}
+
+#[tokio::test]
+async fn store_error_fails_closed() {
+    let store = MemoryStore::new();
+    // A wrong key type makes the set check error
+    store.hash_set("addresses", "f", "v");
+    let outcome = lookup(&store).check("carol@example.com").await;
+    assert_eq!(outcome, LookupOutcome::Error);
+}
+
+#[tokio::test]
+async fn broken_set_does_not_hide_an_active_key() {
+    let store = MemoryStore::new();
+    store.hash_set("addresses", "f", "v");
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    let outcome = lookup(&store).check("alice@example.com").await;
+    assert_eq!(outcome, LookupOutcome::KeyHit);
+}
+
+// -- is_sender_blocked --
+
+#[tokio::test]
+async fn sender_blocked_by_address_or_domain() {
+    let store = MemoryStore::new();
+    store.set_add("blocked:alice@example.com", "spam@evil.com");
+    store.set_add("blocked:alice@example.com", "evil.org");
+    let lookup = lookup(&store);
+    assert!(
+        lookup
+            .is_sender_blocked("alice@example.com", "Spam@Evil.com")
+            .await
+    );
+    assert!(
+        lookup
+            .is_sender_blocked("alice@example.com", "x@evil.org")
+            .await
+    );
+    assert!(
+        !lookup
+            .is_sender_blocked("alice@example.com", "x@good.com")
+            .await
+    );
+    assert!(!lookup.is_sender_blocked("alice@example.com", "").await);
+}
+
+#[tokio::test]
+async fn sender_blocked_by_unicode_entry() {
+    let store = MemoryStore::new();
+    store.set_add("blocked:alice@example.com", "bücher.example");
+    store.set_add("blocked:alice@example.com", "spam@пример.example");
+    let lookup = lookup(&store);
+    assert!(
+        lookup
+            .is_sender_blocked("alice@example.com", "x@xn--bcher-kva.example")
+            .await
+    );
+    assert!(
+        lookup
+            .is_sender_blocked("alice@example.com", "spam@xn--e1afmkfd.example")
+            .await
+    );
+    assert!(
+        !lookup
+            .is_sender_blocked("alice@example.com", "ham@xn--e1afmkfd.example")
+            .await
+    );
+}
+
+// -- AUTO_CREATE_MAILBOXES --
+
+#[tokio::test]
+async fn misses_create_the_mailbox_when_enabled() {
+    let store = MemoryStore::new();
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.auto_create_mailboxes = true;
+    config.auto_create_ttl_secs = 600;
+    let lookup = MailboxLookup::new(Arc::new(store.clone()), &config);
+
+    assert_eq!(
+        lookup.check("New@example.com").await,
+        LookupOutcome::Created
+    );
+    let ttl = store.ttl("mb:new@example.com").await.unwrap();
+    assert!(matches!(ttl, Some(599..=600)));
+    assert_eq!(lookup.check("new@example.com").await, LookupOutcome::KeyHit);
+}
+
+#[tokio::test]
+async fn lookup_errors_do_not_create_mailboxes() {
+    let store = MemoryStore::new();
+    store.hash_set("addresses", "f", "v");
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.auto_create_mailboxes = true;
+    let lookup = MailboxLookup::new(Arc::new(store.clone()), &config);
+
+    assert_eq!(lookup.check("new@example.com").await, LookupOutcome::Error);
+    assert!(!store.exists("mb:new@example.com").await.unwrap());
+}
+
+// -- MAILBOX_EXTEND_SECS --
+
+#[tokio::test(start_paused = true)]
+async fn deliveries_extend_mailboxes_up_to_the_cap() {
+    let store = MemoryStore::new();
+    store
+        .set_ex("mb:alice@example.com", "1", 600)
+        .await
+        .unwrap();
+    store.set_add("addresses", "bob@example.com");
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.mailbox_extend_secs = 300;
+    config.mailbox_extend_max_secs = 1000;
+    let lookup = MailboxLookup::new(Arc::new(store.clone()), &config);
+
+    lookup.delivered("alice@example.com").await;
+    assert_eq!(store.ttl("mb:alice@example.com").await.unwrap(), Some(900));
+    lookup.delivered("Alice@example.com").await;
+    assert_eq!(store.ttl("mb:alice@example.com").await.unwrap(), Some(1000));
+
+    // Set members have no key to extend
+    lookup.delivered("bob@example.com").await;
+    assert!(!store.exists("mb:bob@example.com").await.unwrap());
+}
+
+// -- resolve_alias --
+
+#[tokio::test]
+async fn alias_chains_end_at_a_mailbox_within_max_hops() {
+    let store = MemoryStore::new();
+    for (alias, target) in [
+        ("a@example.com", "B@Example.com"),
+        ("b@example.com", "c@example.com"),
+    ] {
+        store.hash_set("aliases", alias, target);
+    }
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.alias_hash = "aliases".to_string();
+    let lookup = MailboxLookup::new(Arc::new(store.clone()), &config);
+
+    let target = lookup.resolve_alias("A@example.com").await.unwrap();
+    assert_eq!(target.as_deref(), Some("c@example.com"));
+    assert_eq!(lookup.resolve_alias("c@example.com").await.unwrap(), None);
+
+    config.alias_max_hops = 1;
+    let lookup = MailboxLookup::new(Arc::new(store.clone()), &config);
+    assert!(matches!(
+        lookup.resolve_alias("a@example.com").await,
+        Err(AliasError::Loop)
+    ));
+}
+
+// -- LOOKUP_REDIS_TIMEOUT_MS --
+
+/// A Redis that accepts commands and never answers.
+struct HungStore;
+
+async fn never<T>() -> T {
+    std::future::pending().await
+}
+
+#[async_trait]
+impl Store for HungStore {
+    async fn exists(&self, _: &str) -> Result<bool, StoreError> {
+        never().await
+    }
+    async fn set_contains(&self, _: &str, _: &[&str]) -> Result<Vec<bool>, StoreError> {
+        never().await
+    }
+    async fn exists_and_contains(
+        &self,
+        _: &str,
+        _: &str,
+        _: &str,
+    ) -> Result<(bool, bool), StoreError> {
+        never().await
+    }
+    async fn set_members(&self, _: &str) -> Result<Vec<String>, StoreError> {
+        never().await
+    }
+    async fn keys_between(&self, _: &str, _: &str) -> Result<Vec<String>, StoreError> {
+        never().await
+    }
+    async fn get(&self, _: &str) -> Result<Option<String>, StoreError> {
+        never().await
+    }
+    async fn set_ex(&self, _: &str, _: &str, _: u64) -> Result<(), StoreError> {
+        never().await
+    }
+    async fn delete(&self, _: &str) -> Result<(), StoreError> {
+        never().await
+    }
+    async fn incr(&self, _: &str, _: u64) -> Result<i64, StoreError> {
+        never().await
+    }
+    async fn decr(&self, _: &str, _: u64) -> Result<i64, StoreError> {
+        never().await
+    }
+    async fn hash_get(&self, _: &str, _: &str) -> Result<Option<String>, StoreError> {
+        never().await
+    }
+    async fn hash_get_all(&self, _: &str) -> Result<HashMap<String, String>, StoreError> {
+        never().await
+    }
+    async fn list_push(&self, _: &str, _: &str, _: u64) -> Result<(), StoreError> {
+        never().await
+    }
+    async fn list_all(&self, _: &str) -> Result<Vec<String>, StoreError> {
+        never().await
+    }
+    async fn list_replace(&self, _: &str, _: &[String], _: u64) -> Result<(), StoreError> {
+        never().await
+    }
+    async fn ttl(&self, _: &str) -> Result<Option<u64>, StoreError> {
+        never().await
+    }
+    async fn expire(&self, _: &str, _: u64) -> Result<bool, StoreError> {
+        never().await
+    }
+    async fn subscribe(&self, _: &str) -> Result<mpsc::UnboundedReceiver<String>, StoreError> {
+        never().await
+    }
+    async fn script_load(&self, _: &str) -> Result<String, StoreError> {
+        never().await
+    }
+    async fn eval_sha(&self, _: &str, _: &[&str], _: &[&str]) -> Result<i64, StoreError> {
+        never().await
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn hung_redis_times_out_as_an_error() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.alias_hash = "aliases".to_string();
+    config.blocklist_key_pattern = "blocked:{address}".to_string();
+    let lookup = MailboxLookup::new(Arc::new(HungStore), &config);
+
+    let started = tokio::time::Instant::now();
+    assert_eq!(
+        lookup.check("alice@example.com").await,
+        LookupOutcome::Error
+    );
+    assert_eq!(started.elapsed(), Duration::from_millis(500));
+    assert!(matches!(
+        lookup.resolve_alias("alice@example.com").await,
+        Err(AliasError::Store(StoreError::Timeout))
+    ));
+    assert!(
+        !lookup
+            .is_sender_blocked("alice@example.com", "bob@example.org")
+            .await
+    );
+}
+
+// -- per-domain layouts --
+
+#[tokio::test]
+async fn domains_can_have_their_own_keys_and_sets() {
+    let store = MemoryStore::new();
+    store
+        .set_ex("t1:mb:ann@tenant1.example", "1", 60)
+        .await
+        .unwrap();
+    store.set_add("t1:addresses", "bob@tenant1.example");
+    store.set_add("addresses", "carol@tenant2.example");
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.redis_domain_key_patterns =
+        [("tenant1.example".to_string(), "t1:mb:{address}".to_string())].into();
+    config.redis_domain_set_names = [
+        ("tenant1.example".to_string(), "t1:addresses".to_string()),
+        ("tenant2.example".to_string(), String::new()),
+    ]
+    .into();
+    let lookup = MailboxLookup::new(Arc::new(store.clone()), &config);
+
+    assert_eq!(
+        lookup.check("ann@tenant1.example").await,
+        LookupOutcome::KeyHit
+    );
+    assert_eq!(
+        lookup.check("bob@tenant1.example").await,
+        LookupOutcome::SetHit
+    );
+    // tenant2 has no set, so the shared one is not consulted
+    assert_eq!(
+        lookup.check("carol@tenant2.example").await,
+        LookupOutcome::Miss
+    );
+    assert_eq!(
+        config.redis_key_for("ann@tenant1.example"),
+        "t1:mb:ann@tenant1.example"
+    );
+}
+
+// -- REDIS_DOMAIN_FORM --
+
+#[tokio::test]
+async fn unicode_domain_form_matches_either_spelling() {
+    let store = MemoryStore::new();
+    store
+        .set_ex("mb:anna@bücher.example", "1", 60)
+        .await
+        .unwrap();
+    store.set_add("addresses", "bob@bücher.example");
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.redis_domain_form = DomainForm::Unicode;
+    let lookup = MailboxLookup::new(Arc::new(store.clone()), &config);
+
+    for address in ["anna@xn--bcher-kva.example", "Anna@BÜCHER.example"] {
+        assert_eq!(lookup.check(address).await, LookupOutcome::KeyHit);
+    }
+    assert_eq!(
+        lookup.check("bob@xn--bcher-kva.example").await,
+        LookupOutcome::SetHit
+    );
+}
+
+#[tokio::test]
+async fn punycode_domain_form_is_default() {
+    let store = MemoryStore::new();
+    store
+        .set_ex("mb:anna@xn--bcher-kva.example", "1", 60)
+        .await
+        .unwrap();
+    let outcome = lookup(&store).check("anna@bücher.example").await;
+    assert_eq!(outcome, LookupOutcome::KeyHit);
+}
+
+// -- CATCH_ALL_KEY_PATTERN --
+
+fn catch_all_lookup(store: &MemoryStore) -> MailboxLookup {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.catch_all_key_pattern = "catchall:{domain}".to_string();
+    MailboxLookup::new(Arc::new(store.clone()), &config)
+}
+
+#[tokio::test]
+async fn catch_all_domain_accepts_unknown_addresses() {
+    let store = MemoryStore::new();
+    store.set_ex("catchall:example.com", "1", 60).await.unwrap();
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    let lookup = catch_all_lookup(&store);
+
+    assert_eq!(
+        lookup.check("Anyone@Example.com").await,
+        LookupOutcome::CatchAll
+    );
+    assert_eq!(
+        lookup.check("alice@example.com").await,
+        LookupOutcome::KeyHit
+    );
+    assert_eq!(
+        lookup.check("anyone@example.org").await,
+        LookupOutcome::Miss
+    );
+}
+
+#[tokio::test]
+async fn catch_all_does_not_mask_lookup_errors() {
+    let store = MemoryStore::new();
+    store.set_ex("catchall:example.com", "1", 60).await.unwrap();
+    store.hash_set("addresses", "f", "v");
+    let outcome = catch_all_lookup(&store).check("carol@example.com").await;
+    assert_eq!(outcome, LookupOutcome::Error);
+}
+
+// -- REDIS_CHECK_MODE=script --
+
+const SCRIPT: &str =
+    "return redis.call('EXISTS', KEYS[1]) + redis.call('SISMEMBER', KEYS[2], ARGV[1])";
+
+/// A lookup in script mode, with `SCRIPT` accepting `vip@example.com` only.
+fn script_lookup(store: &MemoryStore, name: &str) -> MailboxLookup {
+    let path = std::env::temp_dir().join(format!("burngate-{}-{}.lua", name, std::process::id()));
+    std::fs::write(&path, SCRIPT).unwrap();
+    store.define_script(SCRIPT, |keys, args| {
+        assert_eq!(keys, [format!("mb:{}", args[0]).as_str(), "addresses"]);
+        (args == ["vip@example.com"]) as i64
+    });
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.redis_check_mode = CheckMode::Script;
+    config.redis_check_script = Some(path.display().to_string());
+    MailboxLookup::new(Arc::new(store.clone()), &config)
+}
+
+#[tokio::test]
+async fn script_decides_acceptance() {
+    let store = MemoryStore::new();
+    let lookup = script_lookup(&store, "script-decides");
+    store.script_load(SCRIPT).await.unwrap();
+
+    assert_eq!(lookup.check("VIP@example.com").await, LookupOutcome::Found);
+    assert_eq!(lookup.check("vip@example.org").await, LookupOutcome::Miss);
+}
+
+#[tokio::test]
+async fn flushed_script_is_loaded_again() {
+    let store = MemoryStore::new();
+    let lookup = script_lookup(&store, "script-flushed");
+    store.flush_scripts();
+
+    assert_eq!(lookup.check("vip@example.com").await, LookupOutcome::Found);
+}
+
+#[test]
+fn script_sha_matches_redis() {
+    // SCRIPT LOAD "return 1"
+    assert_eq!(
+        store::script_sha("return 1"),
+        "e0e1f9fabfc9d4800c877a703b823ac0578ff8db"
+    );
+}
+
+// -- LOOKUP_CHAIN --
+
+fn stage(
+    kind: StageKind,
+    on_hit: ChainAction,
+    on_miss: ChainAction,
+    on_error: ChainAction,
+) -> ChainStage {
+    ChainStage {
+        kind,
+        on_hit,
+        on_miss,
+        on_error,
+    }
+}
+
+fn chain(store: &MemoryStore, stages: Vec<ChainStage>) -> LookupChain {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.lookup_chain = stages;
+    LookupChain::from_config(Arc::new(store.clone()), &config, None)
+}
+
+#[tokio::test]
+async fn chain_stages_run_in_order_with_their_actions() {
+    use ChainAction::{Accept, Continue, Reject};
+    let store = MemoryStore::new();
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    store.set_add("addresses", "bob@example.com");
+
+    // The built-in key-then-set order
+    let lookup = chain(
+        &store,
+        vec![
+            stage(StageKind::Key, Accept, Continue, Continue),
+            stage(StageKind::Set, Accept, Continue, Continue),
+        ],
+    );
+    assert_eq!(
+        lookup.check("alice@example.com").await,
+        LookupOutcome::KeyHit
+    );
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::SetHit);
+    assert_eq!(lookup.check("carol@example.com").await, LookupOutcome::Miss);
+
+    // A miss on the key stops the chain before the set is asked
+    let lookup = chain(
+        &store,
+        vec![
+            stage(StageKind::Key, Accept, Reject, Reject),
+            stage(StageKind::Set, Accept, Continue, Reject),
+        ],
+    );
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Miss);
+
+    // A hit that rejects makes a denylist
+    let lookup = chain(
+        &store,
+        vec![
+            stage(StageKind::Set, Reject, Continue, Reject),
+            stage(StageKind::Key, Accept, Continue, Reject),
+        ],
+    );
+    store.set_ex("mb:bob@example.com", "1", 60).await.unwrap();
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Miss);
+    assert_eq!(
+        lookup.check("alice@example.com").await,
+        LookupOutcome::KeyHit
+    );
+}
+
+#[tokio::test]
+async fn chain_errors_stop_or_continue_per_stage() {
+    use ChainAction::{Accept, Continue, Reject};
+    let store = MemoryStore::new();
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    store.hash_set("addresses", "f", "v");
+
+    let lookup = chain(
+        &store,
+        vec![
+            stage(StageKind::Set, Accept, Continue, Reject),
+            stage(StageKind::Key, Accept, Continue, Reject),
+        ],
+    );
+    assert_eq!(
+        lookup.check("alice@example.com").await,
+        LookupOutcome::Error
+    );
+
+    let lookup = chain(
+        &store,
+        vec![
+            stage(StageKind::Set, Accept, Continue, Continue),
+            stage(StageKind::Key, Accept, Continue, Reject),
+        ],
+    );
+    assert_eq!(
+        lookup.check("alice@example.com").await,
+        LookupOutcome::KeyHit
+    );
+    // Nothing found after an error: the lookup failed
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Error);
+}
+
+// -- CachedLookup --
+
+#[tokio::test]
+async fn hits_are_served_from_the_cache() {
+    let store = MemoryStore::new();
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    let metrics = Arc::new(Metrics::new());
+    let lookup = lookup::from_config(Arc::new(store.clone()), &config, metrics.clone());
+
+    assert_eq!(
+        lookup.check("alice@example.com").await,
+        LookupOutcome::KeyHit
+    );
+    store.delete("mb:alice@example.com").await.unwrap();
+    assert_eq!(
+        lookup.check("Alice@Example.com").await,
+        LookupOutcome::KeyHit
+    );
+    assert_eq!(metrics.lookup_cache_hits.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.lookup_cache_misses.load(Ordering::Relaxed), 1);
+}
+
+/// Collects the values recorded for `lookup.cache` on any span.
+struct CacheField(Arc<Mutex<Vec<String>>>);
+
+impl tracing::field::Visit for CacheField {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "lookup.cache" {
+            self.0.lock().unwrap().push(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+}
+
+struct CacheLayer(Arc<Mutex<Vec<String>>>);
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CacheLayer {
+    fn on_record(
+        &self,
+        _: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        _: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        values.record(&mut CacheField(self.0.clone()));
+    }
+}
+
+#[tokio::test]
+async fn cache_hits_and_misses_are_recorded_on_the_span() {
+    use tracing::Instrument;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let store = MemoryStore::new();
+    store.set_ex("mb:alice@example.com", "1", 60).await.unwrap();
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    let lookup = lookup::from_config(Arc::new(store), &config, Arc::new(Metrics::new()));
+
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(CacheLayer(recorded.clone()));
+    let _default = tracing::subscriber::set_default(subscriber);
+    let span = tracing::info_span!("smtp.session", lookup.cache = tracing::field::Empty);
+    async {
+        lookup.check("alice@example.com").await;
+        lookup.check("alice@example.com").await;
+    }
+    .instrument(span)
+    .await;
+    assert_eq!(*recorded.lock().unwrap(), ["miss", "hit"]);
+}
+
+#[tokio::test]
+async fn errors_are_not_cached() {
+    let store = MemoryStore::new();
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    let metrics = Arc::new(Metrics::new());
+    let lookup = lookup::from_config(Arc::new(store.clone()), &config, metrics.clone());
+
+    store.hash_set("addresses", "f", "v");
+    assert_eq!(
+        lookup.check("carol@example.com").await,
+        LookupOutcome::Error
+    );
+    store.set_ex("mb:carol@example.com", "1", 60).await.unwrap();
+    assert_eq!(
+        lookup.check("carol@example.com").await,
+        LookupOutcome::KeyHit
+    );
+    assert_eq!(metrics.lookup_cache_hits.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn misses_are_cached_until_invalidated() {
+    let store = MemoryStore::new();
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.lookup_negative_cache_ttl_secs = 60;
+    config.lookup_invalidate_channel = Some("mailbox:created".to_string());
+    let metrics = Arc::new(Metrics::new());
+    let lookup = lookup::from_config(Arc::new(store.clone()), &config, metrics.clone());
+
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Miss);
+    store.set_ex("mb:bob@example.com", "1", 60).await.unwrap();
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Miss);
+    assert_eq!(metrics.lookup_cache_hits.load(Ordering::Relaxed), 1);
+
+    // The subscription starts in the background
+    while store.publish("mailbox:created", "Bob@Example.com") == 0 {
+        tokio::task::yield_now().await;
+    }
+    for _ in 0..100 {
+        if lookup.check("bob@example.com").await == LookupOutcome::KeyHit {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("negative answer was never invalidated");
+}
+
+#[tokio::test]
+async fn keyspace_notifications_invalidate_mailbox_keys() {
+    let store = MemoryStore::new();
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.lookup_negative_cache_ttl_secs = 60;
+    config.lookup_keyspace_notifications = true;
+    let metrics = Arc::new(Metrics::new());
+    let lookup = lookup::from_config(Arc::new(store.clone()), &config, metrics.clone());
+
+    store.set_ex("mb:dave@example.com", "1", 60).await.unwrap();
+    assert_eq!(
+        lookup.check("dave@example.com").await,
+        LookupOutcome::KeyHit
+    );
+    assert_eq!(lookup.check("erin@example.com").await, LookupOutcome::Miss);
+    store.delete("mb:dave@example.com").await.unwrap();
+    store.set_ex("mb:erin@example.com", "1", 60).await.unwrap();
+
+    // Other keys and events are ignored
+    while store.publish("__keyevent@0__:set", "flag:dave@example.com") == 0 {
+        tokio::task::yield_now().await;
+    }
+    while store.publish("__keyevent@0__:expired", "mb:dave@example.com") == 0 {
+        tokio::task::yield_now().await;
+    }
+    while store.publish("__keyevent@0__:set", "mb:erin@example.com") == 0 {
+        tokio::task::yield_now().await;
+    }
+    for _ in 0..100 {
+        if lookup.check("dave@example.com").await == LookupOutcome::Miss
+            && lookup.check("erin@example.com").await == LookupOutcome::KeyHit
+        {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("cached answers were never invalidated");
+}
+
+// -- HttpLookup --
+
+/// Lookup service that knows `alice@example.com`, answers 500 for
+/// `broken@example.com`, and never answers for `slow@example.com`. Returns
+/// its URL, the number of requests and the last request head.
+async fn service() -> (String, Arc<AtomicUsize>, Arc<Mutex<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/v1/exists", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+    let last = Arc::new(Mutex::new(String::new()));
+    let (count, head) = (requests.clone(), last.clone());
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            count.fetch_add(1, Ordering::SeqCst);
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            let status = if request.contains("address=alice%40example.com ") {
+                200
+            } else if request.contains("address=broken%40example.com ") {
+                500
+            } else if request.contains("address=slow%40example.com ") {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                200
+            } else {
+                404
+            };
+            *head.lock().unwrap() = request;
+            let response = format!("HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\n\r\n");
+            let _ = reader.get_mut().write_all(response.as_bytes()).await;
+        }
+    });
+    (url, requests, last)
+}
+
+fn http_config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.lookup_http_token = Some("s3cret".to_string());
+    config.lookup_http_timeout_ms = 200;
+    config
+}
+
+#[tokio::test]
+async fn http_service_decides_existence() {
+    let (url, _, last) = service().await;
+    let lookup = HttpLookup::new(&url, &http_config());
+
+    assert_eq!(
+        lookup.check("Alice@Example.com").await,
+        LookupOutcome::Found
+    );
+    let head = last.lock().unwrap().clone();
+    assert!(head.starts_with("GET /v1/exists?address=alice%40example.com HTTP/1.1\r\n"));
+    assert!(head.contains("Authorization: Bearer s3cret\r\n"));
+
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Miss);
+    assert!(!lookup.should_accept("bob@example.com").await);
+}
+
+#[tokio::test]
+async fn http_answers_are_cached() {
+    let (url, requests, _) = service().await;
+    let mut config = http_config();
+    config.lookup_negative_cache_ttl_secs = 60;
+    let http = Arc::new(HttpLookup::new(&url, &config));
+    let lookup = CachedLookup::new(http, &config, Arc::new(Metrics::new()));
+
+    for _ in 0..3 {
+        assert!(lookup.check("alice@example.com").await.is_hit());
+        assert!(!lookup.check("bob@example.com").await.is_hit());
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn http_errors_fail_closed_and_are_not_cached() {
+    let (url, requests, _) = service().await;
+    let lookup = HttpLookup::new(&url, &http_config());
+
+    assert_eq!(
+        lookup.check("broken@example.com").await,
+        LookupOutcome::Error
+    );
+    assert_eq!(
+        lookup.check("broken@example.com").await,
+        LookupOutcome::Error
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    assert_eq!(lookup.check("slow@example.com").await, LookupOutcome::Error);
+}
+
+#[test]
+fn query_values_are_percent_encoded() {
+    assert_eq!(
+        http::query_escape("a+b@bücher.example"),
+        "a%2Bb%40b%C3%BCcher.example"
+    );
+    assert_eq!(http::query_escape("x.y_z-1~"), "x.y_z-1~");
+}
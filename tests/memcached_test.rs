@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use burngate::config::Config;
+use burngate::lookup::{LookupBackend, LookupOutcome, MemcachedLookup};
+use burngate::memcached::{McError, McPool};
+
+/// Memcached server that has `mb:alice@example.com` set, answers
+/// `SERVER_ERROR` for `mb:broken@example.com`, and never answers for
+/// `mb:slow@example.com`. With `one_get`, it hangs up after each reply.
+/// Returns its address and the number of connections.
+async fn server(one_get: bool) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let count = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            count.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(session(stream, one_get));
+        }
+    });
+    (addr, connections)
+}
+
+async fn session(stream: TcpStream, one_get: bool) {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+        let key = line.trim_end().strip_prefix("get ").unwrap().to_string();
+        line.clear();
+        let reply = match key.as_str() {
+            "mb:alice@example.com" => format!("VALUE {} 0 1\r\n1\r\nEND\r\n", key),
+            "mb:broken@example.com" => "SERVER_ERROR out of memory\r\n".to_string(),
+            "mb:slow@example.com" => std::future::pending().await,
+            _ => "END\r\n".to_string(),
+        };
+        stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        if one_get {
+            return;
+        }
+    }
+}
+
+fn config(addr: &str) -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.lookup_memcached_addr = Some(addr.to_string());
+    config.lookup_memcached_timeout_ms = 200;
+    config
+}
+
+#[tokio::test]
+async fn keys_are_looked_up_over_one_connection() {
+    let (addr, connections) = server(false).await;
+    let lookup = MemcachedLookup::new(&addr, &config(&addr));
+
+    assert_eq!(
+        lookup.check("Alice@Example.com").await,
+        LookupOutcome::Found
+    );
+    assert_eq!(lookup.check("bob@example.com").await, LookupOutcome::Miss);
+    assert_eq!(
+        lookup.check("broken@example.com").await,
+        LookupOutcome::Error
+    );
+    assert_eq!(lookup.check("slow@example.com").await, LookupOutcome::Error);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn closed_pooled_connections_are_replaced() {
+    let (addr, connections) = server(true).await;
+    let lookup = MemcachedLookup::new(&addr, &config(&addr));
+    for _ in 0..3 {
+        assert_eq!(
+            lookup.check("alice@example.com").await,
+            LookupOutcome::Found
+        );
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn unstorable_keys_are_missing_without_a_request() {
+    let (addr, connections) = server(false).await;
+    let pool = McPool::new(&addr, 1, Duration::from_millis(200));
+    assert!(!pool.exists("mb:a b@example.com").await.unwrap());
+    assert!(!pool.exists(&"k".repeat(251)).await.unwrap());
+    assert_eq!(connections.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn unreachable_server_is_an_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let pool = McPool::new(&addr, 1, Duration::from_millis(200));
+    assert!(matches!(pool.exists("k").await, Err(McError::Io(_))));
+}
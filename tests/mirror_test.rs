@@ -0,0 +1,132 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use burngate::config::Config;
+use burngate::esmtp::EsmtpParams;
+use burngate::mirror::{Mirror, MirrorMessage};
+use burngate::session::Metrics;
+
+/// Backend that accepts everything and sends each transaction's RCPT and
+/// body lines down the channel.
+async fn capturing_backend() -> (String, mpsc::UnboundedReceiver<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stream);
+                let _ = reader.get_mut().write_all(b"220 mirror\r\n").await;
+                let mut seen = Vec::new();
+                let mut in_data = false;
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let trimmed = line.trim_end().to_string();
+                    let reply: &[u8] = match trimmed.as_str() {
+                        "." if in_data => {
+                            in_data = false;
+                            let _ = tx.send(std::mem::take(&mut seen));
+                            b"250 queued\r\n"
+                        }
+                        _ if in_data => {
+                            seen.push(trimmed);
+                            continue;
+                        }
+                        "DATA" => {
+                            in_data = true;
+                            b"354 go\r\n"
+                        }
+                        "QUIT" => b"221 bye\r\n",
+                        _ => {
+                            if trimmed.starts_with("RCPT") {
+                                seen.push(trimmed);
+                            }
+                            b"250 OK\r\n"
+                        }
+                    };
+                    let _ = reader.get_mut().write_all(reply).await;
+                }
+            });
+        }
+    });
+    (addr, rx)
+}
+
+fn config(max_pending: usize) -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.mirror_max_pending = max_pending;
+    config.data_timeout_secs = 5;
+    config
+}
+
+fn message() -> MirrorMessage {
+    MirrorMessage {
+        client: None,
+        sender: "a@b.c".to_string(),
+        mail_params: EsmtpParams::default(),
+        recipients: vec![("alice@example.com".to_string(), EsmtpParams::default())],
+        data: b"Subject: hi\r\n\r\nbody\r\n".to_vec(),
+    }
+}
+
+// -- delivery --
+
+#[tokio::test]
+async fn copy_reaches_mirror_backend() {
+    let (addr, mut rx) = capturing_backend().await;
+    let metrics = Arc::new(Metrics::new());
+    let mirror = Mirror::new(&addr, &config(10), metrics.clone());
+
+    assert!(mirror.submit(message()));
+    let seen = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(seen[0], "RCPT TO:<alice@example.com>");
+    assert!(seen.ends_with(&["Subject: hi".into(), "".into(), "body".into()]));
+    assert_eq!(metrics.mirror_errors.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn unreachable_mirror_counts_error() {
+    // Bind and drop a listener to get a port nothing is listening on
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let metrics = Arc::new(Metrics::new());
+    let mirror = Mirror::new(&addr, &config(10), metrics.clone());
+
+    assert!(mirror.submit(message()));
+    for _ in 0..100 {
+        if metrics.mirror_errors.load(Ordering::Relaxed) == 1 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("mirror error was not counted");
+}
+
+// -- backpressure --
+
+#[tokio::test]
+async fn copies_beyond_max_pending_are_dropped() {
+    let metrics = Arc::new(Metrics::new());
+    let mirror = Mirror::new("127.0.0.1:9", &config(0), metrics.clone());
+
+    assert!(!mirror.submit(message()));
+    assert_eq!(metrics.mirror_errors.load(Ordering::Relaxed), 1);
+}
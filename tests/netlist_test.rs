@@ -0,0 +1,93 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use burngate::netlist::{Entries, Listed, NetworkList, NetworkListError};
+
+fn file(name: &str, contents: &str) -> std::path::PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("burngate-netlist-{}-{}", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn entries_are_parsed_with_comments() {
+    let text =
+        "# partners\nallow 198.51.100.0/24\n\nBLOCK 203.0.113.7  # abuser\nblock 2001:db8::/32\n";
+    let entries = Entries::parse(text).unwrap();
+    assert_eq!(entries.networks.len(), 3);
+    assert_eq!(entries.check(ip("198.51.100.9")), Some(Listed::Allow));
+    assert_eq!(entries.check(ip("203.0.113.7")), Some(Listed::Block));
+    assert_eq!(entries.check(ip("203.0.113.8")), None);
+    assert_eq!(entries.check(ip("2001:db8::1")), Some(Listed::Block));
+}
+
+#[test]
+fn invalid_entries_are_reported_by_line() {
+    assert!(matches!(
+        Entries::parse("allow 10.0.0.0/8\ndeny 10.0.0.0/8\n"),
+        Err(NetworkListError::Invalid { line: 2, .. })
+    ));
+    assert!(matches!(
+        Entries::parse("block 10.0.0.0/33\n"),
+        Err(NetworkListError::Invalid { line: 1, .. })
+    ));
+    assert!(matches!(
+        Entries::parse("allow\n"),
+        Err(NetworkListError::Invalid { line: 1, .. })
+    ));
+}
+
+#[test]
+fn most_specific_network_wins_and_block_breaks_ties() {
+    let entries = Entries::parse(
+        "block 10.0.0.0/8\nallow 10.1.0.0/16\nblock 10.1.2.0/24\n\
+         allow 2001:db8::/32\nblock 2001:db8::/32\n",
+    )
+    .unwrap();
+    assert_eq!(entries.check(ip("10.9.9.9")), Some(Listed::Block));
+    assert_eq!(entries.check(ip("10.1.9.9")), Some(Listed::Allow));
+    assert_eq!(entries.check(ip("10.1.2.3")), Some(Listed::Block));
+    assert_eq!(entries.check(ip("2001:db8::5")), Some(Listed::Block));
+}
+
+#[tokio::test]
+async fn reload_swaps_entries_and_keeps_them_on_error() {
+    let path = file("reload", "allow 192.0.2.0/24\n");
+    let list = NetworkList::load(&path).unwrap();
+    assert_eq!(list.counts(), (1, 0));
+    assert_eq!(list.check(ip("192.0.2.1")), Some(Listed::Allow));
+
+    std::fs::write(&path, "block 192.0.2.0/24\nblock 198.51.100.0/24\n").unwrap();
+    list.reload().await.unwrap();
+    assert_eq!(list.counts(), (0, 2));
+    assert_eq!(list.check(ip("192.0.2.1")), Some(Listed::Block));
+
+    // A broken file keeps the previous entries
+    std::fs::write(&path, "block nowhere\n").unwrap();
+    assert!(list.reload().await.is_err());
+    assert_eq!(list.check(ip("192.0.2.1")), Some(Listed::Block));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn changed_file_is_reloaded_by_the_watcher() {
+    let path = file("watch", "allow 192.0.2.0/24\n");
+    let list = Arc::new(NetworkList::load(&path).unwrap());
+    tokio::spawn(list.clone().watch(Duration::from_millis(20)));
+
+    std::fs::write(&path, "allow 192.0.2.0/24\nblock 198.51.100.0/24\n").unwrap();
+    for _ in 0..100 {
+        if list.check(ip("198.51.100.1")).is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(list.check(ip("198.51.100.1")), Some(Listed::Block));
+    std::fs::remove_file(&path).unwrap();
+}
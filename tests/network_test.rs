@@ -0,0 +1,68 @@
+use std::net::IpAddr;
+
+use burngate::network::{contains, parse_list, IpNetwork, NetworkParseError};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+// -- IpNetwork --
+
+#[test]
+fn ipv4_prefix() {
+    let net: IpNetwork = "192.0.2.0/24".parse().unwrap();
+    assert!(net.contains(ip("192.0.2.200")));
+    assert!(!net.contains(ip("192.0.3.1")));
+}
+
+#[test]
+fn ipv6_prefix() {
+    let net: IpNetwork = "2001:db8::/32".parse().unwrap();
+    assert!(net.contains(ip("2001:db8:ffff::1")));
+    assert!(!net.contains(ip("2001:db9::1")));
+    assert!(!net.contains(ip("192.0.2.1")));
+}
+
+#[test]
+fn bare_address_is_single_host() {
+    let net: IpNetwork = "10.1.2.3".parse().unwrap();
+    assert!(net.contains(ip("10.1.2.3")));
+    assert!(!net.contains(ip("10.1.2.4")));
+    assert_eq!(net.to_string(), "10.1.2.3/32");
+}
+
+#[test]
+fn zero_prefix_matches_family() {
+    let net: IpNetwork = "0.0.0.0/0".parse().unwrap();
+    assert!(net.contains(ip("203.0.113.9")));
+    assert!(!net.contains(ip("::1")));
+}
+
+#[test]
+fn ipv4_mapped_client_matches_ipv4_network() {
+    let net: IpNetwork = "192.0.2.0/24".parse().unwrap();
+    assert!(net.contains(ip("::ffff:192.0.2.7")));
+}
+
+#[test]
+fn invalid_networks_rejected() {
+    for s in ["192.0.2.0/33", "::/129", "example.com", "10.0.0.0/x"] {
+        assert_eq!(
+            s.parse::<IpNetwork>(),
+            Err(NetworkParseError(s.to_string())),
+            "{s}"
+        );
+    }
+}
+
+// -- parse_list --
+
+#[test]
+fn list_skips_blanks() {
+    let nets = parse_list(" 10.0.0.0/8, ,::1 ").unwrap();
+    assert_eq!(nets.len(), 2);
+    assert!(contains(&nets, ip("10.9.9.9")));
+    assert!(contains(&nets, ip("::1")));
+    assert!(!contains(&nets, ip("192.0.2.1")));
+    assert!(parse_list("").unwrap().is_empty());
+}
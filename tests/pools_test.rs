@@ -0,0 +1,52 @@
+use burngate::pools::ConnectionPools;
+
+// -- try_acquire --
+
+#[test]
+fn general_pool_used_first() {
+    let pools = ConnectionPools::new(3, 1);
+    let permit = pools.try_acquire(true).unwrap();
+    assert!(!permit.is_reserved());
+    assert_eq!(pools.available(), (1, 1));
+}
+
+#[test]
+fn untrusted_refused_when_general_full() {
+    let pools = ConnectionPools::new(2, 1);
+    let _held = pools.try_acquire(false).unwrap();
+    assert!(pools.try_acquire(false).is_none());
+    assert_eq!(pools.available(), (0, 1));
+}
+
+#[test]
+fn reserve_used_when_general_full() {
+    let pools = ConnectionPools::new(2, 1);
+    let _held = pools.try_acquire(false).unwrap();
+    let permit = pools.try_acquire(true).unwrap();
+    assert!(permit.is_reserved());
+    assert!(pools.try_acquire(true).is_none());
+}
+
+#[test]
+fn dropping_permit_frees_slot() {
+    let pools = ConnectionPools::new(1, 0);
+    let permit = pools.try_acquire(false).unwrap();
+    assert!(pools.try_acquire(false).is_none());
+    drop(permit);
+    assert!(pools.try_acquire(false).is_some());
+}
+
+// -- new --
+
+#[test]
+fn reserve_capped_at_max() {
+    let pools = ConnectionPools::new(2, 5);
+    assert_eq!(pools.available(), (0, 2));
+}
+
+#[test]
+fn unlimited_has_no_reserve() {
+    let pools = ConnectionPools::new(0, 10);
+    assert_eq!(pools.available().1, 0);
+    assert!(!pools.try_acquire(false).unwrap().is_reserved());
+}
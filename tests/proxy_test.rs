@@ -0,0 +1,123 @@
+use std::net::SocketAddr;
+
+use burngate::proxy::{parse_v1, parse_v2, read_v1, read_v2};
+use tokio::io::AsyncReadExt;
+
+fn v2_header(command: u8, family: u8, body: &[u8]) -> ([u8; 16], Vec<u8>) {
+    let mut header = [0u8; 16];
+    header[..12].copy_from_slice(b"\r\n\r\n\0\r\nQUIT\n");
+    header[12] = 0x20 | command;
+    header[13] = family;
+    header[14..16].copy_from_slice(&(body.len() as u16).to_be_bytes());
+    (header, body.to_vec())
+}
+
+// -- parse_v1 --
+
+#[test]
+fn v1_tcp4() {
+    let addr = parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 25\r\n").unwrap();
+    assert_eq!(
+        addr,
+        Some("203.0.113.7:51234".parse::<SocketAddr>().unwrap())
+    );
+}
+
+#[test]
+fn v1_tcp6() {
+    let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 25\r\n").unwrap();
+    assert_eq!(
+        addr,
+        Some("[2001:db8::1]:40000".parse::<SocketAddr>().unwrap())
+    );
+}
+
+#[test]
+fn v1_unknown_uses_socket_peer() {
+    assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+}
+
+#[test]
+fn v1_invalid() {
+    assert!(parse_v1(b"PROXY TCP4 not-an-ip 10.0.0.1 1 25\r\n").is_err());
+    assert!(parse_v1(b"PROXY TCP4 1.2.3.4 10.0.0.1 99999 25\r\n").is_err());
+    assert!(parse_v1(b"PROXY TCP4 1.2.3.4 10.0.0.1 1 25").is_err());
+    assert!(parse_v1(b"EHLO example.com\r\n").is_err());
+}
+
+// -- parse_v2 --
+
+#[test]
+fn v2_ipv4() {
+    let body = [203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0, 25];
+    let (header, body) = v2_header(0x1, 0x11, &body);
+    let addr = parse_v2(&header, &body).unwrap();
+    assert_eq!(
+        addr,
+        Some("203.0.113.7:51234".parse::<SocketAddr>().unwrap())
+    );
+}
+
+#[test]
+fn v2_ipv6() {
+    let mut body = vec![0u8; 36];
+    body[0] = 0x20;
+    body[1] = 0x01;
+    body[15] = 1;
+    body[32..34].copy_from_slice(&40000u16.to_be_bytes());
+    let (header, body) = v2_header(0x1, 0x21, &body);
+    let addr = parse_v2(&header, &body).unwrap();
+    assert_eq!(addr, Some("[2001::1]:40000".parse::<SocketAddr>().unwrap()));
+}
+
+#[test]
+fn v2_local_uses_socket_peer() {
+    let (header, body) = v2_header(0x0, 0x00, &[]);
+    assert_eq!(parse_v2(&header, &body).unwrap(), None);
+}
+
+#[test]
+fn v2_short_block_rejected() {
+    let (header, body) = v2_header(0x1, 0x11, &[1, 2, 3]);
+    assert!(parse_v2(&header, &body).is_err());
+}
+
+#[test]
+fn v2_bad_signature_rejected() {
+    let (mut header, body) = v2_header(0x1, 0x11, &[0; 12]);
+    header[0] = b'X';
+    assert!(parse_v2(&header, &body).is_err());
+}
+
+// -- read_v1 / read_v2 --
+
+#[tokio::test]
+async fn read_v1_stops_at_crlf() {
+    let input = b"PROXY UNKNOWN\r\nEHLO example.com\r\n";
+    let mut reader = &input[..];
+    let line = read_v1(&mut reader).await.unwrap();
+    assert_eq!(line, b"PROXY UNKNOWN\r\n");
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).await.unwrap();
+    assert_eq!(rest, "EHLO example.com\r\n");
+}
+
+#[tokio::test]
+async fn read_v1_too_long() {
+    let input = [b'A'; 200];
+    let mut reader = &input[..];
+    assert!(read_v1(&mut reader).await.is_err());
+}
+
+#[tokio::test]
+async fn read_v2_reads_exact_length() {
+    let (header, body) = v2_header(0x1, 0x11, &[203, 0, 113, 7, 10, 0, 0, 1, 0, 1, 0, 25]);
+    let mut input = header.to_vec();
+    input.extend_from_slice(&body);
+    input.extend_from_slice(b"EHLO");
+    let mut reader = &input[..];
+    let (read_header, read_body) = read_v2(&mut reader).await.unwrap();
+    assert_eq!(read_header, header);
+    assert_eq!(read_body, body);
+    assert_eq!(reader, b"EHLO");
+}
@@ -1,6 +1,15 @@
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
 
-use burngate::ratelimit::IpRateLimiter;
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use burngate::audit::unix_now;
+use burngate::config::{Config, RateLimitBackend};
+use burngate::ratelimit::{ConnectionRateLimiter, IpRateLimiter, StoreRateLimiter};
+use burngate::store::{MemoryStore, SharedStore, Store, StoreError};
 
 #[tokio::test]
 async fn allows_up_to_limit() {
@@ -53,6 +62,31 @@ async fn window_resets_after_expiry() {
     assert!(!limiter.check_and_increment(ip).await);
 }
 
+#[tokio::test(start_paused = true)]
+async fn bursts_are_allowed_then_paced() {
+    // 60 a minute is one a second, after a burst of up to 5
+    let limiter = IpRateLimiter::with_burst(60, 5);
+    let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+    for _ in 0..5 {
+        assert!(limiter.check_and_increment(ip).await);
+    }
+    assert!(!limiter.check_and_increment(ip).await);
+
+    // Sustained traffic gets the rate, not another burst
+    for _ in 0..3 {
+        tokio::time::advance(std::time::Duration::from_millis(1000)).await;
+        assert!(limiter.check_and_increment(ip).await);
+        assert!(!limiter.check_and_increment(ip).await);
+    }
+
+    // An idle client earns its burst back, but no more
+    tokio::time::advance(std::time::Duration::from_secs(600)).await;
+    for _ in 0..5 {
+        assert!(limiter.check_and_increment(ip).await);
+    }
+    assert!(!limiter.check_and_increment(ip).await);
+}
+
 #[tokio::test]
 async fn limit_of_one() {
     let limiter = IpRateLimiter::new(1);
@@ -81,3 +115,211 @@ async fn many_ips_tracked_independently() {
         );
     }
 }
+
+// -- StoreRateLimiter --
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+#[tokio::test]
+async fn store_limit_is_shared_between_instances() {
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    let one = StoreRateLimiter::new(store.clone(), "rl:", 3, HOUR);
+    let other = StoreRateLimiter::new(store.clone(), "rl:", 3, HOUR);
+
+    assert!(one.check_and_increment("a@example.org").await.unwrap());
+    assert!(other.check_and_increment("a@example.org").await.unwrap());
+    assert!(one.check_and_increment("a@example.org").await.unwrap());
+    assert!(!other.check_and_increment("a@example.org").await.unwrap());
+    // Refused events are not counted
+    let window = unix_now() / 3600;
+    let count = store.get(&format!("rl:a@example.org:{}", window)).await;
+    assert_eq!(count.unwrap().as_deref(), Some("3"));
+
+    assert!(one.check_and_increment("b@example.org").await.unwrap());
+}
+
+#[tokio::test]
+async fn store_limit_weighs_the_previous_window() {
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    let limiter = StoreRateLimiter::new(store.clone(), "rl:", 2, HOUR);
+    // However little of the previous window is left, this is over the limit
+    let previous = unix_now() / 3600 - 1;
+    store
+        .set_ex(&format!("rl:busy:{}", previous), "7200", 7200)
+        .await
+        .unwrap();
+    assert!(!limiter.check_and_increment("busy").await.unwrap());
+    assert!(limiter.check_and_increment("quiet").await.unwrap());
+}
+
+/// A `MemoryStore` that yields before every command, like a network round
+/// trip, so concurrent callers interleave.
+struct RoundTripStore(MemoryStore);
+
+#[async_trait]
+impl Store for RoundTripStore {
+    async fn exists(&self, key: &str) -> Result<bool, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.exists(key).await
+    }
+    async fn set_contains(&self, key: &str, members: &[&str]) -> Result<Vec<bool>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.set_contains(key, members).await
+    }
+    async fn exists_and_contains(
+        &self,
+        key: &str,
+        set: &str,
+        member: &str,
+    ) -> Result<(bool, bool), StoreError> {
+        tokio::task::yield_now().await;
+        self.0.exists_and_contains(key, set, member).await
+    }
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.set_members(key).await
+    }
+    async fn keys_between(&self, prefix: &str, suffix: &str) -> Result<Vec<String>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.keys_between(prefix, suffix).await
+    }
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.get(key).await
+    }
+    async fn set_ex(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        tokio::task::yield_now().await;
+        self.0.set_ex(key, value, ttl_secs).await
+    }
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        tokio::task::yield_now().await;
+        self.0.delete(key).await
+    }
+    async fn incr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.incr(key, ttl_secs).await
+    }
+    async fn decr(&self, key: &str, ttl_secs: u64) -> Result<i64, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.decr(key, ttl_secs).await
+    }
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.hash_get(key, field).await
+    }
+    async fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.hash_get_all(key).await
+    }
+    async fn list_push(&self, key: &str, value: &str, ttl_secs: u64) -> Result<(), StoreError> {
+        tokio::task::yield_now().await;
+        self.0.list_push(key, value, ttl_secs).await
+    }
+    async fn list_all(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.list_all(key).await
+    }
+    async fn list_replace(
+        &self,
+        key: &str,
+        values: &[String],
+        ttl_secs: u64,
+    ) -> Result<(), StoreError> {
+        tokio::task::yield_now().await;
+        self.0.list_replace(key, values, ttl_secs).await
+    }
+    async fn ttl(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.ttl(key).await
+    }
+    async fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.expire(key, ttl_secs).await
+    }
+    async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> Result<mpsc::UnboundedReceiver<String>, StoreError> {
+        self.0.subscribe(channel).await
+    }
+    async fn script_load(&self, script: &str) -> Result<String, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.script_load(script).await
+    }
+    async fn eval_sha(&self, sha: &str, keys: &[&str], args: &[&str]) -> Result<i64, StoreError> {
+        tokio::task::yield_now().await;
+        self.0.eval_sha(sha, keys, args).await
+    }
+}
+
+/// Fire `events` calls at once and count how many were allowed.
+async fn allowed_at_once(limiter: &StoreRateLimiter, key: &str, events: usize) -> usize {
+    let calls: Vec<_> = (0..events)
+        .map(|_| {
+            let limiter = limiter.clone();
+            let key = key.to_string();
+            tokio::spawn(async move { limiter.check_and_increment(&key).await.unwrap() })
+        })
+        .collect();
+    let mut allowed = 0;
+    for call in calls {
+        allowed += usize::from(call.await.unwrap());
+    }
+    allowed
+}
+
+#[tokio::test]
+async fn store_limit_holds_under_concurrent_events() {
+    let store: SharedStore = Arc::new(RoundTripStore(MemoryStore::new()));
+    let limiter = StoreRateLimiter::new(store.clone(), "rl:", 10, HOUR);
+    assert_eq!(allowed_at_once(&limiter, "busy", 50).await, 10);
+    let count = store.get(&format!("rl:busy:{}", unix_now() / 3600)).await;
+    assert_eq!(count.unwrap().as_deref(), Some("10"));
+}
+
+#[tokio::test]
+async fn redis_connection_limit_is_shared_between_instances() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.max_connections_per_ip = 2;
+    config.rate_limit_backend = RateLimitBackend::Redis;
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    let one = ConnectionRateLimiter::from_config(store.clone(), &config).unwrap();
+    let other = ConnectionRateLimiter::from_config(store.clone(), &config).unwrap();
+    let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    assert!(one.check_and_increment(ip).await);
+    assert!(other.check_and_increment(ip).await);
+    assert!(!one.check_and_increment(ip).await);
+    assert!(!other.check_and_increment(ip).await);
+    assert!(store
+        .exists(&format!("rl:ip:10.0.0.1:{}", unix_now() / 60))
+        .await
+        .unwrap());
+
+    config.max_connections_per_ip = 0;
+    assert!(ConnectionRateLimiter::from_config(store, &config).is_none());
+}
+
+#[tokio::test]
+async fn redis_connection_limit_holds_under_a_burst() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.max_connections_per_ip = 5;
+    config.rate_limit_backend = RateLimitBackend::Redis;
+    let store: SharedStore = Arc::new(RoundTripStore(MemoryStore::new()));
+    let limiter = Arc::new(ConnectionRateLimiter::from_config(store, &config).unwrap());
+    let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    let connections: Vec<_> = (0..30)
+        .map(|_| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.check_and_increment(ip).await })
+        })
+        .collect();
+    let mut allowed = 0;
+    for connection in connections {
+        allowed += usize::from(connection.await.unwrap());
+    }
+    assert_eq!(allowed, 5);
+}
@@ -0,0 +1,26 @@
+use burngate::rejectlog::RejectLog;
+
+#[test]
+fn lines_have_a_fixed_format() {
+    let path = std::env::temp_dir().join(format!("burngate-rejectlog-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    std::fs::write(&path, "earlier line\n").unwrap();
+
+    let log = RejectLog::open(&path).unwrap();
+    log.rejected("192.0.2.1".parse().unwrap(), "mailbox_not_found");
+    log.blocked("2001:db8::7".parse().unwrap(), "spamtrap", 3600);
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    // Appended, not truncated
+    assert_eq!(lines[0], "earlier line");
+    let (stamp, rest) = lines[1].split_once(' ').unwrap();
+    assert_eq!(stamp.len(), "2026-10-15T10:00:00Z".len());
+    assert!(stamp.ends_with('Z'));
+    assert_eq!(
+        rest,
+        "burngate rejected ip=192.0.2.1 reason=mailbox_not_found"
+    );
+    assert!(lines[2].ends_with(" burngate blocked ip=2001:db8::7 reason=spamtrap ttl=3600"));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,701 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+
+use burngate::dns::{Resolver, Srv};
+use burngate::esmtp::EsmtpParams;
+use burngate::relay::{self, Backend, BackendSession, ClientInfo, Recipient};
+use burngate::tls;
+
+/// One-connection backend. RCPTs for addresses starting with "bad" get a
+/// 550; STARTTLS is advertised but refused; `end_of_data` is written after
+/// the body. Every command line is sent
+/// down the channel.
+async fn backend(end_of_data: &'static str) -> (String, mpsc::UnboundedReceiver<String>) {
+    backend_with("", end_of_data).await
+}
+
+/// `backend` that also advertises `extensions` (`250-`-prefixed lines).
+async fn backend_with(
+    extensions: &'static str,
+    end_of_data: &'static str,
+) -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+        reader
+            .get_mut()
+            .write_all(b"220 backend\r\n")
+            .await
+            .unwrap();
+        let mut in_data = false;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let command = line.trim_end().to_string();
+            let reply = match command.as_str() {
+                "." if in_data => {
+                    in_data = false;
+                    end_of_data
+                }
+                _ if in_data => continue,
+                "DATA" => {
+                    in_data = true;
+                    "354 go\r\n"
+                }
+                c if c.starts_with("RCPT TO:<bad") => "550 5.1.1 no such user\r\n",
+                c if c.starts_with("EHLO") || c.starts_with("LHLO") => {
+                    &format!("250-backend\r\n{extensions}250-STARTTLS\r\n250 DSN\r\n")
+                }
+                c if c.starts_with("XCLIENT") => "220 backend\r\n",
+                "STARTTLS" => "454 4.7.0 TLS not available\r\n",
+                "QUIT" => "221 bye\r\n",
+                _ => "250 OK\r\n",
+            };
+            let _ = tx.send(command);
+            let _ = reader.get_mut().write_all(reply.as_bytes()).await;
+        }
+    });
+    (addr, rx)
+}
+
+/// Backend handle that closes each session after one message.
+fn unpooled(addr: &str, lmtp: bool) -> Backend {
+    Backend::new(addr, lmtp, 0, Duration::from_secs(30))
+}
+
+fn recipients<'a>(addresses: &[&'a str], params: &'a EsmtpParams) -> Vec<Recipient<'a>> {
+    addresses
+        .iter()
+        .map(|address| Recipient { address, params })
+        .collect()
+}
+
+// -- SMTP --
+
+#[tokio::test]
+async fn refused_recipient_is_reported() {
+    let (addr, _) = backend("250 queued\r\n").await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com", "bad@example.com"], &params);
+    let outcome = unpooled(&addr, false)
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(outcome.delivered, ["a@example.com"]);
+    assert_eq!(outcome.rejected.len(), 1);
+    assert_eq!(outcome.rejected[0].0, "bad@example.com");
+    assert_eq!(outcome.rejected[0].1.code, 550);
+}
+
+#[tokio::test]
+async fn all_recipients_refused_is_an_error() {
+    let (addr, _) = backend("250 queued\r\n").await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["bad@example.com"], &params);
+    let result = unpooled(&addr, false)
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await;
+    assert!(result.is_err());
+}
+
+// -- pooling --
+
+/// Drain commands up to and including `last`.
+async fn skip_to(commands: &mut mpsc::UnboundedReceiver<String>, last: &str) {
+    while commands.recv().await.unwrap() != last {}
+}
+
+#[tokio::test]
+async fn pooled_session_is_reused() {
+    // The backend accepts a single connection, so both messages must share it
+    let (addr, mut commands) = backend("250 queued\r\n").await;
+    let pool = Backend::new(&addr, false, 1, Duration::from_secs(30));
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    pool.relay(None, "s@x.y", &params, &rcpts, b"one\r\n")
+        .await
+        .unwrap();
+    assert_eq!(pool.idle_connections(), 1);
+    pool.relay(None, "s@x.y", &params, &rcpts, b"two\r\n")
+        .await
+        .unwrap();
+
+    skip_to(&mut commands, ".").await;
+    assert_eq!(commands.recv().await.unwrap(), "RSET");
+    assert_eq!(commands.recv().await.unwrap(), "MAIL FROM:<s@x.y>");
+}
+
+#[tokio::test]
+async fn unpooled_session_quits_after_message() {
+    let (addr, mut commands) = backend("250 queued\r\n").await;
+    let backend = unpooled(&addr, false);
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    backend
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(backend.idle_connections(), 0);
+
+    skip_to(&mut commands, ".").await;
+    assert_eq!(commands.recv().await.unwrap(), "QUIT");
+}
+
+#[tokio::test]
+async fn held_session_carries_a_client_session_across_messages() {
+    let (addr, mut commands) = backend("250 queued\r\n").await;
+    // No pool: only the held session can carry the second message
+    let backend = unpooled(&addr, false);
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let mut session = BackendSession::default();
+    for body in [&b"one\r\n"[..], b"two\r\n"] {
+        backend
+            .relay_in(&mut session, None, "s@x.y", &params, &rcpts, body)
+            .await
+            .unwrap();
+    }
+    skip_to(&mut commands, ".").await;
+    assert_eq!(commands.recv().await.unwrap(), "RSET");
+    assert_eq!(commands.recv().await.unwrap(), "MAIL FROM:<s@x.y>");
+    skip_to(&mut commands, ".").await;
+
+    backend.release(session).await;
+    assert_eq!(commands.recv().await.unwrap(), "QUIT");
+}
+
+// -- load balancing --
+
+/// Count the messages a backend received so far.
+fn messages(commands: &mut mpsc::UnboundedReceiver<String>) -> usize {
+    let mut count = 0;
+    while let Ok(command) = commands.try_recv() {
+        if command == "." {
+            count += 1;
+        }
+    }
+    count
+}
+
+#[tokio::test]
+async fn messages_follow_backend_weights() {
+    let (heavy, mut heavy_commands) = backend("250 queued\r\n").await;
+    let (light, mut light_commands) = backend("250 queued\r\n").await;
+    // Pooling keeps each one-connection backend usable for every message
+    let backends = Backend::weighted(&[(heavy, 2), (light, 1)], false, 1, Duration::from_secs(30));
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    for _ in 0..6 {
+        backends
+            .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+            .await
+            .unwrap();
+    }
+    assert_eq!(messages(&mut heavy_commands), 4);
+    assert_eq!(messages(&mut light_commands), 2);
+}
+
+#[test]
+fn backend_list_parses_weights() {
+    assert_eq!(
+        relay::parse_targets("a:25*3, b:25").unwrap(),
+        [("a:25".to_string(), 3), ("b:25".to_string(), 1)]
+    );
+    assert!(relay::parse_targets("a:25*0").is_err());
+    assert!(relay::parse_targets("a:25*x").is_err());
+    assert!(relay::parse_targets(" , ").is_err());
+}
+
+// -- retries --
+
+#[tokio::test]
+async fn transient_failure_is_retried_on_next_backend() {
+    let (busy, mut busy_commands) = backend("451 4.3.0 try later\r\n").await;
+    let (spare, mut spare_commands) = backend("250 queued\r\n").await;
+    let backends = Backend::weighted(&[(busy, 1), (spare, 1)], false, 0, Duration::from_secs(30))
+        .with_retries(1, Duration::from_millis(1));
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    backends
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(messages(&mut busy_commands), 1);
+    assert_eq!(messages(&mut spare_commands), 1);
+}
+
+#[tokio::test]
+async fn permanent_failure_is_not_retried() {
+    let (refusing, _) = backend("554 5.6.0 rejected\r\n").await;
+    let (spare, mut spare_commands) = backend("250 queued\r\n").await;
+    let backends = Backend::weighted(
+        &[(refusing, 1), (spare, 1)],
+        false,
+        0,
+        Duration::from_secs(30),
+    )
+    .with_retries(1, Duration::from_millis(1));
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let err = backends
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap_err();
+    assert!(err.is_permanent());
+    assert_eq!(messages(&mut spare_commands), 0);
+}
+
+#[tokio::test]
+async fn unreachable_backend_fails_once_retries_run_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let backend = unpooled(&addr, false).with_retries(2, Duration::from_millis(1));
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let err = backend
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), "connect_error");
+}
+
+// -- address resolution --
+
+/// Nameserver answering every A query with `addrs` (TTL 60) and every
+/// other query with NXDOMAIN. Returns its address and a count of the A
+/// queries seen.
+async fn nameserver(addrs: Vec<[u8; 4]>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let seen = queries.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((_, peer)) = socket.recv_from(&mut buf).await {
+            let mut end = 12;
+            while buf[end] != 0 {
+                end += 1 + buf[end] as usize;
+            }
+            let a = buf[end + 2] == 1;
+            let answers = if a { addrs.len() as u8 } else { 0 };
+            if a {
+                seen.fetch_add(1, Ordering::SeqCst);
+            }
+            let mut reply = buf[..2].to_vec();
+            let rcode = if a { 0 } else { 3 };
+            reply.extend_from_slice(&[0x81, 0x80 | rcode, 0, 1, 0, answers, 0, 0, 0, 0]);
+            reply.extend_from_slice(&buf[12..end + 5]);
+            for addr in addrs.iter().filter(|_| a) {
+                reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                reply.extend_from_slice(addr);
+            }
+            let _ = socket.send_to(&reply, peer).await;
+        }
+    });
+    (server, queries)
+}
+
+#[tokio::test]
+async fn unreachable_address_fails_over_to_the_next() {
+    let (addr, mut commands) = backend("250 queued\r\n").await;
+    let port = addr.rsplit_once(':').unwrap().1;
+    // Nothing listens on 127.0.0.2
+    let (dns, _) = nameserver(vec![[127, 0, 0, 2], [127, 0, 0, 1]]).await;
+    let resolver = Resolver::new(vec![dns], Duration::from_secs(2));
+    let backend = unpooled(&format!("backend.internal:{port}"), false)
+        .with_resolver(Some(resolver), Duration::from_secs(300));
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    backend
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(commands.recv().await.unwrap(), "EHLO burngate");
+}
+
+#[tokio::test]
+async fn resolved_addresses_are_cached() {
+    let (addr, _) = backend("250 queued\r\n").await;
+    let port = addr.rsplit_once(':').unwrap().1;
+    let (dns, queries) = nameserver(vec![[127, 0, 0, 1]]).await;
+    let resolver = Resolver::new(vec![dns], Duration::from_secs(2));
+    let backend = unpooled(&format!("backend.internal:{port}"), false)
+        .with_resolver(Some(resolver), Duration::from_secs(300));
+    backend.probe().await.unwrap();
+    // The one-connection stub is gone, but the name is not looked up again
+    let _ = backend.probe().await;
+    assert_eq!(queries.load(Ordering::SeqCst), 1);
+}
+
+// -- SRV discovery --
+
+fn srv(addr: &str, priority: u16, weight: u16) -> Srv {
+    let (target, port) = addr.rsplit_once(':').unwrap();
+    Srv {
+        priority,
+        weight,
+        port: port.parse().unwrap(),
+        target: target.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn lower_priority_servers_are_all_tried_before_backups() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let (preferred, mut preferred_commands) = backend("250 queued\r\n").await;
+    let (backup, mut backup_commands) = backend("250 queued\r\n").await;
+    let backends = Backend::discovered(false, 0, Duration::from_secs(30))
+        .with_retries(2, Duration::from_millis(1));
+    backends
+        .set_servers(&[
+            srv(&backup, 20, 1),
+            srv(&dead, 10, 1),
+            srv(&preferred, 10, 1),
+        ])
+        .await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    backends
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(messages(&mut preferred_commands), 1);
+    assert_eq!(messages(&mut backup_commands), 0);
+}
+
+#[tokio::test]
+async fn backup_server_takes_over_when_preferred_ones_fail() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let (backup, mut backup_commands) = backend("250 queued\r\n").await;
+    let backends = Backend::discovered(false, 0, Duration::from_secs(30))
+        .with_retries(1, Duration::from_millis(1));
+    backends
+        .set_servers(&[srv(&dead, 0, 5), srv(&backup, 1, 0)])
+        .await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    backends
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(messages(&mut backup_commands), 1);
+}
+
+#[tokio::test]
+async fn relays_follow_the_current_server_list() {
+    let backends = Backend::discovered(false, 0, Duration::from_secs(30));
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let err = backends
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), "connect_error");
+
+    let (first, mut first_commands) = backend("250 queued\r\n").await;
+    let (second, mut second_commands) = backend("250 queued\r\n").await;
+    backends.set_servers(&[srv(&first, 0, 1)]).await;
+    backends
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    backends.set_servers(&[srv(&second, 0, 1)]).await;
+    backends
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(messages(&mut first_commands), 1);
+    assert_eq!(messages(&mut second_commands), 1);
+}
+
+// -- concurrency limit --
+
+#[tokio::test]
+async fn relays_beyond_the_limit_wait_for_a_slot() {
+    // The first backend never answers the message, holding its slot
+    let (stuck, mut stuck_commands) = backend("").await;
+    let (spare, mut spare_commands) = backend("250 queued\r\n").await;
+    let backends = Backend::weighted(&[(stuck, 1), (spare, 1)], false, 0, Duration::from_secs(30))
+        .with_concurrency_limit(1);
+    let first = backends.clone();
+    tokio::spawn(async move {
+        let params = EsmtpParams::default();
+        let rcpts = recipients(&["a@example.com"], &params);
+        let _ = first.relay(None, "s@x.y", &params, &rcpts, b"hi\r\n").await;
+    });
+    skip_to(&mut stuck_commands, ".").await;
+
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let second = backends.relay(None, "s@x.y", &params, &rcpts, b"hi\r\n");
+    assert!(tokio::time::timeout(Duration::from_millis(200), second)
+        .await
+        .is_err());
+    assert!(spare_commands.try_recv().is_err());
+}
+
+// -- PIPELINING --
+
+/// One-connection backend advertising PIPELINING that holds back its
+/// envelope replies until DATA arrives, so a client waiting for each reply
+/// stalls. DATA always gets a 354. Every line, body included, is sent down
+/// the channel.
+async fn pipelining_backend() -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+        reader
+            .get_mut()
+            .write_all(b"220 backend\r\n")
+            .await
+            .unwrap();
+        let mut held = String::new();
+        let mut in_data = false;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let command = line.trim_end().to_string();
+            let _ = tx.send(command.clone());
+            let reply = match command.as_str() {
+                "." if in_data => {
+                    in_data = false;
+                    "250 queued\r\n"
+                }
+                _ if in_data => continue,
+                c if c.starts_with("EHLO") => "250-backend\r\n250 PIPELINING\r\n",
+                c if c.starts_with("MAIL") => {
+                    held.push_str("250 OK\r\n");
+                    continue;
+                }
+                c if c.starts_with("RCPT TO:<bad") => {
+                    held.push_str("550 5.1.1 no such user\r\n");
+                    continue;
+                }
+                c if c.starts_with("RCPT") => {
+                    held.push_str("250 OK\r\n");
+                    continue;
+                }
+                "DATA" => {
+                    in_data = true;
+                    held.push_str("354 go\r\n");
+                    &std::mem::take(&mut held)
+                }
+                "QUIT" => "221 bye\r\n",
+                _ => "250 OK\r\n",
+            };
+            let _ = reader.get_mut().write_all(reply.as_bytes()).await;
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn envelope_is_pipelined_when_advertised() {
+    let (addr, mut commands) = pipelining_backend().await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com", "bad@example.com"], &params);
+    let backend = unpooled(&addr, false);
+    let relay = backend.relay(None, "s@x.y", &params, &rcpts, b"hi\r\n");
+    let outcome = tokio::time::timeout(Duration::from_secs(5), relay)
+        .await
+        .expect("envelope was not pipelined")
+        .unwrap();
+    assert_eq!(outcome.delivered, ["a@example.com"]);
+    assert_eq!(outcome.rejected[0].0, "bad@example.com");
+    skip_to(&mut commands, "DATA").await;
+    assert_eq!(commands.recv().await.unwrap(), "hi");
+}
+
+#[tokio::test]
+async fn pipelined_body_is_withheld_when_no_recipient_is_accepted() {
+    let (addr, mut commands) = pipelining_backend().await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["bad@example.com"], &params);
+    let result = unpooled(&addr, false)
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await;
+    assert!(result.is_err());
+    skip_to(&mut commands, "DATA").await;
+    while let Some(line) = commands.recv().await {
+        assert!(line != "hi" && line != ".", "body sent: {line}");
+    }
+}
+
+// -- STARTTLS --
+
+#[tokio::test]
+async fn opportunistic_tls_falls_back_to_plaintext() {
+    let (addr, mut commands) = backend("250 queued\r\n").await;
+    let backend = unpooled(&addr, false).with_starttls(false, tls::loopback_connector(), None);
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    backend
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(commands.recv().await.unwrap(), "EHLO burngate");
+    assert_eq!(commands.recv().await.unwrap(), "STARTTLS");
+    assert_eq!(commands.recv().await.unwrap(), "MAIL FROM:<s@x.y>");
+}
+
+#[tokio::test]
+async fn required_tls_refuses_plaintext() {
+    let (addr, mut commands) = backend("250 queued\r\n").await;
+    let backend = unpooled(&addr, false).with_starttls(true, tls::loopback_connector(), None);
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let err = backend
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), "tls_error");
+    skip_to(&mut commands, "STARTTLS").await;
+    assert_eq!(commands.recv().await.unwrap(), "QUIT");
+}
+
+// -- client forwarding --
+
+fn client() -> ClientInfo {
+    ClientInfo {
+        addr: "192.0.2.7:40000".parse().unwrap(),
+        helo: Some("mx.sender.example".to_string()),
+        proto: "ESMTPS".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn xclient_is_sent_before_mail_and_session_regreets() {
+    let (addr, mut commands) =
+        backend_with("250-XCLIENT ADDR HELO PROTO\r\n", "250 queued\r\n").await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let pool = Backend::new(&addr, false, 1, Duration::from_secs(30));
+    pool.relay(Some(&client()), "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    // The session now speaks for the client, so it is not pooled
+    assert_eq!(pool.idle_connections(), 0);
+
+    skip_to(&mut commands, "EHLO burngate").await;
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        "XCLIENT ADDR=192.0.2.7 PROTO=ESMTPS HELO=mx.sender.example"
+    );
+    assert_eq!(commands.recv().await.unwrap(), "EHLO burngate");
+    assert_eq!(commands.recv().await.unwrap(), "MAIL FROM:<s@x.y>");
+}
+
+#[tokio::test]
+async fn held_session_skips_repeated_xclient() {
+    let (addr, mut commands) =
+        backend_with("250-XCLIENT ADDR HELO PROTO\r\n", "250 queued\r\n").await;
+    let backend = unpooled(&addr, false);
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    let mut session = BackendSession::default();
+    for _ in 0..2 {
+        backend
+            .relay_in(
+                &mut session,
+                Some(&client()),
+                "s@x.y",
+                &params,
+                &rcpts,
+                b"hi\r\n",
+            )
+            .await
+            .unwrap();
+    }
+    skip_to(&mut commands, ".").await;
+    assert_eq!(commands.recv().await.unwrap(), "RSET");
+    assert_eq!(commands.recv().await.unwrap(), "MAIL FROM:<s@x.y>");
+}
+
+#[tokio::test]
+async fn xforward_sends_only_advertised_attributes() {
+    let (addr, mut commands) = backend_with("250-XFORWARD ADDR PORT\r\n", "250 queued\r\n").await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    unpooled(&addr, false)
+        .relay(Some(&client()), "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+
+    skip_to(&mut commands, "EHLO burngate").await;
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        "XFORWARD ADDR=192.0.2.7 PORT=40000"
+    );
+    assert_eq!(commands.recv().await.unwrap(), "MAIL FROM:<s@x.y>");
+}
+
+#[tokio::test]
+async fn no_client_metadata_without_extension() {
+    let (addr, mut commands) = backend("250 queued\r\n").await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(&["a@example.com"], &params);
+    unpooled(&addr, false)
+        .relay(Some(&client()), "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+
+    skip_to(&mut commands, "EHLO burngate").await;
+    assert_eq!(commands.recv().await.unwrap(), "MAIL FROM:<s@x.y>");
+}
+
+// -- LMTP --
+
+#[tokio::test]
+async fn lmtp_reads_one_reply_per_recipient() {
+    let (addr, mut commands) = backend("250 2.0.0 ok\r\n452 4.2.2 over quota\r\n").await;
+    let params = EsmtpParams::default();
+    let rcpts = recipients(
+        &["a@example.com", "bad@example.com", "full@example.com"],
+        &params,
+    );
+    let outcome = unpooled(&addr, true)
+        .relay(None, "s@x.y", &params, &rcpts, b"hi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(outcome.delivered, ["a@example.com"]);
+    let rejected: Vec<_> = outcome
+        .rejected
+        .iter()
+        .map(|(address, reply)| (*address, reply.code))
+        .collect();
+    assert_eq!(
+        rejected,
+        [("bad@example.com", 550), ("full@example.com", 452)]
+    );
+    assert_eq!(commands.recv().await.unwrap(), "LHLO burngate");
+}
+
+#[tokio::test]
+async fn lmtp_probe_sends_lhlo() {
+    let (addr, mut commands) = backend("").await;
+    unpooled(&addr, true).probe().await.unwrap();
+    assert_eq!(commands.recv().await.unwrap(), "LHLO burngate");
+}
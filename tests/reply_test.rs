@@ -0,0 +1,131 @@
+use burngate::reply::{self, EnhancedStatus, ReplyTemplates, SmtpReply};
+
+// -- Display --
+
+#[test]
+fn single_line_with_status() {
+    assert_eq!(reply::USER_UNKNOWN.to_string(), "550 5.1.1 User unknown");
+}
+
+#[test]
+fn single_line_without_status() {
+    assert_eq!(
+        reply::START_MAIL_INPUT.to_string(),
+        "354 Start mail input; end with <CRLF>.<CRLF>"
+    );
+}
+
+#[test]
+fn multiline_repeats_status() {
+    let help = SmtpReply::formatted(
+        214,
+        Some(EnhancedStatus::new(2, 0, 0)),
+        "Commands:\n  HELO".to_string(),
+    );
+    assert_eq!(help.to_string(), "214-2.0.0 Commands:\r\n214 2.0.0   HELO");
+}
+
+// -- write_to --
+
+#[tokio::test]
+async fn write_to_ends_every_line_with_crlf() {
+    let ehlo = SmtpReply::formatted(250, None, "mx Hello x\nPIPELINING".to_string());
+    let mut out = Vec::new();
+    ehlo.write_to(&mut out).await.unwrap();
+    assert_eq!(out, b"250-mx Hello x\r\n250 PIPELINING\r\n");
+}
+
+// -- parse --
+
+#[test]
+fn parse_single_line() {
+    let reply = SmtpReply::parse(&["550 5.1.1 User unknown"]).unwrap();
+    assert_eq!(reply, reply::USER_UNKNOWN);
+    assert!(!reply.is_positive());
+}
+
+#[test]
+fn parse_multiline() {
+    let reply = SmtpReply::parse(&["250-mx.example Hello", "250-DSN", "250 SIZE 1000"]).unwrap();
+    assert_eq!(reply.code, 250);
+    assert_eq!(reply.status, None);
+    assert_eq!(
+        reply.lines().collect::<Vec<_>>(),
+        ["mx.example Hello", "DSN", "SIZE 1000"]
+    );
+}
+
+#[test]
+fn parse_ignores_status_of_other_class() {
+    let reply = SmtpReply::parse(&["451 5.1.1 odd"]).unwrap();
+    assert_eq!(reply.status, None);
+    assert_eq!(reply.lines().next(), Some("5.1.1 odd"));
+    assert!(reply.is_transient());
+}
+
+#[test]
+fn parse_rejects_missing_code() {
+    assert_eq!(SmtpReply::parse(&["hello"]), None);
+    assert_eq!(SmtpReply::parse::<&str>(&[]), None);
+}
+
+#[test]
+fn enhanced_status_parse() {
+    assert_eq!(
+        EnhancedStatus::parse("4.7.0"),
+        Some(EnhancedStatus::new(4, 7, 0))
+    );
+    assert_eq!(EnhancedStatus::parse("3.0.0"), None);
+    assert_eq!(EnhancedStatus::parse("5.1"), None);
+}
+
+// -- downstream --
+
+#[test]
+fn downstream_keeps_code_and_status_but_not_text() {
+    let backend = SmtpReply::parse(&["550 5.1.1 <x@internal> unknown in table users"]).unwrap();
+    assert_eq!(
+        reply::downstream(&backend).to_string(),
+        "550 5.1.1 Refused by backend"
+    );
+    let backend = SmtpReply::parse(&["451 try later"]).unwrap();
+    assert_eq!(
+        reply::downstream(&backend).to_string(),
+        "451 4.0.0 Temporarily refused by backend, try again later"
+    );
+}
+
+// -- ReplyTemplates --
+
+#[test]
+fn templates_default_to_fixed_text() {
+    let templates = ReplyTemplates::default();
+    assert_eq!(templates.user_unknown("a@example.com"), reply::USER_UNKNOWN);
+    assert_eq!(
+        templates.sender_blocked("a@example.com"),
+        reply::SENDER_BLOCKED
+    );
+}
+
+#[test]
+fn templates_fill_placeholders_and_keep_status() {
+    let templates = ReplyTemplates {
+        unknown_domain: Some("We do not host {domain} ({address})".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        templates.unknown_domain("a@other.test").to_string(),
+        "550 5.1.2 We do not host other.test (a@other.test)"
+    );
+}
+
+#[test]
+fn soft_fail_template_keeps_transient_code() {
+    let templates = ReplyTemplates {
+        mailbox_unavailable: Some("{address} may come back".to_string()),
+        ..Default::default()
+    };
+    let reply = templates.mailbox_unavailable("a@example.com");
+    assert!(reply.is_transient());
+    assert_eq!(reply.to_string(), "450 4.2.1 a@example.com may come back");
+}
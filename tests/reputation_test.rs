@@ -0,0 +1,124 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use burngate::config::Config;
+use burngate::reputation::{decayed, Event, Reputation};
+use burngate::store::{MemoryStore, SharedStore, Store};
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.reputation_enabled = true;
+    config
+}
+
+fn ip() -> IpAddr {
+    "192.0.2.1".parse().unwrap()
+}
+
+#[test]
+fn disabled_by_default() {
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    let mut config = config();
+    config.reputation_enabled = false;
+    assert!(Reputation::from_config(store, &config).is_none());
+}
+
+#[test]
+fn scores_halve_every_half_life() {
+    assert_eq!(decayed(8.0, 0, 3600), 8.0);
+    assert_eq!(decayed(8.0, 3600, 3600), 4.0);
+    assert_eq!(decayed(8.0, 3 * 3600, 3600), 1.0);
+    assert_eq!(decayed(-4.0, 3600, 3600), -2.0);
+    assert_eq!(decayed(8.0, 3600, 0), 8.0);
+}
+
+#[tokio::test]
+async fn events_add_up_and_clean_deliveries_earn_back() {
+    let store = Arc::new(MemoryStore::new());
+    let reputation = Reputation::from_config(store.clone(), &config()).unwrap();
+
+    assert_eq!(reputation.score(ip()).await, 0.0);
+    reputation.record(ip(), Event::UnknownRecipient).await;
+    reputation.record(ip(), Event::EarlyTalker).await;
+    assert_eq!(reputation.score(ip()).await, 6.0);
+    assert_eq!(reputation.record(ip(), Event::Delivered).await, 5.0);
+    // Other IPs are untouched
+    assert_eq!(reputation.score("192.0.2.2".parse().unwrap()).await, 0.0);
+
+    // Good behaviour only earns so much credit
+    for _ in 0..30 {
+        reputation.record(ip(), Event::Delivered).await;
+    }
+    assert_eq!(reputation.score(ip()).await, -10.0);
+    assert!(store.ttl("reputation:192.0.2.1").await.unwrap().unwrap() > 86_400);
+}
+
+#[tokio::test]
+async fn stored_scores_decay_from_their_last_update() {
+    let store = Arc::new(MemoryStore::new());
+    let reputation = Reputation::from_config(store.clone(), &config()).unwrap();
+    let day_ago =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap() - Duration::from_secs(86_400);
+    store
+        .set_ex(
+            "reputation:192.0.2.1",
+            &format!("20.000:{}", day_ago.as_secs()),
+            3600,
+        )
+        .await
+        .unwrap();
+    let score = reputation.score(ip()).await;
+    assert!((9.9..=10.0).contains(&score), "{score}");
+}
+
+#[test]
+fn thresholds_apply_from_their_score_up() {
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    let mut config = config();
+    config.reputation_tarpit_score = 5;
+    config.reputation_tarpit_ms = 1500;
+    config.reputation_greylist_score = 10;
+    config.reputation_reject_score = 0;
+    let reputation = Reputation::from_config(store, &config).unwrap();
+
+    assert_eq!(reputation.tarpit(4.9), None);
+    assert_eq!(reputation.tarpit(5.0), Some(Duration::from_millis(1500)));
+    assert!(!reputation.greylists(9.0));
+    assert!(reputation.greylists(12.5));
+    // 0 turns a threshold off
+    assert!(!reputation.refuses(1000.0));
+}
+
+#[tokio::test]
+async fn greylisting_defers_new_triplets_until_the_delay_passed() {
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    let mut config = config();
+    config.reputation_greylist_delay_secs = 300;
+    let reputation = Reputation::from_config(store.clone(), &config).unwrap();
+    assert!(
+        reputation
+            .greylisted(ip(), "a@b.example", "alice@example.com")
+            .await
+    );
+    assert!(
+        reputation
+            .greylisted(ip(), "a@b.example", "alice@example.com")
+            .await
+    );
+
+    config.reputation_greylist_delay_secs = 0;
+    let reputation = Reputation::from_config(store, &config).unwrap();
+    assert!(
+        !reputation
+            .greylisted(ip(), "a@b.example", "alice@example.com")
+            .await
+    );
+    // A new recipient is a new triplet
+    assert!(
+        reputation
+            .greylisted(ip(), "a@b.example", "bob@example.com")
+            .await
+    );
+}
@@ -0,0 +1,168 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use burngate::config::Config;
+use burngate::rspamd::{Action, Envelope, Rspamd, RspamdError, Verdict};
+
+#[test]
+fn actions_and_headers_are_parsed() {
+    let verdict = Verdict::parse(
+        br#"{"action": "no action", "score": 1.5, "required_score": 15.0, "symbols": {}}"#,
+    )
+    .unwrap();
+    assert_eq!(verdict.action, Action::Accept);
+    assert_eq!(verdict.score, 1.5);
+    assert_eq!(verdict.required_score, 15.0);
+    assert!(verdict.headers.is_empty());
+
+    let verdict = Verdict::parse(br#"{"action": "add header", "score": 7.0}"#).unwrap();
+    assert_eq!(verdict.action, Action::AddHeaders);
+    assert_eq!(verdict.headers, [("X-Spam".to_string(), "Yes".to_string())]);
+
+    let verdict = Verdict::parse(
+        br#"{"action": "rewrite subject", "milter": {"add_headers": {
+            "X-Spamd-Bar": "+++", "X-Spam": {"value": "Yes", "order": 0}}}}"#,
+    )
+    .unwrap();
+    assert_eq!(verdict.action, Action::AddHeaders);
+    assert_eq!(verdict.headers.len(), 2);
+    assert!(verdict
+        .headers
+        .contains(&("X-Spamd-Bar".to_string(), "+++".to_string())));
+
+    for (action, expected) in [
+        ("greylist", Action::Greylist),
+        ("soft reject", Action::SoftReject),
+        ("reject", Action::Reject),
+    ] {
+        let body = format!(r#"{{"action": "{action}"}}"#);
+        assert_eq!(Verdict::parse(body.as_bytes()).unwrap().action, expected);
+    }
+}
+
+#[test]
+fn unusable_replies_are_errors() {
+    assert!(matches!(
+        Verdict::parse(br#"{"action": "discard"}"#),
+        Err(RspamdError::Invalid(_))
+    ));
+    assert!(matches!(
+        Verdict::parse(br#"{"score": 3}"#),
+        Err(RspamdError::Invalid(_))
+    ));
+    assert!(matches!(
+        Verdict::parse(b"<html>"),
+        Err(RspamdError::Invalid(_))
+    ));
+}
+
+/// Rspamd that answers `status` with a chunked `verdict` after `delay`,
+/// keeping the last request head and body.
+async fn rspamd(
+    status: u16,
+    verdict: &'static str,
+    delay: Duration,
+) -> (String, Arc<Mutex<(String, Vec<u8>)>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let last = Arc::new(Mutex::new((String::new(), Vec::new())));
+    let seen = last.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+            *seen.lock().unwrap() = (head, body);
+            tokio::time::sleep(delay).await;
+            let (first, rest) = verdict.split_at(verdict.len() / 2);
+            let response = format!(
+                "HTTP/1.1 {status} OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 {:x}\r\n{first}\r\n{:x}\r\n{rest}\r\n0\r\n\r\n",
+                first.len(),
+                rest.len()
+            );
+            let _ = reader.get_mut().write_all(response.as_bytes()).await;
+        }
+    });
+    (url, last)
+}
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.rspamd_password = Some("q1".to_string());
+    config.rspamd_timeout_ms = 200;
+    config
+}
+
+fn envelope<'a>(recipients: &'a [&'a str]) -> Envelope<'a> {
+    Envelope {
+        ip: "192.0.2.1".parse().unwrap(),
+        helo: Some("client.example.org"),
+        hostname: Some("client.example.org"),
+        sender: "a@b.c",
+        recipients,
+        queue_id: "abc123",
+    }
+}
+
+#[tokio::test]
+async fn message_is_posted_with_its_envelope() {
+    let (url, last) = rspamd(200, r#"{"action": "reject", "score": 20}"#, Duration::ZERO).await;
+    let rspamd = Rspamd::new(&url, &config());
+    let recipients = ["alice@example.com", "bob@example.com"];
+    let verdict = rspamd
+        .check(&envelope(&recipients), b"Subject: hi\r\n\r\nhi\r\n")
+        .await
+        .unwrap();
+    assert_eq!(verdict.action, Action::Reject);
+    assert_eq!(verdict.score, 20.0);
+
+    let (head, body) = last.lock().unwrap().clone();
+    assert!(head.starts_with("POST /checkv2 HTTP/1.1\r\n"), "{head}");
+    for field in [
+        "IP: 192.0.2.1",
+        "Helo: client.example.org",
+        "Hostname: client.example.org",
+        "From: a@b.c",
+        "Rcpt: alice@example.com",
+        "Rcpt: bob@example.com",
+        "Queue-Id: abc123",
+        "Password: q1",
+    ] {
+        assert!(head.contains(&format!("{field}\r\n")), "{field} missing");
+    }
+    assert_eq!(body, b"Subject: hi\r\n\r\nhi\r\n");
+}
+
+#[tokio::test]
+async fn failed_and_slow_scans_are_errors() {
+    let recipients = ["alice@example.com"];
+    let (url, _) = rspamd(500, "{}", Duration::ZERO).await;
+    let result = Rspamd::new(&url, &config())
+        .check(&envelope(&recipients), b"hi\r\n")
+        .await;
+    assert!(matches!(result, Err(RspamdError::Status(500))));
+
+    let (url, _) = rspamd(200, r#"{"action": "no action"}"#, Duration::from_secs(5)).await;
+    let result = Rspamd::new(&url, &config())
+        .check(&envelope(&recipients), b"hi\r\n")
+        .await;
+    assert!(matches!(result, Err(RspamdError::Timeout)));
+}
@@ -0,0 +1,116 @@
+use burngate::rules::{Action, RuleSet, Rules, RulesError, Target};
+
+const MESSAGE: &[u8] = b"From: Bob <bob@example.net>\r\n\
+Subject: Cheap\r\n  watches\r\n\
+X-Mailer: BulkMail 3.1\r\n\
+\r\n\
+Visit http://spam.example/ now\r\n";
+
+fn file(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("burngate-rules-{}-{}", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn rules_are_parsed_with_quotes_and_comments() {
+    let rules = Rules::parse(
+        "# spam\n\
+         subject contains \"cheap watches\" reject 554 \"No \\\"watches\\\" here\"\n\
+         \n\
+         header:X-Mailer regex ^BulkMail score 3  # bulk senders\n\
+         header-name contains x-campaign add-header X-Bulk yes\n\
+         body regex \"https?://spam\\.example/\" discard\n",
+    )
+    .unwrap();
+    assert_eq!(rules.rules.len(), 4);
+    assert_eq!(rules.rules[0].line, 2);
+    assert_eq!(rules.rules[0].target, Target::Header("Subject".to_string()));
+    assert_eq!(
+        rules.rules[0].action,
+        Action::Reject {
+            code: 554,
+            text: "No \"watches\" here".to_string()
+        }
+    );
+    assert_eq!(rules.rules[1].action, Action::Score(3));
+    assert_eq!(rules.rules[2].target, Target::HeaderName);
+    assert_eq!(
+        rules.rules[2].action,
+        Action::AddHeader {
+            name: "X-Bulk".to_string(),
+            value: "yes".to_string()
+        }
+    );
+    assert_eq!(rules.rules[3].action, Action::Discard);
+
+    let rules = Rules::parse("body contains casino reject\n").unwrap();
+    assert_eq!(
+        rules.rules[0].action,
+        Action::Reject {
+            code: 550,
+            text: "Message rejected by content rule".to_string()
+        }
+    );
+}
+
+#[test]
+fn invalid_rules_are_reported_by_line() {
+    for (text, line) in [
+        ("subject contains x discard\nsubject contains x\n", 2),
+        ("envelope contains x discard\n", 1),
+        ("subject like x discard\n", 1),
+        ("subject regex \"(\" discard\n", 1),
+        ("subject contains x reject 250\n", 1),
+        ("subject contains x score lots\n", 1),
+        ("subject contains x add-header \"Bad Name\" y\n", 1),
+        ("subject contains x discard now\n", 1),
+        ("subject contains \"x discard\n", 1),
+    ] {
+        assert!(
+            matches!(Rules::parse(text), Err(RulesError::Invalid { line: l, .. }) if l == line),
+            "{text:?}"
+        );
+    }
+}
+
+#[test]
+fn rules_match_headers_subject_and_body() {
+    let lines = |text: &str| -> Vec<usize> {
+        let rules = Rules::parse(text).unwrap();
+        rules.matching(MESSAGE).map(|rule| rule.line).collect()
+    };
+    // Folded subject is unfolded, substrings ignore case
+    assert_eq!(lines("subject contains \"CHEAP WATCHES\" discard\n"), [1]);
+    assert_eq!(
+        lines("header:x-mailer regex \"^BulkMail [0-9]\" discard\n"),
+        [1]
+    );
+    assert_eq!(
+        lines("header-name regex ^X- discard\nheader-name contains date discard\n"),
+        [1]
+    );
+    // Body rules see only the body
+    assert_eq!(
+        lines("body contains spam.example discard\nbody contains bob@ discard\n"),
+        [1]
+    );
+    assert!(lines("header:Reply-To contains bob discard\n").is_empty());
+}
+
+#[tokio::test]
+async fn reload_swaps_rules_and_keeps_them_on_error() {
+    let path = file("reload", "subject contains cheap discard\n");
+    let rule_set = RuleSet::load(&path).unwrap();
+    assert_eq!(rule_set.rules().matching(MESSAGE).count(), 1);
+
+    std::fs::write(&path, "subject contains dear discard\n").unwrap();
+    rule_set.reload().await.unwrap();
+    assert_eq!(rule_set.rules().matching(MESSAGE).count(), 0);
+
+    // A broken file keeps the previous rules
+    std::fs::write(&path, "subject contains\n").unwrap();
+    assert!(rule_set.reload().await.is_err());
+    assert_eq!(rule_set.rules().rules.len(), 1);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+
+use burngate::sampling::{LogSampler, Suppressed};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+// -- allow --
+
+#[test]
+fn allows_burst_then_suppresses() {
+    let sampler = LogSampler::new(3);
+    let peer = ip("192.0.2.1");
+    let allowed: Vec<bool> = (0..5).map(|_| sampler.allow("rcpt", peer)).collect();
+    assert_eq!(allowed, vec![true, true, true, false, false]);
+}
+
+#[test]
+fn counts_events_and_ips_separately() {
+    let sampler = LogSampler::new(1);
+    assert!(sampler.allow("rcpt", ip("192.0.2.1")));
+    assert!(sampler.allow("rcpt", ip("192.0.2.2")));
+    assert!(sampler.allow("rate_limited", ip("192.0.2.1")));
+    assert!(!sampler.allow("rcpt", ip("192.0.2.1")));
+}
+
+#[test]
+fn zero_burst_suppresses_everything() {
+    let sampler = LogSampler::new(0);
+    assert!(!sampler.allow("rcpt", ip("192.0.2.1")));
+}
+
+// -- drain --
+
+#[test]
+fn drain_reports_only_suppressed() {
+    let sampler = LogSampler::new(2);
+    for _ in 0..5 {
+        sampler.allow("rcpt", ip("192.0.2.1"));
+    }
+    sampler.allow("rcpt", ip("192.0.2.2"));
+
+    let (suppressed, overflow) = sampler.drain();
+    assert_eq!(
+        suppressed,
+        vec![Suppressed {
+            event: "rcpt",
+            ip: ip("192.0.2.1"),
+            count: 3,
+        }]
+    );
+    assert_eq!(overflow, 0);
+}
+
+#[test]
+fn drain_starts_new_window() {
+    let sampler = LogSampler::new(1);
+    let peer = ip("192.0.2.1");
+    assert!(sampler.allow("rcpt", peer));
+    assert!(!sampler.allow("rcpt", peer));
+    sampler.drain();
+    assert!(sampler.allow("rcpt", peer));
+    assert_eq!(sampler.drain(), (vec![], 0));
+}
@@ -0,0 +1,118 @@
+use std::net::SocketAddr;
+
+use burngate::proxy::parse_v1;
+use burngate::selftest::{loopback_target, proxy_v1_header, run, SelfTestError};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// Serve one connection, answering each command with the next scripted reply.
+async fn scripted_server(replies: &'static [&'static str]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+        reader.get_mut().write_all(b"220 test\r\n").await.unwrap();
+        let mut in_data = false;
+        let mut replies = replies.iter();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                return;
+            }
+            if in_data && line != ".\r\n" {
+                continue;
+            }
+            let Some(reply) = replies.next() else { return };
+            in_data = reply.starts_with("354");
+            reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        }
+    });
+    addr
+}
+
+// -- loopback_target --
+
+#[test]
+fn wildcard_v4_uses_localhost() {
+    let target = loopback_target("0.0.0.0:25".parse().unwrap());
+    assert_eq!(target, "127.0.0.1:25".parse::<SocketAddr>().unwrap());
+}
+
+#[test]
+fn wildcard_v6_uses_localhost() {
+    let target = loopback_target("[::]:2525".parse().unwrap());
+    assert_eq!(target, "[::1]:2525".parse::<SocketAddr>().unwrap());
+}
+
+#[test]
+fn specific_address_unchanged() {
+    let target = loopback_target("10.0.0.5:25".parse().unwrap());
+    assert_eq!(target, "10.0.0.5:25".parse::<SocketAddr>().unwrap());
+}
+
+// -- proxy_v1_header --
+
+#[test]
+fn proxy_header_round_trips() {
+    let local: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let peer: SocketAddr = "127.0.0.1:25".parse().unwrap();
+    let header = proxy_v1_header(local, peer);
+    assert_eq!(header, "PROXY TCP4 127.0.0.1 127.0.0.1 40000 25\r\n");
+    assert_eq!(parse_v1(header.as_bytes()).unwrap(), Some(local));
+}
+
+#[test]
+fn proxy_header_v6() {
+    let local: SocketAddr = "[::1]:40000".parse().unwrap();
+    let peer: SocketAddr = "[::1]:25".parse().unwrap();
+    let header = proxy_v1_header(local, peer);
+    assert!(header.starts_with("PROXY TCP6 ::1 ::1 "));
+    assert_eq!(parse_v1(header.as_bytes()).unwrap(), Some(local));
+}
+
+// -- run --
+
+#[tokio::test]
+async fn run_completes_transaction() {
+    let addr = scripted_server(&[
+        "250-test\r\n250 PIPELINING\r\n",
+        "250 OK\r\n",
+        "250 OK\r\n",
+        "354 go\r\n",
+        "250 OK\r\n",
+        "221 bye\r\n",
+    ])
+    .await;
+    run(addr, "selftest@burngate.invalid", false, false, false)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn run_reports_failing_step() {
+    let addr = scripted_server(&["250 test\r\n", "250 OK\r\n", "550 User unknown\r\n"]).await;
+    let err = run(addr, "selftest@burngate.invalid", false, false, false)
+        .await
+        .unwrap_err();
+    match err {
+        SelfTestError::Unexpected { step, reply } => {
+            assert_eq!(step, "RCPT");
+            assert_eq!(reply, "550 User unknown");
+        }
+        other => panic!("unexpected error: {other}"),
+    }
+}
+
+#[tokio::test]
+async fn run_requires_starttls_when_tls_expected() {
+    let addr = scripted_server(&["250 test\r\n"]).await;
+    let err = run(addr, "selftest@burngate.invalid", true, false, false)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        SelfTestError::Unexpected { step: "EHLO", .. }
+    ));
+}
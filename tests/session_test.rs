@@ -118,6 +118,29 @@ fn extract_address_mismatched_brackets() {
     assert_eq!(addr, None);
 }
 
+#[test]
+fn extract_address_quoted_angle_bracket() {
+    let addr = extract_address("TO:<\"weird>name\"@tempy.email> NOTIFY=NEVER");
+    assert_eq!(addr, Some("\"weird>name\"@tempy.email".to_string()));
+}
+
+#[test]
+fn extract_address_escaped_quote() {
+    let addr = extract_address(r#"TO:<"a\">b"@tempy.email>"#);
+    assert_eq!(addr, Some(r#""a\">b"@tempy.email"#.to_string()));
+}
+
+#[test]
+fn extract_address_unterminated_quote() {
+    assert_eq!(extract_address("TO:<\"a>b@tempy.email>"), None);
+}
+
+#[test]
+fn extract_address_strips_source_route() {
+    let addr = extract_address("TO:<@relay.example,@hop.example:user@tempy.email>");
+    assert_eq!(addr, Some("user@tempy.email".to_string()));
+}
+
 // -- is_domain_accepted --
 
 fn make_domains(domains: &[&str]) -> HashSet<String> {
@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use burngate::config::Config;
+use burngate::spamd::{Report, Spamd, SpamdError};
+
+#[test]
+fn reports_are_parsed() {
+    let report = Report::parse(
+        b"SPAMD/1.1 0 EX_OK\r\nContent-length: 21\r\nSpam: True ; 15.3 / 5.0\r\n\r\nBAYES_99,URIBL_BLACK\r\n",
+    )
+    .unwrap();
+    assert!(report.spam);
+    assert_eq!(report.score, 15.3);
+    assert_eq!(report.required, 5.0);
+    assert_eq!(report.tests, ["BAYES_99", "URIBL_BLACK"]);
+    assert_eq!(
+        report.status(),
+        "Yes, score=15.3 required=5.0 tests=BAYES_99,URIBL_BLACK"
+    );
+
+    let report = Report::parse(b"SPAMD/1.1 0 EX_OK\r\nSpam: False ; -0.2 / 5.0\r\n\r\n").unwrap();
+    assert!(!report.spam);
+    assert_eq!(report.status(), "No, score=-0.2 required=5.0 tests=none");
+}
+
+#[test]
+fn failed_or_malformed_replies_are_errors() {
+    for reply in [
+        &b"SPAMD/1.1 76 Bad header line: (EOF)\r\n"[..],
+        b"HTTP/1.1 200 OK\r\n\r\n",
+        b"SPAMD/1.1 0 EX_OK\r\nContent-length: 0\r\n\r\n",
+        b"SPAMD/1.1 0 EX_OK\r\nSpam: True ; lots\r\n\r\n",
+    ] {
+        assert!(matches!(Report::parse(reply), Err(SpamdError::Invalid(_))));
+    }
+}
+
+/// spamd that answers `reply` after `delay`, keeping the last request.
+async fn spamd(reply: &'static str, delay: Duration) -> (String, Arc<Mutex<Vec<u8>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let last = Arc::new(Mutex::new(Vec::new()));
+    let seen = last.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            *seen.lock().unwrap() = request;
+            tokio::time::sleep(delay).await;
+            let _ = stream.write_all(reply.as_bytes()).await;
+        }
+    });
+    (addr, last)
+}
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.spamd_user = Some("burngate".to_string());
+    config.spamd_timeout_ms = 200;
+    config.spamd_max_size = 64;
+    config
+}
+
+#[tokio::test]
+async fn message_is_sent_over_spamc() {
+    let (addr, last) = spamd(
+        "SPAMD/1.1 0 EX_OK\r\nSpam: False ; 1.0 / 5.0\r\n\r\nMISSING_DATE\r\n",
+        Duration::ZERO,
+    )
+    .await;
+    let spamd = Spamd::new(&addr, &config());
+    let report = spamd
+        .check(b"Subject: hi\r\n\r\nhi\r\n")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.tests, ["MISSING_DATE"]);
+    assert_eq!(
+        String::from_utf8(last.lock().unwrap().clone()).unwrap(),
+        "SYMBOLS SPAMC/1.5\r\nContent-length: 19\r\nUser: burngate\r\n\r\nSubject: hi\r\n\r\nhi\r\n"
+    );
+
+    // Over SPAMD_MAX_SIZE: not scanned
+    assert!(spamd.check(&[b'x'; 65]).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn slow_spamd_times_out() {
+    let (addr, _) = spamd(
+        "SPAMD/1.1 0 EX_OK\r\nSpam: False ; 1.0 / 5.0\r\n\r\n",
+        Duration::from_secs(5),
+    )
+    .await;
+    let result = Spamd::new(&addr, &config()).check(b"hi\r\n").await;
+    assert!(matches!(result, Err(SpamdError::Timeout)));
+}
@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use burngate::config::Config;
+use burngate::spamtrap::Spamtraps;
+use burngate::store::{MemoryStore, SharedStore};
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    Config::from_env()
+}
+
+#[test]
+fn disabled_without_addresses() {
+    let store: SharedStore = Arc::new(MemoryStore::new());
+    assert!(Spamtraps::from_config(store, &config()).is_none());
+}
+
+#[tokio::test]
+async fn traps_come_from_config_and_the_store() {
+    let mut config = config();
+    config.spamtrap_addresses = vec!["trap@example.com".to_string()];
+    config.spamtrap_redis_set = Some("spamtraps".to_string());
+    let store = Arc::new(MemoryStore::new());
+    store.set_add("spamtraps", "honey@example.com");
+    let spamtraps = Spamtraps::from_config(store, &config).unwrap();
+
+    assert!(spamtraps.contains("trap@example.com").await);
+    assert!(spamtraps.contains("honey@example.com").await);
+    assert!(!spamtraps.contains("alice@example.com").await);
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use burngate::config::Config;
+use burngate::deadletter::DeadLetter;
+use burngate::esmtp::EsmtpParams;
+use burngate::relay::{Backend, ClientInfo, Recipient};
+use burngate::session::Metrics;
+use burngate::spool::{Spool, SpoolError};
+
+/// Backend that answers MAIL FROM with `mail_reply` and sends each
+/// delivered message's MAIL, RCPT and body lines down the channel.
+async fn backend(mail_reply: &'static str) -> (String, mpsc::UnboundedReceiver<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stream);
+                let _ = reader.get_mut().write_all(b"220 backend\r\n").await;
+                let mut seen = Vec::new();
+                let mut in_data = false;
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let trimmed = line.trim_end().to_string();
+                    let reply = match trimmed.as_str() {
+                        "." if in_data => {
+                            in_data = false;
+                            let _ = tx.send(std::mem::take(&mut seen));
+                            "250 queued\r\n"
+                        }
+                        _ if in_data => {
+                            seen.push(trimmed);
+                            continue;
+                        }
+                        "DATA" => {
+                            in_data = true;
+                            "354 go\r\n"
+                        }
+                        c if c.starts_with("MAIL") => {
+                            seen.push(trimmed);
+                            mail_reply
+                        }
+                        c if c.starts_with("RCPT") => {
+                            seen.push(trimmed);
+                            "250 OK\r\n"
+                        }
+                        c if c.starts_with("EHLO") => "250-backend\r\n250-8BITMIME\r\n250 DSN\r\n",
+                        "QUIT" => "221 bye\r\n",
+                        _ => "250 OK\r\n",
+                    };
+                    let _ = reader.get_mut().write_all(reply.as_bytes()).await;
+                }
+            });
+        }
+    });
+    (addr, rx)
+}
+
+/// Fresh, empty spool directory under the system temp dir.
+fn spool_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("burngate-spool-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn config(max_messages: usize) -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.spool_max_messages = max_messages;
+    config.spool_retry_initial_secs = 0;
+    config.data_timeout_secs = 5;
+    config
+}
+
+fn open(dir: &Path, addr: &str, max_messages: usize) -> Spool {
+    let backend = Backend::new(addr, false, 0, Duration::from_secs(30));
+    Spool::open(
+        dir.to_str().unwrap(),
+        &config(max_messages),
+        backend,
+        Arc::new(Metrics::new()),
+    )
+    .unwrap()
+}
+
+async fn enqueue(spool: &Spool) -> Result<(), SpoolError> {
+    let params = EsmtpParams::parse("NOTIFY=NEVER").unwrap();
+    let client = ClientInfo {
+        addr: "192.0.2.1:40000".parse().unwrap(),
+        helo: Some("mx.example.org".to_string()),
+        proto: "ESMTP".to_string(),
+    };
+    let recipients = [Recipient {
+        address: "alice@example.com",
+        params: &params,
+    }];
+    spool
+        .enqueue(
+            Some(&client),
+            "a@b.c",
+            &EsmtpParams::parse("BODY=8BITMIME").unwrap(),
+            &recipients,
+            b"Subject: hi\r\n\r\nbody\r\n",
+        )
+        .await
+}
+
+fn files(dir: &Path, extension: &str) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == extension)
+        })
+        .count()
+}
+
+/// Wait until the spool has no messages left.
+async fn drained(spool: &Spool) {
+    for _ in 0..250 {
+        if spool.is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("spool was not drained");
+}
+
+// -- enqueue --
+
+#[tokio::test]
+async fn enqueue_writes_message_to_disk() {
+    let dir = spool_dir("enqueue");
+    let spool = open(&dir, "127.0.0.1:9", 10);
+    enqueue(&spool).await.unwrap();
+    assert_eq!(spool.len(), 1);
+    assert_eq!(files(&dir, "msg"), 1);
+    assert_eq!(files(&dir, "tmp"), 0);
+}
+
+#[tokio::test]
+async fn full_spool_refuses_message() {
+    let dir = spool_dir("full");
+    let spool = open(&dir, "127.0.0.1:9", 1);
+    enqueue(&spool).await.unwrap();
+    assert!(matches!(enqueue(&spool).await, Err(SpoolError::Full)));
+    assert_eq!(files(&dir, "msg"), 1);
+}
+
+#[tokio::test]
+async fn messages_on_disk_are_requeued_at_open() {
+    let dir = spool_dir("reopen");
+    enqueue(&open(&dir, "127.0.0.1:9", 10)).await.unwrap();
+    assert_eq!(open(&dir, "127.0.0.1:9", 10).len(), 1);
+}
+
+// -- delivery --
+
+#[tokio::test]
+async fn worker_delivers_envelope_and_removes_file() {
+    let dir = spool_dir("deliver");
+    let (addr, mut rx) = backend("250 OK\r\n").await;
+    let spool = open(&dir, &addr, 10);
+    enqueue(&spool).await.unwrap();
+    tokio::spawn(spool.clone().run());
+
+    let seen = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(seen[0], "MAIL FROM:<a@b.c> BODY=8BITMIME");
+    assert_eq!(seen[1], "RCPT TO:<alice@example.com> NOTIFY=NEVER");
+    assert_eq!(seen[2..], ["Subject: hi", "", "body"]);
+    drained(&spool).await;
+    assert_eq!(files(&dir, "msg"), 0);
+}
+
+#[tokio::test]
+async fn permanently_refused_message_is_kept_as_failed() {
+    let dir = spool_dir("refused");
+    let (addr, _rx) = backend("550 5.7.1 no\r\n").await;
+    let spool = open(&dir, &addr, 10);
+    enqueue(&spool).await.unwrap();
+    tokio::spawn(spool.clone().run());
+
+    drained(&spool).await;
+    assert_eq!(files(&dir, "msg"), 0);
+    assert_eq!(files(&dir, "failed"), 1);
+}
+
+#[tokio::test]
+async fn refused_message_moves_to_dead_letters() {
+    let dir = spool_dir("dead-letter");
+    let dead_dir = spool_dir("dead-letter-sink");
+    let (addr, _rx) = backend("550 5.7.1 no\r\n").await;
+    let metrics = Arc::new(Metrics::new());
+    let dead_letter = DeadLetter::dir(dead_dir.to_str().unwrap(), metrics.clone()).unwrap();
+    let spool = open(&dir, &addr, 10).with_dead_letter(Some(dead_letter));
+    enqueue(&spool).await.unwrap();
+    tokio::spawn(spool.clone().run());
+
+    drained(&spool).await;
+    assert_eq!(files(&dir, "msg"), 0);
+    assert_eq!(files(&dir, "failed"), 0);
+    assert_eq!(files(&dead_dir, "eml"), 1);
+    assert_eq!(metrics.dead_lettered.load(Ordering::Relaxed), 1);
+}
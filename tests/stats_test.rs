@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use burngate::audit::unix_now;
+use burngate::config::Config;
+use burngate::stats::DeliveryStats;
+use burngate::store::{MemoryStore, Store};
+
+fn stats(store: &MemoryStore) -> DeliveryStats {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.stats_retention_days = 2;
+    DeliveryStats::new(Arc::new(store.clone()), &config)
+}
+
+// -- DeliveryStats --
+
+#[tokio::test]
+async fn deliveries_count_per_domain_and_mailbox_per_day() {
+    let store = MemoryStore::new();
+    let stats = stats(&store);
+    stats
+        .record(&["Alice@example.com", "bob@example.com"])
+        .await;
+    stats.record(&["alice@example.com"]).await;
+
+    let day = unix_now() / 86_400;
+    let count = |key: String| {
+        let store = store.clone();
+        async move { store.get(&key).await.unwrap() }
+    };
+    assert_eq!(
+        count(format!("stats:example.com:accepted:{day}"))
+            .await
+            .as_deref(),
+        Some("3")
+    );
+    assert_eq!(
+        count(format!("stats:alice@example.com:received:{day}"))
+            .await
+            .as_deref(),
+        Some("2")
+    );
+    assert_eq!(
+        count(format!("stats:bob@example.com:received:{day}"))
+            .await
+            .as_deref(),
+        Some("1")
+    );
+    let ttl = store
+        .ttl(&format!("stats:example.com:accepted:{day}"))
+        .await
+        .unwrap();
+    assert!(ttl.is_some_and(|secs| secs <= 2 * 86_400));
+}
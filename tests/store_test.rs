@@ -0,0 +1,211 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use burngate::config::Config;
+use burngate::session::Metrics;
+use burngate::store::{FailoverStore, MemoryStore, Store, StoreError};
+use tokio::time::Duration;
+
+// -- MemoryStore --
+
+#[tokio::test]
+async fn missing_keys_are_empty() {
+    let store = MemoryStore::new();
+    assert!(!store.exists("k").await.unwrap());
+    assert_eq!(store.get("k").await.unwrap(), None);
+    assert_eq!(store.set_contains("k", &["a"]).await.unwrap(), vec![false]);
+    assert!(store.hash_get_all("k").await.unwrap().is_empty());
+    assert!(store.list_all("k").await.unwrap().is_empty());
+    assert_eq!(store.ttl("k").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn set_membership_in_order() {
+    let store = MemoryStore::new();
+    store.set_add("blocked", "spam@evil.com");
+    store.set_add("blocked", "evil.org");
+    let found = store
+        .set_contains("blocked", &["spam@evil.com", "good.com", "evil.org"])
+        .await
+        .unwrap();
+    assert_eq!(found, vec![true, false, true]);
+    assert!(store.exists("blocked").await.unwrap());
+}
+
+#[tokio::test]
+async fn exists_and_contains_answers_both() {
+    let store = MemoryStore::new();
+    store.set_ex("k", "1", 60).await.unwrap();
+    store.set_add("s", "a");
+    let both = |key, member| store.exists_and_contains(key, "s", member);
+    assert_eq!(both("k", "a").await.unwrap(), (true, true));
+    assert_eq!(both("k", "b").await.unwrap(), (true, false));
+    assert_eq!(both("x", "a").await.unwrap(), (false, true));
+    assert!(matches!(
+        store.exists_and_contains("x", "k", "a").await,
+        Err(StoreError::WrongType(_))
+    ));
+}
+
+#[tokio::test]
+async fn keys_between_matches_prefix_and_suffix() {
+    let store = MemoryStore::new();
+    for key in ["mb:a@x", "mb:b@x:meta", "mb:", "other:c@x"] {
+        store.set_ex(key, "1", 60).await.unwrap();
+    }
+    let mut keys = store.keys_between("mb:", "").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["mb:", "mb:a@x", "mb:b@x:meta"]);
+    assert_eq!(
+        store.keys_between("mb:", ":meta").await.unwrap(),
+        ["mb:b@x:meta"]
+    );
+
+    store.set_add("s", "a");
+    assert_eq!(store.set_members("s").await.unwrap(), ["a"]);
+    assert!(store.set_members("missing").await.unwrap().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn set_ex_expires() {
+    let store = MemoryStore::new();
+    store.set_ex("mb:a@b.c", "1", 60).await.unwrap();
+    assert_eq!(store.get("mb:a@b.c").await.unwrap().as_deref(), Some("1"));
+    assert_eq!(store.ttl("mb:a@b.c").await.unwrap(), Some(60));
+
+    tokio::time::advance(Duration::from_secs(61)).await;
+    assert!(!store.exists("mb:a@b.c").await.unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn incr_keeps_first_expiry() {
+    let store = MemoryStore::new();
+    assert_eq!(store.incr("count", 10).await.unwrap(), 1);
+    tokio::time::advance(Duration::from_secs(6)).await;
+    assert_eq!(store.incr("count", 10).await.unwrap(), 2);
+
+    // Window started at the first increment, not the second
+    tokio::time::advance(Duration::from_secs(5)).await;
+    assert_eq!(store.incr("count", 10).await.unwrap(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn decr_takes_back_an_incr() {
+    let store = MemoryStore::new();
+    store.incr("count", 10).await.unwrap();
+    assert_eq!(store.incr("count", 10).await.unwrap(), 2);
+    assert_eq!(store.decr("count", 10).await.unwrap(), 1);
+
+    // And keeps the expiry the first increment started
+    tokio::time::advance(Duration::from_secs(11)).await;
+    assert!(!store.exists("count").await.unwrap());
+}
+
+#[tokio::test(start_paused = true)]
+async fn list_push_and_replace() {
+    let store = MemoryStore::new();
+    store.list_push("log", "a", 100).await.unwrap();
+    store.list_push("log", "b", 100).await.unwrap();
+    assert_eq!(store.list_all("log").await.unwrap(), vec!["a", "b"]);
+
+    store
+        .list_replace("log", &["c".to_string()], 50)
+        .await
+        .unwrap();
+    assert_eq!(store.list_all("log").await.unwrap(), vec!["c"]);
+    assert_eq!(store.ttl("log").await.unwrap(), Some(50));
+
+    store.list_replace("log", &[], 50).await.unwrap();
+    assert!(!store.exists("log").await.unwrap());
+}
+
+#[tokio::test]
+async fn delete_removes_key() {
+    let store = MemoryStore::new();
+    store.set_ex("k", "v", 10).await.unwrap();
+    store.delete("k").await.unwrap();
+    assert!(!store.exists("k").await.unwrap());
+}
+
+#[tokio::test]
+async fn wrong_type_is_an_error() {
+    let store = MemoryStore::new();
+    store.set_add("s", "x");
+    assert!(matches!(
+        store.list_all("s").await,
+        Err(StoreError::WrongType(_))
+    ));
+    assert!(matches!(
+        store.incr("s", 10).await,
+        Err(StoreError::WrongType(_))
+    ));
+}
+
+#[tokio::test]
+async fn subscribers_get_published_messages() {
+    let store = MemoryStore::new();
+    assert_eq!(store.publish("news", "lost"), 0);
+    let mut first = store.subscribe("news").await.unwrap();
+    let second = store.subscribe("news").await.unwrap();
+    assert_eq!(store.publish("news", "hello"), 2);
+    assert_eq!(first.recv().await.as_deref(), Some("hello"));
+
+    drop(second);
+    assert_eq!(store.publish("news", "again"), 1);
+    assert_eq!(store.publish("other", "x"), 0);
+}
+
+// -- FailoverStore --
+
+fn failover(urls: &[&str], backoff_ms: u64) -> (FailoverStore, Arc<Metrics>) {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.redis_timeout_ms = 200;
+    config.redis_backoff_initial_ms = backoff_ms;
+    let urls: Vec<String> = urls.iter().map(|u| u.to_string()).collect();
+    let metrics = Arc::new(Metrics::new());
+    let store = FailoverStore::new(&urls, &config, metrics.clone()).unwrap();
+    (store, metrics)
+}
+
+#[tokio::test]
+async fn failover_fails_when_no_node_answers() {
+    let (store, metrics) = failover(&["redis://127.0.0.1:1", "redis://:secret@127.0.0.1:2"], 0);
+    assert!(matches!(store.connect().await, Err(StoreError::Redis(_))));
+    assert!(store.exists("k").await.is_err());
+    assert_eq!(store.current_addr(), "127.0.0.1:1");
+    assert_eq!(metrics.redis_consecutive_errors.load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.redis_last_success.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn unreachable_redis_is_retried_after_a_backoff() {
+    let (store, metrics) = failover(&["redis://127.0.0.1:1"], 50);
+    assert!(matches!(store.exists("k").await, Err(StoreError::Redis(_))));
+    // Backing off: no attempt is made
+    assert!(matches!(
+        store.exists("k").await,
+        Err(StoreError::Unavailable)
+    ));
+    assert_eq!(metrics.redis_consecutive_errors.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.redis_reconnects.load(Ordering::Relaxed), 0);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert!(matches!(store.exists("k").await, Err(StoreError::Redis(_))));
+    assert_eq!(metrics.redis_consecutive_errors.load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.redis_reconnects.load(Ordering::Relaxed), 1);
+    // The delay doubled
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert!(matches!(
+        store.exists("k").await,
+        Err(StoreError::Unavailable)
+    ));
+}
+
+#[test]
+fn failover_rejects_bad_urls() {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let config = Config::from_env();
+    let urls = vec!["redis://127.0.0.1".to_string(), "not a url".to_string()];
+    assert!(FailoverStore::new(&urls, &config, Arc::new(Metrics::new())).is_err());
+}
@@ -0,0 +1,169 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use burngate::config::Config;
+use burngate::http::HttpUrl;
+use burngate::session::Metrics;
+use burngate::webhook::{DeliveryEvent, Webhook};
+
+/// HTTP endpoint answering successive requests with `statuses` (the last
+/// one repeats). Each request's head and body are sent down the channel.
+async fn endpoint(statuses: &'static [u16]) -> (String, mpsc::UnboundedReceiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks/delivery", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut served = 0;
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+            let _ = tx.send((head, String::from_utf8(body).unwrap()));
+
+            let status = statuses[served.min(statuses.len() - 1)];
+            served += 1;
+            let response = format!("HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\n\r\n");
+            let _ = reader.get_mut().write_all(response.as_bytes()).await;
+        }
+    });
+    (url, rx)
+}
+
+fn config() -> Config {
+    std::env::set_var("ACCEPTED_DOMAINS", "example.com");
+    let mut config = Config::from_env();
+    config.webhook_token = Some("s3cret".to_string());
+    config.webhook_retries = 2;
+    config.webhook_retry_backoff_ms = 1;
+    config
+}
+
+fn event() -> DeliveryEvent {
+    DeliveryEvent {
+        session_id: Some("671E2C4000001".to_string()),
+        sender: "a@b.c".to_string(),
+        recipients: vec!["alice@example.com".to_string()],
+        size: 42,
+        message_id: Some("<1@b.c>".to_string()),
+    }
+}
+
+async fn recv(rx: &mut mpsc::UnboundedReceiver<(String, String)>) -> (String, String) {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+async fn wait_for_errors(metrics: &Metrics, count: u64) {
+    for _ in 0..100 {
+        if metrics.webhook_errors.load(Ordering::Relaxed) == count {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("webhook errors never reached {count}");
+}
+
+// -- URLs --
+
+#[test]
+fn urls_split_into_host_port_and_path() {
+    let url: HttpUrl = "https://hooks.tempy.email/v1/delivered?x=1"
+        .parse()
+        .unwrap();
+    assert!(url.https);
+    assert_eq!(url.host, "hooks.tempy.email");
+    assert_eq!(url.port, 443);
+    assert_eq!(url.path, "/v1/delivered?x=1");
+
+    let url: HttpUrl = "http://[::1]:8080".parse().unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("[::1]", 8080, "/")
+    );
+
+    assert!("ftp://x/".parse::<HttpUrl>().is_err());
+    assert!("http://:80/".parse::<HttpUrl>().is_err());
+    assert!("http://x:port/".parse::<HttpUrl>().is_err());
+}
+
+// -- delivery --
+
+#[tokio::test]
+async fn event_is_posted_as_json() {
+    let (url, mut rx) = endpoint(&[204]).await;
+    let metrics = Arc::new(Metrics::new());
+    let webhook = Webhook::new(&url, &config(), metrics.clone());
+
+    assert!(webhook.submit(event()));
+    let (head, body) = recv(&mut rx).await;
+    assert!(head.starts_with("POST /hooks/delivery HTTP/1.1\r\n"));
+    assert!(head.contains("Content-Type: application/json\r\n"));
+    assert!(head.contains("Authorization: Bearer s3cret\r\n"));
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["event"], "delivered");
+    assert_eq!(json["session_id"], "671E2C4000001");
+    assert_eq!(json["recipients"][0], "alice@example.com");
+    assert_eq!(json["size"], 42);
+    assert_eq!(json["message_id"], "<1@b.c>");
+    assert_eq!(metrics.webhook_errors.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn server_errors_are_retried() {
+    let (url, mut rx) = endpoint(&[503, 200]).await;
+    let metrics = Arc::new(Metrics::new());
+    let webhook = Webhook::new(&url, &config(), metrics.clone());
+
+    webhook.submit(event());
+    let (_, first) = recv(&mut rx).await;
+    let (_, second) = recv(&mut rx).await;
+    assert_eq!(first, second);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(metrics.webhook_errors.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn client_errors_are_not_retried() {
+    let (url, mut rx) = endpoint(&[400]).await;
+    let metrics = Arc::new(Metrics::new());
+    let webhook = Webhook::new(&url, &config(), metrics.clone());
+
+    webhook.submit(event());
+    recv(&mut rx).await;
+    wait_for_errors(&metrics, 1).await;
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn gives_up_after_retries() {
+    let (url, mut rx) = endpoint(&[500]).await;
+    let metrics = Arc::new(Metrics::new());
+    let webhook = Webhook::new(&url, &config(), metrics.clone());
+
+    webhook.submit(event());
+    wait_for_errors(&metrics, 1).await;
+    for _ in 0..3 {
+        recv(&mut rx).await;
+    }
+    assert!(rx.try_recv().is_err());
+}